@@ -0,0 +1,111 @@
+//! Alerts on failed commands - a JSON webhook and/or an email sent by the local `sendmail`.
+//!
+//! Alerts carry the class of the failure (see [`FailureClass`]), so alerting rules can tell an unreachable server from
+//! corrupted backup data. Failures to deliver an alert are reported, but don't change the result of the command.
+
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use err_context::AnyError;
+use serde::Serialize;
+use structopt::StructOpt;
+use url::Url;
+
+use crate::errors::{self, FailureClass};
+
+const SENDMAIL: &str = "sendmail";
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct AlertOptions {
+    /// URL failures are POSTed to as JSON, with the class of the failure (`network`, `auth`, `corruption`, `quota` or
+    /// `other`)
+    #[structopt(long, env = "RBACKUP_ALERT_WEBHOOK")]
+    pub alert_webhook: Option<Url>,
+    /// Address failures are mailed to, by the local `sendmail`
+    #[structopt(long, env = "RBACKUP_ALERT_EMAIL")]
+    pub alert_email: Option<String>,
+    /// Alert just failures of these classes (comma separated); all of them when not set
+    #[structopt(long, use_delimiter = true)]
+    pub alert_on: Vec<FailureClass>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub profile: String,
+    pub host: String,
+    /// Unix timestamp (seconds) of the failure
+    pub timestamp: u64,
+    pub error: String,
+    pub class: FailureClass,
+}
+
+impl Alert {
+    pub fn of(profile: &str, e: &AnyError) -> Alert {
+        let mut buf = [0u8; 256];
+        let host = nix::unistd::gethostname(&mut buf)
+            .map(|h| h.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        Alert {
+            profile: profile.to_string(),
+            host,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs(),
+            error: e.to_string(),
+            class: errors::classify_any(e),
+        }
+    }
+}
+
+fn post_webhook(url: &Url, alert: &Alert) -> Result<(), AnyError> {
+    let client = reqwest::blocking::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?;
+    let resp = client.post(url.as_str()).json(alert).send()?;
+
+    if !resp.status().is_success() {
+        return Err(AnyError::from(format!("Webhook responded with {}", resp.status())));
+    }
+
+    Ok(())
+}
+
+fn mail(to: &str, alert: &Alert) -> io::Result<()> {
+    let mut child = Command::new(SENDMAIL).arg("-t").stdin(Stdio::piped()).spawn()?;
+
+    {
+        let mut stdin = child.stdin.take().expect("Missing sendmail input");
+        write!(
+            stdin,
+            "To: {}\nSubject: rbackup2 {} failed on {} ({})\n\n{}\n\nFailure class: {}\n",
+            to, alert.profile, alert.host, alert.class, alert.error, alert.class
+        )?;
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::new(ErrorKind::Other, format!("{} exited with {}", SENDMAIL, status)));
+    }
+
+    Ok(())
+}
+
+/// Sends the alert wherever configured.
+pub fn send(options: &AlertOptions, alert: &Alert) {
+    if !options.alert_on.is_empty() && !options.alert_on.contains(&alert.class) {
+        return;
+    }
+
+    if let Some(url) = &options.alert_webhook {
+        if let Err(e) = post_webhook(url, alert) {
+            eprintln!("Warning: could not send the alert to {}: {}", url, e);
+        }
+    }
+
+    if let Some(to) = &options.alert_email {
+        if let Err(e) = mail(to, alert) {
+            eprintln!("Warning: could not mail the alert to {}: {}", to, e);
+        }
+    }
+}
@@ -0,0 +1,1005 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use err_context::AnyError;
+use libcommon::paths::{self, ObjectType, NAMES_DIR};
+use libcommon::structs::{
+    CapabilitiesResponse, CapacityReport, CatalogEntry, CleanupReport, GcStatus, LatencyResponse, LocksResponse, LogEvent,
+    MaintenanceRequest, NameInfo, Priority,
+};
+use log::*;
+use rdedup_lib::backends::{Backend, BackendThread};
+use rdedup_lib::{DecryptHandle, EncryptHandle, PassphraseFn, Repo as RdedupRepo, WriteStats};
+use sgdata::SGData;
+use url::Url;
+use uuid::Uuid;
+
+use crate::callbacks;
+use crate::delta;
+use crate::device::{self, DeviceOptions, Snapshot};
+use crate::failed_queue::FailedQueue;
+use crate::integrity::{self, VerifyOptions};
+use crate::inventory::{self, Inventory};
+use crate::keys::{self, KeySlot, KEYS_DIR};
+use crate::local_crypt::LocalKey;
+use crate::memory;
+use crate::peer::{ChunkCacheDir, Peers};
+use crate::pipe;
+use crate::progress::{self, ProgressReader};
+use crate::recovery::{self, RECOVERY_SLOT};
+use crate::remote::{RemoteBackend, CHUNK_CACHE};
+use crate::reports::*;
+use crate::resume::{self, ProgressWriter};
+use crate::runs::{self, Checkpoint};
+use crate::snapshot::{self, RestoreOptions};
+use crate::transport::Transport;
+use crate::verify::{self, Sample};
+
+const VERIFY_CACHE_SIZE: usize = 256 * 1024 * 1024;
+
+const CONFIG_FILE: &str = "config.yml";
+
+/// Temporary names chunks are uploaded under by a repair
+const REPAIR_NAME_PREFIX: &str = "rbackup2-repair-";
+
+/// Number of buffers in flight between the archiver and the repo, unless limited by memory budget
+pub(crate) const PIPE_CAPACITY: usize = 64;
+
+/// Client of a single repository served by rbackup2 server.
+pub struct Client {
+    remote: RemoteBackend,
+    repo: RdedupRepo,
+    capabilities: CapabilitiesResponse,
+    /// Client state dir, keeping e.g. progress of restores
+    state_dir: Option<PathBuf>,
+    /// Names are stored in it, see `set_namespace`
+    namespace: Option<String>,
+}
+
+impl Client {
+    /// Opens the repository; `token` is attached to all requests sent to the server, written objects are signed by
+    /// `signing_key` when set.
+    pub fn open(server_url: Url, token: Option<String>, signing_key: Option<String>) -> Result<Client, AnyError> {
+        Client::open_backend(RemoteBackend::new(server_url, token, signing_key))
+    }
+
+    /// Same as `open`, but keeps the repository config cached in client `state_dir`, so commands don't download it again
+    /// and again over slow links. The cache is encrypted by `shared_key`, if any - the key of data on the local disk
+    /// shared by all the profiles (see `set_local_key`).
+    pub fn open_cached(
+        server_url: Url,
+        token: Option<String>,
+        signing_key: Option<String>,
+        state_dir: &Path,
+        shared_key: Option<LocalKey>,
+    ) -> Result<Client, AnyError> {
+        let remote = RemoteBackend::new(server_url, token, signing_key);
+        if let Some(key) = shared_key {
+            remote.set_shared_key(key);
+        }
+        remote.set_config_cache(state_dir);
+
+        let mut client = Client::open_backend(remote)?;
+        client.state_dir = Some(state_dir.to_path_buf());
+
+        Ok(client)
+    }
+
+    /// Opens the repository talking to the server through given transport instead of the default HTTP client.
+    pub fn open_with_transport(
+        server_url: Url,
+        token: Option<String>,
+        signing_key: Option<String>,
+        transport: Arc<dyn Transport>,
+    ) -> Result<Client, AnyError> {
+        Client::open_backend(RemoteBackend::with_transport(server_url, token, signing_key, transport))
+    }
+
+    fn open_backend(remote: RemoteBackend) -> Result<Client, AnyError> {
+        let server_url = remote.server_url().clone();
+        let capabilities = remote.negotiate()?;
+
+        // the repo must use the very same backend so settings made through the client (e.g. retention) apply
+        let create_backend = {
+            let remote = remote.clone();
+            move |_: &url1::Url| -> io::Result<Box<dyn Backend + Send + Sync>> { Ok(Box::new(remote.clone())) }
+        };
+
+        let repo = RdedupRepo::open_custom(&url1::Url::parse(server_url.as_str())?, &create_backend, None)?;
+
+        Ok(Client {
+            remote,
+            repo,
+            capabilities,
+            state_dir: None,
+            namespace: None,
+        })
+    }
+
+    pub fn repo(&self) -> &RdedupRepo {
+        &self.repo
+    }
+
+    /// Confines names to `namespace` (e.g. hostname), so machines sharing a repository don't collide: names given to
+    /// the client are stored prefixed by it, and names of other namespaces are not listed.
+    pub fn set_namespace(&mut self, namespace: String) {
+        self.namespace = Some(namespace);
+    }
+
+    /// Name `name` is stored under.
+    fn stored_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        match &self.namespace {
+            Some(namespace) => Cow::Owned(paths::namespaced_name(namespace, name)),
+            None => Cow::Borrowed(name),
+        }
+    }
+
+    /// Whether stored name `name` belongs to the namespace of the client.
+    fn in_namespace(&self, name: &str) -> bool {
+        match &self.namespace {
+            Some(namespace) => paths::strip_namespace(namespace, name).is_some(),
+            None => true,
+        }
+    }
+
+    /// Stores `source` file or directory under `name`; with `retain_until` (unix timestamp) set, the server refuses to
+    /// remove or overwrite the name before that time.
+    pub fn store(&self, source: &Path, name: &str, retain_until: Option<u64>, passfn: PassphraseFn) -> io::Result<StoreResult> {
+        let name = &self.stored_name(name);
+        let stored = self.store_with(source, name, retain_until, passfn, |wh| self.write_source(source, name, wh));
+        callbacks::report("store", name, stored)
+    }
+
+    /// Stores image of block device `device` under `name`, read from a snapshot of it when asked for.
+    pub fn store_device(
+        &self,
+        device: &Path,
+        name: &str,
+        retain_until: Option<u64>,
+        options: &DeviceOptions,
+        passfn: PassphraseFn,
+    ) -> io::Result<StoreResult> {
+        let name = &self.stored_name(name);
+        // released once the image is stored
+        let snapshot = match options.snapshot {
+            Some(kind) => Some(Snapshot::create(kind, device, options)?),
+            None => None,
+        };
+        let source = snapshot.as_ref().map(Snapshot::device).unwrap_or(device);
+
+        let stored = self.store_with(device, name, retain_until, passfn, |wh| {
+            let mut file = device::open(source)?;
+            let len = device::size(&mut file)?;
+            progress::start("store", name, Some(len));
+            progress::set_current_file(source);
+            Ok(((len, 1), self.repo.write(name, ProgressReader::new(&file), wh)?))
+        });
+        callbacks::report("store", name, stored)
+    }
+
+    /// Writes `source` file or directory under `name`, returning size and count of the source files, and write stats.
+    fn write_source(&self, source: &Path, name: &str, wh: &EncryptHandle) -> io::Result<((u64, u64), WriteStats)> {
+        if source.is_dir() {
+            progress::start("store", name, None);
+
+            let source = source.to_path_buf();
+            self.write_piped(name, wh, move |writer| snapshot::write_tree(&source, writer))
+        } else {
+            let file = std::fs::File::open(source)?;
+            let metadata = file.metadata()?;
+            // pipes (FIFOs, process substitution) have no length, their size is known only once they are read whole
+            let total = if metadata.is_file() { Some(metadata.len()) } else { None };
+            progress::start("store", name, total);
+            progress::set_current_file(source);
+
+            let mut reader = ProgressReader::new(&file);
+            let stats = self.repo.write(name, &mut reader, wh)?;
+            Ok(((reader.read_bytes(), 1), stats))
+        }
+    }
+
+    /// Stores just the metadata of `source` file tree under `name`, without the contents of the files; with `hash`, the
+    /// files are read to record hashes of their contents. See `inventory`.
+    pub fn store_inventory(
+        &self,
+        source: &Path,
+        name: &str,
+        retain_until: Option<u64>,
+        hash: bool,
+        passfn: PassphraseFn,
+    ) -> io::Result<StoreResult> {
+        let name = &self.stored_name(name);
+        let stored = self.store_with(source, name, retain_until, passfn, |wh| {
+            progress::start("store", name, None);
+
+            let source = source.to_path_buf();
+            self.write_piped(name, wh, move |writer| inventory::write(&source, hash, writer))
+        });
+        callbacks::report("store", name, stored)
+    }
+
+    /// Makes following stores queue chunks and indexes failing to upload on the network in the client state dir instead
+    /// of failing right away; the stores still fail in the end, without storing the name. See `retry_failed`.
+    pub fn queue_failed_uploads(&self) -> io::Result<()> {
+        self.remote.set_failed_queue(self.failed_queue()?);
+        Ok(())
+    }
+
+    /// Uploads objects queued by stores which failed (see `queue_failed_uploads`), storing their names at last.
+    pub fn retry_failed(&self) -> io::Result<RetryFailedResult> {
+        let start = Instant::now();
+        let queue = self.failed_queue()?;
+        // keeps GC away, same as during stores
+        let _lock = self.remote.lock_shared()?;
+        let mut thread = self.remote.new_thread()?;
+
+        let mut names = Vec::new();
+        let mut uploaded = 0;
+        // on the server, but possibly in a batch not sent yet
+        let mut written = Vec::new();
+
+        // names come last, once all the data they reference are stored
+        for object in queue.list()? {
+            let data = queue.read(&object, self.remote.shared_key())?;
+            let is_name = ObjectType::of(&object.path) == ObjectType::Name;
+
+            if is_name {
+                self.remote.set_retention(object.retain_until);
+            }
+
+            // writes of names send the batched writes first
+            thread.write(object.path.clone(), data, false)?;
+            uploaded += 1;
+            written.push(object);
+
+            if is_name {
+                for object in written.drain(..) {
+                    queue.remove(&object)?;
+
+                    if ObjectType::of(&object.path) == ObjectType::Name {
+                        let name = object.path.strip_prefix(NAMES_DIR).unwrap_or(&object.path);
+                        names.push(name.to_string_lossy().into_owned());
+                    }
+                }
+            }
+        }
+
+        self.remote.flush_writes()?;
+        for object in written {
+            queue.remove(&object)?;
+        }
+
+        Ok(RetryFailedResult {
+            uploaded,
+            names,
+            duration_ms: start.elapsed().as_millis(),
+        })
+    }
+
+    /// Cleans up after stores of the client namespace interrupted by the death of their process (see `runs`): releases
+    /// their locks left on the server and removes their names staged there. Returns the names, storing them again
+    /// resumes the stores. Without client state dir there are no checkpoints, so nothing to clean up.
+    pub fn recover_interrupted(&self) -> io::Result<Vec<String>> {
+        let state_dir = match &self.state_dir {
+            Some(state_dir) => state_dir,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut names = Vec::new();
+
+        for (checkpoint, run) in runs::interrupted(state_dir, self.remote.server_url())? {
+            if !self.in_namespace(&run.name) {
+                continue;
+            }
+
+            warn!(
+                "Store of {} started at {} by process {} was interrupted, cleaning up after it",
+                run.name, run.started, run.pid
+            );
+
+            for lock in &run.locks {
+                self.remote.release_lock(*lock)?;
+            }
+
+            match self.remote.remove_pending_name(&run.name) {
+                Ok(()) => (),
+                // e.g. append-only tokens; the server cleanup removes it in the end
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    warn!("Could not remove staged name {}, left to the server: {}", run.name, e)
+                }
+                Err(e) => return Err(e),
+            }
+
+            runs::remove(&checkpoint)?;
+
+            let name = match &self.namespace {
+                Some(namespace) => paths::strip_namespace(namespace, &run.name).unwrap_or(&run.name),
+                None => &run.name,
+            };
+            names.push(name.to_string());
+        }
+
+        Ok(names)
+    }
+
+    /// Queue of failed uploads of this repository in the client state dir.
+    fn failed_queue(&self) -> io::Result<FailedQueue> {
+        let state_dir = self.state_dir.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Failed uploads can be queued only with client state dir",
+            )
+        })?;
+        let (_, config) = self.read_config()?;
+
+        Ok(FailedQueue::open(state_dir, self.remote.server_url(), &config.to_linear_vec()))
+    }
+
+    /// Stores data written by `write` (returning size and count of the source files, and write stats) and records them
+    /// into the catalog.
+    fn store_with(
+        &self,
+        source: &Path,
+        name: &str,
+        retain_until: Option<u64>,
+        passfn: PassphraseFn,
+        write: impl FnOnce(&EncryptHandle) -> io::Result<((u64, u64), WriteStats)>,
+    ) -> io::Result<StoreResult> {
+        let start = Instant::now();
+
+        // ended (and the checkpoint removed) once the store returns, whatever the outcome
+        let _run = match &self.state_dir {
+            Some(state_dir) => {
+                self.recover_interrupted()?;
+                Some(
+                    self.remote
+                        .checkpoint(Checkpoint::start(state_dir, self.remote.server_url(), name, source)?),
+                )
+            }
+            None => None,
+        };
+
+        self.remote.set_retention(retain_until);
+        self.remote.expect_name_version(name)?;
+
+        let wh = self.repo.unlock_encrypt(&passfn)?;
+
+        let ((source_bytes, files), stats) = write(&wh)?;
+        debug!("Source {:?} stats {:?}", source, stats);
+
+        let entry = CatalogEntry {
+            name: name.to_string(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs(),
+            source_bytes,
+            new_bytes: stats.new_bytes,
+        };
+
+        // the data are stored already, the catalog is just for capacity planning
+        if let Err(e) = self.remote.record_catalog(&entry) {
+            warn!("Could not record {} into the server catalog: {}", name, e);
+        }
+
+        Ok(StoreResult {
+            name: name.to_string(),
+            source_bytes,
+            files,
+            new_chunks: stats.new_chunks,
+            new_bytes: stats.new_bytes,
+            duration_ms: start.elapsed().as_millis(),
+        })
+    }
+
+    /// Restores `name` into `dest` - a file, or a directory when the name holds a directory snapshot. With `delta`, only
+    /// data not present in existing `dest` directory are downloaded.
+    ///
+    /// Progress of file restores is recorded in the state dir (when the client has one); `resume` continues an
+    /// interrupted restore of the file where it stopped. Directories can't be resumed that way, for them it's the same
+    /// as `delta`.
+    ///
+    /// Restored files are checked against the hashes recorded at store time as `verify` asks.
+    #[allow(clippy::too_many_arguments)]
+    pub fn restore(
+        &self,
+        name: &str,
+        dest: &Path,
+        options: &RestoreOptions,
+        verify: &VerifyOptions,
+        delta: bool,
+        resume: bool,
+        passfn: PassphraseFn,
+    ) -> io::Result<RestoreResult> {
+        let name = &self.stored_name(name);
+        // someone is waiting for the data, the server serves them before backups
+        let _priority = self.remote.prioritize(Priority::High);
+        callbacks::report(
+            "restore",
+            name,
+            self.restore_inner(name, dest, options, verify, delta, resume, passfn),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn restore_inner(
+        &self,
+        name: &str,
+        dest: &Path,
+        options: &RestoreOptions,
+        verify: &VerifyOptions,
+        delta: bool,
+        resume: bool,
+        passfn: PassphraseFn,
+    ) -> io::Result<RestoreResult> {
+        let start = Instant::now();
+
+        let offset = match &self.state_dir {
+            Some(state_dir) if resume && !dest.is_dir() => resume::recorded_offset(state_dir, name, dest)?,
+            None if resume => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Restores can be resumed only with client state dir",
+                ))
+            }
+            _ => 0,
+        };
+
+        let seed = if (delta || resume) && dest.is_dir() {
+            Some(delta::seed(&self.remote, dest, passfn)?)
+        } else if offset > 0 {
+            Some(delta::seed_file(&self.remote, dest, offset, passfn)?)
+        } else {
+            None
+        };
+
+        progress::start("restore", name, None);
+
+        let ((bytes, manifest), served_by) = self.read_piped(name, passfn, |reader| {
+            let reader = ProgressReader::new(reader);
+            match &self.state_dir {
+                Some(state_dir) => snapshot::restore_with(reader, dest, options, |input| {
+                    let mut writer = ProgressWriter::open(state_dir, name, dest, offset)?;
+                    let bytes = io::copy(input, &mut writer)?;
+                    writer.finish()?;
+                    Ok(bytes)
+                }),
+                None => snapshot::restore(reader, dest, options),
+            }
+        })?;
+
+        // hashes the restored files once they're complete on the disk
+        let integrity = if verify.enabled() {
+            Some(integrity::verify(dest, manifest.as_ref(), verify)?)
+        } else {
+            None
+        };
+
+        Ok(RestoreResult {
+            name: name.to_string(),
+            bytes,
+            reused_bytes: seed.as_ref().map(|_| self.remote.seeded_bytes()).unwrap_or(0),
+            duration_ms: start.elapsed().as_millis(),
+            served_by,
+            integrity,
+        })
+    }
+
+    /// Runs the whole restore of `name` (download, decryption, decompression) discarding the data, to prove it's
+    /// restorable.
+    pub fn restore_test(&self, name: &str, passfn: PassphraseFn) -> io::Result<RestoreTestResult> {
+        let name = &self.stored_name(name);
+        let start = Instant::now();
+        let _priority = self.remote.prioritize(Priority::Low);
+
+        let ((bytes, entries), served_by) = self.read_piped(name, passfn, snapshot::test_restore)?;
+
+        let duration = start.elapsed();
+
+        Ok(RestoreTestResult {
+            name: name.to_string(),
+            bytes,
+            entries,
+            duration_ms: duration.as_millis(),
+            throughput_mb_per_sec: bytes as f64 / 1_000_000.0 / duration.as_secs_f64().max(0.001),
+            served_by,
+        })
+    }
+
+    /// Reads metadata-only snapshot `name`.
+    pub fn inventory(&self, name: &str, passfn: PassphraseFn) -> io::Result<Inventory> {
+        let name = &self.stored_name(name);
+        Ok(self.read_piped(name, passfn, inventory::read)?.0)
+    }
+
+    /// Removes just the name, making its data unreachable; the space is reclaimed by a later GC.
+    pub fn forget(&self, name: &str) -> io::Result<ForgetResult> {
+        let name = &self.stored_name(name);
+        self.repo.rm(name)?;
+
+        Ok(ForgetResult { name: name.to_string() })
+    }
+
+    /// Materializes directory snapshot `name` into `dest`, hard-linking files unchanged since a previous export in
+    /// `link_dest`.
+    pub fn export_tree(
+        &self,
+        name: &str,
+        dest: &Path,
+        link_dest: Option<&Path>,
+        options: &RestoreOptions,
+        passfn: PassphraseFn,
+    ) -> io::Result<ExportResult> {
+        let name = &self.stored_name(name);
+        let start = Instant::now();
+
+        let (stats, served_by) = self.read_piped(name, passfn, |reader| snapshot::export_tree(reader, dest, link_dest, options))?;
+
+        Ok(ExportResult {
+            name: name.to_string(),
+            bytes: stats.bytes,
+            linked_files: stats.linked_files,
+            linked_bytes: stats.linked_bytes,
+            duration_ms: start.elapsed().as_millis(),
+            served_by,
+        })
+    }
+
+    /// Writes `name` into `output` as a tar archive readable by standard tools. The server decodes it when it can
+    /// (requires admin token), unless `local` is set; the client does otherwise.
+    pub fn export_tar(&self, name: &str, output: &mut dyn Write, local: bool, passfn: PassphraseFn) -> io::Result<ExportTarResult> {
+        let name = &self.stored_name(name);
+        let start = Instant::now();
+
+        let (bytes, decoded_by) = if self.capabilities.tar_export && !local {
+            let mut resp = self.remote.export_tar(name)?;
+            (io::copy(&mut resp, output)?, Decoder::Server)
+        } else {
+            let (bytes, _) = self.read_piped(name, passfn, |reader| snapshot::write_tar(reader, name, output))?;
+            (bytes, Decoder::Client)
+        };
+
+        output.flush()?;
+
+        Ok(ExportTarResult {
+            name: name.to_string(),
+            bytes,
+            decoded_by,
+            duration_ms: start.elapsed().as_millis(),
+        })
+    }
+
+    /// Stores data produced by `producer` in a background thread under `name`. Returns the result of the producer with
+    /// write stats.
+    fn write_piped<T: Send + 'static>(
+        &self,
+        name: &str,
+        wh: &EncryptHandle,
+        producer: impl FnOnce(pipe::PipeWriter) -> io::Result<T> + Send + 'static,
+    ) -> io::Result<(T, WriteStats)> {
+        let (writer, reader) = pipe::pipe(memory::pipe_capacity(PIPE_CAPACITY));
+
+        let producer = thread::spawn(move || producer(writer));
+
+        let stats = self.repo.write(name, ProgressReader::new(reader), wh);
+        let produced = producer.join().expect("Producer thread panicked");
+
+        Ok((produced?, stats?))
+    }
+
+    /// Reads `name` in a background thread, letting `consumer` process the data as they come. Returns also the server
+    /// which served the data.
+    fn read_piped<T>(
+        &self,
+        name: &str,
+        passfn: PassphraseFn,
+        consumer: impl FnOnce(pipe::PipeReader) -> io::Result<T>,
+    ) -> io::Result<(T, Url)> {
+        let _read_only = self.remote.read_only();
+        let rh = self.repo.unlock_decrypt(&passfn)?;
+        let (writer, reader) = pipe::pipe(memory::pipe_capacity(PIPE_CAPACITY));
+
+        let reader_thread = {
+            let repo = self.repo.clone();
+            let name = name.to_string();
+
+            thread::spawn(move || {
+                let mut writer = writer;
+
+                match repo.read(&name, &mut writer, &rh) {
+                    Ok(_) => true,
+                    Err(e) => {
+                        writer.abort(e);
+                        false
+                    }
+                }
+            })
+        };
+
+        // dropping the reader (when the consumer fails) stops the reading thread
+        let result = consumer(reader);
+        let read = reader_thread.join().expect("Reader thread panicked");
+
+        // chunks downloaded by a read completed by rdedup are verified
+        self.remote.settle_cached_chunks(read);
+
+        Ok((result?, self.remote.served_by()))
+    }
+
+    /// Verifies given names (or all names in the repository when `None`) using `jobs` parallel workers; with `sample`,
+    /// only a part of the chunks is checked.
+    pub fn verify(
+        &self,
+        names: Option<Vec<String>>,
+        jobs: usize,
+        sample: Option<Sample>,
+        passfn: PassphraseFn,
+    ) -> io::Result<VerifyReport> {
+        let _read_only = self.remote.read_only();
+        let _priority = self.remote.prioritize(Priority::Low);
+        let rh = self.repo.unlock_decrypt(&passfn)?;
+
+        let names = match names {
+            Some(names) => names.iter().map(|name| self.stored_name(name).into_owned()).collect(),
+            None => self.repo.list_names()?.into_iter().filter(|name| self.in_namespace(name)).collect(),
+        };
+
+        // names usually share most of their chunks, don't download them again for each of them
+        CHUNK_CACHE.set_capacity(memory::cache_bytes(VERIFY_CACHE_SIZE));
+
+        self.remote.set_verify_sample(sample);
+        self.remote.set_verifying(true);
+        let report = verify::verify_names(&self.repo, &rh, names, memory::verify_jobs(jobs));
+        self.remote.set_verifying(false);
+        self.remote.set_verify_sample(None);
+
+        Ok(VerifyReport {
+            served_by: Some(self.remote.served_by()),
+            sample,
+            ..report
+        })
+    }
+
+    /// Repairs `name` whose chunks got corrupted, using its original `source` which must not have changed since it was
+    /// stored: the corrupted chunks are removed, the source is chunked again, and only the chunks missing now get
+    /// uploaded (under a temporary name, removed afterwards). The name is verified once more at the end.
+    pub fn repair(&self, name: &str, source: &Path, passfn: PassphraseFn) -> io::Result<RepairResult> {
+        let short_name = name;
+        let name = &self.stored_name(name);
+        let start = Instant::now();
+        let rh = self.repo.unlock_decrypt(&passfn)?;
+
+        self.remote.record_chunk_paths();
+        let report = self.verify_one(name, &rh);
+        let chunk_paths = self.remote.take_chunk_paths();
+        let corrupted: Vec<String> = report?.into_iter().map(|(digest, _)| digest).collect();
+
+        if corrupted.is_empty() {
+            return Ok(RepairResult {
+                name: name.to_string(),
+                corrupted,
+                unrepaired: Vec::new(),
+                new_chunks: 0,
+                new_bytes: 0,
+                duration_ms: start.elapsed().as_millis(),
+            });
+        }
+
+        let mut thread = self.remote.new_thread()?;
+
+        let mut corrupted_paths = HashMap::new();
+        for path in chunk_paths.into_sorted()? {
+            let path = PathBuf::from(path?);
+
+            if let Some(digest) = paths::path_digest(&path).filter(|d| corrupted.iter().any(|c| c == d)) {
+                corrupted_paths.insert(digest.to_string(), path.clone());
+            }
+        }
+
+        for digest in &corrupted {
+            let path = corrupted_paths
+                .remove(digest)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, format!("Location of corrupted chunk {} is not known", digest)))?;
+
+            warn!("Removing corrupted chunk {:?}", path);
+            thread.remove(path)?;
+        }
+
+        // the same data make the same chunks, so only those just removed are written
+        // in the namespace too, the server may confine the client to it
+        let temp_name = self
+            .stored_name(&format!("{}{}-{}", REPAIR_NAME_PREFIX, short_name, Uuid::new_v4()))
+            .into_owned();
+        let wh = self.repo.unlock_encrypt(&passfn)?;
+        let written = self.write_source(source, &temp_name, &wh);
+
+        if written.is_ok() {
+            if let Err(e) = self.repo.rm(&temp_name) {
+                warn!("Could not remove temporary name {}: {}", temp_name, e);
+            }
+        }
+        let (_, stats) = written?;
+
+        let unrepaired = self.verify_one(name, &rh)?.into_iter().map(|(digest, _)| digest).collect();
+
+        Ok(RepairResult {
+            name: name.to_string(),
+            corrupted,
+            unrepaired,
+            new_chunks: stats.new_chunks,
+            new_bytes: stats.new_bytes,
+            duration_ms: start.elapsed().as_millis(),
+        })
+    }
+
+    /// Verifies whole `name`, returning its corrupted chunks; fails when the verification can't be finished.
+    fn verify_one(&self, name: &str, rh: &DecryptHandle) -> io::Result<Vec<(String, String)>> {
+        self.remote.set_verifying(true);
+        let report = verify::verify_names(&self.repo, rh, vec![name.to_string()], 1);
+        self.remote.set_verifying(false);
+        let report = report.names.into_iter().next().expect("Verified name missing in the report");
+
+        match report.failure {
+            Some(failure) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Could not verify {}: {}", name, failure),
+            )),
+            None => Ok(report.corrupted),
+        }
+    }
+
+    /// Collects everything describing the repository and the server it's served by.
+    pub fn info(&self) -> io::Result<RepoInfo> {
+        let (generation, config) = self.read_config()?;
+
+        Ok(RepoInfo {
+            server: self.remote.server_url().clone(),
+            server_build: self.remote.server_build()?,
+            layout_version: self.capabilities.layout_version,
+            generation,
+            config: String::from_utf8_lossy(&config.to_linear_vec()).to_string(),
+            locks: self.remote.locks()?,
+        })
+    }
+
+    /// Config of the repository, with its newest generation.
+    fn read_config(&self) -> io::Result<(Option<String>, SGData)> {
+        let mut thread = self.remote.new_thread()?;
+
+        let mut generations: Vec<String> = thread
+            .list(PathBuf::new())?
+            .iter()
+            .filter_map(|p| p.file_name().map(|f| f.to_string_lossy().to_string()))
+            .filter(|f| paths::is_generation_dir(f))
+            .collect();
+        generations.sort();
+
+        let generation = generations.last().cloned();
+
+        let config = match thread.read(PathBuf::from(CONFIG_FILE)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && generation.is_some() => {
+                thread.read(Path::new(generation.as_ref().unwrap()).join(CONFIG_FILE))?
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok((generation, config))
+    }
+
+    pub fn key_slots(&self) -> io::Result<Vec<KeySlot>> {
+        let mut thread = self.remote.new_thread()?;
+
+        let entries = match thread.list(PathBuf::from(KEYS_DIR)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        entries
+            .iter()
+            .filter_map(|p| p.file_name())
+            .map(|file_name| {
+                let data = thread.read(Path::new(KEYS_DIR).join(file_name))?;
+                serde_json::from_slice(&data.to_linear_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect()
+    }
+
+    /// Translates passphrase of a key slot to the master passphrase of the repository; passphrases not opening any
+    /// slot are returned as they are (the master one doesn't need a slot).
+    pub fn resolve_passphrase(&self, passphrase: &str) -> io::Result<String> {
+        for slot in self.key_slots()? {
+            if let Some(master) = slot.open(passphrase)? {
+                debug!("Unlocked through key slot {}", slot.name);
+                return Ok(master);
+            }
+        }
+
+        Ok(passphrase.to_string())
+    }
+
+    /// Adds key slot `name` unlocking the repository with `new_passphrase`; `passfn` must unlock the repository.
+    pub fn add_key(&self, name: &str, new_passphrase: &str, passfn: PassphraseFn) -> io::Result<()> {
+        let master = passfn()?;
+
+        // make sure it's really the master passphrase, otherwise the slot would be useless
+        self.repo.unlock_decrypt(&|| Ok(master.clone()))?;
+
+        let mut thread = self.remote.new_thread()?;
+        let path = keys::slot_path(name);
+
+        if thread.read_metadata(path.clone()).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Key slot {} already exists", name),
+            ));
+        }
+
+        let slot = KeySlot::seal(name, &master, new_passphrase)?;
+        thread.write(path, SGData::from_single(serde_json::to_vec_pretty(&slot)?), false)
+    }
+
+    /// Creates recovery codes, any `threshold` of `shares` codes making it possible to add a key slot without knowing
+    /// any passphrase (see `recover`); `passfn` must unlock the repository.
+    pub fn create_recovery_codes(&self, shares: u8, threshold: u8, passfn: PassphraseFn) -> io::Result<RecoveryCodes> {
+        let secret = recovery::generate_secret()?;
+        let codes = recovery::split(&secret, shares, threshold)?;
+
+        self.add_key(RECOVERY_SLOT, &recovery::slot_passphrase(&secret), passfn)?;
+
+        Ok(RecoveryCodes {
+            slot: RECOVERY_SLOT.to_string(),
+            threshold,
+            codes,
+        })
+    }
+
+    /// Adds key slot `name` unlocking the repository with `new_passphrase`, authorized by recovery `codes` instead of a
+    /// passphrase.
+    pub fn recover(&self, codes: &[String], name: &str, new_passphrase: &str) -> io::Result<()> {
+        let passphrase = recovery::slot_passphrase(&recovery::combine(codes)?);
+
+        let slot = self
+            .key_slots()?
+            .into_iter()
+            .find(|slot| slot.name == RECOVERY_SLOT)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Repository has no recovery codes"))?;
+
+        let master = slot.open(&passphrase)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Recovery codes don't unlock the repository, they were replaced",
+            )
+        })?;
+
+        self.add_key(name, new_passphrase, &|| Ok(master.clone()))
+    }
+
+    /// Removes key slot `name`; `passfn` must unlock the repository.
+    pub fn remove_key(&self, name: &str, passfn: PassphraseFn) -> io::Result<()> {
+        self.repo.unlock_decrypt(&passfn)?;
+
+        self.remote.new_thread()?.remove(keys::slot_path(name))
+    }
+
+    /// Puts the server into maintenance mode (or back out of it when `enabled` is false).
+    pub fn set_maintenance(&self, enabled: bool, until: Option<String>) -> io::Result<()> {
+        self.remote.set_maintenance(&MaintenanceRequest { enabled, until })
+    }
+
+    /// Removes garbage left in the server staging areas by crashes (requires admin token).
+    pub fn run_cleanup(&self) -> io::Result<CleanupReport> {
+        self.remote.run_cleanup()
+    }
+
+    /// Shows who holds locks of the repository.
+    pub fn locks(&self) -> io::Result<LocksResponse> {
+        self.remote.locks()
+    }
+
+    /// Shows how long storage operations of the server take - to tell slow disks from slow networks (requires admin
+    /// token).
+    pub fn backend_latency(&self) -> io::Result<LatencyResponse> {
+        self.remote.backend_latency()
+    }
+
+    /// Makes operations wait up to `wait` for an exclusively locked repository (e.g. by GC) instead of failing.
+    /// Capacity planning report of the server, with `top` largest names (requires admin token).
+    pub fn capacity_report(&self, top: usize) -> io::Result<CapacityReport> {
+        self.remote.capacity_report(top)
+    }
+
+    /// Follows the server log (requires admin token); `level` is the least severe level shown, `module` limits the
+    /// events to a module of the server.
+    pub fn tail_logs<F: FnMut(&LogEvent)>(&self, level: Option<&str>, module: Option<&str>, recent: bool, f: F) -> io::Result<()> {
+        self.remote.tail_logs(level, module, recent, f)
+    }
+
+    pub fn set_lock_wait(&self, wait: Option<Duration>) {
+        self.remote.set_lock_wait(wait)
+    }
+
+    /// Repository data kept on the local disk get encrypted by `key`.
+    pub fn set_local_key(&self, key: LocalKey) {
+        self.remote.set_local_key(key)
+    }
+
+    /// Downloaded chunks are kept in `cache` and read from there, by this client and its peers.
+    pub fn set_chunk_cache(&self, cache: ChunkCacheDir) {
+        self.remote.set_chunk_cache(cache)
+    }
+
+    /// Chunks are looked for on `peers` before they are downloaded from the server.
+    pub fn set_peers(&self, peers: Peers) {
+        self.remote.set_peers(peers)
+    }
+
+    /// Restores and verifications read from `replica` when the primary server can't serve them.
+    pub fn set_replica(&self, replica: Url) {
+        self.remote.set_replica(replica)
+    }
+
+    /// Stored names; within a namespace, only those of it, without the namespace prefix. Names staged and not committed
+    /// (yet) are included when `include_pending` (requires admin token).
+    pub fn names(&self, include_pending: bool) -> io::Result<Vec<NameInfo>> {
+        let names = self.remote.names(include_pending)?.names;
+
+        let namespace = match &self.namespace {
+            Some(namespace) => namespace,
+            None => return Ok(names),
+        };
+
+        Ok(names
+            .into_iter()
+            .filter_map(|info| {
+                let name = paths::strip_namespace(namespace, &info.name)?.to_string();
+                Some(NameInfo { name, ..info })
+            })
+            .collect())
+    }
+
+    pub fn stats(&self) -> io::Result<RepoStats> {
+        let stats = self.remote.stats()?;
+
+        Ok(RepoStats {
+            objects: stats.objects,
+            bytes: stats.bytes,
+            cold_objects: stats.cold_objects,
+            cold_bytes: stats.cold_bytes,
+        })
+    }
+
+    /// Runs GC; with `remote` the server runs it itself and `progress` is called with its status updates, otherwise
+    /// the client orchestrates it over the network.
+    pub fn gc<F: FnMut(&GcStatus)>(&self, grace_time_secs: u64, remote: bool, progress: F) -> io::Result<GcReport> {
+        let start = Instant::now();
+
+        let before = self.stats()?;
+
+        if remote {
+            self.remote.start_gc(grace_time_secs)?;
+
+            let status = self.remote.watch_gc(progress)?;
+
+            if let Some(e) = status.error {
+                return Err(io::Error::new(io::ErrorKind::Other, format!("Server GC failed: {}", e)));
+            }
+        } else {
+            self.repo.gc(grace_time_secs)?;
+        }
+
+        let after = self.stats()?;
+
+        Ok(GcReport {
+            reclaimed_objects: before.objects.saturating_sub(after.objects),
+            reclaimed_bytes: before.bytes.saturating_sub(after.bytes),
+            before,
+            after,
+            duration_ms: start.elapsed().as_millis(),
+        })
+    }
+}
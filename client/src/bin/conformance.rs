@@ -0,0 +1,75 @@
+//! Runs the backend conformance checks (see `rbackup2_client::conformance`) against a local directory, as the server
+//! stores the objects, or against a running server through the remote backend of the client.
+//!
+//! The checks write into a scratch directory of the repository and remove what they wrote; against a server, the token
+//! must allow removals and renames (not append-only).
+
+use std::path::PathBuf;
+
+use err_context::AnyError;
+use rbackup2_client::conformance::{self, ConformanceReport};
+use rbackup2_client::remote::RemoteBackend;
+use rdedup_lib::backends::local::Local;
+use structopt::StructOpt;
+use url::Url;
+use uuid::Uuid;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "conformance")]
+struct Opts {
+    /// Directory checked through the local backend
+    #[structopt(long, conflicts_with = "server", required_unless = "server")]
+    local: Option<PathBuf>,
+    /// Server checked through the remote backend
+    #[structopt(long)]
+    server: Option<Url>,
+    #[structopt(long, env = "RBACKUP_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    #[structopt(long, env = "RBACKUP_SIGNING_KEY", hide_env_values = true)]
+    signing_key: Option<String>,
+    /// Directory (relative to the repository root) the checks work in; a new one is used when not set
+    #[structopt(long)]
+    scratch: Option<PathBuf>,
+    /// Prints the report as JSON
+    #[structopt(long)]
+    json: bool,
+}
+
+fn run(opts: Opts) -> Result<ConformanceReport, AnyError> {
+    let scratch = opts
+        .scratch
+        // servers refuse modifications of their internals, dot-prefixed entries other than the staging directory
+        .unwrap_or_else(|| PathBuf::from(format!(".pending/conformance/{}", Uuid::new_v4())));
+
+    let report = match (opts.local, opts.server) {
+        (Some(dir), _) => conformance::run(&Local::new(dir), &scratch)?,
+        (None, Some(url)) => {
+            let remote = RemoteBackend::new(url, opts.token, opts.signing_key);
+            // the layout of the served repository is known only then
+            remote.negotiate()?;
+            conformance::run(&remote, &scratch)?
+        }
+        (None, None) => unreachable!("Either directory or server is required"),
+    };
+
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        conformance::print_report(&report);
+    }
+
+    Ok(report)
+}
+
+fn main() {
+    env_logger::init();
+
+    match run(Opts::from_args()) {
+        Ok(report) if report.is_ok() => (),
+        Ok(_) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,476 @@
+//! Soak test of the whole stack: a server started for the test, simulated clients storing, restoring, verifying and
+//! forgetting names concurrently with server-side GC runs, all of them with faults injected into their requests.
+//!
+//! Operations are expected to fail now and then (that's what the faults are for), data must not. Once the time is up,
+//! a client without faults checks the invariants: every successfully stored name (not forgotten since) exists, no
+//! forgotten name came back, all names verify and restore to the data they were stored from. Any violation fails the
+//! run; the report is printed as JSON either way.
+//!
+//! Requires the server binary and `rdedup` (to create the repository). The server listens on its default port, nothing
+//! else may be using it.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use err_context::AnyError;
+use log::*;
+use rbackup2_client::api::Client;
+use rbackup2_client::chaos::{ChaosOptions, ChaosTransport};
+use rbackup2_client::integrity::VerifyOptions;
+use rbackup2_client::remote;
+use rbackup2_client::snapshot::RestoreOptions;
+use rdedup_lib::PassphraseFn;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sodiumoxide::randombytes::{randombytes, randombytes_uniform};
+use structopt::StructOpt;
+use url::Url;
+use uuid::Uuid;
+
+const SERVER_URL: &str = "http://localhost:8090";
+const ADMIN_TOKEN: &str = "stress-admin";
+const PASSPHRASE: &str = "stress-test";
+
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stores, restores and forgets wait this long for GC holding the exclusive lock
+const LOCK_WAIT: Duration = Duration::from_secs(120);
+
+/// Data shared by all the stored files, so their chunks are deduplicated (and GC has to keep the shared ones)
+const SHARED_BYTES: usize = 512 * 1024;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "stress")]
+struct Opts {
+    /// Server binary started for the test
+    #[structopt(long, default_value = "rbackup2-server")]
+    server_bin: PathBuf,
+    /// rdedup binary creating the repository
+    #[structopt(long, default_value = "rdedup")]
+    rdedup_bin: PathBuf,
+    /// Number of simulated clients
+    #[structopt(long, default_value = "4")]
+    clients: usize,
+    /// How long the clients keep working, e.g. `10m`
+    #[structopt(long, default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+    duration: Duration,
+    /// Server-side GC is started this often
+    #[structopt(long, default_value = "20s", parse(try_from_str = humantime::parse_duration))]
+    gc_interval: Duration,
+    /// Size of the largest stored file
+    #[structopt(long, default_value = "4000000")]
+    max_file_bytes: usize,
+    /// Probability (0-1) of a request failing, before or after it reaches the server
+    #[structopt(long, default_value = "0.02")]
+    failure_rate: f64,
+    /// Probability (0-1) of a response being cut off midway
+    #[structopt(long, default_value = "0.01")]
+    disconnect_rate: f64,
+    /// Requests get delayed by up to this long (e.g. `200ms`)
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    max_delay: Option<Duration>,
+    /// Keep the work directory (repository, server log) instead of removing it
+    #[structopt(long)]
+    keep: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operation {
+    Store,
+    Restore,
+    Verify,
+    Forget,
+    Gc,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Store => "store",
+            Operation::Restore => "restore",
+            Operation::Verify => "verify",
+            Operation::Forget => "forget",
+            Operation::Gc => "gc",
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct OperationCount {
+    succeeded: usize,
+    failed: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    clients: usize,
+    duration_secs: u64,
+    operations: BTreeMap<&'static str, OperationCount>,
+    /// Names existing at the end
+    names: usize,
+    violations: Vec<String>,
+}
+
+/// What the clients did to the repository, as far as they know.
+#[derive(Default)]
+struct Ledger {
+    /// Names stored successfully (and not forgotten since), with SHA-256 of their data
+    stored: HashMap<String, Vec<u8>>,
+    /// Names whose store or forget failed - they may exist or not
+    uncertain: HashSet<String>,
+    /// Names forgotten successfully
+    forgotten: HashSet<String>,
+}
+
+/// State shared by the clients.
+struct Shared {
+    opts: Opts,
+    url: Url,
+    work: PathBuf,
+    shared_data: Vec<u8>,
+    deadline: Instant,
+    ledger: Mutex<Ledger>,
+    report: Mutex<Report>,
+}
+
+impl Shared {
+    fn count(&self, operation: Operation, result: &io::Result<()>) {
+        let mut report = self.report.lock().unwrap();
+        let count = report.operations.entry(operation.name()).or_default();
+
+        match result {
+            Ok(()) => count.succeeded += 1,
+            Err(e) => {
+                debug!("{} failed: {}", operation.name(), e);
+                count.failed += 1;
+            }
+        }
+    }
+
+    fn violation(&self, violation: String) {
+        error!("Invariant violated: {}", violation);
+        self.report.lock().unwrap().violations.push(violation);
+    }
+
+    fn chaos(&self) -> ChaosOptions {
+        ChaosOptions {
+            chaos_failure_rate: self.opts.failure_rate,
+            chaos_disconnect_rate: self.opts.disconnect_rate,
+            chaos_delay: self.opts.max_delay,
+        }
+    }
+
+    /// Client with faults injected into its requests; opening may take a few attempts then.
+    fn open_client(&self) -> Result<Client, AnyError> {
+        let mut attempt = 0;
+
+        loop {
+            let transport = Arc::new(ChaosTransport::new(remote::http_transport(), self.chaos())?);
+
+            match Client::open_with_transport(self.url.clone(), Some(ADMIN_TOKEN.to_string()), None, transport) {
+                Ok(client) => {
+                    client.set_lock_wait(Some(LOCK_WAIT));
+                    return Ok(client);
+                }
+                Err(e) if attempt < 10 => {
+                    debug!("Could not open the repository (attempt {}): {}", attempt, e);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Server process running for the test, killed once dropped.
+struct Server {
+    child: Child,
+}
+
+impl Server {
+    fn start(opts: &Opts, work: &Path, url: &Url) -> Result<Server, AnyError> {
+        let repo = work.join("repo");
+
+        let status = Command::new(&opts.rdedup_bin)
+            .arg("--dir")
+            .arg(&repo)
+            .arg("init")
+            .env("RDEDUP_PASSPHRASE", PASSPHRASE)
+            .status()?;
+        if !status.success() {
+            return Err(AnyError::from(format!("rdedup init failed: {}", status)));
+        }
+
+        let config = work.join("server.toml");
+        fs::write(
+            &config,
+            format!("data_dir = {:?}\nadmin_tokens = [{:?}]\n", repo.to_string_lossy(), ADMIN_TOKEN),
+        )?;
+
+        let log = File::create(work.join("server.log"))?;
+        let child = Command::new(&opts.server_bin)
+            .env("RBACKUP_CONFIG", &config)
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .stdin(Stdio::null())
+            .spawn()?;
+        let server = Server { child };
+
+        let mut capabilities = url.clone();
+        capabilities.set_path("capabilities");
+        let start = Instant::now();
+
+        while start.elapsed() < SERVER_START_TIMEOUT {
+            match reqwest::blocking::get(capabilities.as_str()) {
+                Ok(resp) if resp.status().is_success() => return Ok(server),
+                _ => thread::sleep(Duration::from_millis(100)),
+            }
+        }
+
+        Err(AnyError::from(format!("Server didn't start, see {:?}", work.join("server.log"))))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn random(below: usize) -> usize {
+    randombytes_uniform(below.max(1) as u32) as usize
+}
+
+fn file_digest(path: &Path) -> io::Result<Vec<u8>> {
+    Ok(Sha256::digest(&fs::read(path)?).to_vec())
+}
+
+/// Work of a single simulated client until the deadline.
+fn run_client(shared: &Shared, id: usize) -> Result<(), AnyError> {
+    let client = shared.open_client()?;
+    let resolve_passphrase = || client.resolve_passphrase(PASSPHRASE);
+    let passfn: PassphraseFn = &resolve_passphrase;
+
+    let dir = shared.work.join(format!("client-{}", id));
+    fs::create_dir_all(&dir)?;
+
+    let mut own: Vec<String> = Vec::new();
+    let mut stored = 0;
+
+    while Instant::now() < shared.deadline {
+        let operation = match random(10) {
+            _ if own.is_empty() => Operation::Store,
+            0..=4 => Operation::Store,
+            5..=7 => Operation::Restore,
+            8 => Operation::Verify,
+            _ => Operation::Forget,
+        };
+
+        let result = match operation {
+            Operation::Store => {
+                let name = format!("client-{}-{}", id, stored);
+                stored += 1;
+
+                // partly shared, partly unique data
+                let source = dir.join("source");
+                let mut data = shared.shared_data[..random(SHARED_BYTES)].to_vec();
+                data.extend_from_slice(&randombytes(random(shared.opts.max_file_bytes)));
+                fs::write(&source, &data)?;
+
+                let result = client.store(&source, &name, None, passfn).map(|_| ());
+                let mut ledger = shared.ledger.lock().unwrap();
+                match &result {
+                    Ok(()) => {
+                        ledger.stored.insert(name.clone(), Sha256::digest(&data).to_vec());
+                        own.push(name);
+                    }
+                    Err(_) => {
+                        ledger.uncertain.insert(name);
+                    }
+                }
+                result
+            }
+            Operation::Restore => {
+                let name = &own[random(own.len())];
+                let dest = dir.join("restored");
+                let _ = fs::remove_file(&dest);
+
+                let result = client
+                    .restore(name, &dest, &RestoreOptions::default(), &VerifyOptions::default(), false, false, passfn)
+                    .map(|_| ());
+                if result.is_ok() {
+                    let expected = shared.ledger.lock().unwrap().stored.get(name).cloned();
+                    if expected != Some(file_digest(&dest)?) {
+                        shared.violation(format!("{} restored by client {} differs from the stored data", name, id));
+                    }
+                }
+                result
+            }
+            Operation::Verify => {
+                let name = own[random(own.len())].clone();
+
+                client.verify(Some(vec![name.clone()]), 1, None, passfn).map(|report| {
+                    for name in report.names.iter().filter(|n| !n.corrupted.is_empty()) {
+                        shared.violation(format!("{} has corrupted chunks: {:?}", name.name, name.corrupted));
+                    }
+                })
+            }
+            Operation::Forget => {
+                let name = own.swap_remove(random(own.len()));
+
+                let result = client.forget(&name).map(|_| ());
+                let mut ledger = shared.ledger.lock().unwrap();
+                ledger.stored.remove(&name);
+                match &result {
+                    Ok(()) => ledger.forgotten.insert(name),
+                    Err(_) => ledger.uncertain.insert(name),
+                };
+                result
+            }
+            Operation::Gc => unreachable!("GC is run by its own thread"),
+        };
+
+        shared.count(operation, &result);
+    }
+
+    Ok(())
+}
+
+/// Starts server-side GC every `gc_interval` until the deadline.
+fn run_gc(shared: &Shared) -> Result<(), AnyError> {
+    let client = shared.open_client()?;
+
+    loop {
+        let next = Instant::now() + shared.opts.gc_interval;
+        if next >= shared.deadline {
+            return Ok(());
+        }
+        thread::sleep(next - Instant::now());
+
+        let result = client.gc(0, true, |_| ()).map(|_| ());
+        shared.count(Operation::Gc, &result);
+    }
+}
+
+/// Checks the repository against the ledger, by a client without faults.
+fn check(shared: &Shared) -> Result<(), AnyError> {
+    let client = Client::open(shared.url.clone(), Some(ADMIN_TOKEN.to_string()), None)?;
+    client.set_lock_wait(Some(LOCK_WAIT));
+    let resolve_passphrase = || client.resolve_passphrase(PASSPHRASE);
+    let passfn: PassphraseFn = &resolve_passphrase;
+
+    let ledger = shared.ledger.lock().unwrap();
+    let names: HashSet<String> = client.names(false)?.into_iter().map(|n| n.name).collect();
+    shared.report.lock().unwrap().names = names.len();
+
+    for name in ledger.stored.keys().filter(|n| !names.contains(*n)) {
+        shared.violation(format!("Stored name {} is lost", name));
+    }
+    for name in ledger.forgotten.iter().filter(|n| names.contains(*n)) {
+        shared.violation(format!("Forgotten name {} exists", name));
+    }
+
+    info!("Verifying all {} names", names.len());
+    let report = client.verify(None, 4, None, passfn)?;
+    for name in report.names.iter().filter(|n| !n.is_ok()) {
+        shared.violation(format!(
+            "{} failed verification: {:?} {:?}",
+            name.name, name.corrupted, name.failure
+        ));
+    }
+
+    info!("Restoring all {} stored names", ledger.stored.len());
+    let dest = shared.work.join("check");
+    for (name, digest) in &ledger.stored {
+        let _ = fs::remove_file(&dest);
+
+        match client.restore(name, &dest, &RestoreOptions::default(), &VerifyOptions::default(), false, false, passfn) {
+            Ok(_) if &file_digest(&dest)? == digest => (),
+            Ok(_) => shared.violation(format!("{} restores to other data than it was stored from", name)),
+            Err(e) => shared.violation(format!("{} can't be restored: {}", name, e)),
+        }
+    }
+
+    Ok(())
+}
+
+fn run(opts: Opts) -> Result<Report, AnyError> {
+    sodiumoxide::init().map_err(|_| AnyError::from("Could not initialize sodiumoxide"))?;
+
+    let work = std::env::temp_dir().join(format!("rbackup2-stress-{}", Uuid::new_v4()));
+    fs::create_dir(&work)?;
+    info!("Stress test in {:?}", work);
+
+    let url = Url::parse(SERVER_URL)?;
+    let server = Server::start(&opts, &work, &url)?;
+
+    let shared = Arc::new(Shared {
+        url,
+        work: work.clone(),
+        shared_data: randombytes(SHARED_BYTES),
+        deadline: Instant::now() + opts.duration,
+        ledger: Mutex::new(Ledger::default()),
+        report: Mutex::new(Report {
+            clients: opts.clients,
+            duration_secs: opts.duration.as_secs(),
+            ..Report::default()
+        }),
+        opts,
+    });
+
+    let mut threads = Vec::new();
+    for id in 0..shared.opts.clients {
+        let shared = Arc::clone(&shared);
+        threads.push(thread::spawn(move || run_client(&shared, id)));
+    }
+    {
+        let shared = Arc::clone(&shared);
+        threads.push(thread::spawn(move || run_gc(&shared)));
+    }
+
+    for thread in threads {
+        match thread.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => shared.violation(format!("Client gave up: {}", e)),
+            Err(_) => shared.violation("Client panicked".to_string()),
+        }
+    }
+
+    check(&shared)?;
+    drop(server);
+
+    if shared.opts.keep {
+        println!("Work directory kept: {:?}", work);
+    } else {
+        fs::remove_dir_all(&work)?;
+    }
+
+    let report = std::mem::take(&mut *shared.report.lock().unwrap());
+    Ok(report)
+}
+
+fn main() {
+    env_logger::init();
+
+    let result = run(Opts::from_args()).and_then(|report| {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        Ok(report.violations.is_empty())
+    });
+
+    match result {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
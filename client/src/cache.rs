@@ -0,0 +1,105 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use log::*;
+use sgdata::SGData;
+
+/// In-memory cache of objects, shared by all backend threads.
+///
+/// Disabled (zero capacity) unless created with capacity; operations reading the same objects repeatedly (e.g. verify of
+/// multiple names sharing most of their chunks) enable it to avoid fetching them from the server again.
+pub struct ChunkCache {
+    max_bytes: AtomicUsize,
+    inner: Mutex<ChunkCacheInner>,
+}
+
+#[derive(Default)]
+struct ChunkCacheInner {
+    entries: HashMap<PathBuf, SGData>,
+    order: VecDeque<PathBuf>,
+    bytes: usize,
+}
+
+impl ChunkCache {
+    pub fn new() -> ChunkCache {
+        ChunkCache {
+            max_bytes: AtomicUsize::new(0),
+            inner: Mutex::new(ChunkCacheInner::default()),
+        }
+    }
+
+    pub fn with_capacity(max_bytes: usize) -> ChunkCache {
+        let cache = ChunkCache::new();
+        cache.set_capacity(max_bytes);
+        cache
+    }
+
+    pub fn set_capacity(&self, max_bytes: usize) {
+        debug!("Setting chunk cache capacity to {}B", max_bytes);
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, path: &Path) -> Option<SGData> {
+        if self.max_bytes.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        self.inner.lock().unwrap().entries.get(path).cloned()
+    }
+
+    /// Drops the entry, e.g. when the object gets modified.
+    pub fn remove(&self, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(data) = inner.entries.remove(path) {
+            inner.bytes -= data.len();
+            inner.order.retain(|p| p != path);
+        }
+    }
+
+    /// Drops all the entries below `dir`, e.g. when the directory gets removed.
+    pub fn remove_dir(&self, dir: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let removed: Vec<PathBuf> = inner.entries.keys().filter(|p| p.starts_with(dir)).cloned().collect();
+        for path in removed {
+            if let Some(data) = inner.entries.remove(&path) {
+                inner.bytes -= data.len();
+            }
+        }
+
+        inner.order.retain(|p| !p.starts_with(dir));
+    }
+
+    pub fn insert(&self, path: PathBuf, data: &SGData) {
+        let max_bytes = self.max_bytes.load(Ordering::Relaxed);
+
+        if data.len() > max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.entries.contains_key(&path) {
+            return;
+        }
+
+        // evict the oldest entries first
+        while inner.bytes + data.len() > max_bytes {
+            match inner.order.pop_front() {
+                Some(evicted) => {
+                    if let Some(d) = inner.entries.remove(&evicted) {
+                        inner.bytes -= d.len();
+                    }
+                }
+                None => break,
+            }
+        }
+
+        inner.bytes += data.len();
+        inner.order.push_back(path.clone());
+        inner.entries.insert(path, data.clone());
+    }
+}
@@ -0,0 +1,61 @@
+//! Callbacks of the store and restore engines, for frontends embedding the client as a library (e.g. GUI apps) instead
+//! of running the CLI; the engines themselves are the methods of `api::Client`.
+//!
+//! Registered for the whole process, same as the `progress` tracking. They are called from the threads doing the work,
+//! so they should return quickly.
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use once_cell::sync::Lazy;
+
+static CALLBACKS: Lazy<RwLock<Option<Arc<dyn Callbacks>>>> = Lazy::new(|| RwLock::new(None));
+
+/// Implement the methods of interest, the rest do nothing.
+pub trait Callbacks: Send + Sync {
+    /// A file of the source (store) or the destination (restore) starts being processed.
+    fn on_file_start(&self, _path: &Path) {}
+
+    /// A chunk got stored on the server; `digest` is hex, `bytes` the size of the stored (compressed and encrypted)
+    /// chunk. Chunks already present in the repository are not uploaded, so they are not reported.
+    fn on_chunk_uploaded(&self, _digest: &str, _bytes: u64) {}
+
+    /// Operation (`store`, `restore`) on `name` failed; the error is returned by the engine right after.
+    fn on_error(&self, _operation: &'static str, _name: &str, _error: &io::Error) {}
+}
+
+/// Registers `callbacks`, replacing those registered before.
+pub fn set(callbacks: Arc<dyn Callbacks>) {
+    *CALLBACKS.write().unwrap() = Some(callbacks);
+}
+
+pub fn clear() {
+    *CALLBACKS.write().unwrap() = None;
+}
+
+fn with(f: impl FnOnce(&dyn Callbacks)) {
+    // cloned, so callbacks may (re)register callbacks
+    let callbacks = CALLBACKS.read().unwrap().clone();
+
+    if let Some(callbacks) = callbacks {
+        f(&*callbacks);
+    }
+}
+
+pub(crate) fn file_started(path: &Path) {
+    with(|c| c.on_file_start(path));
+}
+
+pub(crate) fn chunk_uploaded(digest: &str, bytes: u64) {
+    with(|c| c.on_chunk_uploaded(digest, bytes));
+}
+
+/// Passes `result` through, reporting its error.
+pub(crate) fn report<T>(operation: &'static str, name: &str, result: io::Result<T>) -> io::Result<T> {
+    if let Err(e) = &result {
+        with(|c| c.on_error(operation, name, e));
+    }
+
+    result
+}
@@ -0,0 +1,151 @@
+//! Fault injection, so retries, resumption and alerting can be seen working before they're relied upon.
+//!
+//! When enabled, requests of the remote backend go through [`ChaosTransport`], which randomly delays them, fails them
+//! (before or after they reach the server) and cuts off their responses midway.
+
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use err_context::AnyError;
+use log::*;
+use once_cell::sync::OnceCell;
+use sodiumoxide::randombytes::randombytes_uniform;
+use structopt::StructOpt;
+
+use crate::transport::{Request, Response, Transport};
+
+static OPTIONS: OnceCell<ChaosOptions> = OnceCell::new();
+
+/// Resolution of the probabilities
+const SCALE: u32 = 1_000_000;
+
+/// Faults injected into requests to the server; meant for testing deployments only, hence hidden.
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct ChaosOptions {
+    /// Probability (0-1) of a request failing, before or after it reaches the server
+    #[structopt(long, hidden = true, default_value = "0")]
+    pub chaos_failure_rate: f64,
+    /// Probability (0-1) of a response being cut off midway
+    #[structopt(long, hidden = true, default_value = "0")]
+    pub chaos_disconnect_rate: f64,
+    /// Requests get delayed by up to this long (e.g. `2s`)
+    #[structopt(long, hidden = true, parse(try_from_str = humantime::parse_duration))]
+    pub chaos_delay: Option<Duration>,
+}
+
+impl ChaosOptions {
+    fn is_enabled(&self) -> bool {
+        self.chaos_failure_rate > 0.0 || self.chaos_disconnect_rate > 0.0 || self.chaos_delay.is_some()
+    }
+}
+
+fn happens(probability: f64) -> bool {
+    probability > 0.0 && f64::from(randombytes_uniform(SCALE)) < probability * f64::from(SCALE)
+}
+
+fn check(options: &ChaosOptions) -> Result<(), AnyError> {
+    for rate in &[options.chaos_failure_rate, options.chaos_disconnect_rate] {
+        if !(0.0..=1.0).contains(rate) {
+            return Err(AnyError::from(format!("Invalid chaos probability {}, expected 0-1", rate)));
+        }
+    }
+
+    sodiumoxide::init().map_err(|_| AnyError::from("Could not initialize sodiumoxide"))
+}
+
+/// Injects the faults into all requests to the server; does nothing when no fault is configured.
+pub fn enable(options: ChaosOptions) -> Result<(), AnyError> {
+    if !options.is_enabled() {
+        return Ok(());
+    }
+
+    check(&options)?;
+
+    warn!("Injecting faults into requests: {:?}", options);
+    OPTIONS.set(options).map_err(|_| AnyError::from("Chaos already enabled"))
+}
+
+/// `transport` injecting the faults, when enabled.
+pub fn wrap(transport: Arc<dyn Transport>) -> Arc<dyn Transport> {
+    match OPTIONS.get() {
+        Some(options) => Arc::new(ChaosTransport {
+            inner: transport,
+            options: options.clone(),
+        }),
+        None => transport,
+    }
+}
+
+pub struct ChaosTransport {
+    inner: Arc<dyn Transport>,
+    options: ChaosOptions,
+}
+
+impl ChaosTransport {
+    /// Injects the faults into requests of a single client only, unlike `enable`.
+    pub fn new(inner: Arc<dyn Transport>, options: ChaosOptions) -> Result<ChaosTransport, AnyError> {
+        check(&options)?;
+
+        Ok(ChaosTransport { inner, options })
+    }
+}
+
+impl Transport for ChaosTransport {
+    fn send(&self, request: Request) -> io::Result<Response> {
+        if let Some(max) = self.options.chaos_delay {
+            let delay = max.mul_f64(f64::from(randombytes_uniform(SCALE)) / f64::from(SCALE));
+            trace!("Chaos: delaying {} {} by {:?}", request.method, request.url, delay);
+            thread::sleep(delay);
+        }
+
+        let fail = happens(self.options.chaos_failure_rate);
+
+        // half of the failures lose just the response, the server has processed the request
+        if fail && randombytes_uniform(2) == 0 {
+            debug!("Chaos: failing {} {} before sending", request.method, request.url);
+            return Err(Error::new(ErrorKind::BrokenPipe, "Injected failure before sending the request"));
+        }
+
+        let method = request.method.clone();
+        let url = request.url.clone();
+        let resp = self.inner.send(request)?;
+
+        if fail {
+            debug!("Chaos: dropping response to {} {}", method, url);
+            return Err(Error::new(ErrorKind::BrokenPipe, "Injected failure after sending the request"));
+        }
+
+        if happens(self.options.chaos_disconnect_rate) {
+            let status = resp.status();
+            let headers = resp.headers().clone();
+            // the cut is somewhere within the first few kB, which most bodies have
+            let limit = u64::from(randombytes_uniform(4096));
+
+            debug!("Chaos: cutting response to {} {} off after {}B", method, url, limit);
+            return Ok(Response::new(status, headers, Disconnecting { inner: resp.take(limit) }));
+        }
+
+        Ok(resp)
+    }
+}
+
+/// Fails once the limited body is read.
+struct Disconnecting {
+    inner: io::Take<Response>,
+}
+
+impl Read for Disconnecting {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        match self.inner.read(buf)? {
+            0 if self.inner.limit() == 0 => Err(Error::new(ErrorKind::ConnectionReset, "Injected disconnect")),
+            read => Ok(read),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! On-disk cache of repository config and key slots, read by every command when opening the repository.
+//!
+//! Entries are revalidated by their ETag (`If-None-Match`), so an unchanged object costs a round trip without a body.
+//! They are kept in the `spool` format, encrypted by the key shared by the profiles with `--encrypt-local`.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use libcommon::paths::ObjectType;
+use log::*;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::keys::KEYS_DIR;
+use crate::local_crypt::LocalKey;
+use crate::spool;
+
+const CACHE_DIR: &str = "config-cache";
+
+pub struct ConfigCache {
+    dir: PathBuf,
+}
+
+/// Objects worth caching - small, read by each command and rarely changed.
+pub fn is_cached(path: &Path) -> bool {
+    ObjectType::of(path) == ObjectType::Config || path.starts_with(KEYS_DIR)
+}
+
+impl ConfigCache {
+    /// Cache of repository at `server_url`, inside client state dir.
+    pub fn new(state_dir: &Path, server_url: &Url) -> ConfigCache {
+        let server = hex::encode(Sha256::digest(server_url.as_str().as_bytes()));
+
+        ConfigCache {
+            dir: state_dir.join(CACHE_DIR).join(&server[..16]),
+        }
+    }
+
+    fn entry_path(&self, path: &Path) -> Option<PathBuf> {
+        // the paths come from rdedup, but they end up in a local directory
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+
+        Some(self.dir.join(path))
+    }
+
+    fn etag_path(entry: &Path) -> PathBuf {
+        let mut etag = entry.as_os_str().to_owned();
+        etag.push(".etag");
+        PathBuf::from(etag)
+    }
+
+    /// ETag and data of cached object; entries written with another `key` are dropped.
+    pub fn get(&self, path: &Path, key: Option<&LocalKey>) -> Option<(String, Vec<u8>)> {
+        let entry = self.entry_path(path)?;
+
+        let etag = fs::read_to_string(ConfigCache::etag_path(&entry)).ok()?;
+        let data = match fs::read(&entry).and_then(|file| spool::decode(file, key)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Dropping cached {:?}: {}", path, e);
+                self.remove(path);
+                return None;
+            }
+        };
+
+        Some((etag, data))
+    }
+
+    pub fn put(&self, path: &Path, etag: &str, data: &[u8], key: Option<&LocalKey>) {
+        let entry = match self.entry_path(path) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let result = fs::create_dir_all(entry.parent().expect("Cache entry without parent"))
+            .and_then(|_| spool::encode(data, key))
+            .and_then(|data| fs::write(&entry, data))
+            .and_then(|_| fs::write(ConfigCache::etag_path(&entry), etag));
+
+        // the cache is just an optimization
+        if let Err(e) = result {
+            warn!("Could not cache {:?}: {}", path, e);
+            self.remove(path);
+        }
+    }
+
+    pub fn remove(&self, path: &Path) {
+        let entry = match self.entry_path(path) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        // the ETag goes first, the entry isn't valid without it
+        for file in &[ConfigCache::etag_path(&entry), entry] {
+            match fs::remove_file(file) {
+                Err(e) if e.kind() != ErrorKind::NotFound => warn!("Could not remove cached {:?}: {}", file, e),
+                _ => (),
+            }
+        }
+    }
+}
@@ -0,0 +1,342 @@
+//! Conformance checks of the contract of rdedup backends (`Backend`/`BackendThread`), which every backend must pass
+//! whatever it stores the objects in - the local directory of the server, the server over HTTP, or anything added later.
+//!
+//! The contract, as rdedup relies on it:
+//!
+//! - written objects read back unchanged, from any thread of the backend,
+//! - idempotent writes of an existing object succeed (content-addressed objects are written again and again),
+//!   non-idempotent ones replace it,
+//! - reads, metadata reads, removals and renames of missing objects fail with `NotFound`,
+//! - renames move the object, replacing the target if it exists; they may be deferred (the remote backend sends them in
+//!   batches), failures of those surface by the next operation of the thread,
+//! - listings return every entry of the directory exactly once, in no particular order (bare file names or full paths);
+//!   a missing directory lists empty or fails with `NotFound`.
+//!
+//! `remove_dir_all` isn't checked, servers allow it for whole generation directories only. The checks work in a scratch
+//! directory of the backend and remove what they wrote; the scratch directory itself may be left behind.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use rdedup_lib::backends::{Backend, BackendThread};
+use serde::Serialize;
+use sgdata::SGData;
+
+/// Files written by the listing check
+const LISTED_FILES: usize = 5;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+    pub check: &'static str,
+    /// Why the backend failed the check
+    pub failure: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConformanceReport {
+    pub scratch: PathBuf,
+    pub checks: Vec<CheckResult>,
+    pub failed: usize,
+}
+
+impl ConformanceReport {
+    pub fn is_ok(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+type Check = fn(&dyn Backend, &mut dyn BackendThread, &Path) -> io::Result<()>;
+
+const CHECKS: &[(&str, Check)] = &[
+    ("write-read", write_read),
+    ("metadata", metadata),
+    ("idempotent-write", idempotent_write),
+    ("overwrite", overwrite),
+    ("threads-share-objects", threads_share_objects),
+    ("missing-not-found", missing_not_found),
+    ("rename", rename),
+    ("rename-replaces", rename_replaces),
+    ("rename-missing", rename_missing),
+    ("remove", remove),
+    ("list", list),
+    ("list-missing", list_missing),
+];
+
+fn violation(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::Other, message.into())
+}
+
+fn data(tag: &str) -> SGData {
+    SGData::from_single(format!("rbackup2 conformance check: {}", tag).into_bytes())
+}
+
+fn read_back(thread: &mut dyn BackendThread, path: &Path, expected: &SGData) -> io::Result<()> {
+    let read = thread.read(path.to_path_buf())?.to_linear_vec();
+
+    if read != expected.to_linear_vec() {
+        return Err(violation(format!(
+            "{:?} read back as {}B different from the {}B written",
+            path,
+            read.len(),
+            expected.len()
+        )));
+    }
+
+    Ok(())
+}
+
+fn expect_not_found<T>(operation: &str, path: &Path, result: io::Result<T>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(violation(format!(
+            "{} of missing {:?} failed with {:?}, not NotFound",
+            operation, path, e
+        ))),
+        Ok(_) => Err(violation(format!("{} of missing {:?} succeeded", operation, path))),
+    }
+}
+
+fn write_read(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let path = dir.join("object");
+    let data = data("write-read");
+
+    thread.write(path.clone(), data.clone(), false)?;
+    read_back(thread, &path, &data)
+}
+
+fn metadata(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let path = dir.join("object");
+    let data = data("metadata");
+
+    thread.write(path.clone(), data.clone(), false)?;
+    let metadata = thread.read_metadata(path.clone())?;
+
+    if metadata.len != data.len() as u64 {
+        return Err(violation(format!(
+            "Metadata of {:?} says {}B, {}B were written",
+            path,
+            metadata.len,
+            data.len()
+        )));
+    }
+
+    Ok(())
+}
+
+fn idempotent_write(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let path = dir.join("object");
+    let data = data("idempotent-write");
+
+    thread.write(path.clone(), data.clone(), true)?;
+    thread.write(path.clone(), data.clone(), true)?;
+    read_back(thread, &path, &data)
+}
+
+fn overwrite(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let path = dir.join("object");
+    let newer = data("overwrite, the newer version");
+
+    thread.write(path.clone(), data("overwrite"), false)?;
+    thread.write(path.clone(), newer.clone(), false)?;
+    read_back(thread, &path, &newer)
+}
+
+fn threads_share_objects(backend: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let path = dir.join("object");
+    let data = data("threads-share-objects");
+
+    thread.write(path.clone(), data.clone(), false)?;
+
+    let mut other = backend.new_thread()?;
+    read_back(other.as_mut(), &path, &data)
+}
+
+fn missing_not_found(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let path = dir.join("missing");
+
+    expect_not_found("Read", &path, thread.read(path.clone()))?;
+    expect_not_found("Metadata read", &path, thread.read_metadata(path.clone()))?;
+    expect_not_found("Removal", &path, thread.remove(path.clone()))
+}
+
+fn rename(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let (from, to) = (dir.join("from"), dir.join("to"));
+    let data = data("rename");
+
+    thread.write(from.clone(), data.clone(), false)?;
+    thread.rename(from.clone(), to.clone())?;
+
+    read_back(thread, &to, &data)?;
+    expect_not_found("Read of renamed", &from, thread.read(from.clone()))
+}
+
+fn rename_replaces(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let (from, to) = (dir.join("from"), dir.join("to"));
+    let replacing = data("rename-replaces");
+
+    thread.write(to.clone(), data("rename-replaces, the replaced one"), false)?;
+    thread.write(from.clone(), replacing.clone(), false)?;
+    thread.rename(from, to.clone())?;
+
+    read_back(thread, &to, &replacing)
+}
+
+fn rename_missing(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let from = dir.join("missing");
+    // a deferred rename fails by the listing
+    let result = thread
+        .rename(from.clone(), dir.join("to"))
+        .and_then(|()| thread.list(dir.to_path_buf()));
+
+    expect_not_found("Rename", &from, result)
+}
+
+fn remove(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let path = dir.join("object");
+
+    thread.write(path.clone(), data("remove"), false)?;
+    thread.remove(path.clone())?;
+
+    expect_not_found("Read of removed", &path, thread.read(path.clone()))
+}
+
+fn list(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let written: BTreeSet<String> = (0..LISTED_FILES).map(|i| format!("object-{}", i)).collect();
+
+    for name in &written {
+        thread.write(dir.join(name), data(name), false)?;
+    }
+
+    let listed = thread.list(dir.to_path_buf())?;
+    // the backend may return either bare file names or full paths
+    let names: Vec<String> = listed
+        .iter()
+        .map(|p| p.file_name().unwrap_or_else(|| p.as_os_str()).to_string_lossy().to_string())
+        .collect();
+    let unique: BTreeSet<String> = names.iter().cloned().collect();
+
+    if unique.len() != names.len() {
+        return Err(violation(format!("Listing of {:?} returned duplicate entries: {:?}", dir, names)));
+    }
+
+    if unique != written {
+        return Err(violation(format!(
+            "Listing of {:?} returned {:?}, {:?} were written",
+            dir, unique, written
+        )));
+    }
+
+    Ok(())
+}
+
+fn list_missing(_: &dyn Backend, thread: &mut dyn BackendThread, dir: &Path) -> io::Result<()> {
+    let missing = dir.join("missing");
+
+    match thread.list(missing.clone()) {
+        Ok(entries) if entries.is_empty() => Ok(()),
+        Ok(entries) => Err(violation(format!("Missing {:?} listed {:?}", missing, entries))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(violation(format!(
+            "Listing of missing {:?} failed with {:?}, not NotFound",
+            missing, e
+        ))),
+    }
+}
+
+/// Removes objects below `dir` left by a check, as far as the backend lets it.
+fn clean(thread: &mut dyn BackendThread, dir: &Path) {
+    let entries = match thread.list(dir.to_path_buf()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries {
+        let file_name = entry.file_name().unwrap_or_else(|| entry.as_os_str());
+        let _ = thread.remove(dir.join(file_name));
+    }
+}
+
+/// Runs all the checks against `backend`, each in its own directory below `scratch`.
+pub fn run(backend: &dyn Backend, scratch: &Path) -> io::Result<ConformanceReport> {
+    let mut thread = backend.new_thread()?;
+    let mut checks = Vec::with_capacity(CHECKS.len());
+
+    for (name, check) in CHECKS {
+        let dir = scratch.join(name);
+
+        debug!("Running conformance check {} in {:?}", name, dir);
+
+        let result = check(backend, thread.as_mut(), &dir);
+        clean(thread.as_mut(), &dir);
+
+        checks.push(CheckResult {
+            check: name,
+            failure: result.err().map(|e| e.to_string()),
+        });
+    }
+
+    Ok(ConformanceReport {
+        scratch: scratch.to_path_buf(),
+        failed: checks.iter().filter(|c| c.failure.is_some()).count(),
+        checks,
+    })
+}
+
+pub fn print_report(report: &ConformanceReport) {
+    for check in &report.checks {
+        match &check.failure {
+            Some(failure) => println!("{}: FAILED: {}", check.check, failure),
+            None => println!("{}: OK", check.check),
+        }
+    }
+
+    println!("{} of {} checks failed", report.failed, report.checks.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rdedup_lib::backends::local::Local;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn assert_conforms(report: &ConformanceReport) {
+        let failures: Vec<_> = report
+            .checks
+            .iter()
+            .filter_map(|c| c.failure.as_ref().map(|failure| format!("{}: {}", c.check, failure)))
+            .collect();
+
+        assert!(failures.is_empty(), "Failed checks: {:#?}", failures);
+        assert_eq!(report.checks.len(), CHECKS.len());
+    }
+
+    #[test]
+    fn local_backend_conforms() {
+        let dir = std::env::temp_dir().join(format!("rbackup2-conformance-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let report = run(&Local::new(dir.clone()), Path::new("conformance"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_conforms(&report.unwrap());
+    }
+
+    /// The scratch directory the conformance binary uses by default, servers refuse other dot-prefixed ones.
+    #[test]
+    fn local_backend_conforms_in_staging_directory() {
+        let dir = std::env::temp_dir().join(format!("rbackup2-conformance-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let scratch = Path::new(".pending/conformance").join(Uuid::new_v4().to_string());
+        let report = run(&Local::new(dir.clone()), &scratch);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_conforms(&report.unwrap());
+    }
+}
@@ -0,0 +1,194 @@
+//! Delta restore - data already present in the restore destination aren't downloaded again.
+//!
+//! The destination is archived and chunked locally exactly like a store of it would be, but the resulting objects are
+//! kept in a local seed directory instead of being uploaded. Chunks of the restored snapshot found there are then read
+//! locally; only the differing ones are downloaded.
+//!
+//! Resumed restores (see [`crate::resume`]) seed from the part of the file restored already the same way.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use libcommon::paths::ObjectType;
+use log::*;
+use rdedup_lib::backends::{Backend, BackendThread, Lock, Metadata};
+use rdedup_lib::{PassphraseFn, Repo as RdedupRepo};
+use sgdata::SGData;
+use uuid::Uuid;
+
+use crate::api::PIPE_CAPACITY;
+use crate::local_crypt;
+use crate::memory;
+use crate::pipe;
+use crate::pipe::PipeWriter;
+use crate::remote::RemoteBackend;
+use crate::snapshot;
+
+/// Name the destination is "stored" under; names are never written anywhere.
+const SEED_NAME: &str = "delta-seed";
+
+/// Seed of a running delta restore; removed once dropped.
+pub struct Seed {
+    remote: RemoteBackend,
+    dir: PathBuf,
+}
+
+impl Drop for Seed {
+    fn drop(&mut self) {
+        self.remote.set_seed(None);
+
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("Could not remove delta restore seed {:?}: {}", self.dir, e);
+        }
+    }
+}
+
+/// Chunks `dest` directory into a local seed and makes `remote` read the chunks found there locally.
+pub fn seed(remote: &RemoteBackend, dest: &Path, passfn: PassphraseFn) -> io::Result<Seed> {
+    debug!("Seeding delta restore from {:?}", dest);
+
+    let dest = dest.to_path_buf();
+    seed_with(remote, passfn, move |writer| snapshot::write_tree(&dest, writer).map(|_| ()))
+}
+
+/// Same as `seed`, but chunks first `len` bytes of `file`.
+pub fn seed_file(remote: &RemoteBackend, file: &Path, len: u64, passfn: PassphraseFn) -> io::Result<Seed> {
+    debug!("Seeding delta restore from first {}B of {:?}", len, file);
+
+    let mut input = File::open(file)?.take(len);
+    seed_with(remote, passfn, move |mut writer| {
+        let aborter = writer.clone();
+
+        io::copy(&mut input, &mut writer).map(|_| ()).map_err(|e| {
+            aborter.abort(Error::new(e.kind(), e.to_string()));
+            e
+        })
+    })
+}
+
+/// Chunks data written by `producer` (in a background thread) into a new seed.
+fn seed_with(
+    remote: &RemoteBackend,
+    passfn: PassphraseFn,
+    producer: impl FnOnce(PipeWriter) -> io::Result<()> + Send + 'static,
+) -> io::Result<Seed> {
+    let dir = std::env::temp_dir().join(format!("rbackup2-seed-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir)?;
+
+    // removes the dir on failure
+    let seed = Seed {
+        remote: remote.clone(),
+        dir: dir.clone(),
+    };
+
+    debug!("Seeding into {:?}", dir);
+
+    let create_backend = {
+        let backend = SeedBackend {
+            remote: remote.clone(),
+            dir: dir.clone(),
+        };
+        move |_: &url1::Url| -> io::Result<Box<dyn Backend + Send + Sync>> { Ok(Box::new(backend.clone())) }
+    };
+
+    let url = url1::Url::parse(remote.server_url().as_str()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let repo = RdedupRepo::open_custom(&url, &create_backend, None).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    let wh = repo.unlock_encrypt(&passfn)?;
+
+    let (writer, reader) = pipe::pipe(memory::pipe_capacity(PIPE_CAPACITY));
+
+    let producer = thread::spawn(move || producer(writer));
+
+    let stats = repo.write(SEED_NAME, reader, &wh);
+    producer.join().expect("Seed producer thread panicked")?;
+    stats?;
+
+    remote.set_seed(Some(dir));
+
+    Ok(seed)
+}
+
+/// Reads through the remote backend, but keeps written chunks and indexes in the seed directory.
+#[derive(Clone)]
+struct SeedBackend {
+    remote: RemoteBackend,
+    dir: PathBuf,
+}
+
+impl Backend for SeedBackend {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.remote.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.remote.lock_shared()
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(SeedThread {
+            remote: self.remote.new_thread()?,
+            backend: self.remote.clone(),
+            dir: self.dir.clone(),
+        }))
+    }
+}
+
+struct SeedThread {
+    remote: Box<dyn BackendThread>,
+    /// Source of the local data key
+    backend: RemoteBackend,
+    dir: PathBuf,
+}
+
+fn unsupported() -> Error {
+    Error::new(ErrorKind::Other, "Not supported while seeding delta restore")
+}
+
+impl BackendThread for SeedThread {
+    fn remove_dir_all(&mut self, _path: PathBuf) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn rename(&mut self, _src_path: PathBuf, _dst_path: PathBuf) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn write(&mut self, path: PathBuf, sg: SGData, _idempotent: bool) -> io::Result<()> {
+        match ObjectType::of(&path) {
+            ObjectType::Chunk | ObjectType::Index => {
+                let dest = self.dir.join(&path);
+                fs::create_dir_all(dest.parent().expect("Object path without parent"))?;
+                local_crypt::write(self.backend.local_key(), &dest, &sg.to_linear_vec())
+            }
+            _ => {
+                trace!("Discarding seed write of {:?}", path);
+                Ok(())
+            }
+        }
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.remote.read(path)
+    }
+
+    fn remove(&mut self, _path: PathBuf) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.remote.read_metadata(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.remote.list(path)
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: Sender<io::Result<Vec<PathBuf>>>) {
+        self.remote.list_recursively(path, tx)
+    }
+}
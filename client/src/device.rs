@@ -0,0 +1,222 @@
+//! Image-level backups of raw block devices (e.g. disks of virtual machines), optionally read from an LVM or ZFS
+//! snapshot taken for the run, so the image is consistent while the device stays in use.
+
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use err_context::AnyError;
+use log::*;
+use structopt::StructOpt;
+
+/// Prefix of zvol device nodes
+const ZVOL_DIR: &str = "/dev/zvol/";
+
+/// How long to wait for udev to create the device node of a new snapshot
+const DEVICE_NODE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEVICE_NODE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotKind {
+    Lvm,
+    Zfs,
+}
+
+impl FromStr for SnapshotKind {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lvm" => Ok(SnapshotKind::Lvm),
+            "zfs" => Ok(SnapshotKind::Zfs),
+            _ => Err(AnyError::from(format!("Unknown snapshot kind {:?}, expected `lvm` or `zfs`", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct DeviceOptions {
+    /// The source is a raw block device, stored as an image
+    #[structopt(long)]
+    pub device: bool,
+    /// Read the device from a snapshot (`lvm` or `zfs`) created for the run and released afterwards; needs root
+    #[structopt(long, requires = "device")]
+    pub snapshot: Option<SnapshotKind>,
+    /// Size of LVM snapshots, in `lvcreate --extents` syntax - the writes to the device during the run must fit
+    #[structopt(long, default_value = "10%ORIGIN")]
+    pub lvm_snapshot_extents: String,
+}
+
+/// Size of the device; block devices report zero length in their metadata.
+pub fn size(file: &mut File) -> io::Result<u64> {
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(size)
+}
+
+/// Opens `device`, refusing anything but a block device.
+pub fn open(device: &Path) -> io::Result<File> {
+    let file = File::open(device)?;
+
+    if !file.metadata()?.file_type().is_block_device() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is not a block device", device),
+        ));
+    }
+
+    Ok(file)
+}
+
+/// Snapshot of a device, released once dropped.
+pub struct Snapshot {
+    kind: SnapshotKind,
+    device: PathBuf,
+    /// LVM logical volume or ZFS datasets (the clone first) to destroy
+    release: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn create(kind: SnapshotKind, device: &Path, options: &DeviceOptions) -> io::Result<Snapshot> {
+        let suffix = format!(
+            "rbackup2-{}",
+            SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs()
+        );
+
+        let snapshot = match kind {
+            SnapshotKind::Lvm => create_lvm(device, &suffix, &options.lvm_snapshot_extents)?,
+            SnapshotKind::Zfs => create_zfs(device, &suffix)?,
+        };
+
+        // dropped when the device doesn't show up, releasing the snapshot
+        wait_for_node(&snapshot.device)?;
+
+        info!("Reading {:?} from {:?} snapshot {:?}", device, kind, snapshot.device);
+
+        Ok(snapshot)
+    }
+
+    pub fn device(&self) -> &Path {
+        &self.device
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        for target in &self.release {
+            let result = match self.kind {
+                SnapshotKind::Lvm => run("lvremove", &["--force", target]),
+                SnapshotKind::Zfs => run("zfs", &["destroy", target]),
+            };
+
+            match result {
+                Ok(_) => debug!("Released snapshot {}", target),
+                Err(e) => {
+                    warn!("Could not release snapshot {}: {}", target, e);
+                    eprintln!("Warning: could not release snapshot {}: {}", target, e);
+                }
+            }
+        }
+    }
+}
+
+fn create_lvm(device: &Path, suffix: &str, extents: &str) -> io::Result<Snapshot> {
+    let device_str = device.to_string_lossy();
+    let names = run("lvs", &["--noheadings", "-o", "vg_name,lv_name", &device_str])?;
+    let mut names = names.split_whitespace();
+
+    let (vg, lv) = match (names.next(), names.next()) {
+        (Some(vg), Some(lv)) => (vg.to_string(), lv.to_string()),
+        _ => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{:?} is not an LVM logical volume", device),
+            ))
+        }
+    };
+
+    let name = format!("{}-{}", lv, suffix);
+    run(
+        "lvcreate",
+        &["--snapshot", "--extents", extents, "--name", &name, &format!("{}/{}", vg, lv)],
+    )?;
+
+    Ok(Snapshot {
+        kind: SnapshotKind::Lvm,
+        device: Path::new("/dev").join(&vg).join(&name),
+        release: vec![format!("{}/{}", vg, name)],
+    })
+}
+
+/// ZFS snapshots of zvols don't get device nodes by default, so the snapshot is cloned.
+fn create_zfs(device: &Path, suffix: &str) -> io::Result<Snapshot> {
+    let dataset = device.to_str().and_then(|d| d.strip_prefix(ZVOL_DIR)).ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is not a ZFS volume (expected {}<pool>/<volume>)", device, ZVOL_DIR),
+        )
+    })?;
+
+    let snapshot = format!("{}@{}", dataset, suffix);
+    let clone = format!("{}-{}", dataset, suffix);
+
+    run("zfs", &["snapshot", &snapshot])?;
+
+    if let Err(e) = run("zfs", &["clone", &snapshot, &clone]) {
+        let _ = run("zfs", &["destroy", &snapshot]);
+        return Err(e);
+    }
+
+    Ok(Snapshot {
+        kind: SnapshotKind::Zfs,
+        device: Path::new(ZVOL_DIR).join(&clone),
+        release: vec![clone, snapshot],
+    })
+}
+
+fn wait_for_node(device: &Path) -> io::Result<()> {
+    let start = Instant::now();
+
+    while !device.exists() {
+        if start.elapsed() > DEVICE_NODE_TIMEOUT {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("Device {:?} of the snapshot didn't appear", device),
+            ));
+        }
+
+        thread::sleep(DEVICE_NODE_POLL_INTERVAL);
+    }
+
+    Ok(())
+}
+
+/// Runs `program`, returning its stdout; fails with its stderr.
+fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    debug!("Running {} {:?}", program, args);
+
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not run {}: {}", program, e)))?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!(
+                "{} failed ({}): {}",
+                program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
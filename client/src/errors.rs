@@ -0,0 +1,124 @@
+//! Classification of failures, so alerting can tell "server down" from "backup data corrupt".
+//!
+//! Errors raised by the client itself (see [`classified`]) carry their class explicitly; other errors are classified by
+//! their kind and OS error code.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::str::FromStr;
+
+use err_context::AnyError;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureClass {
+    /// The server is unreachable, unavailable or in maintenance; retrying later likely helps
+    Network,
+    /// The token is missing, invalid or lacks permissions for the operation
+    Auth,
+    /// Data read from the repository are corrupted
+    Corruption,
+    /// The server (or local) disk is full, or a quota was exceeded
+    Quota,
+    Other,
+}
+
+impl fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FailureClass::Network => "network",
+            FailureClass::Auth => "auth",
+            FailureClass::Corruption => "corruption",
+            FailureClass::Quota => "quota",
+            FailureClass::Other => "other",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for FailureClass {
+    type Err = AnyError;
+
+    fn from_str(s: &str) -> Result<FailureClass, AnyError> {
+        match s {
+            "network" => Ok(FailureClass::Network),
+            "auth" => Ok(FailureClass::Auth),
+            "corruption" => Ok(FailureClass::Corruption),
+            "quota" => Ok(FailureClass::Quota),
+            "other" => Ok(FailureClass::Other),
+            _ => Err(AnyError::from(format!(
+                "Invalid failure class {:?}, expected network, auth, corruption, quota or other",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClassifiedError {
+    class: FailureClass,
+    message: String,
+}
+
+impl fmt::Display for ClassifiedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ClassifiedError {}
+
+/// Error of `kind` with explicit class, for failures the kind alone doesn't describe well.
+pub fn classified(kind: ErrorKind, class: FailureClass, message: impl Into<String>) -> io::Error {
+    io::Error::new(
+        kind,
+        ClassifiedError {
+            class,
+            message: message.into(),
+        },
+    )
+}
+
+pub fn classify(e: &io::Error) -> FailureClass {
+    if let Some(classified) = e.get_ref().and_then(|inner| inner.downcast_ref::<ClassifiedError>()) {
+        return classified.class;
+    }
+
+    // rdedup wraps errors of the backend into its own ones
+    if let Some(inner) = e.get_ref().and_then(|inner| inner.downcast_ref::<io::Error>()) {
+        return classify(inner);
+    }
+
+    match e.raw_os_error() {
+        Some(nix::libc::ENOSPC) | Some(nix::libc::EDQUOT) => return FailureClass::Quota,
+        Some(nix::libc::EHOSTUNREACH) | Some(nix::libc::ENETUNREACH) => return FailureClass::Network,
+        _ => (),
+    }
+
+    match e.kind() {
+        ErrorKind::BrokenPipe
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::TimedOut => FailureClass::Network,
+        ErrorKind::PermissionDenied => FailureClass::Auth,
+        ErrorKind::InvalidData | ErrorKind::UnexpectedEof => FailureClass::Corruption,
+        _ => FailureClass::Other,
+    }
+}
+
+/// Class of an error surfaced by a command.
+pub fn classify_any(e: &AnyError) -> FailureClass {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => classify(e),
+        None => e
+            .downcast_ref::<ClassifiedError>()
+            .map(|classified| classified.class)
+            .unwrap_or(FailureClass::Other),
+    }
+}
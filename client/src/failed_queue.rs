@@ -0,0 +1,182 @@
+//! Queue of uploads which failed on the network, kept in the client state dir (`store --queue-failed`).
+//!
+//! Chunks and indexes failing to upload are queued instead of aborting the store; the name is queued too then, since
+//! committing it would make a broken backup visible. `retry-failed` uploads the queued objects, names last, salvaging
+//! mostly complete runs over flaky links.
+//!
+//! The objects are queued as they would be stored by the server - encrypted by rdedup already - in the `spool` format,
+//! sealed by the key shared by the profiles too with `--encrypt-local` (see `local_crypt`). Objects queued with the key
+//! need it to be retried.
+//!
+//! The queue is shared by all the profiles of a repository using the state dir, and it's addressed by the repository
+//! paths, which carry the digest of chunks and indexes: a chunk failing in stores of several profiles is queued once and
+//! `retry-failed` uploads it once. Each repository has its own queue - the same chunk has the same digest, but not the
+//! same data, in repositories with different keys.
+
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libcommon::paths::{self, ObjectType};
+use log::*;
+use sgdata::SGData;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::local_crypt::LocalKey;
+use crate::peer;
+use crate::spool;
+
+const QUEUE_DIR: &str = "failed-uploads";
+const OBJECTS_DIR: &str = "objects";
+/// Retention of queued names, unix timestamps stored under the path of the name
+const RETENTION_DIR: &str = "retention";
+
+#[derive(Debug)]
+pub struct FailedQueue {
+    dir: PathBuf,
+    /// Objects queued by this process
+    queued: AtomicU64,
+    /// Objects of those found queued already
+    deduplicated: AtomicU64,
+}
+
+/// Object waiting for upload.
+#[derive(Debug, Clone)]
+pub struct QueuedObject {
+    /// Repository path of the object
+    pub path: PathBuf,
+    pub retain_until: Option<u64>,
+}
+
+impl FailedQueue {
+    /// Queue of the repository served by `server_url`, told apart by its `config` (which changes with the keys) from
+    /// a repository created there anew.
+    pub fn open(state_dir: &Path, server_url: &Url, config: &[u8]) -> FailedQueue {
+        let queues = state_dir.join(QUEUE_DIR);
+
+        if queues.join(OBJECTS_DIR).exists() {
+            warn!(
+                "Uploads queued by an older client in {:?} aren't retried, their repository is unknown",
+                queues.join(OBJECTS_DIR)
+            );
+        }
+
+        let repository = format!("{}-{}", peer::repository_id(server_url), &hex::encode(Sha256::digest(config))[..16]);
+
+        FailedQueue {
+            dir: queues.join(repository),
+            queued: AtomicU64::new(0),
+            deduplicated: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of objects queued by this process.
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Number of objects queued by this process which were found queued already, see `push`.
+    pub fn deduplicated(&self) -> u64 {
+        self.deduplicated.load(Ordering::Relaxed)
+    }
+
+    /// Queues object at repository `path`, encrypted when there's a `key`.
+    pub fn push(&self, path: &Path, sg: &SGData, retain_until: Option<u64>, key: Option<&LocalKey>) -> io::Result<()> {
+        let file = self.dir.join(OBJECTS_DIR).join(path);
+
+        // content-addressed, so the same data, queued by an earlier run of any profile; files are written whole
+        if paths::path_digest(path).is_some() && file.exists() {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+            self.deduplicated.fetch_add(1, Ordering::Relaxed);
+            debug!("Upload of {:?} is queued already", path);
+
+            return Ok(());
+        }
+
+        write_file(&file, &spool::encode(&sg.to_linear(), key)?)?;
+
+        if let Some(retain_until) = retain_until {
+            write_file(&self.dir.join(RETENTION_DIR).join(path), retain_until.to_string().as_bytes())?;
+        }
+
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        debug!("Queued failed upload of {:?}", path);
+
+        Ok(())
+    }
+
+    /// Queued objects, those referenced by others (chunks, indexes) first.
+    pub fn list(&self) -> io::Result<Vec<QueuedObject>> {
+        let mut paths = Vec::new();
+        collect(&self.dir.join(OBJECTS_DIR), Path::new(""), &mut paths)?;
+
+        paths.sort_by_key(|path| ObjectType::of(path) == ObjectType::Name);
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let retain_until = match fs::read_to_string(self.dir.join(RETENTION_DIR).join(&path)) {
+                    Ok(until) => Some(until.trim().parse().map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?),
+                    Err(e) if e.kind() == ErrorKind::NotFound => None,
+                    Err(e) => return Err(e),
+                };
+
+                Ok(QueuedObject { path, retain_until })
+            })
+            .collect()
+    }
+
+    /// Data of queued `object`, pushed with the same `key`.
+    pub fn read(&self, object: &QueuedObject, key: Option<&LocalKey>) -> io::Result<SGData> {
+        let data = spool::decode(fs::read(self.dir.join(OBJECTS_DIR).join(&object.path))?, key)
+            .map_err(|e| io::Error::new(e.kind(), format!("Queued {:?}: {}", object.path, e)))?;
+
+        Ok(SGData::from_single(data))
+    }
+
+    /// Removes uploaded object from the queue.
+    pub fn remove(&self, object: &QueuedObject) -> io::Result<()> {
+        fs::remove_file(self.dir.join(OBJECTS_DIR).join(&object.path))?;
+
+        match fs::remove_file(self.dir.join(RETENTION_DIR).join(&object.path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the file whole or not at all, so an interrupted run doesn't leave a truncated object queued.
+fn write_file(file: &Path, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(file.parent().expect("Queued path without parent"))?;
+
+    let temp = file.with_file_name(format!(
+        ".{}.tmp",
+        file.file_name().expect("Queued path without file name").to_string_lossy()
+    ));
+    fs::write(&temp, data)?;
+    fs::rename(&temp, file)
+}
+
+fn collect(dir: &Path, relative: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            collect(&entry.path(), &path, paths)?;
+        } else if !entry.file_name().to_string_lossy().starts_with('.') {
+            paths.push(path);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Summaries of past store runs, kept in the client state dir as JSON lines (one file per state dir, all profiles).
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{self, FailureClass};
+use crate::reports::{HistoryReport, ProfileTrend, StoreResult};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// `$XDG_STATE_HOME/rbackup2`, falling back to `~/.local/state/rbackup2`.
+pub fn default_state_dir() -> PathBuf {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            Path::new(&home).join(".local").join("state")
+        }
+    };
+
+    base.join("rbackup2")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub profile: String,
+    pub name: String,
+    /// Unix timestamp (seconds) of the run end
+    pub timestamp: u64,
+    /// Set when the run failed
+    pub error: Option<String>,
+    pub error_class: Option<FailureClass>,
+    pub source_bytes: u64,
+    pub files: u64,
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+    pub duration_ms: u128,
+}
+
+impl RunSummary {
+    pub fn of(profile: &str, name: &str, result: &io::Result<StoreResult>) -> RunSummary {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs();
+
+        match result {
+            Ok(result) => RunSummary {
+                profile: profile.to_string(),
+                name: name.to_string(),
+                timestamp,
+                error: None,
+                error_class: None,
+                source_bytes: result.source_bytes,
+                files: result.files,
+                new_chunks: result.new_chunks,
+                new_bytes: result.new_bytes,
+                duration_ms: result.duration_ms,
+            },
+            Err(e) => RunSummary {
+                profile: profile.to_string(),
+                name: name.to_string(),
+                timestamp,
+                error: Some(e.to_string()),
+                error_class: Some(errors::classify(e)),
+                source_bytes: 0,
+                files: 0,
+                new_chunks: 0,
+                new_bytes: 0,
+                duration_ms: 0,
+            },
+        }
+    }
+}
+
+pub fn record(state_dir: &Path, summary: &RunSummary) -> io::Result<()> {
+    fs::create_dir_all(state_dir)?;
+
+    let mut file = OpenOptions::new().create(true).append(true).open(state_dir.join(HISTORY_FILE))?;
+
+    let mut line = serde_json::to_vec(summary)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// Loads last `last` runs of each profile (or just of given one).
+pub fn load(state_dir: &Path, profile: Option<&str>, last: usize) -> io::Result<BTreeMap<String, Vec<RunSummary>>> {
+    let file = match fs::File::open(state_dir.join(HISTORY_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut runs: BTreeMap<String, Vec<RunSummary>> = BTreeMap::new();
+
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+
+        // a run killed while recording may leave broken line behind, it's not worth failing for
+        let summary: RunSummary = match serde_json::from_str(&line) {
+            Ok(summary) => summary,
+            Err(e) => {
+                warn!("Skipping invalid history record: {}", e);
+                continue;
+            }
+        };
+
+        if profile.map_or(true, |p| p == summary.profile) {
+            runs.entry(summary.profile.clone()).or_default().push(summary);
+        }
+    }
+
+    for profile_runs in runs.values_mut() {
+        let skip = profile_runs.len().saturating_sub(last);
+        profile_runs.drain(..skip);
+    }
+
+    Ok(runs)
+}
+
+pub fn report(runs: BTreeMap<String, Vec<RunSummary>>) -> HistoryReport {
+    let profiles = runs
+        .into_iter()
+        .map(|(profile, runs)| {
+            let successful: Vec<&RunSummary> = runs.iter().filter(|r| r.error.is_none()).collect();
+
+            let avg_new_bytes = if successful.is_empty() {
+                0
+            } else {
+                successful.iter().map(|r| r.new_bytes).sum::<u64>() / successful.len() as u64
+            };
+
+            let source_growth_bytes = match (successful.first(), successful.last()) {
+                (Some(first), Some(last)) => last.source_bytes as i64 - first.source_bytes as i64,
+                _ => 0,
+            };
+
+            ProfileTrend {
+                profile,
+                failed: runs.len() - successful.len(),
+                avg_new_bytes,
+                source_growth_bytes,
+                last_success: successful.last().map(|r| r.timestamp),
+                runs,
+            }
+        })
+        .collect();
+
+    HistoryReport { profiles }
+}
+
+pub fn print_report(report: &HistoryReport) {
+    for trend in &report.profiles {
+        println!("Profile {}:", trend.profile);
+
+        for run in &trend.runs {
+            match &run.error {
+                None => println!(
+                    "  {} {:<30} {:>14}B source {:>8} files {:>14}B new {:>8}ms",
+                    run.timestamp, run.name, run.source_bytes, run.files, run.new_bytes, run.duration_ms
+                ),
+                Some(e) => println!(
+                    "  {} {:<30} FAILED ({}): {}",
+                    run.timestamp,
+                    run.name,
+                    run.error_class.unwrap_or(FailureClass::Other),
+                    e
+                ),
+            }
+        }
+
+        println!(
+            "  {} runs, {} failed, avg new {}B per run, source grew by {}B, last success: {}",
+            trend.runs.len(),
+            trend.failed,
+            trend.avg_new_bytes,
+            trend.source_growth_bytes,
+            trend.last_success.map(|t| t.to_string()).unwrap_or_else(|| "never".to_string())
+        );
+    }
+}
@@ -0,0 +1,202 @@
+//! Pre/post hooks of stores, making consistent application-level backups (database dumps, flushed tables) possible
+//! without wrapper scripts around the client.
+//!
+//! Hooks of a profile are kept in `hooks/<profile>.json` in the client state dir:
+//!
+//! ```json
+//! {"hooks": [{"path": "/srv/db/dump.sql", "pre": "pg_dump -f /srv/db/dump.sql app", "post": "rm /srv/db/dump.sql"}]}
+//! ```
+//!
+//! A hook applies to stores whose source contains its `path`: the `pre` command runs before the store starts chunking
+//! and the `post` command after the store ends, whatever its outcome. A failed `pre` command aborts the store, post
+//! commands of the hooks whose `pre` already ran still run then. Commands run by `sh -c` and get JSON describing the
+//! run on stdin, including the error and its class for `post` commands of failed stores. Their stdout is discarded,
+//! stderr passed through.
+
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{self, FailureClass};
+
+const HOOKS_DIR: &str = "hooks";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn default_timeout_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HooksFile {
+    hooks: Vec<Hook>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Hook {
+    /// Absolute path the hook prepares
+    pub path: PathBuf,
+    pub pre: Option<String>,
+    pub post: Option<String>,
+    /// Commands running longer are killed, failing
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Phase {
+    Pre,
+    Post,
+}
+
+/// Sent to the commands on stdin.
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    phase: Phase,
+    profile: &'a str,
+    name: &'a str,
+    path: &'a Path,
+    /// Outcome of the store, for post commands
+    success: Option<bool>,
+    error: Option<String>,
+    error_class: Option<FailureClass>,
+}
+
+pub struct Hooks {
+    profile: String,
+    hooks: Vec<Hook>,
+}
+
+impl Hooks {
+    /// Hooks of `profile`, none when it has no hooks file.
+    pub fn load(state_dir: &Path, profile: &str) -> io::Result<Hooks> {
+        let file = state_dir.join(HOOKS_DIR).join(format!("{}.json", profile));
+
+        let hooks = match fs::read(&file) {
+            Ok(content) => {
+                let parsed: HooksFile = serde_json::from_slice(&content)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid hooks file {:?}: {}", file, e)))?;
+                debug!("Loaded {} hooks of profile {}", parsed.hooks.len(), profile);
+                parsed.hooks
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Hooks {
+            profile: profile.to_string(),
+            hooks,
+        })
+    }
+
+    /// Runs `store` of `source` under `name` between the pre and post commands of the hooks applying to it. Failures of
+    /// post commands fail an otherwise successful store - they may have left an application locked.
+    pub fn around<T>(&self, source: &Path, name: &str, store: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        let source = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+        let mut started = Vec::new();
+
+        for hook in self.hooks.iter().filter(|h| h.path.starts_with(&source)) {
+            if let Some(command) = &hook.pre {
+                if let Err(e) = self.run(hook, Phase::Pre, command, name, None) {
+                    let _ = self.run_post(&started, name, Some(&e));
+                    return Err(e);
+                }
+            }
+
+            started.push(hook);
+        }
+
+        let result = store();
+        let post = self.run_post(&started, name, result.as_ref().err());
+
+        match (result, post) {
+            (Ok(_), Err(e)) => Err(e),
+            (result, _) => result,
+        }
+    }
+
+    /// Runs post commands of `started` hooks (in reverse order, all of them), returning the first failure.
+    fn run_post(&self, started: &[&Hook], name: &str, error: Option<&Error>) -> io::Result<()> {
+        let mut result = Ok(());
+
+        for hook in started.iter().rev() {
+            if let Some(command) = &hook.post {
+                if let Err(e) = self.run(hook, Phase::Post, command, name, Some(error)) {
+                    eprintln!("Warning: {}", e);
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// `outcome` is the error of the store (if it failed) for post commands.
+    fn run(&self, hook: &Hook, phase: Phase, command: &str, name: &str, outcome: Option<Option<&Error>>) -> io::Result<()> {
+        info!("Running {:?} hook of {:?}: {}", phase, hook.path, command);
+
+        let payload = Payload {
+            phase,
+            profile: &self.profile,
+            name,
+            path: &hook.path,
+            success: outcome.map(|e| e.is_none()),
+            error: outcome.flatten().map(|e| e.to_string()),
+            error_class: outcome.flatten().map(errors::classify),
+        };
+
+        let failed = |reason: String| Error::new(ErrorKind::Other, format!("{:?} hook of {:?} failed: {}", phase, hook.path, reason));
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| failed(format!("could not run it: {}", e)))?;
+
+        {
+            let mut stdin = child.stdin.take().expect("Missing hook input");
+            let json = serde_json::to_vec(&payload)?;
+            // commands not interested in the payload may exit without reading it
+            if let Err(e) = stdin.write_all(&json) {
+                debug!("Hook didn't take its payload: {}", e);
+            }
+        }
+
+        match wait(&mut child, Duration::from_secs(hook.timeout_secs))? {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(failed(format!("exited with {}", status))),
+            None => Err(failed(format!("killed after {}s", hook.timeout_secs))),
+        }
+    }
+}
+
+/// Exit status of `child`, `None` when it had to be killed after `timeout`.
+fn wait(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+
+        if start.elapsed() > timeout {
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
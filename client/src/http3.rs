@@ -0,0 +1,211 @@
+//! HTTP/3 (QUIC) transport, used when the server advertises HTTP/3 listener and it's enabled by the user.
+//!
+//! QUIC needs tokio 1, the connection is driven by its own runtime.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes};
+use err_context::AnyError;
+use h3::client::SendRequest;
+use log::*;
+use once_cell::sync::OnceCell;
+use tokio1::runtime::Runtime;
+use tokio1::sync::mpsc;
+use url::Url;
+
+use crate::transport::{Request, RequestBody, Response, Transport};
+
+static OPTIONS: OnceCell<Http3Options> = OnceCell::new();
+
+/// Received body parts buffered ahead of the reader.
+const BODY_BUFFERS: usize = 16;
+
+#[derive(Debug, Clone, Default)]
+pub struct Http3Options {
+    /// PEM file with CA certificate(s) the server certificate is verified by; system roots are used when not set
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// Enables HTTP/3 for servers advertising it.
+pub fn enable(options: Http3Options) -> Result<(), AnyError> {
+    OPTIONS.set(options).map_err(|_| AnyError::from("HTTP/3 already enabled"))
+}
+
+pub fn options() -> Option<&'static Http3Options> {
+    OPTIONS.get()
+}
+
+fn root_certs(options: &Http3Options) -> Result<rustls::RootCertStore, AnyError> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    match &options.ca_cert {
+        Some(path) => {
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))? {
+                roots.add(&rustls::Certificate(cert))?;
+            }
+        }
+        None => {
+            for cert in rustls_native_certs::load_native_certs()? {
+                roots.add(&rustls::Certificate(cert.0))?;
+            }
+        }
+    }
+
+    Ok(roots)
+}
+
+pub struct Http3Transport {
+    runtime: Runtime,
+    send_request: SendRequest<h3_quinn::OpenStreams, Bytes>,
+    /// Server the connection leads to
+    host: String,
+    port: Option<u16>,
+    /// For requests to other servers (e.g. a replica)
+    fallback: Arc<dyn Transport>,
+    _endpoint: quinn::Endpoint,
+}
+
+impl Http3Transport {
+    pub fn connect(
+        server_url: &Url,
+        http3_port: u16,
+        options: &Http3Options,
+        fallback: Arc<dyn Transport>,
+    ) -> Result<Http3Transport, AnyError> {
+        let host = server_url.host_str().ok_or_else(|| AnyError::from("Server URL without host"))?;
+        let addr = (host, http3_port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| AnyError::from(format!("Could not resolve {}", host)))?;
+
+        let mut tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_certs(options)?)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+
+        let runtime = tokio1::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("http3")
+            .build()?;
+
+        let (endpoint, send_request) = runtime.block_on(async {
+            let local: SocketAddr = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }.parse()?;
+
+            let mut endpoint = quinn::Endpoint::client(local)?;
+            endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+
+            let connection = endpoint.connect(addr, host)?.await?;
+            let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(connection)).await?;
+
+            tokio1::spawn(async move {
+                if let Err(e) = futures::future::poll_fn(|cx| driver.poll_close(cx)).await {
+                    debug!("HTTP/3 connection closed: {}", e);
+                }
+            });
+
+            Ok::<_, AnyError>((endpoint, send_request))
+        })?;
+
+        debug!("Connected to {} over HTTP/3", addr);
+
+        Ok(Http3Transport {
+            runtime,
+            send_request,
+            host: host.to_string(),
+            port: server_url.port_or_known_default(),
+            fallback,
+            _endpoint: endpoint,
+        })
+    }
+}
+
+impl Transport for Http3Transport {
+    fn send(&self, request: Request) -> io::Result<Response> {
+        if request.url.host_str() != Some(self.host.as_str()) || request.url.port_or_known_default() != self.port {
+            return self.fallback.send(request);
+        }
+
+        // written objects are limited in size by the server, buffering them is fine
+        let body = match request.body {
+            RequestBody::Empty => Vec::new(),
+            RequestBody::Bytes(data) => data,
+            RequestBody::Stream(mut reader) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                data
+            }
+        };
+
+        let mut http_request = http::Request::builder()
+            .method(request.method)
+            .uri(request.url.as_str())
+            .body(())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        *http_request.headers_mut() = request.headers;
+
+        let mut send_request = self.send_request.clone();
+        let (tx, rx) = mpsc::channel(BODY_BUFFERS);
+
+        let (status, headers) = self
+            .runtime
+            .block_on(async move {
+                let mut stream = send_request.send_request(http_request).await?;
+
+                if !body.is_empty() {
+                    stream.send_data(Bytes::from(body)).await?;
+                }
+                stream.finish().await?;
+
+                let response = stream.recv_response().await?;
+
+                // the body is passed on as it comes (listings, GC events are streamed)
+                tokio1::spawn(async move {
+                    loop {
+                        let part = match stream.recv_data().await {
+                            Ok(Some(mut chunk)) => Ok(chunk.copy_to_bytes(chunk.remaining())),
+                            Ok(None) => break,
+                            Err(e) => Err(Error::new(ErrorKind::BrokenPipe, e.to_string())),
+                        };
+
+                        let failed = part.is_err();
+                        // the reader is gone
+                        if tx.send(part).await.is_err() || failed {
+                            break;
+                        }
+                    }
+                });
+
+                Ok::<_, h3::Error>((response.status(), response.headers().clone()))
+            })
+            .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?;
+
+        Ok(Response::new(status, headers, BodyReader { rx, current: Bytes::new() }))
+    }
+}
+
+struct BodyReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.current.has_remaining() {
+            match self.rx.blocking_recv() {
+                Some(part) => self.current = part?,
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.remaining());
+        self.current.copy_to_slice(&mut buf[..len]);
+
+        Ok(len)
+    }
+}
@@ -0,0 +1,154 @@
+//! Per-file SHA-256 hashes of directory snapshots, so restores can check the restored files end to end - chunk checks
+//! can't tell a file corrupted on its way from the chunks to the local disk.
+//!
+//! The hashes are calculated while the files are archived and stored in the last entry of the archive,
+//! [`MANIFEST_PATH`] in its root; restores don't unpack it. Snapshots stored by older clients have none.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use structopt::StructOpt;
+
+use crate::reports::IntegrityReport;
+
+/// Entry of the archive holding the manifest; a file of the same name in the root of a stored directory isn't restored.
+pub const MANIFEST_PATH: &str = ".rbackup2-manifest.json";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Hex SHA-256 of regular files by their path relative to the root of the snapshot
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+impl Manifest {
+    pub fn parse(input: impl Read) -> io::Result<Manifest> {
+        serde_json::from_reader(input).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Path of an archive entry without the `.` components, the way manifests key files.
+pub fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|c| !matches!(c, Component::CurDir)).collect()
+}
+
+pub fn is_manifest(path: &Path) -> bool {
+    normalize(path) == Path::new(MANIFEST_PATH)
+}
+
+/// Hashes the data as they are read.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Hex SHA-256 of all the data read.
+    pub fn finish(self) -> String {
+        hex::encode(self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Checks of restored files against the hashes recorded at store time.
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct VerifyOptions {
+    /// Hash the restored files and compare them with the hashes recorded when the directory was stored
+    #[structopt(long)]
+    pub verify_hashes: bool,
+    /// Write hashes of the restored files into this file, as JSON (implies `--verify-hashes`)
+    #[structopt(long)]
+    pub hash_manifest: Option<PathBuf>,
+}
+
+impl VerifyOptions {
+    pub fn enabled(&self) -> bool {
+        self.verify_hashes || self.hash_manifest.is_some()
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut reader = HashingReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish())
+}
+
+/// Hashes regular files below `dir` into `files`, keyed by their path relative to `root`.
+fn hash_tree(root: &Path, dir: &Path, files: &mut BTreeMap<PathBuf, String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            hash_tree(root, &entry.path(), files)?;
+        } else if file_type.is_file() {
+            let relative = entry.path().strip_prefix(root).expect("Entry outside the tree").to_path_buf();
+            files.insert(relative, hash_file(&entry.path())?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes files restored into `dest` and compares them with the `recorded` manifest. Without one (plain files,
+/// snapshots of older clients) all the restored files are hashed, for the manifest only.
+pub fn verify(dest: &Path, recorded: Option<&Manifest>, options: &VerifyOptions) -> io::Result<IntegrityReport> {
+    let mut restored = Manifest::default();
+    let mut report = IntegrityReport {
+        recorded: recorded.is_some(),
+        ..IntegrityReport::default()
+    };
+
+    match recorded {
+        Some(recorded) => {
+            for (path, hash) in &recorded.files {
+                match hash_file(&dest.join(path)) {
+                    Ok(restored_hash) => {
+                        if restored_hash != *hash {
+                            report.mismatched.push(path.clone());
+                        }
+                        restored.files.insert(path.clone(), restored_hash);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => report.missing.push(path.clone()),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        None if dest.is_dir() => hash_tree(dest, dest, &mut restored.files)?,
+        None => {
+            let name = dest.file_name().map(PathBuf::from).unwrap_or_else(|| dest.to_path_buf());
+            restored.files.insert(name, hash_file(dest)?);
+        }
+    }
+
+    report.files = restored.files.len() as u64;
+
+    if let Some(path) = &options.hash_manifest {
+        fs::write(path, restored.to_vec()?)?;
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,218 @@
+//! Metadata-only snapshots - the file tree with sizes, owners, permissions and optionally hashes of the files, but
+//! without their contents. Cheap inventory of a machine, to be compared with real backups later.
+//!
+//! Stored as newline-delimited JSON: a header line followed by a line per entry.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::pipe::PipeWriter;
+use crate::progress;
+
+/// Beginning of stored inventories, telling them from other data
+pub const INVENTORY_MAGIC: &[u8] = b"{\"inventory\":";
+
+const INVENTORY_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InventoryHeader {
+    /// Format version; keep the field first, it's the magic
+    pub inventory: u32,
+    pub source: String,
+    /// Unix timestamp
+    pub created: u64,
+    /// Whether entries of files carry hashes of their contents
+    pub hashed: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, sockets, FIFOs
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InventoryEntry {
+    /// Relative to the source, same as in directory snapshots (`./dir/file`); non UTF-8 names are stored lossily
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Unix timestamp
+    pub mtime: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    /// SHA-256 of file contents, hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Inventory {
+    pub header: InventoryHeader,
+    pub entries: Vec<InventoryEntry>,
+}
+
+/// Tells whether the data beginning with `head` are a stored inventory.
+pub fn is_inventory(head: &[u8]) -> bool {
+    head.starts_with(INVENTORY_MAGIC)
+}
+
+/// Writes inventory of `source` (directory or a single file) into the pipe, returning total size and count of the files.
+///
+/// On failure the pipe gets aborted, so the consumer doesn't store a truncated inventory.
+pub fn write(source: &Path, hash: bool, writer: PipeWriter) -> io::Result<(u64, u64)> {
+    let aborter = writer.clone();
+
+    let result = write_inner(source, hash, writer);
+
+    if let Err(e) = &result {
+        aborter.abort(io::Error::new(e.kind(), e.to_string()));
+    }
+
+    result
+}
+
+fn write_inner(source: &Path, hash: bool, writer: PipeWriter) -> io::Result<(u64, u64)> {
+    let mut out = BufWriter::new(writer);
+
+    let header = InventoryHeader {
+        inventory: INVENTORY_VERSION,
+        source: source.to_string_lossy().into_owned(),
+        created: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs(),
+        hashed: hash,
+    };
+    write_line(&mut out, &header)?;
+
+    let mut totals = (0, 0);
+
+    if source.is_dir() {
+        write_entry(&mut out, source, Path::new("."), hash, &mut totals)?;
+        write_entries(&mut out, source, Path::new("."), hash, &mut totals)?;
+    } else {
+        let name = source.file_name().map(Path::new).unwrap_or(source);
+        write_entry(&mut out, source, &Path::new(".").join(name), hash, &mut totals)?;
+    }
+
+    out.flush()?;
+
+    Ok(totals)
+}
+
+fn write_entries(out: &mut impl Write, dir: &Path, inventory_dir: &Path, hash: bool, totals: &mut (u64, u64)) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let inventory_path = inventory_dir.join(entry.file_name());
+
+        write_entry(out, &path, &inventory_path, hash, totals)?;
+
+        if entry.file_type()?.is_dir() {
+            write_entries(out, &path, &inventory_path, hash, totals)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_entry(out: &mut impl Write, path: &Path, inventory_path: &Path, hash: bool, totals: &mut (u64, u64)) -> io::Result<()> {
+    progress::set_current_file(path);
+
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+
+    let kind = if file_type.is_file() {
+        EntryKind::File
+    } else if file_type.is_dir() {
+        EntryKind::Dir
+    } else if file_type.is_symlink() {
+        EntryKind::Symlink
+    } else {
+        EntryKind::Other
+    };
+
+    let sha256 = match kind {
+        EntryKind::File if hash => Some(hash_file(path)?),
+        _ => None,
+    };
+
+    if kind == EntryKind::File {
+        totals.0 += metadata.len();
+        totals.1 += 1;
+    }
+
+    let entry = InventoryEntry {
+        path: inventory_path.to_string_lossy().into_owned(),
+        kind,
+        size: metadata.len(),
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime(),
+        link_target: match kind {
+            EntryKind::Symlink => Some(fs::read_link(path)?.to_string_lossy().into_owned()),
+            _ => None,
+        },
+        sha256,
+    };
+
+    write_line(out, &entry)
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn write_line(out: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"\n")
+}
+
+/// Reads a stored inventory.
+pub fn read(mut input: impl Read) -> io::Result<Inventory> {
+    // other data may not have any line breaks at all
+    let mut head = Vec::with_capacity(INVENTORY_MAGIC.len());
+    (&mut input).take(INVENTORY_MAGIC.len() as u64).read_to_end(&mut head)?;
+
+    if !is_inventory(&head) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a metadata-only snapshot"));
+    }
+
+    let mut lines = BufReader::new(Cursor::new(head).chain(input)).lines();
+
+    let header = parse_line::<InventoryHeader>(&lines.next().expect("The magic was read")?)?;
+
+    if header.inventory != INVENTORY_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Unsupported inventory version {}", header.inventory),
+        ));
+    }
+
+    let entries = lines
+        .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+        .map(|line| parse_line(&line?))
+        .collect::<io::Result<_>>()?;
+
+    Ok(Inventory { header, entries })
+}
+
+fn parse_line<T: serde::de::DeserializeOwned>(line: &str) -> io::Result<T> {
+    serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid inventory: {}", e)))
+}
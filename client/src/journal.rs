@@ -0,0 +1,294 @@
+//! Journal of requests to the server, recorded by `--record-journal` and replayed by `audit-idempotency`.
+//!
+//! Requests get retried when their responses are lost, so delivering any of them twice must leave the repository as
+//! delivering it once does. The audit replays a journal against a scratch server twice and compares the repository
+//! after each pass; a difference is a protocol behavior which would corrupt real repositories during retries.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use err_context::AnyError;
+use libcommon::structs::ListResponse;
+use log::*;
+use once_cell::sync::OnceCell;
+use rdedup_lib::backends::Metadata;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::transport::{Request, RequestBody, Response, Transport};
+
+static JOURNAL: OnceCell<Mutex<File>> = OnceCell::new();
+
+/// Request as delivered to the server, without the token.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    method: String,
+    path: String,
+    query: Option<String>,
+    headers: Vec<(String, String)>,
+    /// Hex
+    body: String,
+}
+
+/// Appends all requests to the server to `file` from now on.
+pub fn record(file: &Path) -> Result<(), AnyError> {
+    let file = OpenOptions::new().create(true).append(true).open(file)?;
+
+    warn!("Recording requests to the server into a journal, including the data sent");
+    JOURNAL
+        .set(Mutex::new(file))
+        .map_err(|_| AnyError::from("Journal already recorded"))
+}
+
+/// `transport` recording the requests into the journal, when recorded.
+pub fn wrap(transport: Arc<dyn Transport>) -> Arc<dyn Transport> {
+    match JOURNAL.get() {
+        Some(journal) => Arc::new(RecordingTransport { inner: transport, journal }),
+        None => transport,
+    }
+}
+
+pub struct RecordingTransport {
+    inner: Arc<dyn Transport>,
+    journal: &'static Mutex<File>,
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, mut request: Request) -> io::Result<Response> {
+        // streamed bodies are read whole, recording is meant for tests only
+        let body = match request.body {
+            RequestBody::Empty => Vec::new(),
+            RequestBody::Bytes(data) => data,
+            RequestBody::Stream(mut reader) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                data
+            }
+        };
+
+        let entry = Entry {
+            method: request.method.to_string(),
+            path: request.url.path().to_string(),
+            query: request.url.query().map(str::to_string),
+            headers: request
+                .headers
+                .iter()
+                .filter(|(name, _)| **name != AUTHORIZATION)
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                .collect(),
+            body: hex::encode(&body),
+        };
+
+        {
+            let mut journal = self.journal.lock().unwrap();
+            serde_json::to_writer(&mut *journal, &entry)?;
+            journal.write_all(b"\n")?;
+        }
+
+        request.body = if body.is_empty() {
+            RequestBody::Empty
+        } else {
+            RequestBody::Bytes(body)
+        };
+
+        self.inner.send(request)
+    }
+}
+
+/// Outcome of `audit`.
+#[derive(Debug, Serialize)]
+pub struct AuditReport {
+    pub requests: usize,
+    /// Requests of each pass which failed to deliver or failed on the server
+    pub failed_requests: [usize; 2],
+    /// Objects which differ after the second pass
+    pub differences: Vec<Difference>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    pub path: PathBuf,
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// Server the journal is replayed against; it must hold the repository in the same state as the server did when the
+/// journal started being recorded (typically freshly initialized), and nothing else may use it meanwhile.
+pub struct Scratch {
+    pub url: Url,
+    pub token: Option<String>,
+    pub transport: Arc<dyn Transport>,
+}
+
+impl Scratch {
+    fn send(&self, method: Method, url: Url, mut headers: HeaderMap, body: Vec<u8>) -> io::Result<Response> {
+        if let Some(token) = &self.token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            headers.insert(AUTHORIZATION, value);
+        }
+
+        self.transport.send(Request {
+            method,
+            url,
+            headers,
+            body: if body.is_empty() {
+                RequestBody::Empty
+            } else {
+                RequestBody::Bytes(body)
+            },
+        })
+    }
+
+    fn get(&self, endpoint: &str, path: &Path) -> io::Result<Response> {
+        let mut url = self.url.clone();
+        url.set_path(endpoint);
+        url.query_pairs_mut().append_pair("path", &path.to_string_lossy());
+
+        let resp = self.send(Method::GET, url, HeaderMap::new(), Vec::new())?;
+
+        if resp.status() != StatusCode::OK {
+            let status = resp.status();
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("Scratch server answered {} to {} of {:?}: {}", status, endpoint, path, resp.text()?),
+            ));
+        }
+
+        Ok(resp)
+    }
+
+    /// Sends all `entries`, returning how many of them failed.
+    fn replay(&self, entries: &[Entry]) -> io::Result<usize> {
+        let mut failed = 0;
+
+        for entry in entries {
+            let method = Method::from_bytes(entry.method.as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+            let mut url = self.url.clone();
+            url.set_path(&entry.path);
+            url.set_query(entry.query.as_deref());
+
+            let mut headers = HeaderMap::new();
+            for (name, value) in &entry.headers {
+                let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                let value = HeaderValue::from_str(value).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                headers.insert(name, value);
+            }
+
+            let body = hex::decode(&entry.body).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+            // later requests may depend on the response being received whole
+            match self
+                .send(method, url, headers, body)
+                .and_then(|resp| Ok((resp.status(), resp.bytes()?)))
+            {
+                Ok((status, _)) if !status.is_server_error() => trace!("Replayed {} {}: {}", entry.method, entry.path, status),
+                Ok((status, body)) => {
+                    debug!(
+                        "Replayed {} {} failed: {} {}",
+                        entry.method,
+                        entry.path,
+                        status,
+                        String::from_utf8_lossy(&body)
+                    );
+                    failed += 1;
+                }
+                Err(e) => {
+                    debug!("Replayed {} {} failed: {}", entry.method, entry.path, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        Ok(failed)
+    }
+
+    /// Hashes of all objects of the repository, by their path.
+    fn state(&self) -> io::Result<BTreeMap<PathBuf, Vec<u8>>> {
+        let mut state = BTreeMap::new();
+        let mut dirs = vec![PathBuf::new()];
+
+        while let Some(dir) = dirs.pop() {
+            let listed: ListResponse = self.get("list", &dir)?.json()?;
+
+            for entry in listed.paths {
+                let path = dir.join(entry.file_name().unwrap_or_else(|| entry.as_os_str()));
+                let metadata: Metadata = self.get("read-metadata", &path)?.json()?;
+
+                if metadata.is_file {
+                    let data = self.get("read", &path)?.bytes()?;
+                    state.insert(path, Sha256::digest(&data).to_vec());
+                } else {
+                    dirs.push(path);
+                }
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+fn load(journal: &Path) -> io::Result<Vec<Entry>> {
+    BufReader::new(File::open(journal)?)
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+        .map(|line| serde_json::from_str(&line?).map_err(|e| Error::new(ErrorKind::InvalidData, e)))
+        .collect()
+}
+
+/// Replays `journal` against `scratch` twice, reporting objects the second pass changed.
+pub fn audit(journal: &Path, scratch: &Scratch) -> io::Result<AuditReport> {
+    let entries = load(journal)?;
+    info!("Replaying {} requests of {:?} against {}", entries.len(), journal, scratch.url);
+
+    let first = scratch.replay(&entries)?;
+    let before = scratch.state()?;
+
+    let second = scratch.replay(&entries)?;
+    let after = scratch.state()?;
+
+    let mut differences = Vec::new();
+
+    for (path, hash) in &after {
+        match before.get(path) {
+            None => differences.push(Difference {
+                path: path.clone(),
+                change: Change::Added,
+            }),
+            Some(previous) if previous != hash => differences.push(Difference {
+                path: path.clone(),
+                change: Change::Modified,
+            }),
+            Some(_) => (),
+        }
+    }
+
+    for path in before.keys().filter(|p| !after.contains_key(*p)) {
+        differences.push(Difference {
+            path: path.clone(),
+            change: Change::Removed,
+        });
+    }
+
+    differences.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(AuditReport {
+        requests: entries.len(),
+        failed_requests: [first, second],
+        differences,
+    })
+}
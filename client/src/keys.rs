@@ -0,0 +1,91 @@
+//! Key slots - additional passphrases able to unlock the repository (like LUKS key slots).
+//!
+//! rdedup itself knows a single passphrase (the master one). Each slot stores the master passphrase encrypted by a key
+//! derived from the slot passphrase; a passphrase opening any slot unlocks the repository.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sodiumoxide::crypto::pwhash::argon2id13;
+use sodiumoxide::crypto::secretbox;
+
+/// Directory (relative to the repository root) with the key slots.
+pub const KEYS_DIR: &str = "keys";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub name: String,
+    salt: String,
+    nonce: String,
+    /// Encrypted master passphrase
+    sealed: String,
+    opslimit: usize,
+    memlimit: usize,
+}
+
+pub fn slot_path(name: &str) -> PathBuf {
+    Path::new(KEYS_DIR).join(format!("{}.json", name))
+}
+
+fn derive_key(passphrase: &str, salt: &argon2id13::Salt, opslimit: usize, memlimit: usize) -> io::Result<secretbox::Key> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+
+    argon2id13::derive_key(
+        &mut key.0,
+        passphrase.as_bytes(),
+        salt,
+        argon2id13::OpsLimit(opslimit),
+        argon2id13::MemLimit(memlimit),
+    )
+    .map_err(|_| io::Error::new(io::ErrorKind::Other, "Key derivation failed"))?;
+
+    Ok(key)
+}
+
+fn invalid_slot(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Key slot {} is corrupted", name))
+}
+
+impl KeySlot {
+    /// Creates slot `name` making `passphrase` unlock the repository with `master` passphrase.
+    pub fn seal(name: &str, master: &str, passphrase: &str) -> io::Result<KeySlot> {
+        sodiumoxide::init().map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not initialize sodiumoxide"))?;
+
+        let salt = argon2id13::gen_salt();
+        let nonce = secretbox::gen_nonce();
+        let opslimit = argon2id13::OPSLIMIT_INTERACTIVE.0;
+        let memlimit = argon2id13::MEMLIMIT_INTERACTIVE.0;
+
+        let key = derive_key(passphrase, &salt, opslimit, memlimit)?;
+
+        Ok(KeySlot {
+            name: name.to_string(),
+            salt: hex::encode(salt.0),
+            nonce: hex::encode(nonce.0),
+            sealed: hex::encode(secretbox::seal(master.as_bytes(), &nonce, &key)),
+            opslimit,
+            memlimit,
+        })
+    }
+
+    /// Returns the master passphrase if `passphrase` opens this slot.
+    pub fn open(&self, passphrase: &str) -> io::Result<Option<String>> {
+        let salt = hex::decode(&self.salt)
+            .ok()
+            .and_then(|s| argon2id13::Salt::from_slice(&s))
+            .ok_or_else(|| invalid_slot(&self.name))?;
+        let nonce = hex::decode(&self.nonce)
+            .ok()
+            .and_then(|n| secretbox::Nonce::from_slice(&n))
+            .ok_or_else(|| invalid_slot(&self.name))?;
+        let sealed = hex::decode(&self.sealed).map_err(|_| invalid_slot(&self.name))?;
+
+        let key = derive_key(passphrase, &salt, self.opslimit, self.memlimit)?;
+
+        match secretbox::open(&sealed, &nonce, &key) {
+            Ok(master) => Ok(Some(String::from_utf8(master).map_err(|_| invalid_slot(&self.name))?)),
+            Err(()) => Ok(None),
+        }
+    }
+}
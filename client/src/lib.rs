@@ -0,0 +1,37 @@
+pub mod alert;
+pub mod api;
+mod cache;
+pub mod callbacks;
+pub mod chaos;
+mod config_cache;
+pub mod conformance;
+mod delta;
+pub mod device;
+pub mod errors;
+mod failed_queue;
+pub mod history;
+pub mod hooks;
+#[cfg(feature = "http3")]
+pub mod http3;
+pub mod integrity;
+pub mod inventory;
+pub mod journal;
+pub mod keys;
+pub mod local_crypt;
+pub mod memory;
+pub mod peer;
+mod pipe;
+pub mod progress;
+pub mod recovery;
+pub mod remote;
+pub mod reports;
+mod resume;
+pub mod runs;
+pub mod snapshot;
+pub mod spill_set;
+pub mod spool;
+pub mod timing;
+pub mod transport;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod verify;
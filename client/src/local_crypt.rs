@@ -0,0 +1,112 @@
+//! At-rest encryption of repository data the client keeps on the local disk (delta restore seeds and alike).
+//!
+//! The data are in the server-side form, which is plaintext when the repository itself isn't encrypted. Each profile
+//! has its own random key, kept in the state dir readable by the owner only. Data shared by the profiles (cached config,
+//! queued uploads, chunk cache) are encrypted by the key of `SHARED_PROFILE`.
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use log::*;
+use sodiumoxide::crypto::secretbox;
+
+const KEYS_DIR: &str = "local-keys";
+
+/// Profile of the key of data shared by all the profiles using the state dir
+pub const SHARED_PROFILE: &str = "_shared";
+
+pub struct LocalKey {
+    profile: String,
+    key: secretbox::Key,
+}
+
+fn key_path(state_dir: &Path, profile: &str) -> PathBuf {
+    state_dir.join(KEYS_DIR).join(format!("{}.key", profile))
+}
+
+fn invalid_key(path: &Path) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Local data key {:?} is corrupted", path))
+}
+
+impl LocalKey {
+    /// Loads the key of `profile`, generating it on first use.
+    pub fn load_or_create(state_dir: &Path, profile: &str) -> io::Result<LocalKey> {
+        sodiumoxide::init().map_err(|_| Error::new(ErrorKind::Other, "Could not initialize sodiumoxide"))?;
+
+        let path = key_path(state_dir, profile);
+
+        let key = match fs::read_to_string(&path) {
+            Ok(content) => hex::decode(content.trim())
+                .ok()
+                .and_then(|k| secretbox::Key::from_slice(&k))
+                .ok_or_else(|| invalid_key(&path))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                debug!("Generating local data key for profile {}", profile);
+
+                fs::create_dir_all(path.parent().expect("Key path without parent"))?;
+
+                let key = secretbox::gen_key();
+                let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+                file.write_all(hex::encode(&key.0).as_bytes())?;
+                file.sync_all()?;
+
+                key
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(LocalKey {
+            profile: profile.to_string(),
+            key,
+        })
+    }
+
+    /// Nonce followed by the encrypted data.
+    pub fn seal(&self, data: &[u8]) -> Vec<u8> {
+        let nonce = secretbox::gen_nonce();
+
+        let mut sealed = nonce.0.to_vec();
+        sealed.extend(secretbox::seal(data, &nonce, &self.key));
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let corrupted = || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Local data not encrypted by the key of profile {}", self.profile),
+            )
+        };
+
+        if sealed.len() < secretbox::NONCEBYTES {
+            return Err(corrupted());
+        }
+
+        let (nonce, data) = sealed.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce).ok_or_else(corrupted)?;
+
+        secretbox::open(data, &nonce, &self.key).map_err(|()| corrupted())
+    }
+}
+
+/// Writes `data` into local file, encrypted when there's a key.
+pub fn write(key: Option<&LocalKey>, path: &Path, data: &[u8]) -> io::Result<()> {
+    match key {
+        Some(key) => fs::write(path, key.seal(data)),
+        None => fs::write(path, data),
+    }
+}
+
+/// Reads local file written by [`write`] with the same key.
+pub fn read(key: Option<&LocalKey>, path: &Path) -> io::Result<Vec<u8>> {
+    let data = fs::read(path)?;
+
+    match key {
+        Some(key) => key.open(&data),
+        None => Ok(data),
+    }
+}
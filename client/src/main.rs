@@ -1,22 +1,476 @@
+use std::fs::File;
 use std::io;
+use std::io::{BufRead, ErrorKind};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use err_context::AnyError;
-use log::debug;
-use rdedup_lib::backends::Backend;
-use rdedup_lib::{PassphraseFn, Repo as RdedupRepo};
+use libcommon::build_info::BuildInfo;
+use nix::sys::termios::{self, LocalFlags, SetArg};
+use once_cell::sync::Lazy;
+use once_cell::unsync::OnceCell;
+use rdedup_lib::PassphraseFn;
+use serde::Serialize;
+use structopt::clap::Shell;
+use structopt::StructOpt;
+use url::Url;
 
-use crate::remote::RemoteBackend;
+use rbackup2_client::alert::{self, Alert, AlertOptions};
+use rbackup2_client::api::Client;
+use rbackup2_client::chaos::{self, ChaosOptions};
+use rbackup2_client::device::DeviceOptions;
+use rbackup2_client::errors::{self, FailureClass};
+use rbackup2_client::history;
+use rbackup2_client::history::RunSummary;
+use rbackup2_client::hooks::Hooks;
+#[cfg(feature = "http3")]
+use rbackup2_client::http3::{self, Http3Options};
+use rbackup2_client::integrity::VerifyOptions;
+use rbackup2_client::journal::{self, Scratch};
+use rbackup2_client::local_crypt::{self, LocalKey};
+use rbackup2_client::memory;
+use rbackup2_client::peer::{self, ChunkCacheDir, Peers};
+use rbackup2_client::remote;
+use rbackup2_client::remote::ProxyConfig;
+use rbackup2_client::snapshot::RestoreOptions;
+use rbackup2_client::spool;
+use rbackup2_client::timing;
+#[cfg(feature = "tui")]
+use rbackup2_client::tui;
+use rbackup2_client::verify::{self, Sample};
 
-mod remote;
+mod man;
 
-fn create_backend(u: &url1::Url) -> io::Result<Box<dyn Backend + Send + Sync>> {
-    Ok(Box::new(RemoteBackend::new(url::Url::parse(&u.to_string()).unwrap())))
+/// Profile of commands not working with a single name
+const DEFAULT_PROFILE: &str = "default";
+
+/// Shown by `--version`, identifies the exact build in bug reports
+static LONG_VERSION: Lazy<String> = Lazy::new(|| BuildInfo::new(env!("CARGO_PKG_VERSION")).long_version());
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "rbackup2-client")]
+struct Opts {
+    /// URL of the rbackup2 server
+    #[structopt(long, default_value = "http://localhost:8090")]
+    server: Url,
+    /// Token used to authenticate to the server
+    #[structopt(long, env = "RBACKUP_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Repository secret (shared with the server) written data, lock and admin requests are signed with
+    #[structopt(long, env = "RBACKUP_SIGNING_KEY", hide_env_values = true)]
+    signing_key: Option<String>,
+    /// Warm standby server (a replica of the primary one) serving restores and verifications while the primary is in
+    /// maintenance, exclusively locked or unreachable
+    #[structopt(long, env = "RBACKUP_REPLICA")]
+    replica: Option<Url>,
+    /// Proxy for connections to the server (http, https, socks5 or socks5h URL); `HTTPS_PROXY` and similar env variables
+    /// are used when not set
+    #[structopt(long, env = "RBACKUP_PROXY")]
+    proxy: Option<Url>,
+    #[structopt(long, env = "RBACKUP_PROXY_USER", requires = "proxy")]
+    proxy_user: Option<String>,
+    #[structopt(long, env = "RBACKUP_PROXY_PASSWORD", hide_env_values = true, requires = "proxy-user")]
+    proxy_password: Option<String>,
+    /// Ignore proxy env variables
+    #[structopt(long)]
+    no_env_proxy: bool,
+    /// Use HTTP/3 when the server offers it, falling back to HTTP/1.1
+    #[cfg(feature = "http3")]
+    #[structopt(long)]
+    http3: bool,
+    /// CA certificate(s) (PEM) the server HTTP/3 certificate is verified by, instead of the system ones
+    #[cfg(feature = "http3")]
+    #[structopt(long, requires = "http3")]
+    http3_ca: Option<PathBuf>,
+    /// Passphrase of the repository - the master one or of any key slot; asked for on the terminal when not set
+    #[structopt(long, env = "RBACKUP_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+    /// Namespace (e.g. hostname) of the names, for repositories shared by many machines; names of other namespaces are
+    /// not seen
+    #[structopt(long, env = "RBACKUP_NAMESPACE")]
+    namespace: Option<String>,
+    /// Directory with client state (run history, local data keys)
+    #[structopt(long, env = "RBACKUP_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+    /// Encrypt repository data kept on the local disk (e.g. delta restore seed) by a key of the backup profile, and data
+    /// shared by the profiles (e.g. queued uploads) by a key of the state dir
+    #[structopt(long, env = "RBACKUP_ENCRYPT_LOCAL")]
+    encrypt_local: bool,
+    /// Compress uploads queued while offline and cached repository data on the local disk by zstd of this level (1-22)
+    #[structopt(long, env = "RBACKUP_SPOOL_COMPRESSION")]
+    spool_compression: Option<i32>,
+    /// Wait up to this long (e.g. `10m`) when the repository is exclusively locked, instead of failing
+    #[structopt(long, parse(try_from_str = humantime::parse_duration))]
+    wait_for_lock: Option<Duration>,
+    /// Memory (e.g. `512M`) shared by in-memory buffers, caches and parallel jobs; sized by defaults when not set
+    #[structopt(long, env = "RBACKUP_MEMORY_LIMIT", parse(try_from_str = memory::parse_size))]
+    memory_limit: Option<usize>,
+    /// Keep downloaded chunks in this directory, reading them from there by later restores and serving them to peers
+    /// (`serve-peer`)
+    #[structopt(long, env = "RBACKUP_CHUNK_CACHE_DIR")]
+    chunk_cache_dir: Option<PathBuf>,
+    /// Machine on the LAN (running `serve-peer`) asked for chunks before the server; may be repeated
+    #[structopt(long = "peer", env = "RBACKUP_PEERS", use_delimiter = true)]
+    peers: Vec<Url>,
+    /// Token shared by the peers
+    #[structopt(long, env = "RBACKUP_PEER_TOKEN", hide_env_values = true)]
+    peer_token: Option<String>,
+    /// Record all requests to the server, with their data, into this journal for `audit-idempotency`; for tests only
+    #[structopt(long)]
+    record_journal: Option<PathBuf>,
+    /// Print results as JSON
+    #[structopt(long)]
+    json: bool,
+    /// Print where the time of requests to the server went (network, server queueing, server storage) to stderr
+    #[structopt(long)]
+    timing: bool,
+    /// Show a live dashboard (current file, throughput, ETA, ...) during stores and restores instead of the usual output
+    #[cfg(feature = "tui")]
+    #[structopt(long)]
+    tui: bool,
+    #[structopt(flatten)]
+    alert: AlertOptions,
+    #[structopt(flatten)]
+    chaos: ChaosOptions,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Stores a file or a directory under given name
+    Store {
+        source: PathBuf,
+        name: String,
+        /// Prevent removal or overwrite of the name for this many days
+        #[structopt(long)]
+        retain_days: Option<u64>,
+        /// Backup profile the run is recorded under in the history and whose pre/post hooks run (see `hooks/<profile>.json` in
+        /// the state dir); defaults to the name
+        #[structopt(long)]
+        profile: Option<String>,
+        /// Store only metadata of the file tree (sizes, owners, permissions, times), not the contents of the files
+        #[structopt(long, conflicts_with = "device")]
+        metadata_only: bool,
+        /// Record hashes of the contents of the files into the metadata-only snapshot (reads all the files)
+        #[structopt(long, requires = "metadata-only")]
+        hash: bool,
+        /// Queue chunks failing to upload on the network for `retry-failed` instead of aborting right away
+        #[structopt(long)]
+        queue_failed: bool,
+        #[structopt(flatten)]
+        device: DeviceOptions,
+    },
+    /// Uploads objects queued by failed `store --queue-failed` runs, finishing their stores
+    RetryFailed,
+    /// Shows history of store runs and their trends
+    History {
+        /// Show only this profile
+        #[structopt(long)]
+        profile: Option<String>,
+        /// Number of last runs shown per profile
+        #[structopt(long, default_value = "30")]
+        last: usize,
+    },
+    /// Restores a name into given file (or directory)
+    Restore {
+        name: String,
+        #[structopt(required_unless = "test")]
+        dest: Option<PathBuf>,
+        /// Only test the restore - go through all the data but discard them, reporting throughput
+        #[structopt(long)]
+        test: bool,
+        /// Download only data which differ from the existing destination directory
+        #[structopt(long, conflicts_with = "test")]
+        delta: bool,
+        /// Continue an interrupted restore of the file where it stopped, without downloading the restored part again
+        #[structopt(long, conflicts_with = "test")]
+        resume: bool,
+        #[structopt(flatten)]
+        options: RestoreOptions,
+        #[structopt(flatten)]
+        verify: VerifyOptions,
+    },
+    /// Exports a directory snapshot into a plain directory
+    ExportTree {
+        name: String,
+        dest: PathBuf,
+        /// Hard-link files unchanged since this previous export instead of writing them again
+        #[structopt(long)]
+        link_dest: Option<PathBuf>,
+        #[structopt(flatten)]
+        options: RestoreOptions,
+    },
+    /// Exports a name as a tar archive, readable by standard tools; decoded by the server when it has the passphrase
+    /// (requires admin token)
+    ExportTar {
+        name: String,
+        /// File the archive is written to; standard output when not set
+        #[structopt(long)]
+        output: Option<PathBuf>,
+        /// Decode the name by the client even when the server can do it
+        #[structopt(long)]
+        local: bool,
+    },
+    /// Shows a metadata-only snapshot
+    Inventory { name: String },
+    /// Removes a name without running GC; its data are reclaimed by the next GC
+    Forget { name: String },
+    /// Verifies integrity of stored name(s)
+    Verify {
+        #[structopt(required_unless = "all")]
+        name: Option<String>,
+        /// Verify all names in the repository
+        #[structopt(long)]
+        all: bool,
+        /// Number of names verified concurrently
+        #[structopt(long, default_value = "1")]
+        jobs: usize,
+        /// Check only this share of chunks (e.g. `5%`), chosen randomly; indexes and names are checked whole
+        #[structopt(long, parse(try_from_str = Sample::parse_percent))]
+        sample: Option<f64>,
+        /// Seed choosing the sampled chunks, to reproduce a previous sampled run
+        #[structopt(long, requires = "sample")]
+        seed: Option<u64>,
+    },
+    /// Uploads corrupted chunks of a name again from its original source, which must not have changed since the store
+    Repair { name: String, source: PathBuf },
+    /// Removes unreachable data from the repository
+    Gc {
+        /// Data younger than this is kept even if unreachable
+        #[structopt(long, default_value = "86400")]
+        grace_time: u64,
+        /// Let the server run the GC locally (requires admin token)
+        #[structopt(long)]
+        remote: bool,
+    },
+    /// Shows repository configuration and server capabilities
+    Info,
+    /// Lists stored names
+    Names {
+        /// Includes names staged by clients and not committed (yet), for diagnostics (requires admin token)
+        #[structopt(long)]
+        include_pending: bool,
+    },
+    /// Toggles server maintenance mode (requires admin token)
+    Maintenance {
+        /// Leave the maintenance mode
+        #[structopt(long)]
+        off: bool,
+        /// When the maintenance is expected to end, shown to users
+        #[structopt(long)]
+        until: Option<String>,
+    },
+    /// Removes garbage left in the server staging areas by crashes right away, instead of waiting for the scheduled
+    /// cleanup (requires admin token)
+    Cleanup,
+    /// Shows repository usage
+    Stats,
+    /// Shows current holders of repository locks
+    Locks,
+    /// Shows latency percentiles of the server storage operations, per operation (requires admin token)
+    Latency,
+    /// Shows capacity planning report - growth per week, the largest names and when the server disk fills (requires
+    /// admin token)
+    Report {
+        /// Number of the largest names shown
+        #[structopt(long, default_value = "10")]
+        top: usize,
+    },
+    /// Follows the server log (requires admin token)
+    Logs {
+        /// Least severe level shown (`error`, `warn`, `info`, `debug`, `trace`)
+        #[structopt(long)]
+        level: Option<String>,
+        /// Only events of this server module, e.g. `rbackup2_server::gc`
+        #[structopt(long)]
+        module: Option<String>,
+        /// Show the recent events kept by the server first
+        #[structopt(long)]
+        recent: bool,
+    },
+    /// Lists key slots - additional passphrases able to unlock the repository
+    ListKeys,
+    /// Adds key slot unlocking the repository with a new passphrase
+    AddKey {
+        slot: String,
+        #[structopt(long, env = "RBACKUP_NEW_PASSPHRASE", hide_env_values = true)]
+        new_passphrase: String,
+    },
+    /// Removes key slot
+    RemoveKey { slot: String },
+    /// Creates printable recovery codes, any `threshold` of them adding a key slot when all passphrases are lost (see
+    /// `recover`); best done right after the repository is created
+    RecoveryCodes {
+        #[structopt(long, default_value = "5")]
+        shares: u8,
+        #[structopt(long, default_value = "3")]
+        threshold: u8,
+    },
+    /// Adds key slot unlocking the repository with a new passphrase, authorized by recovery codes
+    Recover {
+        #[structopt(required = true)]
+        codes: Vec<String>,
+        #[structopt(long, default_value = "recovered")]
+        slot: String,
+        #[structopt(long, env = "RBACKUP_NEW_PASSPHRASE", hide_env_values = true)]
+        new_passphrase: String,
+    },
+    /// Replays a journal (see `--record-journal`) twice against a scratch server holding the repository as it was when
+    /// the recording started, failing when the second pass changes the repository - requests must survive retries
+    AuditIdempotency {
+        journal: PathBuf,
+        /// URL of the scratch server - never a production one, the journal gets written into it
+        #[structopt(long)]
+        scratch_server: Url,
+        /// Token used to authenticate to the scratch server
+        #[structopt(long, env = "RBACKUP_SCRATCH_TOKEN", hide_env_values = true)]
+        scratch_token: Option<String>,
+    },
+    /// Serves the chunk cache (`--chunk-cache-dir`) to restores of machines on the LAN using this one as `--peer`
+    ServePeer {
+        #[structopt(long, default_value = "0.0.0.0:8091")]
+        listen: SocketAddr,
+    },
+    /// Prints shell completion script
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    /// Prints man page
+    Man,
 }
 
-fn main() -> Result<(), AnyError> {
+fn print<T: Serialize + std::fmt::Debug>(json: bool, result: &T) -> Result<(), AnyError> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(result)?);
+    } else {
+        println!("{:#?}", result);
+    }
+
+    Ok(())
+}
+
+/// Profile whose key encrypts local data of the command - the same one the runs are recorded under in the history.
+fn local_profile(command: &Command) -> String {
+    match command {
+        Command::Store {
+            profile: Some(profile), ..
+        } => profile.clone(),
+        Command::Store { name, .. } | Command::Restore { name, .. } | Command::ExportTree { name, .. } => name.clone(),
+        _ => DEFAULT_PROFILE.to_string(),
+    }
+}
+
+/// Asks for the passphrase on the terminal, without echoing it.
+fn prompt_passphrase() -> io::Result<String> {
+    let stdin = io::stdin();
+    let fd = stdin.as_raw_fd();
+
+    if !nix::unistd::isatty(fd).unwrap_or(false) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "Passphrase required, use --passphrase or RBACKUP_PASSPHRASE",
+        ));
+    }
+
+    let to_io = |e: nix::Error| io::Error::new(ErrorKind::Other, e);
+    let original = termios::tcgetattr(fd).map_err(to_io)?;
+    let mut silent = original.clone();
+    silent.local_flags.remove(LocalFlags::ECHO);
+
+    eprint!("Passphrase: ");
+    termios::tcsetattr(fd, SetArg::TCSANOW, &silent).map_err(to_io)?;
+
+    let mut line = String::new();
+    let read = stdin.lock().read_line(&mut line);
+
+    let _ = termios::tcsetattr(fd, SetArg::TCSANOW, &original);
+    eprintln!();
+    read?;
+
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+fn main() {
     env_logger::init();
 
-    let passfn: PassphraseFn = &|| Ok("prdel".to_owned());
+    // print errors in human-readable form, the server messages (e.g. maintenance) are meant to be shown as they are
+    let opts = Opts::from_clap(&Opts::clap().long_version(LONG_VERSION.as_str()).get_matches());
+
+    let alerts = opts.alert.clone();
+    let profile = local_profile(&opts.command);
+    let print_timing = opts.timing;
+
+    #[cfg(feature = "tui")]
+    let dashboard = match opts.command {
+        Command::Store { .. } | Command::Restore { .. } if opts.tui => match tui::start() {
+            Ok(dashboard) => Some(dashboard),
+            Err(e) => {
+                eprintln!("Could not start the dashboard: {}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let result = run(opts);
+
+    #[cfg(feature = "tui")]
+    drop(dashboard);
+
+    if print_timing {
+        timing::print_report(&timing::report());
+    }
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        alert::send(&alerts, &Alert::of(&profile, &e));
+        std::process::exit(1);
+    }
+}
+
+fn run(opts: Opts) -> Result<(), AnyError> {
+    let state_dir = opts.state_dir.clone().unwrap_or_else(history::default_state_dir);
+
+    // these don't need the server at all
+    match opts.command {
+        Command::Completions { shell } => {
+            Opts::clap().gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut io::stdout());
+            return Ok(());
+        }
+        Command::Man => {
+            man::write_man_page(Opts::clap(), &mut io::stdout())?;
+            return Ok(());
+        }
+        Command::ServePeer { listen } => {
+            let dir = opts
+                .chunk_cache_dir
+                .ok_or_else(|| AnyError::from("Serving peers needs --chunk-cache-dir"))?;
+            let key = if opts.encrypt_local {
+                Some(LocalKey::load_or_create(&state_dir, local_crypt::SHARED_PROFILE)?)
+            } else {
+                None
+            };
+
+            peer::serve(ChunkCacheDir::new(&dir), listen, opts.peer_token, key)?;
+            return Ok(());
+        }
+        Command::History { profile, last } => {
+            let report = history::report(history::load(&state_dir, profile.as_deref(), last)?);
+
+            if opts.json {
+                print(true, &report)?
+            } else {
+                history::print_report(&report)
+            }
+
+            return Ok(());
+        }
+        _ => (),
+    }
 
     // let repo = RdedupRepo::init_custom(
     //     &url1::Url::parse("http://localhost:8090")?,
@@ -26,23 +480,280 @@ fn main() -> Result<(), AnyError> {
     //     None,
     // )?;
 
-    let repo = RdedupRepo::open_custom(&url1::Url::parse("http://localhost:8090")?, &create_backend, None)?;
+    let proxy = opts.proxy.map(|url| ProxyConfig {
+        url,
+        username: opts.proxy_user,
+        password: opts.proxy_password,
+    });
+    remote::init_http_client(proxy.as_ref(), opts.no_env_proxy)?;
+
+    #[cfg(feature = "http3")]
+    if opts.http3 {
+        http3::enable(Http3Options { ca_cert: opts.http3_ca })?;
+    }
+
+    if let Some(limit) = opts.memory_limit {
+        memory::set_limit(limit)?;
+    }
+
+    if let Some(level) = opts.spool_compression {
+        spool::set_compression(level)?;
+    }
+
+    chaos::enable(opts.chaos)?;
+
+    if let Command::AuditIdempotency {
+        journal,
+        scratch_server,
+        scratch_token,
+    } = &opts.command
+    {
+        let scratch = Scratch {
+            url: scratch_server.clone(),
+            token: scratch_token.clone(),
+            transport: remote::http_transport(),
+        };
+        let report = journal::audit(journal, &scratch)?;
+        print(opts.json, &report)?;
+
+        if !report.differences.is_empty() {
+            return Err(AnyError::from(format!(
+                "Replaying the journal again changed {} objects",
+                report.differences.len()
+            )));
+        }
+
+        return Ok(());
+    }
+
+    if let Some(journal) = &opts.record_journal {
+        journal::record(journal)?;
+    }
+
+    let shared_key = if opts.encrypt_local {
+        Some(LocalKey::load_or_create(&state_dir, local_crypt::SHARED_PROFILE)?)
+    } else {
+        None
+    };
+
+    let mut client = Client::open_cached(opts.server, opts.token, opts.signing_key, &state_dir, shared_key)?;
+    if let Some(namespace) = opts.namespace {
+        client.set_namespace(namespace);
+    }
+    client.set_lock_wait(opts.wait_for_lock);
+    if let Some(replica) = opts.replica {
+        client.set_replica(replica);
+    }
+    if let Some(dir) = &opts.chunk_cache_dir {
+        client.set_chunk_cache(ChunkCacheDir::new(dir));
+    }
+    if !opts.peers.is_empty() {
+        client.set_peers(Peers::new(opts.peers, opts.peer_token)?);
+    }
+
+    if opts.encrypt_local {
+        client.set_local_key(LocalKey::load_or_create(&state_dir, &local_profile(&opts.command))?);
+    }
+
+    // asked for only by commands decrypting or encrypting the data
+    let passphrase = OnceCell::new();
+    if let Some(given) = opts.passphrase {
+        let _ = passphrase.set(given);
+    }
+    let resolve_passphrase = || client.resolve_passphrase(passphrase.get_or_try_init(prompt_passphrase)?);
+    let passfn: PassphraseFn = &resolve_passphrase;
+
+    match opts.command {
+        Command::Store {
+            source,
+            name,
+            retain_days,
+            profile,
+            metadata_only,
+            hash,
+            queue_failed,
+            device,
+        } => {
+            if queue_failed {
+                client.queue_failed_uploads()?;
+            }
+
+            let retain_until = retain_days.map(|days| {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+                now.as_secs() + days * 24 * 3600
+            });
+
+            let profile = profile.as_deref().unwrap_or(&name);
+            let hooks = Hooks::load(&state_dir, profile)?;
+
+            let result = hooks.around(&source, &name, || {
+                if metadata_only {
+                    client.store_inventory(&source, &name, retain_until, hash, passfn)
+                } else if device.device {
+                    client.store_device(&source, &name, retain_until, &device, passfn)
+                } else {
+                    client.store(&source, &name, retain_until, passfn)
+                }
+            });
+
+            // failed runs are recorded too, so failing scheduled jobs show up in the history
+            let summary = RunSummary::of(profile, &name, &result);
+            if let Err(e) = history::record(&state_dir, &summary) {
+                eprintln!("Warning: could not record the run into history: {}", e);
+            }
+
+            print(opts.json, &result?)?
+        }
+        Command::Restore { name, test: true, .. } => print(opts.json, &client.restore_test(&name, passfn)?)?,
+        Command::Restore {
+            name,
+            dest: Some(dest),
+            options,
+            verify,
+            delta,
+            resume,
+            ..
+        } => {
+            let result = client.restore(&name, &dest, &options, &verify, delta, resume, passfn)?;
+            print(opts.json, &result)?;
+
+            if let Some(integrity) = result.integrity.filter(|i| !i.is_intact()) {
+                let message = format!(
+                    "{} restored files differ from the stored ones, {} are missing",
+                    integrity.mismatched.len(),
+                    integrity.missing.len()
+                );
+                return Err(errors::classified(ErrorKind::InvalidData, FailureClass::Corruption, message).into());
+            }
+        }
+        Command::Restore { dest: None, .. } => unreachable!("Destination is required unless testing"),
+        Command::ExportTree {
+            name,
+            dest,
+            link_dest,
+            options,
+        } => print(
+            opts.json,
+            &client.export_tree(&name, &dest, link_dest.as_deref(), &options, passfn)?,
+        )?,
+        Command::ExportTar { name, output, local } => match output {
+            Some(output) => print(opts.json, &client.export_tar(&name, &mut File::create(output)?, local, passfn)?)?,
+            // the result would mix with the archive
+            None => {
+                client.export_tar(&name, &mut io::stdout().lock(), local, passfn)?;
+            }
+        },
+        Command::Inventory { name } => print(opts.json, &client.inventory(&name, passfn)?)?,
+        Command::Forget { name } => print(opts.json, &client.forget(&name)?)?,
+        Command::RetryFailed => print(opts.json, &client.retry_failed()?)?,
+        Command::Verify {
+            name,
+            all,
+            jobs,
+            sample,
+            seed,
+        } => {
+            let names = if all { None } else { Some(name.into_iter().collect()) };
+            let sample = sample.map(|percent| Sample::new(percent, seed));
+            let report = client.verify(names, jobs, sample, passfn)?;
+
+            if opts.json {
+                print(true, &report)?
+            } else {
+                verify::print_report(&report)
+            }
 
-    let source = "/data/Fotky/A7III/DSC00383.ARW";
-    // let source = "/data/Fotky/DSC27456.ARW";
-    let dest = "filename4.dat";
+            // so the corruption is alerted
+            if let Some(class) = report.failure_class() {
+                let message = format!("Verification of {} names failed", report.failed);
+                return Err(errors::classified(ErrorKind::InvalidData, class, message).into());
+            }
+        }
+        Command::Repair { name, source } => {
+            let result = client.repair(&name, &source, passfn)?;
+            print(opts.json, &result)?;
 
-    let wh = repo.unlock_encrypt(&passfn)?;
-    let file = std::fs::File::open(source)?;
-    let stats = repo.write(dest, &file, &wh)?;
-    debug!("File {:?} stats {:?}", file, stats);
+            if !result.unrepaired.is_empty() {
+                let message = format!("{} chunks of {} could not be repaired", result.unrepaired.len(), name);
+                return Err(errors::classified(ErrorKind::InvalidData, FailureClass::Corruption, message).into());
+            }
+        }
+        Command::Gc { grace_time, remote } => {
+            // closures capture whole `opts`, which is partially moved already
+            let json = opts.json;
+            let report = client.gc(grace_time, remote, |status| {
+                if !json {
+                    eprintln!("GC running for {}s", status.elapsed_ms / 1000);
+                }
+            })?;
 
-    let rh = repo.unlock_decrypt(&passfn)?;
-    let mut file = std::fs::File::create("/tmp/file.dat")?;
-    repo.read(dest, &mut file, &rh)?;
+            print(opts.json, &report)?
+        }
+        Command::Info => {
+            let info = client.info()?;
 
-    let meta = file.metadata()?;
-    println!("Meta: {:?}", meta);
+            if opts.json {
+                print(true, &info)?
+            } else {
+                println!("Server: {}", info.server);
+                match &info.server_build {
+                    Some(build) => println!("Server version: {}", build.long_version()),
+                    None => println!("Server version: -"),
+                }
+                println!("Layout version: {}", info.layout_version);
+                println!("Generation: {}", info.generation.as_deref().unwrap_or("-"));
+                match &info.locks.exclusive {
+                    Some(lock) => println!("Exclusive lock: {} since {}", lock.holder, lock.since),
+                    None => println!("Exclusive lock: -"),
+                }
+                println!("Shared locks: {}", info.locks.shared.len());
+                println!("Config:\n{}", info.config);
+            }
+        }
+        Command::Maintenance { off, until } => client.set_maintenance(!off, until)?,
+        Command::Names { include_pending } => print(opts.json, &client.names(include_pending)?)?,
+        Command::Cleanup => print(opts.json, &client.run_cleanup()?)?,
+        Command::Stats => print(opts.json, &client.stats()?)?,
+        Command::Locks => print(opts.json, &client.locks()?)?,
+        Command::Latency => print(opts.json, &client.backend_latency()?)?,
+        Command::Report { top } => print(opts.json, &client.capacity_report(top)?)?,
+        Command::Logs { level, module, recent } => {
+            let json = opts.json;
+            client.tail_logs(level.as_deref(), module.as_deref(), recent, |event| {
+                if json {
+                    println!("{}", serde_json::to_string(event).unwrap_or_default());
+                } else {
+                    let time = UNIX_EPOCH + Duration::from_millis(event.timestamp_ms);
+                    println!(
+                        "{} {:5} {} {}",
+                        humantime::format_rfc3339_millis(time),
+                        event.level,
+                        event.target,
+                        event.message
+                    );
+                }
+            })?
+        }
+        Command::ListKeys => {
+            let slots: Vec<String> = client.key_slots()?.into_iter().map(|slot| slot.name).collect();
+            print(opts.json, &slots)?
+        }
+        Command::AddKey { slot, new_passphrase } => client.add_key(&slot, &new_passphrase, passfn)?,
+        Command::RemoveKey { slot } => client.remove_key(&slot, passfn)?,
+        Command::RecoveryCodes { shares, threshold } => print(opts.json, &client.create_recovery_codes(shares, threshold, passfn)?)?,
+        Command::Recover {
+            codes,
+            slot,
+            new_passphrase,
+        } => client.recover(&codes, &slot, &new_passphrase)?,
+        Command::Completions { .. }
+        | Command::Man
+        | Command::History { .. }
+        | Command::ServePeer { .. }
+        | Command::AuditIdempotency { .. } => {
+            unreachable!("Handled above")
+        }
+    }
 
     Ok(())
 }
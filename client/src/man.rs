@@ -0,0 +1,44 @@
+use std::io;
+use std::io::Write;
+
+use structopt::clap::App;
+
+fn escape(line: &str) -> String {
+    let line = line.replace('\\', "\\\\");
+
+    // lines starting with control characters would be interpreted as roff requests
+    if line.starts_with('.') || line.starts_with('\'') {
+        format!("\\&{}", line)
+    } else {
+        line
+    }
+}
+
+/// Writes roff man page generated from the CLI definition (help2man style).
+pub fn write_man_page<W: Write>(mut app: App, out: &mut W) -> io::Result<()> {
+    let name = app.get_name().to_string();
+
+    let mut help = Vec::new();
+    app.write_long_help(&mut help)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+    writeln!(out, ".TH {} 1 \"\" \"{}\"", name.to_uppercase(), env!("CARGO_PKG_VERSION"))?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(out, "{} \\- client of rbackup2 backup server", name)?;
+    writeln!(out, ".SH DESCRIPTION")?;
+    writeln!(out, ".nf")?;
+
+    for line in String::from_utf8_lossy(&help).lines() {
+        writeln!(out, "{}", escape(line))?;
+    }
+
+    writeln!(out, ".fi")?;
+    writeln!(out, ".SH SEE ALSO")?;
+    writeln!(
+        out,
+        "Options of each subcommand are described by \\fB{} help <subcommand>\\fR.",
+        name
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+//! Memory budget shared by the client subsystems holding data in memory - the archiver pipe, the chunk cache and the
+//! verification workers and the sets spilled to disk - so the client behaves predictably on machines with little memory.
+//!
+//! Each subsystem gets a fixed share of the budget and sizes itself by it; without a limit the defaults apply.
+
+use err_context::AnyError;
+use log::*;
+use once_cell::sync::OnceCell;
+
+static LIMIT: OnceCell<usize> = OnceCell::new();
+
+/// Largest buffer passed through the pipe; bigger writes are split.
+pub const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Shares of the budget (in percent)
+const PIPE_SHARE: usize = 10;
+const CACHE_SHARE: usize = 40;
+const SPILL_SHARE: usize = 10;
+/// Memory of a verification worker - a chunk being processed plus its decompressed data and buffers of rdedup
+const VERIFY_JOB_BYTES: usize = 32 * 1024 * 1024;
+
+/// Pipes are useless without some buffers in flight, whatever the limit.
+const MIN_PIPE_BUFFERS: usize = 4;
+
+pub fn set_limit(bytes: usize) -> Result<(), AnyError> {
+    debug!("Limiting memory to {}B", bytes);
+    LIMIT.set(bytes).map_err(|_| AnyError::from("Memory limit already set"))
+}
+
+fn share(percent: usize) -> Option<usize> {
+    LIMIT.get().map(|limit| limit / 100 * percent)
+}
+
+/// Number of buffers in flight between the archiver and the repository, at most `default`.
+pub fn pipe_capacity(default: usize) -> usize {
+    match share(PIPE_SHARE) {
+        Some(bytes) => (bytes / PIPE_BUFFER_SIZE).max(MIN_PIPE_BUFFERS).min(default),
+        None => default,
+    }
+}
+
+/// Capacity of the chunk cache, at most `default`.
+pub fn cache_bytes(default: usize) -> usize {
+    share(CACHE_SHARE).map(|bytes| bytes.min(default)).unwrap_or(default)
+}
+
+/// Memory of a set spilling to disk (see `spill_set`) beyond it, at most `default`.
+pub fn spill_bytes(default: usize) -> usize {
+    share(SPILL_SHARE).map(|bytes| bytes.min(default)).unwrap_or(default)
+}
+
+/// Number of parallel verification workers, at most `requested`.
+pub fn verify_jobs(requested: usize) -> usize {
+    let remaining = 100 - PIPE_SHARE - CACHE_SHARE - SPILL_SHARE;
+
+    match share(remaining) {
+        Some(bytes) => {
+            let jobs = (bytes / VERIFY_JOB_BYTES).max(1).min(requested);
+            if jobs < requested {
+                info!(
+                    "Running {} verification jobs instead of {} to fit the memory limit",
+                    jobs, requested
+                );
+            }
+            jobs
+        }
+        None => requested,
+    }
+}
+
+/// Parses size like `512M`, `2G` or plain bytes (binary units).
+pub fn parse_size(s: &str) -> Result<usize, AnyError> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+
+    let multiplier: usize = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        other => return Err(AnyError::from(format!("Unknown size unit {:?}", other))),
+    };
+
+    let number: usize = number.parse().map_err(|_| AnyError::from(format!("Invalid size {:?}", s)))?;
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| AnyError::from(format!("Size {:?} too big", s)))
+}
@@ -0,0 +1,270 @@
+//! Restores from chunk caches of sibling machines on the LAN, before downloading over the WAN from the server.
+//!
+//! Clients with a chunk cache (`--chunk-cache-dir`) keep the chunks they download there; `serve-peer` makes the cache
+//! available to others, which ask for chunks by digest (`GET /chunk/<repository>/<digest>`) when restoring with
+//! `--peer`. Chunks missing on all the peers (or on unreachable ones) come from the server.
+//!
+//! Chunks are exchanged in their server-side form, encrypted by rdedup when the repository is, and rdedup verifies the
+//! digest of each chunk it reads as always - a peer can't slip other data in. The digest is of the plaintext, so the
+//! client can't check the chunks itself; they're staged and get into the cache only once the read verifying them
+//! succeeds. With `--encrypt-local`, the cache is encrypted by the key shared by the profiles (see `local_crypt`). The
+//! repository is identified by the URL of its server, so siblings must use the same one.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use err_context::AnyError;
+use log::*;
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::local_crypt::{self, LocalKey};
+
+/// Peers are on the LAN, so the slow ones are skipped quickly
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TOKEN_HEADER: &str = "x-peer-token";
+
+/// Longest request head `serve` accepts
+const MAX_HEAD_BYTES: u64 = 8 * 1024;
+
+/// Peer requests `serve` answers at once; further connections wait to be accepted
+const SERVE_THREADS: usize = 8;
+
+/// Chunks waiting for verification, in the directory of the repository; not a valid digest prefix, so never served
+const STAGED_DIR: &str = "staged";
+
+/// Identifies repository of `server_url` between peers.
+pub fn repository_id(server_url: &Url) -> String {
+    hex::encode(Sha256::digest(server_url.as_str().as_bytes()))[..16].to_string()
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Chunks downloaded by the client, by repository and digest.
+#[derive(Debug, Clone)]
+pub struct ChunkCacheDir {
+    dir: PathBuf,
+}
+
+impl ChunkCacheDir {
+    pub fn new(dir: &Path) -> ChunkCacheDir {
+        ChunkCacheDir { dir: dir.to_path_buf() }
+    }
+
+    /// The identifiers come from the network, they must not escape the directory.
+    fn file(&self, repository: &str, digest: &str) -> Option<PathBuf> {
+        if !is_hex(repository) || !is_hex(digest) || digest.len() < 4 {
+            return None;
+        }
+
+        Some(self.dir.join(repository).join(&digest[..2]).join(digest))
+    }
+
+    fn staged_file(&self, repository: &str, digest: &str) -> Option<PathBuf> {
+        self.file(repository, digest)?;
+
+        Some(self.dir.join(repository).join(STAGED_DIR).join(digest))
+    }
+
+    /// Cached chunk, written with the same `key`.
+    pub fn get(&self, repository: &str, digest: &str, key: Option<&LocalKey>) -> io::Result<Option<Vec<u8>>> {
+        let file = match self.file(repository, digest) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+
+        match local_crypt::read(key, &file) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Keeps chunk aside, encrypted when there's a `key`, until it's verified and `settle`d.
+    pub fn stage(&self, repository: &str, digest: &str, data: &[u8], key: Option<&LocalKey>) -> io::Result<()> {
+        let staged = match self.staged_file(repository, digest) {
+            Some(staged) => staged,
+            None => return Ok(()),
+        };
+
+        fs::create_dir_all(staged.parent().expect("Staged chunk without parent"))?;
+        local_crypt::write(key, &staged, data)
+    }
+
+    /// Moves staged chunk into the cache when it was `verified`, drops it otherwise.
+    pub fn settle(&self, repository: &str, digest: &str, verified: bool) -> io::Result<()> {
+        let (staged, file) = match (self.staged_file(repository, digest), self.file(repository, digest)) {
+            (Some(staged), Some(file)) => (staged, file),
+            _ => return Ok(()),
+        };
+
+        // staged twice by the same read
+        if !staged.exists() {
+            return Ok(());
+        }
+
+        if !verified {
+            return fs::remove_file(staged);
+        }
+
+        // peers must not get a partially written chunk, the rename is atomic
+        fs::create_dir_all(file.parent().expect("Cached chunk without parent"))?;
+        fs::rename(staged, file)
+    }
+}
+
+/// Sibling machines asked for chunks.
+pub struct Peers {
+    urls: Vec<Url>,
+    token: Option<String>,
+    client: Client,
+}
+
+impl Peers {
+    pub fn new(urls: Vec<Url>, token: Option<String>) -> Result<Peers, AnyError> {
+        // peers are on the LAN, never behind the proxy of the server
+        let client = Client::builder().timeout(PEER_TIMEOUT).no_proxy().build()?;
+
+        Ok(Peers { urls, token, client })
+    }
+
+    /// Chunk `digest` from the first peer having it; failing peers are skipped.
+    pub fn fetch(&self, repository: &str, digest: &str) -> Option<Vec<u8>> {
+        for peer in &self.urls {
+            let mut url = peer.clone();
+            url.set_path(&format!("chunk/{}/{}", repository, digest));
+
+            let mut request = self.client.get(url.as_str());
+            if let Some(token) = &self.token {
+                request = request.header(TOKEN_HEADER, token);
+            }
+
+            match request.send() {
+                Ok(resp) if resp.status() == StatusCode::OK => match resp.bytes() {
+                    Ok(data) => {
+                        trace!("Chunk {} served by peer {}", digest, peer);
+                        return Some(data.to_vec());
+                    }
+                    Err(e) => debug!("Peer {} failed to send chunk {}: {}", peer, digest, e),
+                },
+                Ok(resp) if resp.status() == StatusCode::NOT_FOUND => (),
+                Ok(resp) => debug!("Peer {} answered {} for chunk {}", peer, resp.status(), digest),
+                Err(e) => debug!("Peer {} unavailable: {}", peer, e),
+            }
+        }
+
+        None
+    }
+}
+
+/// What `serve` serves, shared by its threads.
+struct Served {
+    cache: ChunkCacheDir,
+    token: Option<String>,
+    key: Option<LocalKey>,
+}
+
+/// Serves chunks of `cache` (encrypted by `key`) to peers on `listen` until the process is stopped; with `token`, only
+/// to peers sending it.
+pub fn serve(cache: ChunkCacheDir, listen: SocketAddr, token: Option<String>, key: Option<LocalKey>) -> io::Result<()> {
+    let listener = TcpListener::bind(listen)?;
+    info!("Serving chunk cache {:?} to peers on {}", cache.dir, listen);
+
+    let served = Arc::new(Served { cache, token, key });
+
+    // accepting blocks while all the threads are busy and the channel is full, so a flood of peers can't exhaust the
+    // machine
+    let (tx, rx) = mpsc::sync_channel::<TcpStream>(SERVE_THREADS);
+    let rx = Arc::new(Mutex::new(rx));
+
+    for _ in 0..SERVE_THREADS {
+        let rx = Arc::clone(&rx);
+        let served = Arc::clone(&served);
+
+        thread::spawn(move || loop {
+            let stream = rx.lock().unwrap().recv();
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+
+            if let Err(e) = handle(stream, &served) {
+                debug!("Peer request failed: {}", e);
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => tx.send(stream).expect("Peer serving threads gone"),
+            Err(e) => warn!("Could not accept peer connection: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Answers a single request; connections are not kept alive.
+fn handle(stream: TcpStream, served: &Served) -> io::Result<()> {
+    let token = served.token.as_deref();
+
+    stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    let mut authorized = token.is_none();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        if let (Some(expected), Some((name, value))) = (token, line.split_once(':')) {
+            if name.trim().eq_ignore_ascii_case(TOKEN_HEADER) && value.trim() == expected {
+                authorized = true;
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let chunk = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => target.strip_prefix("/chunk/").and_then(|c| c.split_once('/')),
+        _ => return respond(stream, "405 Method Not Allowed", &[]),
+    };
+
+    if !authorized {
+        return respond(stream, "403 Forbidden", &[]);
+    }
+
+    match chunk {
+        Some((repository, digest)) => match served.cache.get(repository, digest, served.key.as_ref())? {
+            Some(data) => respond(stream, "200 OK", &data),
+            None => respond(stream, "404 Not Found", &[]),
+        },
+        None => respond(stream, "404 Not Found", &[]),
+    }
+}
+
+fn respond(mut stream: TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nContent-Type: application/octet-stream\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
@@ -0,0 +1,78 @@
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use crate::memory::PIPE_BUFFER_SIZE;
+
+/// Creates in-memory pipe connecting a producer `Write` with a consumer `Read` running in another thread.
+///
+/// At most `capacity` buffers (each at most `PIPE_BUFFER_SIZE`) are in flight; the producer blocks when the consumer falls behind.
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+    let (tx, rx) = sync_channel(capacity);
+
+    (
+        PipeWriter { tx },
+        PipeReader {
+            rx,
+            current: Cursor::new(Vec::new()),
+        },
+    )
+}
+
+#[derive(Clone)]
+pub struct PipeWriter {
+    tx: SyncSender<io::Result<Vec<u8>>>,
+}
+
+impl PipeWriter {
+    /// Makes the reader fail with given error instead of seeing a (truncated) end of data.
+    pub fn abort(&self, e: io::Error) {
+        // the reader may be gone already, nothing to do then
+        let _ = self.tx.send(Err(e));
+    }
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // bounded buffers keep memory in flight bounded too
+        let len = buf.len().min(PIPE_BUFFER_SIZE);
+
+        self.tx
+            .send(Ok(buf[..len].to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Pipe reader closed"))?;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct PipeReader {
+    rx: Receiver<io::Result<Vec<u8>>>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.rx.recv() {
+                Ok(Ok(data)) => self.current = Cursor::new(data),
+                Ok(Err(e)) => return Err(e),
+                // all writers dropped - end of data
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
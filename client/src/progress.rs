@@ -0,0 +1,188 @@
+//! Progress of the running store or restore, for live displays (`--tui`).
+//!
+//! Tracking is cheap (a few atomics), so it's always on; nothing reads it unless a display is running.
+
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::callbacks;
+use crate::timing;
+
+/// Total is not known
+const UNKNOWN: u64 = u64::MAX;
+
+static PHASE: Lazy<Mutex<Phase>> = Lazy::new(|| Mutex::new(Phase::default()));
+static BYTES: AtomicU64 = AtomicU64::new(0);
+static TOTAL: AtomicU64 = AtomicU64::new(UNKNOWN);
+static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Default)]
+struct Phase {
+    operation: Option<&'static str>,
+    name: String,
+    current_file: Option<PathBuf>,
+    started: Option<Instant>,
+    /// Bytes sent to the server before the operation started
+    sent_bytes_base: u64,
+}
+
+/// Point-in-time view of the progress.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    /// `store`, `restore`; `None` before any started
+    pub operation: Option<&'static str>,
+    pub name: String,
+    pub current_file: Option<PathBuf>,
+    pub elapsed: Duration,
+    /// Data read from the source (store) or written to the destination (restore); for directories, it's the size of the
+    /// snapshot archive, a bit more than the files themselves
+    pub bytes: u64,
+    /// Size of the source; not known for restores
+    pub total: Option<u64>,
+    pub in_flight: u64,
+    /// Requests repeated because the server couldn't serve them at the moment (locked, unreachable)
+    pub retries: u64,
+    /// Request bodies received by the server since the start
+    pub sent_bytes: u64,
+}
+
+impl Progress {
+    /// Bytes per second since the start.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    pub fn ratio(&self) -> Option<f64> {
+        self.total.map(|total| {
+            if total > 0 {
+                (self.bytes as f64 / total as f64).min(1.0)
+            } else {
+                1.0
+            }
+        })
+    }
+
+    /// Remaining time at the throughput so far.
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.total?;
+        let throughput = self.throughput();
+
+        if throughput <= 0.0 {
+            return None;
+        }
+
+        Some(Duration::from_secs_f64(total.saturating_sub(self.bytes) as f64 / throughput))
+    }
+
+    /// How many times less data went to the server than was read from the source; stores only, `None` until the server
+    /// reports received sizes.
+    pub fn dedup_ratio(&self) -> Option<f64> {
+        match self.operation {
+            Some("store") if self.sent_bytes > 0 => Some(self.bytes as f64 / self.sent_bytes as f64),
+            _ => None,
+        }
+    }
+}
+
+/// Starts tracking of a new operation on `name`, with the size of the data when known.
+pub fn start(operation: &'static str, name: &str, total: Option<u64>) {
+    let mut phase = PHASE.lock().unwrap();
+
+    *phase = Phase {
+        operation: Some(operation),
+        name: name.to_string(),
+        current_file: None,
+        started: Some(Instant::now()),
+        sent_bytes_base: timing::report().sent_bytes,
+    };
+
+    BYTES.store(0, Ordering::Relaxed);
+    TOTAL.store(total.unwrap_or(UNKNOWN), Ordering::Relaxed);
+}
+
+/// The total got known only after the start (e.g. after the source was scanned).
+pub fn set_total(total: u64) {
+    TOTAL.store(total, Ordering::Relaxed);
+}
+
+pub fn set_current_file(path: &Path) {
+    PHASE.lock().unwrap().current_file = Some(path.to_path_buf());
+    callbacks::file_started(path);
+}
+
+pub fn add_bytes(bytes: u64) {
+    BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn retried() {
+    RETRIES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Marks a request as in flight until dropped.
+pub fn request() -> InFlight {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+    InFlight(())
+}
+
+pub struct InFlight(());
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn current() -> Progress {
+    let phase = PHASE.lock().unwrap();
+    let total = TOTAL.load(Ordering::Relaxed);
+
+    Progress {
+        operation: phase.operation,
+        name: phase.name.clone(),
+        current_file: phase.current_file.clone(),
+        elapsed: phase.started.map(|s| s.elapsed()).unwrap_or_default(),
+        bytes: BYTES.load(Ordering::Relaxed),
+        total: if total == UNKNOWN { None } else { Some(total) },
+        in_flight: IN_FLIGHT.load(Ordering::Relaxed),
+        retries: RETRIES.load(Ordering::Relaxed),
+        sent_bytes: timing::report().sent_bytes.saturating_sub(phase.sent_bytes_base),
+    }
+}
+
+/// Reader counting the data read through it into the progress.
+pub struct ProgressReader<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R) -> ProgressReader<R> {
+        ProgressReader { inner, read: 0 }
+    }
+
+    /// Data read through this reader - the size of sources not known upfront (pipes).
+    pub fn read_bytes(&self) -> u64 {
+        self.read
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        add_bytes(read as u64);
+        Ok(read)
+    }
+}
@@ -0,0 +1,204 @@
+//! Recovery codes, so losing all the passphrases isn't fatal.
+//!
+//! A random secret opens the `recovery` key slot (see `keys`), the secret itself is not kept anywhere: it's split into
+//! printable codes by Shamir's secret sharing, any `threshold` of them rebuilding it, fewer telling nothing about it.
+//! Codes are meant to be printed right after the repository is created and handed to different people or places.
+
+use std::io;
+use std::io::{Error, ErrorKind};
+
+use sha2::{Digest, Sha256};
+use sodiumoxide::randombytes::randombytes;
+
+/// Key slot the secret opens.
+pub const RECOVERY_SLOT: &str = "recovery";
+
+pub const SECRET_BYTES: usize = 32;
+
+/// Hex digits in a group of a printed code
+const GROUP_LEN: usize = 4;
+
+/// Bytes of the checksum catching mistyped codes
+const CHECKSUM_BYTES: usize = 2;
+
+/// Multiplication in GF(2^8) with the AES polynomial.
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+
+    product
+}
+
+/// Multiplicative inverse in GF(2^8), `a^254`; `a` must not be zero.
+fn inverse(a: u8) -> u8 {
+    let mut result = 1;
+    for _ in 0..254 {
+        result = mul(result, a);
+    }
+    result
+}
+
+/// Polynomial with `coefficients` (the constant one first) at `x`.
+fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+    coefficients.iter().rev().fold(0, |acc, c| mul(acc, x) ^ c)
+}
+
+fn checksum(threshold: u8, index: u8, share: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(&[threshold, index]);
+    hasher.update(share);
+    hasher.finalize()[..CHECKSUM_BYTES].to_vec()
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+/// A share of the secret.
+struct Share {
+    threshold: u8,
+    /// `x` of the share, never zero
+    index: u8,
+    data: Vec<u8>,
+}
+
+impl Share {
+    /// Code printed for the share, `<threshold>-<index>-<data in groups>-<checksum>`.
+    fn to_code(&self) -> String {
+        let data = hex::encode(&self.data);
+        let groups: Vec<&str> = data
+            .as_bytes()
+            .chunks(GROUP_LEN)
+            .map(|g| std::str::from_utf8(g).expect("Hex is ASCII"))
+            .collect();
+
+        format!(
+            "{}-{}-{}-{}",
+            self.threshold,
+            self.index,
+            groups.join("-"),
+            hex::encode(checksum(self.threshold, self.index, &self.data))
+        )
+    }
+
+    /// Parses a code as printed, ignoring whitespace and case.
+    fn parse(code: &str) -> io::Result<Share> {
+        let code: String = code.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+        let parts: Vec<&str> = code.split('-').collect();
+
+        let malformed = || invalid(format!("Malformed recovery code {}", code));
+
+        if parts.len() < 4 {
+            return Err(malformed());
+        }
+
+        let threshold: u8 = parts[0].parse().map_err(|_| malformed())?;
+        let index: u8 = parts[1].parse().map_err(|_| malformed())?;
+        let data = hex::decode(parts[2..parts.len() - 1].concat()).map_err(|_| malformed())?;
+        let sum = hex::decode(parts[parts.len() - 1]).map_err(|_| malformed())?;
+
+        if index == 0 || data.len() != SECRET_BYTES {
+            return Err(malformed());
+        }
+        if sum != checksum(threshold, index, &data) {
+            return Err(invalid(format!("Recovery code {} is mistyped (checksum doesn't match)", code)));
+        }
+
+        Ok(Share { threshold, index, data })
+    }
+}
+
+/// New random secret.
+pub fn generate_secret() -> io::Result<Vec<u8>> {
+    sodiumoxide::init().map_err(|_| Error::new(ErrorKind::Other, "Could not initialize sodiumoxide"))?;
+    Ok(randombytes(SECRET_BYTES))
+}
+
+/// Passphrase of the recovery slot made of the secret.
+pub fn slot_passphrase(secret: &[u8]) -> String {
+    hex::encode(secret)
+}
+
+/// Splits `secret` into `shares` codes, any `threshold` of them rebuilding it.
+pub fn split(secret: &[u8], shares: u8, threshold: u8) -> io::Result<Vec<String>> {
+    if threshold < 2 || threshold > shares {
+        return Err(invalid(format!(
+            "Recovery codes need a threshold of 2 to {} (the number of codes), not {}",
+            shares, threshold
+        )));
+    }
+
+    sodiumoxide::init().map_err(|_| Error::new(ErrorKind::Other, "Could not initialize sodiumoxide"))?;
+
+    let mut split: Vec<Share> = (1..=shares)
+        .map(|index| Share {
+            threshold,
+            index,
+            data: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+
+    for byte in secret {
+        let mut coefficients = vec![*byte];
+        coefficients.extend(randombytes(threshold as usize - 1));
+
+        for share in &mut split {
+            share.data.push(evaluate(&coefficients, share.index));
+        }
+    }
+
+    Ok(split.iter().map(Share::to_code).collect())
+}
+
+/// Rebuilds the secret from enough `codes` (extra ones are ignored).
+pub fn combine(codes: &[String]) -> io::Result<Vec<u8>> {
+    let mut shares: Vec<Share> = codes.iter().map(|c| Share::parse(c)).collect::<io::Result<_>>()?;
+
+    shares.sort_by_key(|s| s.index);
+    shares.dedup_by_key(|s| s.index);
+
+    let threshold = match shares.first() {
+        Some(share) => share.threshold,
+        None => return Err(invalid("No recovery codes given".to_string())),
+    };
+
+    if shares.iter().any(|s| s.threshold != threshold) {
+        return Err(invalid("Recovery codes of different splits given".to_string()));
+    }
+    if shares.len() < threshold as usize {
+        return Err(invalid(format!(
+            "{} different recovery codes needed, {} given",
+            threshold,
+            shares.len()
+        )));
+    }
+
+    let shares = &shares[..threshold as usize];
+
+    // Lagrange interpolation at zero
+    let secret = (0..SECRET_BYTES)
+        .map(|byte| {
+            shares.iter().fold(0, |acc, share| {
+                let basis = shares
+                    .iter()
+                    .filter(|other| other.index != share.index)
+                    .fold(1, |basis, other| mul(basis, mul(other.index, inverse(other.index ^ share.index))));
+
+                acc ^ mul(share.data[byte], basis)
+            })
+        })
+        .collect();
+
+    Ok(secret)
+}
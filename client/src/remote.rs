@@ -1,128 +1,1626 @@
+use std::collections::HashMap;
 use std::io;
-use std::io::{Cursor, Error, ErrorKind, Read};
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::io::{BufRead, BufReader, Cursor, Error, ErrorKind, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use err_context::AnyError;
-use libcommon::structs::{ListResponse, SharedLockResponse};
+use hmac::{Hmac, Mac, NewMac};
+use libcommon::build_info::BuildInfo;
+use libcommon::layout::{Layout, LAYOUT_VERSION_HEADER};
+use libcommon::paths::{self, ObjectType, NAMES_DIR, PENDING_DIR};
+use libcommon::request_signature;
+use libcommon::structs::{
+    CapabilitiesResponse, CapacityReport, CatalogEntry, CleanupReport, ExclusiveLockResponse, GcStatus, LatencyResponse, LockHolder,
+    LocksResponse, LogEvent, MaintenanceRequest, NamesResponse, Priority, RenameBatchRequest, RenameBatchResponse, RenameEntry, RepoState,
+    SharedLockResponse, StatsResponse, WriteBatchEntry, WriteBatchRequest, WriteBatchResponse, EXCLUSIVE_LOCK_HEADER, GENERATION_HEADER,
+    HASH_MISMATCH_HEADER, MAINTENANCE_HEADER, PRIORITY_HEADER, SESSION_HEADER, SIGNATURE_HEADER,
+};
 use log::*;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use rdedup_lib::backends::{Backend, BackendThread, Lock, Metadata};
-use reqwest::blocking::{Body, Client};
-use reqwest::StatusCode;
+use reqwest::blocking::Client;
+use reqwest::header::ETAG;
+use reqwest::{Method, Proxy, StatusCode};
+use serde::de::DeserializeOwned;
 use sgdata::SGData;
 use sha2::*;
 use url::Url;
 use uuid::Uuid;
 
-static CLIENT: Lazy<Client> = Lazy::new(|| Client::builder().connection_verbose(false).build().unwrap());
+use crate::cache::ChunkCache;
+use crate::callbacks;
+use crate::chaos;
+use crate::config_cache::{self, ConfigCache};
+use crate::errors::{self, FailureClass};
+use crate::failed_queue::FailedQueue;
+#[cfg(feature = "http3")]
+use crate::http3::{self, Http3Transport};
+use crate::journal;
+use crate::local_crypt::{self, LocalKey};
+use crate::memory;
+use crate::peer::{self, ChunkCacheDir, Peers};
+use crate::progress;
+use crate::runs::{Checkpoint, HeldLock};
+use crate::spill_set::SpillSet;
+use crate::timing;
+use crate::transport::{BlockingTransport, RequestBuilder, Response, Transport};
+use crate::verify::{self, Sample};
 
+static CLIENT: OnceCell<Client> = OnceCell::new();
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Renewals are never sent more often, whatever the server says
+const MIN_RENEWAL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub static CHUNK_CACHE: Lazy<ChunkCache> = Lazy::new(ChunkCache::new);
+
+/// Name objects are tiny, but re-fetched by each operation on the name (e.g. verify followed by restore).
+///
+/// Keyed by the object path, which includes the repository generation - GC moving names to a new generation makes the
+/// old entries unused. Changes made through this client invalidate the entries.
+static NAME_CACHE: Lazy<ChunkCache> = Lazy::new(|| ChunkCache::with_capacity(NAME_CACHE_SIZE));
+
+const NAME_CACHE_SIZE: usize = 1024 * 1024;
+
+/// Memory of paths recorded by `record_chunk_paths`, those over it are spilled to disk
+const CHUNK_PATHS_MEMORY: usize = 64 * 1024 * 1024;
+
+fn cache_for(path: &Path) -> Option<&'static ChunkCache> {
+    match ObjectType::of(path) {
+        ObjectType::Chunk | ObjectType::Index => Some(&CHUNK_CACHE),
+        ObjectType::Name => Some(&NAME_CACHE),
+        ObjectType::Config | ObjectType::Other => None,
+    }
+}
+
+/// Explicitly configured proxy; `HTTP_PROXY`/`HTTPS_PROXY`/`ALL_PROXY` env variables are honoured without it.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// `http://`, `https://`, `socks5://` or `socks5h://` (resolving names through the proxy) URL
+    pub url: Url,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+fn build_http_client(proxy: Option<&ProxyConfig>, no_env_proxy: bool) -> reqwest::Result<Client> {
+    let mut builder = Client::builder().connection_verbose(false);
+
+    if no_env_proxy {
+        builder = builder.no_proxy();
+    }
+
+    if let Some(config) = proxy {
+        debug!("Using proxy {}", config.url);
+
+        let mut proxy = Proxy::all(config.url.clone())?;
+
+        if let Some(username) = &config.username {
+            proxy = proxy.basic_auth(username, config.password.as_deref().unwrap_or_default());
+        }
+
+        builder = builder.proxy(proxy);
+    }
+
+    builder.build()
+}
+
+/// Sets up HTTP client used for all communication with the server; must be called before any request is made.
+pub fn init_http_client(proxy: Option<&ProxyConfig>, no_env_proxy: bool) -> Result<(), AnyError> {
+    let client = build_http_client(proxy, no_env_proxy)?;
+
+    CLIENT.set(client).map_err(|_| AnyError::from("HTTP client already initialized"))
+}
+
+/// Transport by the HTTP client shared by all connections, see `init_http_client`.
+pub fn http_transport() -> Arc<dyn Transport> {
+    let client = CLIENT.get_or_init(|| build_http_client(None, false).unwrap()).clone();
+
+    Arc::new(BlockingTransport::new(client))
+}
+
+#[derive(Clone)]
 pub struct RemoteBackend {
     inner: Arc<RemoteBackendInner>,
 }
 
-pub struct RemoteBackendInner {
-    server_url: Url,
-}
+pub struct RemoteBackendInner {
+    server_url: Url,
+    token: Option<String>,
+    /// Sent with each request, so the server can serve the client by the same backend thread
+    session: String,
+    /// Replaced once the server offers a better protocol
+    transport: RwLock<Arc<dyn Transport>>,
+    /// Retention (unix timestamp) applied to names committed through this backend
+    retain_until: Mutex<Option<u64>>,
+    /// Layout of the server storage, negotiated through `/capabilities`
+    layout: OnceCell<Layout>,
+    /// Server accepts batches of small writes
+    write_batch: AtomicBool,
+    /// Server reads objects moved to another generation meanwhile itself, see `read_other_generation`
+    generation_fallback: AtomicBool,
+    write_queue: Mutex<WriteQueue>,
+    /// Chunks outside of it aren't read while verifying
+    verify_sample: Mutex<Option<Sample>>,
+    /// Versions of names as seen before storing them, the commits fail when they change meanwhile
+    name_versions: Mutex<HashMap<PathBuf, NameVersion>>,
+    /// Paths of read chunks (and indexes) by their hex digest, recorded while repairing
+    chunk_paths: Mutex<Option<SpillSet>>,
+    /// Key the written objects are signed with, shared with the server
+    signing_key: Option<Vec<u8>>,
+    /// How long to wait for an exclusively locked repository
+    lock_wait: Mutex<Option<Duration>>,
+    /// Warm standby serving reads while the primary can't
+    replica: OnceCell<Url>,
+    /// Only reading operations are running, the replica may be used
+    read_only: AtomicBool,
+    /// Set once the primary failed and the replica took over
+    use_replica: AtomicBool,
+    /// Directory with objects chunked locally for delta restore, read instead of downloading them
+    seed: Mutex<Option<PathBuf>>,
+    /// Bytes of objects read from the seed
+    seeded_bytes: AtomicU64,
+    /// Encrypts repository data kept on the local disk
+    local_key: OnceCell<LocalKey>,
+    /// Encrypts data on the local disk shared by all the profiles
+    shared_key: OnceCell<LocalKey>,
+    /// Config and key slots kept between runs
+    config_cache: OnceCell<ConfigCache>,
+    /// Uploads failed on the network are queued there instead of failing the store
+    failed_queue: OnceCell<FailedQueue>,
+    /// Downloaded chunks are kept there, for later restores and peers
+    chunk_cache: OnceCell<ChunkCacheDir>,
+    /// Digests of chunks staged for the chunk cache until the read is verified
+    staged_chunks: Mutex<Vec<String>>,
+    /// Sibling machines asked for chunks before the server
+    peers: OnceCell<Peers>,
+    /// Verifications check the data on the server, they bypass the chunk cache and peers
+    verifying: AtomicBool,
+    /// Priority class of the operation running, sent with each request
+    priority: Mutex<Priority>,
+    /// Exclusive lock held, its writes pass while others are refused
+    exclusive_lock: Mutex<Option<Uuid>>,
+    /// Checkpoint of the store running, locks are recorded into it
+    checkpoint: Mutex<Option<Checkpoint>>,
+}
+
+impl RemoteBackendInner {
+    fn layout(&self) -> Layout {
+        self.layout.get().copied().unwrap_or(Layout::V1)
+    }
+
+    fn lock_taken(&self, id: Uuid, kind: LockKind) {
+        if let Some(checkpoint) = &mut *self.checkpoint.lock().unwrap() {
+            checkpoint.lock_taken(HeldLock {
+                id,
+                exclusive: kind == LockKind::Exclusive,
+            });
+        }
+    }
+
+    fn lock_released(&self, id: Uuid) {
+        if let Some(checkpoint) = &mut *self.checkpoint.lock().unwrap() {
+            checkpoint.lock_released(id);
+        }
+    }
+
+    /// Queues small write, sending the batch once it's big enough.
+    fn queue_write(&self, entry: WriteBatchEntry, sg: &SGData) -> io::Result<()> {
+        let mut queue = self.write_queue.lock().unwrap();
+
+        for part in sg.as_parts() {
+            queue.data.extend_from_slice(part);
+        }
+        queue.writes.push(entry);
+
+        if queue.writes.len() >= WRITE_BATCH_SIZE || queue.data.len() >= WRITE_BATCH_BYTES {
+            self.send_writes(&mut queue)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends queued writes. Other threads wait meanwhile, so once it returns, all writes queued before are stored.
+    fn flush_writes(&self) -> io::Result<()> {
+        self.send_writes(&mut self.write_queue.lock().unwrap())
+    }
+
+    fn send_writes(&self, queue: &mut WriteQueue) -> io::Result<()> {
+        if queue.writes.is_empty() {
+            return Ok(());
+        }
+
+        let mut batch = std::mem::take(queue);
+        let mut retries = 0;
+
+        loop {
+            trace!("remote write batch of {} entries, {}B", batch.writes.len(), batch.data.len());
+
+            let mut body = serde_json::to_vec(&WriteBatchRequest {
+                writes: batch.writes.clone(),
+            })
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            body.push(b'\n');
+            body.extend_from_slice(&batch.data);
+
+            let mut url = self.endpoint();
+            url.set_path("write-batch");
+
+            let resp = self.request(Method::POST, url).body(Cursor::new(body)).send()?;
+
+            if resp.status() != StatusCode::OK {
+                return Err(error_from_response(resp));
+            }
+
+            let response: WriteBatchResponse = resp.json()?;
+
+            // writes corrupted on the way are sent again, the results are in the order of the writes
+            let mut corrupted = WriteQueue::default();
+            let mut offset = 0;
+
+            for (entry, result) in batch.writes.into_iter().zip(response.results) {
+                let data = &batch.data[offset..offset + entry.len];
+                offset += entry.len;
+
+                if result.hash_mismatch && retries < HASH_MISMATCH_RETRIES {
+                    corrupted.data.extend_from_slice(data);
+                    corrupted.writes.push(entry);
+                } else if let Some(error) = result.error {
+                    // the writes are independent, but there's no way to report more than the first failure
+                    return Err(Error::new(
+                        ErrorKind::Other,
+                        format!("Could not write {:?}: {}", result.path, error),
+                    ));
+                }
+            }
+
+            if corrupted.writes.is_empty() {
+                return Ok(());
+            }
+
+            warn!(
+                "{} writes of a batch got corrupted on the way, sending them again",
+                corrupted.writes.len()
+            );
+            batch = corrupted;
+            retries += 1;
+        }
+    }
+
+    /// Server the requests go to - the primary, unless the replica took over.
+    fn endpoint(&self) -> Url {
+        match self.replica.get() {
+            Some(replica) if self.use_replica.load(Ordering::Relaxed) => replica.clone(),
+            _ => self.server_url.clone(),
+        }
+    }
+
+    fn can_fall_back(&self) -> bool {
+        self.replica.get().is_some() && self.read_only.load(Ordering::Relaxed) && !self.use_replica.load(Ordering::Relaxed)
+    }
+
+    fn storage_path(&self, path: &Path) -> String {
+        let path = self.layout().to_storage(path);
+        path.to_str().expect("Invalid utf-8 path").to_string()
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, endpoint: &str) -> io::Result<T> {
+        let mut url = self.endpoint();
+        url.set_path(endpoint);
+
+        let resp = self.request(Method::GET, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        resp.json::<T>()
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        // injected faults count as the network
+        let transport = timing::wrap(chaos::wrap(journal::wrap(Arc::clone(&self.transport.read().unwrap()))));
+        let signed = request_signature::is_signed(url.path());
+        let priority = *self.priority.lock().unwrap();
+        let mut req = RequestBuilder::new(transport, method, url)
+            .header(SESSION_HEADER, &self.session)
+            .header(PRIORITY_HEADER, priority.as_str());
+
+        if let Some(lock_id) = *self.exclusive_lock.lock().unwrap() {
+            req = req.header(EXCLUSIVE_LOCK_HEADER, lock_id.to_string());
+        }
+
+        if let (true, Some(key)) = (signed, &self.signing_key) {
+            req = req.sign(key);
+        }
+
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+}
+
+/// Version of a name the client expects to be replacing, see `RemoteBackend::expect_name_version`.
+#[derive(Debug, Clone)]
+enum NameVersion {
+    Absent,
+    /// ETag of the name
+    Seen(String),
+}
+
+/// Whether the server refused a write as corrupted on the way; it should be sent again.
+fn is_hash_mismatch(resp: &Response) -> bool {
+    resp.status() == StatusCode::CONFLICT && resp.headers().contains_key(HASH_MISMATCH_HEADER)
+}
+
+/// Converts an unexpected response into an error, passing the server-provided message through where it's meant for the user.
+fn error_from_response(resp: Response) -> Error {
+    trace!("Received: {:?}", resp);
+
+    // the server is down for users as well
+    if resp.headers().contains_key(MAINTENANCE_HEADER) {
+        return errors::classified(ErrorKind::Other, FailureClass::Network, resp.text().unwrap_or_default());
+    }
+
+    if is_hash_mismatch(&resp) {
+        return errors::classified(
+            ErrorKind::InvalidData,
+            FailureClass::Network,
+            "Data got corrupted on the way to the server repeatedly",
+        );
+    }
+
+    match resp.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            errors::classified(ErrorKind::PermissionDenied, FailureClass::Auth, resp.text().unwrap_or_default())
+        }
+        StatusCode::NOT_FOUND => Error::new(ErrorKind::NotFound, AnyError::from("File not found")),
+        StatusCode::CONFLICT => Error::new(ErrorKind::Other, AnyError::from(resp.text().unwrap_or_default())),
+        StatusCode::PRECONDITION_FAILED => errors::classified(
+            ErrorKind::Other,
+            FailureClass::Other,
+            "Name changed concurrently - another client stored it meanwhile",
+        ),
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::INSUFFICIENT_STORAGE => {
+            errors::classified(ErrorKind::Other, FailureClass::Quota, resp.text().unwrap_or_default())
+        }
+        status if status.is_server_error() => {
+            errors::classified(ErrorKind::InvalidData, FailureClass::Network, format!("Server error: {}", status))
+        }
+        _ => errors::classified(ErrorKind::InvalidData, FailureClass::Other, "Invalid response"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+impl LockKind {
+    fn endpoint(self) -> &'static str {
+        match self {
+            LockKind::Shared => "lock-shared",
+            LockKind::Exclusive => "lock-exclusive",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LockKind::Shared => "shared",
+            LockKind::Exclusive => "exclusive",
+        }
+    }
+}
+
+pub struct RemoteLock {
+    id: Uuid,
+    kind: LockKind,
+    backend: Arc<RemoteBackendInner>,
+    /// Keeps renewing the lease; stopped by dropping the sender
+    renewal: Option<(mpsc::Sender<()>, JoinHandle<()>)>,
+}
+
+impl RemoteLock {
+    /// Starts renewing the lease of the lock, once `ttl_ms` (as reported by the server) is set.
+    fn start_renewal(&mut self, ttl_ms: Option<u64>) {
+        self.renewal = ttl_ms.map(|ttl_ms| {
+            let (stop, stopped) = mpsc::channel();
+            let backend = Arc::clone(&self.backend);
+            let (lock_id, kind) = (self.id, self.kind);
+
+            let renewal = thread::Builder::new()
+                .name("lock-renewal".to_string())
+                .spawn(move || renew_lease(backend, lock_id, kind, Duration::from_millis(ttl_ms), stopped))
+                .expect("Could not start lock renewal thread");
+
+            (stop, renewal)
+        });
+    }
+}
+
+/// Renews the lease whenever a third of the TTL reported by the server passes, so clock of the client doesn't matter.
+fn renew_lease(backend: Arc<RemoteBackendInner>, lock_id: Uuid, kind: LockKind, ttl: Duration, stop: mpsc::Receiver<()>) {
+    let mut ttl = ttl;
+
+    loop {
+        match stop.recv_timeout((ttl / 3).max(MIN_RENEWAL_INTERVAL)) {
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            _ => return,
+        }
+
+        let mut url = backend.endpoint();
+        url.set_path(&format!("{}/renew", kind.endpoint()));
+        url.query_pairs_mut().append_pair("lock_id", lock_id.to_string().as_str());
+
+        // responses to renewals of exclusive locks have the same fields, and more
+        match backend.request(Method::POST, url).send() {
+            Ok(resp) if resp.status() == StatusCode::OK => match resp.json::<SharedLockResponse>() {
+                Ok(SharedLockResponse { ttl_ms: Some(ttl_ms), .. }) => {
+                    trace!("Renewed {} lock {} for {}ms", kind.name(), lock_id, ttl_ms);
+                    ttl = Duration::from_millis(ttl_ms);
+                }
+                Ok(_) => return,
+                Err(e) => warn!("Invalid renewal response for {} lock {}: {}", kind.name(), lock_id, e),
+            },
+            Ok(resp) if resp.status() == StatusCode::NOT_FOUND => {
+                error!("{} lock {} expired, the repository is not locked anymore", kind.name(), lock_id);
+                return;
+            }
+            // tried again sooner than the lease ends
+            Ok(resp) => warn!("Could not renew {} lock {}: {}", kind.name(), lock_id, error_from_response(resp)),
+            Err(e) => warn!("Could not renew {} lock {}: {}", kind.name(), lock_id, e),
+        }
+    }
+}
+
+impl Drop for RemoteLock {
+    fn drop(&mut self) {
+        trace!("Dropping RemoteLock");
+
+        if let Some((stop, renewal)) = self.renewal.take() {
+            drop(stop);
+            let _ = renewal.join();
+        }
+
+        if self.kind == LockKind::Exclusive {
+            *self.backend.exclusive_lock.lock().unwrap() = None;
+        }
+
+        let mut url = self.backend.endpoint();
+        url.set_path(self.kind.endpoint());
+        url.query_pairs_mut().append_pair("lock_id", self.id.to_string().as_str());
+
+        let resp = self.backend.request(Method::DELETE, url).send().expect("Could not drop RemoteLock");
+
+        if resp.status() != StatusCode::OK {
+            let status = resp.status();
+            let body = resp.bytes().unwrap_or_default();
+            let body_str = std::str::from_utf8(body.as_slice());
+
+            trace!("Could not remove remote lock: {:?} {:?}", status, body_str);
+        } else {
+            self.backend.lock_released(self.id);
+        }
+    }
+}
+
+impl RemoteBackend {
+    pub fn new(url: Url, token: Option<String>, signing_key: Option<String>) -> RemoteBackend {
+        RemoteBackend::with_transport(url, token, signing_key, http_transport())
+    }
+
+    pub fn with_transport(url: Url, token: Option<String>, signing_key: Option<String>, transport: Arc<dyn Transport>) -> RemoteBackend {
+        RemoteBackend {
+            inner: Arc::new(RemoteBackendInner {
+                server_url: url,
+                token,
+                session: Uuid::new_v4().to_string(),
+                transport: RwLock::new(transport),
+                retain_until: Mutex::new(None),
+                layout: OnceCell::new(),
+                write_batch: AtomicBool::new(false),
+                generation_fallback: AtomicBool::new(false),
+                write_queue: Mutex::new(WriteQueue::default()),
+                verify_sample: Mutex::new(None),
+                chunk_paths: Mutex::new(None),
+                name_versions: Mutex::new(HashMap::new()),
+                signing_key: signing_key.map(String::into_bytes),
+                lock_wait: Mutex::new(None),
+                replica: OnceCell::new(),
+                read_only: AtomicBool::new(false),
+                use_replica: AtomicBool::new(false),
+                seed: Mutex::new(None),
+                seeded_bytes: AtomicU64::new(0),
+                local_key: OnceCell::new(),
+                shared_key: OnceCell::new(),
+                config_cache: OnceCell::new(),
+                failed_queue: OnceCell::new(),
+                chunk_cache: OnceCell::new(),
+                staged_chunks: Mutex::new(Vec::new()),
+                peers: OnceCell::new(),
+                verifying: AtomicBool::new(false),
+                priority: Mutex::new(Priority::Normal),
+                exclusive_lock: Mutex::new(None),
+                checkpoint: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Fetches server capabilities and sets up path translation for the server's storage layout.
+    pub fn negotiate(&self) -> io::Result<CapabilitiesResponse> {
+        trace!("remote capabilities");
+
+        let caps = self.inner.get_json::<CapabilitiesResponse>("capabilities")?;
+
+        let layout = Layout::from_version(caps.layout_version).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                AnyError::from(format!(
+                    "Unsupported repository layout version {}, upgrade the client",
+                    caps.layout_version
+                )),
+            )
+        })?;
+
+        debug!("Server uses layout {:?}", layout);
+        let _ = self.inner.layout.set(layout);
+        self.inner.write_batch.store(caps.write_batch, Ordering::Relaxed);
+        self.inner.generation_fallback.store(caps.generation_fallback, Ordering::Relaxed);
+
+        #[cfg(feature = "http3")]
+        self.upgrade_to_http3(&caps);
+
+        Ok(caps)
+    }
+
+    /// Switches to HTTP/3 when enabled and offered by the server, staying with HTTP/1.1 if it doesn't work out.
+    #[cfg(feature = "http3")]
+    fn upgrade_to_http3(&self, caps: &CapabilitiesResponse) {
+        let (port, options) = match (caps.http3_port, http3::options()) {
+            (Some(port), Some(options)) => (port, options),
+            _ => return,
+        };
+
+        let current = Arc::clone(&self.inner.transport.read().unwrap());
+
+        match Http3Transport::connect(&self.inner.server_url, port, options, current) {
+            Ok(transport) => {
+                info!("Using HTTP/3 on port {}", port);
+                *self.inner.transport.write().unwrap() = Arc::new(transport);
+            }
+            Err(e) => warn!("Could not connect over HTTP/3, falling back to HTTP/1.1: {}", e),
+        }
+    }
+
+    /// Puts the server into (or out of) maintenance mode; requires admin token.
+    pub fn set_maintenance(&self, request: &MaintenanceRequest) -> io::Result<()> {
+        trace!("remote set maintenance {:?}", request);
+
+        let mut url = self.inner.endpoint();
+        url.set_path("admin/maintenance");
+
+        let resp = self.inner.request(Method::POST, url).json(request).send()?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(error_from_response(resp)),
+        }
+    }
+
+    /// Records a finished store into the server catalog of snapshots.
+    pub fn record_catalog(&self, entry: &CatalogEntry) -> io::Result<()> {
+        trace!("remote record catalog {:?}", entry);
+
+        let mut url = self.inner.endpoint();
+        url.set_path("catalog");
+
+        let resp = self.inner.request(Method::POST, url).json(entry).send()?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(error_from_response(resp)),
+        }
+    }
+
+    /// Name decoded by the server as a tar stream (requires admin token and the server having the passphrase).
+    pub fn export_tar(&self, name: &str) -> io::Result<Response> {
+        trace!("remote export tar {}", name);
+
+        let mut url = self.inner.endpoint();
+        url.set_path("admin/export");
+        url.query_pairs_mut().append_pair("name", name);
+
+        let resp = self.inner.request(Method::GET, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        Ok(resp)
+    }
+
+    /// Fetches capacity planning report (requires admin token).
+    pub fn capacity_report(&self, top: usize) -> io::Result<CapacityReport> {
+        trace!("remote capacity report");
+
+        let mut url = self.inner.endpoint();
+        url.set_path("admin/report");
+        url.query_pairs_mut().append_pair("top", &top.to_string());
+
+        let resp = self.inner.request(Method::GET, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        resp.json::<CapacityReport>()
+    }
+
+    /// Runs cleanup of the server staging areas (requires admin token).
+    pub fn run_cleanup(&self) -> io::Result<CleanupReport> {
+        trace!("remote run cleanup");
+
+        let mut url = self.inner.endpoint();
+        url.set_path("admin/cleanup");
+
+        let resp = self.inner.request(Method::POST, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        resp.json::<CleanupReport>()
+    }
+
+    /// Starts GC executed by the server itself (requires admin token).
+    pub fn start_gc(&self, grace_time_secs: u64) -> io::Result<()> {
+        trace!("remote start gc");
+
+        let mut url = self.inner.endpoint();
+        url.set_path("admin/gc");
+        url.query_pairs_mut().append_pair("grace_time", &grace_time_secs.to_string());
+
+        let resp = self.inner.request(Method::POST, url).send()?;
+
+        match resp.status() {
+            StatusCode::ACCEPTED => Ok(()),
+            _ => Err(error_from_response(resp)),
+        }
+    }
+
+    /// Follows progress of the server-side GC, calling `f` with each status update; returns the final status.
+    pub fn watch_gc<F: FnMut(&GcStatus)>(&self, mut f: F) -> io::Result<GcStatus> {
+        trace!("remote watch gc");
+
+        let mut url = self.inner.endpoint();
+        url.set_path("admin/gc/events");
+
+        let resp = self.inner.request(Method::GET, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        for line in BufReader::new(resp).lines() {
+            let line = line?;
+
+            if let Some(data) = line.strip_prefix("data: ") {
+                let status: GcStatus = serde_json::from_str(data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                f(&status);
+
+                if status.finished {
+                    return Ok(status);
+                }
+            }
+        }
+
+        Err(Error::new(ErrorKind::UnexpectedEof, "GC events ended before the GC finished"))
+    }
+
+    /// Follows the server log (requires admin token), calling `f` with each event until the connection ends. With
+    /// `recent`, the events kept by the server come first.
+    pub fn tail_logs<F: FnMut(&LogEvent)>(&self, level: Option<&str>, module: Option<&str>, recent: bool, mut f: F) -> io::Result<()> {
+        trace!("remote tail logs");
+
+        let mut url = self.inner.endpoint();
+        url.set_path("admin/logs/stream");
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(level) = level {
+                query.append_pair("level", level);
+            }
+            if let Some(module) = module {
+                query.append_pair("module", module);
+            }
+            query.append_pair("recent", &recent.to_string());
+        }
+
+        let resp = self.inner.request(Method::GET, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        for line in BufReader::new(resp).lines() {
+            let line = line?;
+
+            if let Some(data) = line.strip_prefix("data: ") {
+                let event: LogEvent = serde_json::from_str(data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                f(&event);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Limits reads of chunks to the sample while verifying.
+    pub fn set_verify_sample(&self, sample: Option<Sample>) {
+        *self.inner.verify_sample.lock().unwrap() = sample;
+    }
+
+    /// Verifications running read chunks from the server only.
+    pub(crate) fn set_verifying(&self, verifying: bool) {
+        self.inner.verifying.store(verifying, Ordering::Relaxed);
+    }
+
+    /// Makes the commit of `name` fail when another client stores the name meanwhile - remembers its current version.
+    /// Servers not reporting versions of names don't get the check.
+    pub fn expect_name_version(&self, name: &str) -> io::Result<()> {
+        let path = Path::new(NAMES_DIR).join(name);
+
+        let mut url = self.inner.endpoint();
+        url.set_path("read");
+        url.query_pairs_mut().append_pair("path", &self.inner.storage_path(&path));
+
+        let resp = self.inner.request(Method::GET, url).send()?;
+
+        let version = match resp.status() {
+            StatusCode::NOT_FOUND => NameVersion::Absent,
+            StatusCode::OK => match resp.headers().get(ETAG).and_then(|v| v.to_str().ok()) {
+                Some(etag) => NameVersion::Seen(etag.to_string()),
+                None => return Ok(()),
+            },
+            _ => return Err(error_from_response(resp)),
+        };
+
+        trace!("Expecting {:?} of {:?}", version, path);
+        self.inner.name_versions.lock().unwrap().insert(path, version);
+
+        Ok(())
+    }
+
+    /// Starts recording paths of read content addressed objects, so corrupted ones found by verification can be
+    /// removed.
+    pub(crate) fn record_chunk_paths(&self) {
+        *self.inner.chunk_paths.lock().unwrap() = Some(SpillSet::new(memory::spill_bytes(CHUNK_PATHS_MEMORY)));
+    }
+
+    /// Stops the recording, returning paths of the objects read since it started.
+    pub(crate) fn take_chunk_paths(&self) -> SpillSet {
+        let recorded = self.inner.chunk_paths.lock().unwrap().take();
+        recorded.unwrap_or_else(|| SpillSet::new(0))
+    }
+
+    /// Makes taking locks wait up to `wait` for the repository to be unlocked instead of failing right away.
+    pub fn set_lock_wait(&self, wait: Option<Duration>) {
+        *self.inner.lock_wait.lock().unwrap() = wait;
+    }
+
+    /// Build of the server; none when the server is too old to report it.
+    pub fn server_build(&self) -> io::Result<Option<BuildInfo>> {
+        trace!("remote version");
+
+        match self.inner.get_json::<BuildInfo>("version") {
+            Ok(build) => Ok(Some(build)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn locks(&self) -> io::Result<LocksResponse> {
+        trace!("remote locks");
+
+        self.inner.get_json::<LocksResponse>("locks")
+    }
+
+    /// Latencies of the server storage operations (requires admin token).
+    pub fn backend_latency(&self) -> io::Result<LatencyResponse> {
+        trace!("remote backend latency");
+
+        self.inner.get_json::<LatencyResponse>("admin/latency")
+    }
+
+    pub fn server_url(&self) -> &Url {
+        &self.inner.server_url
+    }
+
+    /// Keeps downloaded chunks in `cache`, reading them from there later; see `peer`.
+    pub fn set_chunk_cache(&self, cache: ChunkCacheDir) {
+        let _ = self.inner.chunk_cache.set(cache);
+    }
+
+    /// Moves chunks downloaded by the read just finished into the chunk cache when it succeeded - rdedup verified them
+    /// then - drops them otherwise.
+    pub(crate) fn settle_cached_chunks(&self, verified: bool) {
+        let staged = mem::take(&mut *self.inner.staged_chunks.lock().unwrap());
+        let cache = match self.inner.chunk_cache.get() {
+            Some(cache) => cache,
+            None => return,
+        };
+        let repository = peer::repository_id(&self.inner.server_url);
+
+        for digest in staged {
+            if let Err(e) = cache.settle(&repository, &digest, verified) {
+                warn!("Could not cache chunk {}: {}", digest, e);
+            }
+        }
+    }
+
+    /// Asks `peers` for chunks before downloading them from the server.
+    pub fn set_peers(&self, peers: Peers) {
+        let _ = self.inner.peers.set(peers);
+    }
+
+    /// Sets up warm standby server, used for reading when the primary is locked, in maintenance or unreachable.
+    pub fn set_replica(&self, replica: Url) {
+        let _ = self.inner.replica.set(replica);
+    }
+
+    /// Allows falling back to the replica until the returned guard is dropped; nothing may be written meanwhile.
+    pub fn read_only(&self) -> ReadOnlyGuard {
+        self.inner.read_only.store(true, Ordering::Relaxed);
+
+        ReadOnlyGuard {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Sends requests with `priority` until the returned guard is dropped, so the server serves them before or after
+    /// those of other clients.
+    pub fn prioritize(&self, priority: Priority) -> PriorityGuard {
+        let previous = std::mem::replace(&mut *self.inner.priority.lock().unwrap(), priority);
+
+        PriorityGuard {
+            inner: Arc::clone(&self.inner),
+            previous,
+        }
+    }
+
+    /// Records locks taken from now on into `checkpoint` of the store starting; the store ends (and the checkpoint is
+    /// removed) when the returned guard is dropped.
+    pub fn checkpoint(&self, checkpoint: Checkpoint) -> CheckpointGuard {
+        *self.inner.checkpoint.lock().unwrap() = Some(checkpoint);
+
+        CheckpointGuard {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Releases lock left on the server by an interrupted run (see `runs`); locks expired meanwhile are fine.
+    pub fn release_lock(&self, lock: HeldLock) -> io::Result<()> {
+        let kind = if lock.exclusive { LockKind::Exclusive } else { LockKind::Shared };
+
+        debug!("Releasing {} lock {} left behind", kind.name(), lock.id);
+
+        let mut url = self.inner.endpoint();
+        url.set_path(kind.endpoint());
+        url.query_pairs_mut().append_pair("lock_id", lock.id.to_string().as_str());
+
+        let resp = self.inner.request(Method::DELETE, url).send()?;
+
+        match resp.status() {
+            StatusCode::OK | StatusCode::NOT_FOUND => Ok(()),
+            _ => Err(error_from_response(resp)),
+        }
+    }
+
+    /// Removes name staged on the server and never committed, e.g. by an interrupted run.
+    pub fn remove_pending_name(&self, name: &str) -> io::Result<()> {
+        let path = Path::new(PENDING_DIR).join(NAMES_DIR).join(name);
+
+        match self.new_thread()?.remove(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes repository data stored on the local disk (e.g. delta restore seed) encrypted by `key`.
+    pub fn set_local_key(&self, key: LocalKey) {
+        let _ = self.inner.local_key.set(key);
+    }
+
+    /// Makes repository data stored on the local disk for all the profiles (e.g. cached config) encrypted by `key`.
+    pub fn set_shared_key(&self, key: LocalKey) {
+        let _ = self.inner.shared_key.set(key);
+    }
+
+    /// Keeps repository config in `state_dir`, revalidating it instead of downloading it by each command.
+    pub fn set_config_cache(&self, state_dir: &Path) {
+        let _ = self.inner.config_cache.set(ConfigCache::new(state_dir, &self.inner.server_url));
+    }
+
+    /// Chunks and indexes failing to upload on the network get queued in `queue` instead of failing the store; see
+    /// `failed_queue`.
+    pub(crate) fn set_failed_queue(&self, queue: FailedQueue) {
+        let _ = self.inner.failed_queue.set(queue);
+    }
+
+    /// Sends batched writes, so the server has all the objects written so far.
+    pub(crate) fn flush_writes(&self) -> io::Result<()> {
+        self.inner.flush_writes()
+    }
+
+    pub(crate) fn local_key(&self) -> Option<&LocalKey> {
+        self.inner.local_key.get()
+    }
+
+    pub(crate) fn shared_key(&self) -> Option<&LocalKey> {
+        self.inner.shared_key.get()
+    }
+
+    pub(crate) fn set_seed(&self, dir: Option<PathBuf>) {
+        self.inner.seeded_bytes.store(0, Ordering::Relaxed);
+        *self.inner.seed.lock().unwrap() = dir;
+    }
+
+    /// Bytes of objects found in the delta restore seed, i.e. not downloaded.
+    pub fn seeded_bytes(&self) -> u64 {
+        self.inner.seeded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Server which actually served the data.
+    pub fn served_by(&self) -> Url {
+        self.inner.endpoint()
+    }
+
+    pub fn set_retention(&self, retain_until: Option<u64>) {
+        *self.inner.retain_until.lock().unwrap() = retain_until;
+    }
+
+    /// Lists names stored in the repository, including their server-side metadata; also those staged and not committed
+    /// (yet) when `include_pending` (requires admin token).
+    pub fn names(&self, include_pending: bool) -> io::Result<NamesResponse> {
+        trace!("remote names include_pending={}", include_pending);
+
+        if !include_pending {
+            return self.inner.get_json::<NamesResponse>("names");
+        }
+
+        let mut url = self.inner.endpoint();
+        url.set_path("names");
+        url.query_pairs_mut().append_pair("include-pending", "true");
+
+        let resp = self.inner.request(Method::GET, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        resp.json::<NamesResponse>()
+    }
+
+    /// Fetches usage statistics of the whole repository from the server.
+    pub fn stats(&self) -> io::Result<StatsResponse> {
+        trace!("remote stats");
+
+        self.inner.get_json::<StatsResponse>("stats")
+    }
+}
+
+/// Max number of renames sent in one `/rename-batch` request.
+const RENAME_BATCH_SIZE: usize = 512;
+
+/// Indexes up to this size are sent in `/write-batch` requests instead of one by one.
+const SMALL_WRITE_SIZE: usize = 64 * 1024;
+
+/// Writes corrupted on the way to the server (see `HASH_MISMATCH_HEADER`) are sent again this many times
+const HASH_MISMATCH_RETRIES: usize = 3;
+/// Max number of writes and their total size in one batch.
+const WRITE_BATCH_SIZE: usize = 256;
+const WRITE_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Small writes waiting to be sent in a batch, shared by all threads - the name must not be written by one thread
+/// while indexes written by another one are still queued.
+#[derive(Default)]
+struct WriteQueue {
+    writes: Vec<WriteBatchEntry>,
+    data: Vec<u8>,
+}
+
+pub struct RemoteBackendThread {
+    backend: Arc<RemoteBackendInner>,
+    /// Renames not sent to the server yet; any other operation sends them first, so they're never observed unapplied
+    /// through this thread
+    pending_renames: Vec<RenameEntry>,
+}
+
+pub struct ReadOnlyGuard {
+    inner: Arc<RemoteBackendInner>,
+}
+
+impl Drop for ReadOnlyGuard {
+    fn drop(&mut self) {
+        // the replica must never receive writes
+        self.inner.read_only.store(false, Ordering::Relaxed);
+        self.inner.use_replica.store(false, Ordering::Relaxed);
+    }
+}
+
+pub struct PriorityGuard {
+    inner: Arc<RemoteBackendInner>,
+    previous: Priority,
+}
+
+impl Drop for PriorityGuard {
+    fn drop(&mut self) {
+        *self.inner.priority.lock().unwrap() = self.previous;
+    }
+}
+
+pub struct CheckpointGuard {
+    inner: Arc<RemoteBackendInner>,
+}
+
+impl Drop for CheckpointGuard {
+    fn drop(&mut self) {
+        if let Some(checkpoint) = self.inner.checkpoint.lock().unwrap().take() {
+            checkpoint.finish();
+        }
+    }
+}
+
+impl RemoteBackend {
+    /// Requests lock of `kind`, waiting (as set by `set_lock_wait`) while the repository is locked exclusively.
+    fn put_lock(&self, kind: LockKind) -> io::Result<Response> {
+        let mut url = self.inner.endpoint();
+        url.set_path(kind.endpoint());
+
+        let wait = *self.inner.lock_wait.lock().unwrap();
+        let deadline = wait.map(|wait| Instant::now() + wait);
+
+        loop {
+            let resp = self.inner.request(Method::PUT, url.clone()).send()?;
+
+            if resp.status() != StatusCode::LOCKED {
+                return Ok(resp);
+            }
+
+            let holder = resp.json::<LockHolder>()?;
+
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => {
+                    eprintln!(
+                        "Repository is exclusively locked by {} since {}, waiting ({}s left)",
+                        holder.holder,
+                        holder.since,
+                        deadline.saturating_duration_since(Instant::now()).as_secs()
+                    );
+                    thread::sleep(LOCK_POLL_INTERVAL);
+                    progress::retried();
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::WouldBlock,
+                        format!("Repository is exclusively locked by {} since {}", holder.holder, holder.since),
+                    ))
+                }
+            }
+        }
+    }
+
+    fn try_lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        trace!("Creating RemoteLock at {}", self.inner.endpoint());
+
+        let resp = self.put_lock(LockKind::Shared)?;
+
+        if resp.status() != StatusCode::CREATED {
+            trace!("Could not create remote lock");
+            return Err(error_from_response(resp));
+        }
+
+        let lr = resp.json::<SharedLockResponse>().unwrap();
+
+        trace!("Created remote shared lock {}", lr.lock_id);
+
+        let mut lock = RemoteLock {
+            id: lr.lock_id,
+            kind: LockKind::Shared,
+            backend: Arc::clone(&self.inner),
+            renewal: None,
+        };
+        self.inner.lock_taken(lock.id, lock.kind);
+        lock.start_renewal(lr.ttl_ms);
+
+        Ok(Box::new(lock))
+    }
+
+    /// Renews exclusive lock `lock_id`, reporting whether it's drained already.
+    fn renew_exclusive(&self, lock_id: Uuid) -> io::Result<ExclusiveLockResponse> {
+        let mut url = self.inner.endpoint();
+        url.set_path("lock-exclusive/renew");
+        url.query_pairs_mut().append_pair("lock_id", lock_id.to_string().as_str());
+
+        let resp = self.inner.request(Method::POST, url).send()?;
+
+        match resp.status() {
+            StatusCode::OK => resp.json::<ExclusiveLockResponse>(),
+            _ => Err(error_from_response(resp)),
+        }
+    }
+}
+
+impl Backend for RemoteBackend {
+    /// Waits until holders of shared locks finish their work; the server refuses new ones meanwhile.
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        trace!("Creating exclusive RemoteLock at {}", self.inner.endpoint());
+
+        let resp = self.put_lock(LockKind::Exclusive)?;
+
+        if resp.status() != StatusCode::CREATED {
+            trace!("Could not create remote exclusive lock");
+            return Err(error_from_response(resp));
+        }
+
+        let mut lr = resp.json::<ExclusiveLockResponse>()?;
+
+        trace!("Created remote exclusive lock {}, state {:?}", lr.lock_id, lr.state);
+
+        // released when waiting fails
+        let mut lock = RemoteLock {
+            id: lr.lock_id,
+            kind: LockKind::Exclusive,
+            backend: Arc::clone(&self.inner),
+            renewal: None,
+        };
+        self.inner.lock_taken(lock.id, lock.kind);
+
+        while lr.state != RepoState::ExclusiveHeld {
+            match lr.state {
+                RepoState::Draining { count } if count > 0 => {
+                    eprintln!("Waiting for {} client(s) working with the repository to finish", count)
+                }
+                _ => eprintln!("Waiting for a process working with the repository directly to finish"),
+            }
+
+            thread::sleep(LOCK_POLL_INTERVAL);
+            progress::retried();
+            lr = self.renew_exclusive(lr.lock_id)?;
+        }
+
+        *self.inner.exclusive_lock.lock().unwrap() = Some(lr.lock_id);
+        lock.start_renewal(lr.ttl_ms);
+
+        Ok(Box::new(lock))
+    }
+
+    /// In read-only mode, falls back to the replica when the primary can't be locked (maintenance, exclusive lock,
+    /// unreachable).
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        match self.try_lock_shared() {
+            Err(e)
+                if self.inner.can_fall_back() && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Other | ErrorKind::BrokenPipe) =>
+            {
+                let replica = self.inner.replica.get().expect("Missing replica");
+
+                warn!("Primary server unavailable ({}), switching to replica {}", e, replica);
+                eprintln!(
+                    "Primary server {} unavailable ({}), reading from replica {}",
+                    self.inner.server_url, e, replica
+                );
+
+                self.inner.use_replica.store(true, Ordering::Relaxed);
+                progress::retried();
+                self.try_lock_shared()
+            }
+            result => result,
+        }
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(RemoteBackendThread {
+            backend: Arc::clone(&self.inner),
+            pending_renames: Vec::new(),
+        }))
+    }
+}
+
+fn calculate_digest(sg: &SGData) -> Vec<u8> {
+    let mut sha256 = sha2::Sha256::default();
+
+    for sg_part in sg.as_parts() {
+        sha256.update(sg_part);
+    }
+
+    let mut vec_result = vec![0u8; 32];
+    vec_result.copy_from_slice(&sha256.finalize());
+
+    vec_result
+}
+
+fn calculate_signature(key: &[u8], path: &str, sg: &SGData) -> String {
+    let mut mac = Hmac::<sha2::Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+
+    mac.update(path.as_bytes());
+    mac.update(&[0]);
+    for sg_part in sg.as_parts() {
+        mac.update(sg_part);
+    }
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+impl RemoteBackendThread {
+    /// Sends all queued operations, so the server sees all the changes made so far.
+    fn flush_pending(&mut self) -> io::Result<()> {
+        self.backend.flush_writes()?;
+        self.flush_renames()
+    }
+
+    /// Sends queued renames to the server in a single request.
+    fn flush_renames(&mut self) -> io::Result<()> {
+        if self.pending_renames.is_empty() {
+            return Ok(());
+        }
+
+        let renames = std::mem::take(&mut self.pending_renames);
+
+        trace!("remote rename batch of {} entries", renames.len());
+
+        let mut url = self.backend.endpoint();
+        url.set_path("rename-batch");
+
+        let resp = self
+            .backend
+            .request(Method::POST, url)
+            .json(&RenameBatchRequest { renames })
+            .send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        let response: RenameBatchResponse = resp.json()?;
+
+        // the renames are independent, but there's no way to report more than the first failure
+        match response.results.into_iter().find(|r| r.error.is_some()) {
+            Some(failed) => {
+                let kind = if failed.not_found { ErrorKind::NotFound } else { ErrorKind::Other };
+                let message = format!(
+                    "Could not rename {:?} to {:?}: {}",
+                    failed.from,
+                    failed.to,
+                    failed.error.unwrap_or_default()
+                );
+                Err(Error::new(kind, message))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Lists `path` calling `f` for each entry as it's received, without holding the whole response in memory.
+    pub fn list_each<F: FnMut(PathBuf)>(&mut self, path: PathBuf, mut f: F) -> io::Result<()> {
+        trace!("remote list: {:?}", path);
+
+        self.flush_pending()?;
+
+        let mut url = self.backend.endpoint();
+        url.set_path("list-stream");
+        url.query_pairs_mut().append_pair("path", &self.backend.storage_path(&path));
+
+        let resp = self.backend.request(Method::GET, url).send()?;
+
+        if resp.status() != StatusCode::OK {
+            return Err(error_from_response(resp));
+        }
+
+        for line in BufReader::new(resp).lines() {
+            let line = line?;
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let entry: PathBuf = serde_json::from_str(&line).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            f(self.backend.layout().from_storage(&entry));
+        }
+
+        Ok(())
+    }
+
+    fn commit_name(&mut self, path: PathBuf) -> io::Result<()> {
+        trace!("remote commit name: {:?}", path);
+
+        let mut url = self.backend.endpoint();
+        url.set_path("commit-name");
+        url.query_pairs_mut().append_pair("path", &self.backend.storage_path(&path));
+
+        if let Some(until) = *self.backend.retain_until.lock().unwrap() {
+            url.query_pairs_mut().append_pair("retain_until", until.to_string().as_str());
+        }
+
+        let mut req = self.backend.request(Method::POST, url);
 
-pub struct RemoteLock {
-    id: Uuid,
-    backend: Arc<RemoteBackendInner>,
+        match self.backend.name_versions.lock().unwrap().remove(&path) {
+            Some(NameVersion::Absent) => req = req.header("if-none-match", "*"),
+            Some(NameVersion::Seen(etag)) => req = req.header("if-match", etag),
+            None => (),
+        }
+
+        let resp = req.send()?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(error_from_response(resp)),
+        }
+    }
 }
 
-impl Drop for RemoteLock {
+impl Drop for RemoteBackendThread {
     fn drop(&mut self) {
-        trace!("Dropping RemoteLock");
+        if let Err(e) = self.flush_renames() {
+            warn!("Could not finish renames: {}", e);
+        }
 
-        let mut url = self.backend.server_url.clone();
-        url.set_path("lock-shared");
-        url.query_pairs_mut().append_pair("lock_id", self.id.to_string().as_str());
+        if let Err(e) = self.backend.flush_writes() {
+            warn!("Could not finish writes: {}", e);
+        }
+    }
+}
 
-        let resp = CLIENT.delete(url).send().expect("Could not drop RemoteLock");
+impl RemoteBackendThread {
+    /// Queues name whose objects couldn't be uploaded, failing the store.
+    fn queue_name(&self, queue: &FailedQueue, path: &Path, sg: &SGData) -> io::Result<()> {
+        queue.push(path, sg, *self.backend.retain_until.lock().unwrap(), self.backend.shared_key.get())?;
 
-        if resp.status() != StatusCode::OK {
-            let status = resp.status();
-            let body = resp.bytes().unwrap().to_vec();
-            let body_str = std::str::from_utf8(body.as_slice());
+        Err(errors::classified(
+            ErrorKind::Other,
+            FailureClass::Network,
+            format!(
+                "Upload of {} objects failed ({} of them queued already), the name is not stored - `retry-failed` uploads \
+                 them and finishes the store",
+                queue.queued(),
+                queue.deduplicated()
+            ),
+        ))
+    }
 
-            trace!("Could not remove remote lock: {:?} {:?}", status, body_str);
+    fn uncache_config(&self, path: &Path) {
+        if let Some(config_cache) = self.backend.config_cache.get() {
+            if config_cache::is_cached(path) {
+                config_cache.remove(path);
+            }
         }
     }
-}
 
-impl RemoteBackend {
-    pub fn new(url: Url) -> RemoteBackend {
-        RemoteBackend {
-            inner: Arc::new(RemoteBackendInner { server_url: url }),
+    /// Chunk from the local chunk cache or from one of the peers; failures of either just mean a download.
+    fn read_cached_chunk(&self, path: &Path) -> Option<SGData> {
+        if ObjectType::of(path) != ObjectType::Chunk || self.backend.verifying.load(Ordering::Relaxed) {
+            return None;
+        }
+        let digest = paths::path_digest(path)?;
+        let repository = peer::repository_id(&self.backend.server_url);
+
+        if let Some(cache) = self.backend.chunk_cache.get() {
+            match cache.get(&repository, digest, self.backend.shared_key.get()) {
+                Ok(Some(data)) => {
+                    trace!("Serving {:?} from chunk cache", path);
+                    return Some(SGData::from_single(data));
+                }
+                Ok(None) => (),
+                Err(e) => warn!("Could not read cached chunk {}: {}", digest, e),
+            }
         }
+
+        let data = self.backend.peers.get()?.fetch(&repository, digest)?;
+        self.cache_chunk(path, &data);
+
+        Some(SGData::from_single(data))
     }
-}
 
-pub struct RemoteBackendThread {
-    backend: Arc<RemoteBackendInner>,
-}
+    /// Stages downloaded chunk for the chunk cache, see `settle_cached_chunks`.
+    fn cache_chunk(&self, path: &Path, data: &[u8]) {
+        let (cache, digest) = match (self.backend.chunk_cache.get(), paths::path_digest(path)) {
+            (Some(cache), Some(digest)) if ObjectType::of(path) == ObjectType::Chunk => (cache, digest),
+            _ => return,
+        };
 
-impl Backend for RemoteBackend {
-    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
-        unimplemented!()
+        let repository = peer::repository_id(&self.backend.server_url);
+        match cache.stage(&repository, digest, data, self.backend.shared_key.get()) {
+            Ok(()) => self.backend.staged_chunks.lock().unwrap().push(digest.to_string()),
+            Err(e) => warn!("Could not cache chunk {}: {}", digest, e),
+        }
     }
 
-    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
-        trace!("Dropping RemoteLock");
+    fn read_seed(&self, path: &Path) -> io::Result<Option<SGData>> {
+        let seed = match &*self.backend.seed.lock().unwrap() {
+            Some(dir) if matches!(ObjectType::of(path), ObjectType::Chunk | ObjectType::Index) => dir.join(path),
+            _ => return Ok(None),
+        };
 
-        let mut url = self.inner.server_url.clone();
-        url.set_path("lock-shared");
+        match local_crypt::read(self.backend.local_key.get(), &seed) {
+            Ok(data) => {
+                self.backend.seeded_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                Ok(Some(SGData::from_single(data)))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 
-        let resp = CLIENT.put(url).send().expect("Could not drop RemoteLock");
+    fn read_object(&mut self, path: PathBuf) -> io::Result<SGData> {
+        trace!("remote read: {:?}", path);
 
-        if resp.status() != StatusCode::CREATED {
-            let status = resp.status();
-            let body = resp.bytes().unwrap().to_vec();
-            let body_str = std::str::from_utf8(body.as_slice());
+        self.flush_pending()?;
 
-            trace!("Could not create remote lock: {:?} {:?}", status, body_str);
+        if let Some(sample) = *self.backend.verify_sample.lock().unwrap() {
+            if ObjectType::of(&path) == ObjectType::Chunk && !sample.includes(&path) {
+                return Err(verify::sampled_out());
+            }
+        }
 
-            return Err(Error::new(ErrorKind::InvalidData, AnyError::from("Invalid response")));
+        if let Some(chunk_paths) = self.backend.chunk_paths.lock().unwrap().as_mut() {
+            if paths::path_digest(&path).is_some() {
+                chunk_paths.insert(path.to_string_lossy().into_owned())?;
+            }
         }
 
-        let lr = resp.json::<SharedLockResponse>().unwrap();
+        let cache = cache_for(&path);
 
-        trace!("Created remote shared lock {}", lr.lock_id);
+        if let Some(data) = cache.and_then(|c| c.get(&path)) {
+            trace!("Serving {:?} from cache", path);
+            return Ok(data);
+        }
 
-        Ok(Box::new(RemoteLock {
-            id: lr.lock_id,
-            backend: Arc::clone(&self.inner),
-        }))
-    }
+        if let Some(data) = self.read_seed(&path)? {
+            trace!("Serving {:?} from delta restore seed", path);
+            return Ok(data);
+        }
 
-    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
-        Ok(Box::new(RemoteBackendThread {
-            backend: Arc::clone(&self.inner),
-        }))
-    }
-}
+        if let Some(data) = self.read_cached_chunk(&path) {
+            return Ok(data);
+        }
 
-fn calculate_digest(sg: &SGData) -> Vec<u8> {
-    let mut sha256 = sha2::Sha256::default();
+        let config_cache = self.backend.config_cache.get().filter(|_| config_cache::is_cached(&path));
+        let cached = config_cache.and_then(|c| c.get(&path, self.backend.shared_key.get()));
 
-    for sg_part in sg.as_parts() {
-        sha256.update(sg_part);
+        let mut url = self.backend.endpoint();
+        let storage_path = self.backend.storage_path(&path);
+
+        url.set_path("read");
+        url.query_pairs_mut().append_pair("path", &storage_path);
+
+        let mut request = self.backend.request(Method::GET, url);
+        if let Some(generation) = paths::generation(Path::new(&storage_path)) {
+            request = request.header(GENERATION_HEADER, generation);
+        }
+        if let Some((etag, _)) = &cached {
+            request = request.header("if-none-match", etag);
+        }
+
+        let resp = request.send()?;
+
+        if matches!(resp.status(), StatusCode::OK | StatusCode::NOT_MODIFIED) {
+            if let Some(version) = resp.headers().get(LAYOUT_VERSION_HEADER) {
+                let version = version.to_str().ok().and_then(|v| v.parse().ok());
+                if version != Some(self.backend.layout().version()) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        AnyError::from(format!("Repository layout changed to {:?} meanwhile, reconnect", version)),
+                    ));
+                }
+            }
+        }
+
+        match resp.status() {
+            StatusCode::OK => {
+                let etag = resp.headers().get(ETAG).and_then(|v| v.to_str().ok()).map(str::to_string);
+                let data = resp.bytes()?;
+
+                if let (Some(config_cache), Some(etag)) = (config_cache, etag) {
+                    config_cache.put(&path, &etag, &data, self.backend.shared_key.get());
+                }
+                self.cache_chunk(&path, &data);
+
+                let data = SGData::from_single(data);
+                if let Some(cache) = cache {
+                    cache.insert(path, &data);
+                }
+                Ok(data)
+            }
+            StatusCode::NOT_MODIFIED if cached.is_some() => {
+                trace!("Cached {:?} still valid", path);
+                let (_, data) = cached.expect("Missing cached data");
+                Ok(SGData::from_single(data))
+            }
+            StatusCode::NOT_FOUND => {
+                trace!("Received: {:?}", resp);
+                self.uncache_config(&path);
+                Err(Error::new(ErrorKind::NotFound, AnyError::from("File not found")))
+            }
+            _ => Err(error_from_response(resp)),
+        }
     }
 
-    let mut vec_result = vec![0u8; 32];
-    vec_result.copy_from_slice(&sha256.finalize());
+    /// GC moves the objects still in use to a new generation directory, so a read racing the move finds nothing at the
+    /// path rdedup resolved before. Content-addressed objects are looked for once in the other generations then, newest
+    /// first; `None` when they are in none of them, or when the server looked for them there already.
+    fn read_other_generation(&mut self, path: &Path) -> Option<io::Result<SGData>> {
+        if self.backend.generation_fallback.load(Ordering::Relaxed) {
+            return None;
+        }
 
-    vec_result
+        let generation = paths::generation(path)?.to_string();
+
+        let mut generations: Vec<String> = match self.list(PathBuf::new()) {
+            Ok(entries) => entries
+                .iter()
+                .filter_map(|p| p.file_name().map(|f| f.to_string_lossy().to_string()))
+                .filter(|f| paths::is_generation_dir(f) && *f != generation)
+                .collect(),
+            Err(e) => return Some(Err(e)),
+        };
+        generations.sort_by(|a, b| b.cmp(a));
+
+        for other in generations {
+            match self.read_object(paths::in_generation(path, &other)) {
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                result => {
+                    debug!("{:?} was moved to generation {} meanwhile", path, other);
+                    return Some(result);
+                }
+            }
+        }
+
+        None
+    }
 }
 
 impl BackendThread for RemoteBackendThread {
-    fn remove_dir_all(&mut self, _path: PathBuf) -> io::Result<()> {
-        unimplemented!()
+    /// Removes a whole (generation) directory; renames out of it queued by GC are sent first.
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        trace!("remote remove dir: {:?}", path);
+
+        CHUNK_CACHE.remove_dir(&path);
+        NAME_CACHE.remove_dir(&path);
+
+        self.flush_pending()?;
+
+        let mut url = self.backend.endpoint();
+        url.set_path("remove-dir");
+        url.query_pairs_mut().append_pair("path", &self.backend.storage_path(&path));
+
+        let resp = self.backend.request(Method::DELETE, url).send()?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(error_from_response(resp)),
+        }
     }
 
-    fn rename(&mut self, _src_path: PathBuf, _dst_path: PathBuf) -> io::Result<()> {
-        unimplemented!()
+    /// Renames are queued and sent in batches - GC moves chunks between generations one by one.
+    fn rename(&mut self, src_path: PathBuf, dst_path: PathBuf) -> io::Result<()> {
+        trace!("remote rename: {:?} -> {:?}", src_path, dst_path);
+
+        NAME_CACHE.remove(&src_path);
+        NAME_CACHE.remove(&dst_path);
+
+        self.pending_renames.push(RenameEntry {
+            from: PathBuf::from(self.backend.storage_path(&src_path)),
+            to: PathBuf::from(self.backend.storage_path(&dst_path)),
+        });
+
+        if self.pending_renames.len() >= RENAME_BATCH_SIZE {
+            self.flush_renames()?;
+        }
+
+        Ok(())
     }
 
     fn write(&mut self, path: PathBuf, sg: SGData, idempotent: bool) -> io::Result<()> {
@@ -130,64 +1628,150 @@ impl BackendThread for RemoteBackendThread {
 
         trace!("remote write: path={:?} hash={} len={}B idem={}", path, hash, sg.len(), idempotent);
 
-        let mut url = self.backend.server_url.clone();
+        NAME_CACHE.remove(&path);
+        self.uncache_config(&path);
+
+        let storage_path = self.backend.storage_path(&path);
+        let signature = self
+            .backend
+            .signing_key
+            .as_ref()
+            .map(|key| calculate_signature(key, &storage_path, &sg));
+        // lets the server refuse writes into generations sealed by GC
+        let generation = paths::generation(Path::new(&storage_path)).map(str::to_string);
+
+        let failed_queue = self.backend.failed_queue.get();
+
+        // deep index levels of large files are many tiny objects; batches can't be queued when failed, though
+        if self.backend.write_batch.load(Ordering::Relaxed)
+            && failed_queue.is_none()
+            && ObjectType::of(&path) == ObjectType::Index
+            && sg.len() <= SMALL_WRITE_SIZE
+        {
+            let entry = WriteBatchEntry {
+                path: PathBuf::from(storage_path),
+                hash,
+                len: sg.len(),
+                signature,
+                generation,
+            };
+
+            return self.backend.queue_write(entry, &sg);
+        }
+
+        self.flush_pending()?;
+
+        // rdedup writes the name only after all its chunks and indexes are stored, so the name is staged and committed
+        // right away - a client dying in between leaves nothing visible
+        let pending = ObjectType::of(&path) == ObjectType::Name;
+
+        if let Some(queue) = failed_queue {
+            if pending && queue.queued() > 0 {
+                return self.queue_name(queue, &path, &sg);
+            }
+        }
+
+        let mut url = self.backend.endpoint();
         url.set_path("write");
 
-        let data = SGDataWrapper::new(sg);
+        let len = sg.len() as u64;
+        // parts of the data are shared, the copies are cheap
+        let queued = failed_queue.map(|queue| (queue, sg.clone()));
+        let mut retries = 0;
 
-        let resp = CLIENT
-            .post(url)
-            .header("path", path.to_str().unwrap())
-            .header("hash", hash)
-            .body(Body::new(data))
-            .send()
-            .map_err(|e| (Error::new(ErrorKind::BrokenPipe, e)))?;
+        let sent = loop {
+            let mut req = self.backend.request(Method::POST, url.clone()).header("hash", &hash);
 
-        if resp.status() != StatusCode::OK {
-            trace!("Received: {:?}", resp);
-            trace!("Error: {:?}", std::str::from_utf8(resp.bytes().unwrap().to_vec().as_slice()));
-            return Err(Error::new(ErrorKind::InvalidData, AnyError::from("Invalid response")));
+            if let Some(signature) = &signature {
+                req = req.header(SIGNATURE_HEADER, signature);
+            }
+
+            if let Some(generation) = &generation {
+                req = req.header(GENERATION_HEADER, generation);
+            }
+
+            req = req.header("path", &storage_path);
+
+            if pending {
+                req = req.header("pending", "true");
+            }
+
+            match req.body(SGDataWrapper::new(sg.clone())).send() {
+                Ok(resp) if is_hash_mismatch(&resp) && retries < HASH_MISMATCH_RETRIES => {
+                    warn!("Upload of {:?} got corrupted on the way, sending it again", path);
+                    retries += 1;
+                }
+                Ok(resp) if resp.status() != StatusCode::OK => break Err(error_from_response(resp)),
+                sent => break sent.map(|_| ()),
+            }
+        };
+
+        match (sent, queued) {
+            (Err(e), Some((queue, sg))) if errors::classify(&e) == FailureClass::Network => {
+                if pending {
+                    return self.queue_name(queue, &path, &sg);
+                }
+
+                if matches!(ObjectType::of(&path), ObjectType::Chunk | ObjectType::Index) {
+                    warn!("Upload of {:?} failed, queued for retry: {}", path, e);
+                    return queue.push(&path, &sg, None, self.backend.shared_key.get());
+                }
+
+                return Err(e);
+            }
+            (sent, _) => sent?,
+        }
+
+        if pending {
+            self.commit_name(path)?;
+        } else if ObjectType::of(&path) == ObjectType::Chunk {
+            if let Some(digest) = paths::path_digest(&path) {
+                callbacks::chunk_uploaded(digest, len);
+            }
         }
 
         Ok(())
     }
 
     fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
-        trace!("remote read: {:?}", path);
+        match self.read_object(path.clone()) {
+            Err(e) if e.kind() == ErrorKind::NotFound => self.read_other_generation(&path).unwrap_or(Err(e)),
+            result => result,
+        }
+    }
 
-        let mut url = self.backend.server_url.clone();
-        url.set_path("read");
-        url.query_pairs_mut()
-            .append_pair("path", path.to_str().expect("Invalid utf-8 path"));
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        trace!("remote remove: {:?}", path);
+
+        if let Some(cache) = cache_for(&path) {
+            cache.remove(&path);
+        }
+        self.uncache_config(&path);
+
+        self.flush_pending()?;
 
-        let resp = CLIENT.get(url).send().map_err(|e| (Error::new(ErrorKind::BrokenPipe, e)))?;
+        let mut url = self.backend.endpoint();
+        url.set_path("remove");
+        url.query_pairs_mut().append_pair("path", &self.backend.storage_path(&path));
+
+        let resp = self.backend.request(Method::DELETE, url).send()?;
 
         match resp.status() {
-            StatusCode::OK => Ok(SGData::from_single(resp.bytes().unwrap().to_vec())),
-            StatusCode::NOT_FOUND => {
-                trace!("Received: {:?}", resp);
-                Err(Error::new(ErrorKind::NotFound, AnyError::from("File not found")))
-            }
-            _ => {
-                trace!("Received: {:?}", resp);
-                Err(Error::new(ErrorKind::InvalidData, AnyError::from("Invalid response")))
-            }
+            StatusCode::OK => Ok(()),
+            _ => Err(error_from_response(resp)),
         }
     }
 
-    fn remove(&mut self, _path: PathBuf) -> io::Result<()> {
-        unimplemented!()
-    }
-
     fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
         trace!("remote read metadata: {:?}", path);
 
-        let mut url = self.backend.server_url.clone();
+        self.flush_pending()?;
+
+        let mut url = self.backend.endpoint();
         url.set_path("read-metadata");
-        url.query_pairs_mut()
-            .append_pair("path", path.to_str().expect("Invalid utf-8 path"));
+        url.query_pairs_mut().append_pair("path", &self.backend.storage_path(&path));
 
-        let resp = CLIENT.get(url).send().map_err(|e| (Error::new(ErrorKind::BrokenPipe, e)))?;
+        let resp = self.backend.request(Method::GET, url).send()?;
 
         match resp.status() {
             StatusCode::OK => {
@@ -199,33 +1783,14 @@ impl BackendThread for RemoteBackendThread {
                 trace!("Received: {:?}", resp);
                 Err(Error::new(ErrorKind::NotFound, AnyError::from("File not found")))
             }
-            _ => {
-                trace!("Received: {:?}", resp);
-                Err(Error::new(ErrorKind::InvalidData, AnyError::from("Invalid response")))
-            }
+            _ => Err(error_from_response(resp)),
         }
     }
 
     fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
-        trace!("remote list: {:?}", path);
-
-        let mut url = self.backend.server_url.clone();
-        url.set_path("list");
-        url.query_pairs_mut()
-            .append_pair("path", path.to_str().expect("Invalid utf-8 path"));
-
-        let resp = CLIENT.get(url).send().map_err(|e| (Error::new(ErrorKind::BrokenPipe, e)))?;
-
-        if resp.status() != StatusCode::OK {
-            trace!("Received: {:?}", resp);
-            return Err(Error::new(ErrorKind::InvalidData, AnyError::from("Invalid response")));
-        }
-
-        let lr = resp.json::<ListResponse>().unwrap();
-
-        trace!("Received {:?}", lr);
-
-        Ok(lr.paths)
+        let mut paths = Vec::new();
+        self.list_each(path, |p| paths.push(p))?;
+        Ok(paths)
     }
 
     fn list_recursively(&mut self, _path: PathBuf, _tx: Sender<io::Result<Vec<PathBuf>>>) {
@@ -252,3 +1817,284 @@ impl Read for SGDataWrapper {
         self.data.read(buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use libcommon::structs::WriteResult;
+    use reqwest::header::{HeaderMap, HeaderValue};
+
+    use super::*;
+    use crate::transport::{MockTransport, Request, RequestBody};
+
+    fn response(status: StatusCode, body: impl Into<Vec<u8>>) -> io::Result<Response> {
+        Ok(Response::new(status, HeaderMap::new(), Cursor::new(body.into())))
+    }
+
+    fn query(url: &Url, name: &str) -> Option<String> {
+        url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.into_owned())
+    }
+
+    fn backend(transport: &Arc<MockTransport>) -> RemoteBackend {
+        RemoteBackend::with_transport(Url::parse("http://server").unwrap(), None, None, transport.clone())
+    }
+
+    fn requests_to(transport: &MockTransport, endpoint: &str) -> usize {
+        transport.requests().iter().filter(|(_, url)| url.path() == endpoint).count()
+    }
+
+    fn body(request: Request) -> Vec<u8> {
+        match request.body {
+            RequestBody::Empty => Vec::new(),
+            RequestBody::Bytes(data) => data,
+            RequestBody::Stream(mut reader) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data).unwrap();
+                data
+            }
+        }
+    }
+
+    fn hash_mismatch() -> io::Result<Response> {
+        let mut headers = HeaderMap::new();
+        headers.insert(HASH_MISMATCH_HEADER, HeaderValue::from_static("true"));
+
+        Ok(Response::new(StatusCode::CONFLICT, headers, Cursor::new(Vec::new())))
+    }
+
+    /// Server corrupting the first `corrupted` single writes on the way, checking the hash of the others.
+    fn corrupting_server(corrupted: usize) -> Arc<MockTransport> {
+        let writes = AtomicUsize::new(0);
+
+        Arc::new(MockTransport::new(move |request| match request.url.path() {
+            "/write" => {
+                let hash = request.headers["hash"].to_str().unwrap().to_string();
+                let data = body(request);
+                assert_eq!(hash, hex::encode(calculate_digest(&SGData::from_single(data))));
+
+                if writes.fetch_add(1, Ordering::SeqCst) < corrupted {
+                    hash_mismatch()
+                } else {
+                    response(StatusCode::OK, "")
+                }
+            }
+            endpoint => panic!("Unexpected request to {}", endpoint),
+        }))
+    }
+
+    /// Server accepting write batches, recording them; the entries of the first batch at `corrupted` positions are
+    /// reported as corrupted on the way.
+    fn batching_server(corrupted: &'static [usize], batches: Arc<Mutex<Vec<(WriteBatchRequest, Vec<u8>)>>>) -> Arc<MockTransport> {
+        Arc::new(MockTransport::new(move |request| match request.url.path() {
+            "/capabilities" => response(StatusCode::OK, r#"{"layout_version":1,"write_batch":true}"#),
+            "/write-batch" => {
+                let data = body(request);
+                let header_len = data.iter().position(|b| *b == b'\n').unwrap();
+                let batch: WriteBatchRequest = serde_json::from_slice(&data[..header_len]).unwrap();
+
+                let mut batches = batches.lock().unwrap();
+                let first = batches.is_empty();
+                let results = batch
+                    .writes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, entry)| WriteResult {
+                        path: entry.path.clone(),
+                        error: None,
+                        hash_mismatch: first && corrupted.contains(&i),
+                    })
+                    .collect();
+                batches.push((batch, data[header_len + 1..].to_vec()));
+
+                response(StatusCode::OK, serde_json::to_vec(&WriteBatchResponse { results }).unwrap())
+            }
+            endpoint => panic!("Unexpected request to {}", endpoint),
+        }))
+    }
+
+    fn index_path(i: usize) -> PathBuf {
+        Path::new("gen1/index/12/ab").join(format!("12ab34cd56ef12ab34cd56ef12ab34c{}", i))
+    }
+
+    /// Server holding the repository exclusively locked for the first `locked` requests of a shared lock.
+    fn locked_server(locked: usize) -> Arc<MockTransport> {
+        let attempts = AtomicUsize::new(0);
+
+        Arc::new(MockTransport::new(move |request| match (&request.method, request.url.path()) {
+            (&Method::PUT, "/lock-shared") if attempts.fetch_add(1, Ordering::SeqCst) < locked => {
+                response(StatusCode::LOCKED, r#"{"lock_id":null,"holder":"gc","since":1600000000}"#)
+            }
+            (&Method::PUT, "/lock-shared") => response(StatusCode::CREATED, r#"{"lock_id":"6f1c2a8e-3b4d-4e5f-8a9b-0c1d2e3f4a5b"}"#),
+            (&Method::DELETE, "/lock-shared") => response(StatusCode::OK, ""),
+            (_, endpoint) => panic!("Unexpected request to {}", endpoint),
+        }))
+    }
+
+    /// Server moving chunk `digest` from generation `gen1` to `gen2` before it's read.
+    fn moved_chunk_server(digest: &'static str, generation_fallback: bool) -> Arc<MockTransport> {
+        Arc::new(MockTransport::new(move |request| {
+            match (request.url.path(), query(&request.url, "path")) {
+                ("/capabilities", _) => response(
+                    StatusCode::OK,
+                    format!(r#"{{"layout_version":1,"generation_fallback":{}}}"#, generation_fallback),
+                ),
+                ("/read", Some(path)) if path == format!("gen2/chunk/{}/{}/{}", &digest[..2], &digest[2..4], digest) => {
+                    response(StatusCode::OK, "moved chunk")
+                }
+                ("/read", _) => response(StatusCode::NOT_FOUND, ""),
+                ("/list-stream", _) => response(StatusCode::OK, "\"gen1\"\n\"gen2\"\n\"name\"\n"),
+                (endpoint, _) => panic!("Unexpected request to {}", endpoint),
+            }
+        }))
+    }
+
+    #[test]
+    fn reads_chunk_moved_to_other_generation() {
+        let digest = "a1b2c3d4e5f6a1b2c3d4e5f6a1b2c3d4";
+        let transport = moved_chunk_server(digest, false);
+        let mut thread = backend(&transport).new_thread().unwrap();
+
+        let data = thread.read(Path::new("gen1/chunk/a1/b2").join(digest)).unwrap();
+
+        assert_eq!(data.to_linear_vec(), b"moved chunk");
+        // from its generation, then from the other one
+        assert_eq!(requests_to(&transport, "/read"), 2);
+        assert_eq!(requests_to(&transport, "/list-stream"), 1);
+    }
+
+    #[test]
+    fn leaves_generation_fallback_to_server_doing_it() {
+        let digest = "b1c2d3e4f5a6b1c2d3e4f5a6b1c2d3e4";
+        let transport = moved_chunk_server(digest, true);
+        let backend = backend(&transport);
+        backend.negotiate().unwrap();
+
+        let e = backend
+            .new_thread()
+            .unwrap()
+            .read(Path::new("gen1/chunk/b1/c2").join(digest))
+            .unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+        assert_eq!(requests_to(&transport, "/read"), 1);
+        assert_eq!(requests_to(&transport, "/list-stream"), 0);
+    }
+
+    #[test]
+    fn resends_write_corrupted_on_the_way() {
+        let transport = corrupting_server(1);
+        let mut thread = backend(&transport).new_thread().unwrap();
+
+        thread
+            .write(
+                Path::new("gen1/chunk/c1/d2").join("c1d2e3f4a5b6c1d2e3f4a5b6c1d2e3f4"),
+                SGData::from_single(b"chunk data".to_vec()),
+                true,
+            )
+            .unwrap();
+
+        assert_eq!(requests_to(&transport, "/write"), 2);
+    }
+
+    #[test]
+    fn gives_up_on_write_corrupted_repeatedly() {
+        let transport = corrupting_server(usize::MAX);
+        let mut thread = backend(&transport).new_thread().unwrap();
+
+        let result = thread.write(
+            Path::new("gen1/chunk/c1/d2").join("c1d2e3f4a5b6c1d2e3f4a5b6c1d2e3f4"),
+            SGData::from_single(b"chunk data".to_vec()),
+            true,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(requests_to(&transport, "/write"), HASH_MISMATCH_RETRIES + 1);
+    }
+
+    #[test]
+    fn batches_small_index_writes_until_flushed() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let transport = batching_server(&[], Arc::clone(&batches));
+        let backend = backend(&transport);
+        backend.negotiate().unwrap();
+        let mut thread = backend.new_thread().unwrap();
+
+        for i in 0..3 {
+            thread.write(index_path(i), SGData::from_single(vec![i as u8; 10]), true).unwrap();
+        }
+
+        assert_eq!(requests_to(&transport, "/write-batch"), 0);
+
+        backend.inner.flush_writes().unwrap();
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 1);
+
+        let (batch, data) = &batches[0];
+        let paths: Vec<_> = batch.writes.iter().map(|entry| entry.path.clone()).collect();
+        assert_eq!(paths, (0..3).map(index_path).collect::<Vec<_>>());
+        assert_eq!(data, &[vec![0u8; 10], vec![1u8; 10], vec![2u8; 10]].concat());
+        assert_eq!(requests_to(&transport, "/write"), 0);
+    }
+
+    #[test]
+    fn sends_full_write_batch_right_away() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let transport = batching_server(&[], Arc::clone(&batches));
+        let backend = backend(&transport);
+        backend.negotiate().unwrap();
+        let mut thread = backend.new_thread().unwrap();
+
+        for i in 0..WRITE_BATCH_SIZE {
+            thread.write(index_path(i % 10), SGData::from_single(vec![1]), true).unwrap();
+        }
+
+        assert_eq!(requests_to(&transport, "/write-batch"), 1);
+        assert_eq!(batches.lock().unwrap()[0].0.writes.len(), WRITE_BATCH_SIZE);
+    }
+
+    #[test]
+    fn resends_batch_writes_corrupted_on_the_way() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let transport = batching_server(&[1], Arc::clone(&batches));
+        let backend = backend(&transport);
+        backend.negotiate().unwrap();
+        let mut thread = backend.new_thread().unwrap();
+
+        for i in 0..3 {
+            thread.write(index_path(i), SGData::from_single(vec![i as u8; 10]), true).unwrap();
+        }
+        backend.inner.flush_writes().unwrap();
+
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 2);
+
+        let (batch, data) = &batches[1];
+        assert_eq!(batch.writes.len(), 1);
+        assert_eq!(batch.writes[0].path, index_path(1));
+        assert_eq!(data, &vec![1u8; 10]);
+    }
+
+    #[test]
+    fn waits_for_exclusive_lock_release() {
+        let transport = locked_server(1);
+        let backend = backend(&transport);
+        backend.set_lock_wait(Some(Duration::from_secs(60)));
+
+        let lock = backend.try_lock_shared().unwrap();
+        drop(lock);
+
+        assert_eq!(transport.requests().iter().filter(|(method, _)| *method == Method::PUT).count(), 2);
+    }
+
+    #[test]
+    fn fails_on_exclusive_lock_without_waiting() {
+        let transport = locked_server(usize::MAX);
+
+        let e = backend(&transport).try_lock_shared().err().unwrap();
+
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        assert_eq!(requests_to(&transport, "/lock-shared"), 1);
+    }
+}
@@ -0,0 +1,216 @@
+use std::path::PathBuf;
+
+use libcommon::build_info::BuildInfo;
+use libcommon::structs::LocksResponse;
+use serde::Serialize;
+use url::Url;
+
+use crate::errors::FailureClass;
+use crate::history::RunSummary;
+use crate::verify::Sample;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoreResult {
+    pub name: String,
+    pub source_bytes: u64,
+    pub files: u64,
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+    pub duration_ms: u128,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RetryFailedResult {
+    /// Objects uploaded from the queue
+    pub uploaded: u64,
+    /// Names stored at last
+    pub names: Vec<String>,
+    pub duration_ms: u128,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepairResult {
+    pub name: String,
+    /// Hex digests of the chunks found corrupted
+    pub corrupted: Vec<String>,
+    /// Chunks still corrupted after the repair - the source doesn't contain them (it has changed since the store)
+    pub unrepaired: Vec<String>,
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+    pub duration_ms: u128,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreResult {
+    pub name: String,
+    pub bytes: u64,
+    /// Stored bytes found in the destination by delta restore instead of downloading them
+    pub reused_bytes: u64,
+    pub duration_ms: u128,
+    /// Server (the primary or the replica) the data came from
+    pub served_by: Url,
+    /// Set when asked to check the restored files
+    pub integrity: Option<IntegrityReport>,
+}
+
+/// Restored files checked against their hashes recorded at store time, see `integrity`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IntegrityReport {
+    /// The snapshot has the hashes recorded; plain files and snapshots of older clients don't
+    pub recorded: bool,
+    /// Restored files hashed
+    pub files: u64,
+    /// Files restored with other contents than stored
+    pub mismatched: Vec<PathBuf>,
+    /// Files recorded, but not restored
+    pub missing: Vec<PathBuf>,
+}
+
+impl IntegrityReport {
+    pub fn is_intact(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoreTestResult {
+    pub name: String,
+    pub bytes: u64,
+    /// Entries of a directory snapshot
+    pub entries: Option<u64>,
+    pub duration_ms: u128,
+    pub throughput_mb_per_sec: f64,
+    pub served_by: Url,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ForgetResult {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportResult {
+    pub name: String,
+    pub bytes: u64,
+    /// Files hard-linked from the previous export
+    pub linked_files: u64,
+    pub linked_bytes: u64,
+    pub duration_ms: u128,
+    pub served_by: Url,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecoveryCodes {
+    /// Key slot the codes open
+    pub slot: String,
+    /// Codes needed to recover
+    pub threshold: u8,
+    pub codes: Vec<String>,
+}
+
+/// Where an exported name got decoded.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decoder {
+    Server,
+    Client,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportTarResult {
+    pub name: String,
+    /// Size of the archive
+    pub bytes: u64,
+    pub decoded_by: Decoder,
+    pub duration_ms: u128,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NameVerifyReport {
+    pub name: String,
+    pub scanned_bytes: u64,
+    /// Hex digests of corrupted chunks with error descriptions
+    pub corrupted: Vec<(String, String)>,
+    /// Chunks left out by sampled verification
+    pub skipped_chunks: u64,
+    /// Set when the verification could not be finished at all
+    pub failure: Option<String>,
+    /// Set when corrupted chunks were found or the verification failed
+    pub failure_class: Option<FailureClass>,
+}
+
+impl NameVerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupted.is_empty() && self.failure.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub names: Vec<NameVerifyReport>,
+    pub scanned_bytes: u64,
+    pub failed: usize,
+    pub served_by: Option<Url>,
+    /// Set for sampled verification
+    pub sample: Option<Sample>,
+}
+
+impl VerifyReport {
+    /// Class of the failed verifications; corrupted data outweigh failures to finish the verification.
+    pub fn failure_class(&self) -> Option<FailureClass> {
+        let classes: Vec<FailureClass> = self.names.iter().filter_map(|n| n.failure_class).collect();
+
+        if classes.contains(&FailureClass::Corruption) {
+            Some(FailureClass::Corruption)
+        } else {
+            classes.first().copied()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepoInfo {
+    pub server: Url,
+    pub server_build: Option<BuildInfo>,
+    pub layout_version: u32,
+    /// Current (newest) generation of the repository data
+    pub generation: Option<String>,
+    /// Raw repository config (chunking, encryption, compression, nesting...)
+    pub config: String,
+    pub locks: LocksResponse,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RepoStats {
+    pub objects: u64,
+    pub bytes: u64,
+    /// Part of the above migrated to the cold storage tier of the server
+    pub cold_objects: u64,
+    pub cold_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileTrend {
+    pub profile: String,
+    pub runs: Vec<RunSummary>,
+    pub failed: usize,
+    /// Average of newly stored bytes over successful runs
+    pub avg_new_bytes: u64,
+    /// Change of the source size between the first and the last successful run
+    pub source_growth_bytes: i64,
+    pub last_success: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryReport {
+    pub profiles: Vec<ProfileTrend>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GcReport {
+    pub before: RepoStats,
+    pub after: RepoStats,
+    pub reclaimed_objects: u64,
+    pub reclaimed_bytes: u64,
+    pub duration_ms: u128,
+}
@@ -0,0 +1,155 @@
+//! Resumable restores of plain files.
+//!
+//! While a file is restored, the number of bytes safely on disk is recorded in the client state dir every
+//! `CHECKPOINT_BYTES`. An interrupted restore can then be resumed: the restored part of the file is chunked into a delta
+//! seed (see [`crate::delta`]), so its chunks aren't downloaded again, and writing continues at the recorded offset.
+//!
+//! rdedup reads a name from its beginning only, so data before the offset still go through decryption, they're just
+//! discarded instead of written.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const RESTORES_DIR: &str = "restores";
+
+/// How much data get written between two records of the progress
+const CHECKPOINT_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Progress {
+    name: String,
+    dest: PathBuf,
+    /// Bytes of the file written and synced to disk
+    offset: u64,
+}
+
+fn progress_path(state_dir: &Path, name: &str, dest: &Path) -> io::Result<PathBuf> {
+    // relative destinations must not collide when restoring from different directories
+    let dest = std::env::current_dir()?.join(dest);
+    let id = hex::encode(Sha256::digest(format!("{}\0{}", name, dest.display()).as_bytes()));
+
+    Ok(state_dir.join(RESTORES_DIR).join(format!("{}.json", &id[..16])))
+}
+
+/// Offset an interrupted restore of `name` into `dest` got to; zero when there's nothing to resume.
+pub fn recorded_offset(state_dir: &Path, name: &str, dest: &Path) -> io::Result<u64> {
+    let progress: Progress = match fs::read(progress_path(state_dir, name, dest)?) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        data => serde_json::from_slice(&data?)?,
+    };
+
+    // the file may have been truncated (or removed) since
+    let len = match fs::metadata(dest) {
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        metadata => metadata?.len(),
+    };
+
+    if progress.name != name || len < progress.offset {
+        warn!(
+            "Restore of {} into {:?} can't be resumed, the destination changed; restoring from the beginning",
+            name, dest
+        );
+        return Ok(0);
+    }
+
+    info!("Resuming restore of {} into {:?} at {}B", name, dest, progress.offset);
+
+    Ok(progress.offset)
+}
+
+/// Writes restored file, skipping the part restored already and recording the progress.
+pub struct ProgressWriter {
+    file: File,
+    path: PathBuf,
+    progress: Progress,
+    /// Bytes of the input still to be discarded
+    skip: u64,
+    unrecorded: u64,
+}
+
+impl ProgressWriter {
+    /// Opens `dest` for restore of `name`, continuing at `offset` (see `recorded_offset`); zero starts over.
+    pub fn open(state_dir: &Path, name: &str, dest: &Path, offset: u64) -> io::Result<ProgressWriter> {
+        let path = progress_path(state_dir, name, dest)?;
+        fs::create_dir_all(path.parent().expect("Progress file without parent"))?;
+
+        let mut file = OpenOptions::new().write(true).create(true).open(dest)?;
+        // data written after the last record aren't known to be complete
+        file.set_len(offset)?;
+        file.seek(SeekFrom::Start(offset))?;
+
+        Ok(ProgressWriter {
+            file,
+            path,
+            progress: Progress {
+                name: name.to_string(),
+                dest: dest.to_path_buf(),
+                offset,
+            },
+            skip: offset,
+            unrecorded: 0,
+        })
+    }
+
+    fn record(&mut self) -> io::Result<()> {
+        // the recorded offset must never get ahead of the data on disk
+        self.file.sync_data()?;
+
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.progress)?)?;
+        fs::rename(&tmp, &self.path)?;
+
+        trace!("Restore of {} recorded at {}B", self.progress.name, self.progress.offset);
+        self.unrecorded = 0;
+
+        Ok(())
+    }
+
+    /// Completes the restore, forgetting its progress.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.skip > 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Restored data are shorter than the part restored before",
+            ));
+        }
+
+        self.file.flush()?;
+
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.skip > 0 {
+            let skipped = self.skip.min(buf.len() as u64);
+            self.skip -= skipped;
+            return Ok(skipped as usize);
+        }
+
+        let written = self.file.write(buf)?;
+        self.progress.offset += written as u64;
+        self.unrecorded += written as u64;
+
+        if self.unrecorded >= CHECKPOINT_BYTES {
+            self.record()?;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,183 @@
+//! Checkpoints of store runs in progress, kept in the client state dir, so a run interrupted by the death of its process
+//! (a crash, an update of the daemon running it) is found by the next one and cleaned up deterministically.
+//!
+//! A checkpoint is written when a store starts and updated with each lock the run takes on the server; it's removed once
+//! the run ends, whether it succeeded or failed. A checkpoint of a process no longer running is thus an interrupted run:
+//! its locks are released (they'd block GC until their lease runs out, or forever without leases) and its name staged
+//! on the server, if any, is removed - the name was never committed, the repository holds its previous version. Running
+//! the store again resumes it, the chunks uploaded before the interruption are not uploaded again.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use nix::errno::Errno;
+use nix::sys::signal;
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+use uuid::Uuid;
+
+const RUNS_DIR: &str = "runs";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeldLock {
+    pub id: Uuid,
+    pub exclusive: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunState {
+    /// Name being stored, as stored (including the namespace)
+    pub name: String,
+    pub source: PathBuf,
+    pub server: Url,
+    pub pid: u32,
+    /// Unix timestamp (seconds) of the run start
+    pub started: u64,
+    /// Locks held on the server
+    pub locks: Vec<HeldLock>,
+}
+
+impl RunState {
+    /// Whether the process running the store is gone; pids get reused, so a live one doesn't prove the run is.
+    pub fn is_interrupted(&self) -> bool {
+        if self.pid == std::process::id() {
+            return false;
+        }
+
+        matches!(
+            signal::kill(Pid::from_raw(self.pid as i32), None),
+            Err(nix::Error::Sys(Errno::ESRCH))
+        )
+    }
+}
+
+/// Checkpoint file of the run of `name` stored on `server` - one per name, the name can't be stored twice at once.
+fn checkpoint_path(state_dir: &Path, server: &Url, name: &str) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(server.as_str().as_bytes());
+    hasher.update(&[0]);
+    hasher.update(name.as_bytes());
+
+    state_dir
+        .join(RUNS_DIR)
+        .join(format!("{}.json", &hex::encode(hasher.finalize())[..32]))
+}
+
+/// Checkpoint of the running store.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    state: RunState,
+}
+
+impl Checkpoint {
+    pub fn start(state_dir: &Path, server: &Url, name: &str, source: &Path) -> io::Result<Checkpoint> {
+        let checkpoint = Checkpoint {
+            path: checkpoint_path(state_dir, server, name),
+            state: RunState {
+                name: name.to_string(),
+                source: source.to_path_buf(),
+                server: server.clone(),
+                pid: std::process::id(),
+                started: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs(),
+                locks: Vec::new(),
+            },
+        };
+
+        fs::create_dir_all(checkpoint.path.parent().expect("Checkpoint path without parent"))?;
+        checkpoint.save()?;
+
+        Ok(checkpoint)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let data = serde_json::to_vec(&self.state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        // a torn checkpoint would hide locks left behind
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, data)?;
+        fs::rename(&temp, &self.path)
+    }
+
+    fn saved(&self) {
+        if let Err(e) = self.save() {
+            warn!("Could not update checkpoint {:?}: {}", self.path, e);
+        }
+    }
+
+    pub fn lock_taken(&mut self, lock: HeldLock) {
+        self.state.locks.push(lock);
+        self.saved();
+    }
+
+    pub fn lock_released(&mut self, id: Uuid) {
+        self.state.locks.retain(|l| l.id != id);
+        self.saved();
+    }
+
+    /// The run ended (one way or the other), there's nothing to clean up after it.
+    pub fn finish(self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove checkpoint {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Runs with checkpoints in `state_dir` on `server`, along with their checkpoint files.
+fn list(state_dir: &Path, server: &Url) -> io::Result<Vec<(PathBuf, RunState)>> {
+    let entries = match fs::read_dir(state_dir.join(RUNS_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut runs = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+
+        if path.extension().map(|e| e != "json").unwrap_or(true) {
+            continue;
+        }
+
+        let state: RunState = match fs::read(&path)
+            .and_then(|data| serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+        {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Skipping unreadable checkpoint {:?}: {}", path, e);
+                continue;
+            }
+        };
+
+        if state.server == *server {
+            runs.push((path, state));
+        }
+    }
+
+    Ok(runs)
+}
+
+/// Interrupted runs on `server`, oldest first, with their checkpoint files.
+pub fn interrupted(state_dir: &Path, server: &Url) -> io::Result<Vec<(PathBuf, RunState)>> {
+    let mut runs: Vec<_> = list(state_dir, server)?
+        .into_iter()
+        .filter(|(_, run)| run.is_interrupted())
+        .collect();
+    runs.sort_by_key(|(_, run)| run.started);
+
+    Ok(runs)
+}
+
+/// Drops checkpoint `path` of a run cleaned up after.
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
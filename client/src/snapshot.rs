@@ -0,0 +1,458 @@
+//! Directories are stored as tar streams; the archive carries ownership, permissions and timestamps of the stored files.
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Chain, Cursor, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use nix::unistd::{fchownat, geteuid, FchownatFlags, Gid, Group, Uid, User};
+use structopt::StructOpt;
+use tar::{Archive, Builder, EntryType, Header};
+use uuid::Uuid;
+
+use crate::integrity::{self, HashingReader, Manifest, MANIFEST_PATH};
+use crate::inventory;
+use crate::pipe::PipeWriter;
+use crate::progress;
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Controls how ownership and permissions stored in a snapshot get applied when restoring.
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct RestoreOptions {
+    /// Map stored owner UIDs to other ones, e.g. `1000:1001,1002:1003`
+    #[structopt(long, parse(try_from_str = RestoreOptions::parse_id_map), default_value = "")]
+    pub owner_map: HashMap<u32, u32>,
+    /// Map stored group GIDs to other ones, e.g. `100:1001`
+    #[structopt(long, parse(try_from_str = RestoreOptions::parse_id_map), default_value = "")]
+    pub group_map: HashMap<u32, u32>,
+    /// Use stored numeric UIDs/GIDs instead of resolving stored user and group names
+    #[structopt(long)]
+    pub numeric_ids: bool,
+    /// Don't restore stored permissions; files get the default ones (umask)
+    #[structopt(long)]
+    pub no_perms: bool,
+}
+
+impl RestoreOptions {
+    /// Parses ID mapping in form `from:to[,from:to...]`.
+    pub fn parse_id_map(s: &str) -> Result<HashMap<u32, u32>, String> {
+        s.split(',')
+            .filter(|p| !p.is_empty())
+            .map(|pair| {
+                let mut parts = pair.splitn(2, ':');
+
+                match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+                    (Some(Ok(from)), Some(Ok(to))) => Ok((from, to)),
+                    _ => Err(format!("Invalid ID mapping '{}', expected <from>:<to>", pair)),
+                }
+            })
+            .collect()
+    }
+
+    /// Only root can give files away; others restore everything as owned by themselves unless asked for mapping.
+    fn should_chown(&self) -> bool {
+        geteuid().is_root() || !self.owner_map.is_empty() || !self.group_map.is_empty()
+    }
+
+    fn resolve_owner(&self, header: &Header) -> io::Result<(u32, u32)> {
+        let uid = header.uid()? as u32;
+        let gid = header.gid()? as u32;
+
+        let uid = match self.owner_map.get(&uid) {
+            Some(mapped) => *mapped,
+            None if !self.numeric_ids => header
+                .username()
+                .ok()
+                .flatten()
+                .and_then(|name| User::from_name(name).ok().flatten())
+                .map(|user| user.uid.as_raw())
+                .unwrap_or(uid),
+            None => uid,
+        };
+
+        let gid = match self.group_map.get(&gid) {
+            Some(mapped) => *mapped,
+            None if !self.numeric_ids => header
+                .groupname()
+                .ok()
+                .flatten()
+                .and_then(|name| Group::from_name(name).ok().flatten())
+                .map(|group| group.gid.as_raw())
+                .unwrap_or(gid),
+            None => gid,
+        };
+
+        Ok((uid, gid))
+    }
+}
+
+/// Writes `source` directory as tar archive into the pipe, returning total size and count of stored files.
+///
+/// On failure the pipe gets aborted, so the consumer doesn't store a truncated snapshot.
+pub fn write_tree(source: &Path, writer: PipeWriter) -> io::Result<(u64, u64)> {
+    let aborter = writer.clone();
+
+    let result = write_tree_inner(source, writer);
+
+    if let Err(e) = &result {
+        aborter.abort(io::Error::new(e.kind(), e.to_string()));
+    }
+
+    result
+}
+
+fn write_tree_inner(source: &Path, writer: PipeWriter) -> io::Result<(u64, u64)> {
+    let (size, files) = tree_size(source)?;
+    progress::set_total(size);
+
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    builder.append_dir(".", source)?;
+
+    let mut manifest = Manifest::default();
+    append_entries(&mut builder, source, Path::new("."), &mut manifest)?;
+    append_manifest(&mut builder, &manifest)?;
+
+    builder.finish()?;
+
+    Ok((size, files))
+}
+
+/// Same as `Builder::append_dir_all`, entry by entry so the progress knows the file being stored; regular files are
+/// hashed into `manifest` as they are archived.
+fn append_entries(builder: &mut Builder<PipeWriter>, dir: &Path, archive_dir: &Path, manifest: &mut Manifest) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let archive_path = archive_dir.join(entry.file_name());
+        let file_type = entry.file_type()?;
+
+        progress::set_current_file(&path);
+
+        if file_type.is_file() {
+            let file = File::open(&path)?;
+            let mut header = Header::new_gnu();
+            header.set_metadata(&file.metadata()?);
+
+            // a file growing meanwhile must not overflow its entry
+            let mut reader = HashingReader::new(file.take(header.size()?));
+            builder.append_data(&mut header, &archive_path, &mut reader)?;
+            manifest.files.insert(integrity::normalize(&archive_path), reader.finish());
+        } else {
+            builder.append_path_with_name(&path, &archive_path)?;
+        }
+
+        if file_type.is_dir() {
+            append_entries(builder, &path, &archive_path, manifest)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends the manifest as the last entry, once all the files are hashed.
+fn append_manifest(builder: &mut Builder<PipeWriter>, manifest: &Manifest) -> io::Result<()> {
+    let data = manifest.to_vec()?;
+
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .as_secs(),
+    );
+
+    builder.append_data(&mut header, Path::new(".").join(MANIFEST_PATH), data.as_slice())
+}
+
+/// Returns total size and count of files in the tree.
+fn tree_size(dir: &Path) -> io::Result<(u64, u64)> {
+    let mut size = 0;
+    let mut files = 0;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            let (dir_size, dir_files) = tree_size(&entry.path())?;
+            size += dir_size;
+            files += dir_files;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+            files += 1;
+        }
+    }
+
+    Ok((size, files))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TreeStats {
+    /// Total size of all files in the tree
+    pub bytes: u64,
+    /// Files hard-linked from a previous export instead of being written
+    pub linked_files: u64,
+    pub linked_bytes: u64,
+    /// Hashes of the files recorded at store time, see `integrity`
+    pub manifest: Option<Manifest>,
+}
+
+/// Peeks at the beginning of the data, telling whether it's a directory snapshot; returns the data back for reading.
+fn detect_tree<R: Read>(mut input: R) -> io::Result<(bool, Chain<Cursor<Vec<u8>>, R>)> {
+    let mut head = Vec::with_capacity(TAR_BLOCK_SIZE);
+    (&mut input).take(TAR_BLOCK_SIZE as u64).read_to_end(&mut head)?;
+
+    let is_tree = head.len() == TAR_BLOCK_SIZE && &head[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()] == TAR_MAGIC;
+
+    Ok((is_tree, Cursor::new(head).chain(input)))
+}
+
+/// Restores data read from `input` into `dest` - either unpacks a snapshot of a directory, or writes a plain file.
+///
+/// Returns number of restored bytes and, for directory snapshots recording them, hashes of the stored files.
+pub fn restore(input: impl Read, dest: &Path, options: &RestoreOptions) -> io::Result<(u64, Option<Manifest>)> {
+    restore_with(input, dest, options, |input| io::copy(input, &mut File::create(dest)?))
+}
+
+/// Same as `restore`, but a plain file is written by `write_file` instead.
+pub fn restore_with(
+    input: impl Read,
+    dest: &Path,
+    options: &RestoreOptions,
+    write_file: impl FnOnce(&mut dyn Read) -> io::Result<u64>,
+) -> io::Result<(u64, Option<Manifest>)> {
+    let (is_tree, mut input) = detect_tree(input)?;
+
+    if is_tree {
+        let stats = unpack_tree(input, dest, None, options)?;
+        Ok((stats.bytes, stats.manifest))
+    } else if inventory::is_inventory(input.get_ref().0.get_ref()) {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The name holds a metadata-only snapshot, there are no data to restore",
+        ))
+    } else {
+        progress::set_current_file(dest);
+        Ok((write_file(&mut input)?, None))
+    }
+}
+
+/// Goes through the whole data like restore would, but discards them; returns the number of bytes and, for directory
+/// snapshots, the number of entries in the archive.
+pub fn test_restore(input: impl Read) -> io::Result<(u64, Option<u64>)> {
+    let (is_tree, mut input) = detect_tree(input)?;
+
+    if !is_tree {
+        return Ok((io::copy(&mut input, &mut io::sink())?, None));
+    }
+
+    let mut archive = Archive::new(input);
+    let mut bytes = 0;
+    let mut entries = 0;
+
+    // parse the archive too, not just the raw stream
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        if integrity::is_manifest(&entry.path()?) {
+            continue;
+        }
+
+        bytes += io::copy(&mut entry, &mut io::sink())?;
+        entries += 1;
+    }
+
+    Ok((bytes, Some(entries)))
+}
+
+/// Materializes a directory snapshot into `dest`; files unchanged since a previous export in `link_dest` are hard-linked
+/// to it instead of being written again (like `rsync --link-dest`).
+pub fn export_tree(input: impl Read, dest: &Path, link_dest: Option<&Path>, options: &RestoreOptions) -> io::Result<TreeStats> {
+    let (is_tree, input) = detect_tree(input)?;
+
+    if !is_tree {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a directory snapshot"));
+    }
+
+    unpack_tree(input, dest, link_dest, options)
+}
+
+/// Writes data read from `input` into `output` as a tar archive: directory snapshots are archives already, a plain file
+/// becomes the only entry of one, named `name`. Returns the size of the archive.
+pub fn write_tar(input: impl Read, name: &str, output: &mut dyn Write) -> io::Result<u64> {
+    let (is_tree, mut input) = detect_tree(input)?;
+
+    if is_tree {
+        return io::copy(&mut input, output);
+    }
+
+    // size of the entry goes first, the file has to be spooled
+    let spooled = SpooledFile::create()?;
+    let size = io::copy(&mut input, &mut &spooled.file)?;
+    (&spooled.file).seek(SeekFrom::Start(0))?;
+
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .as_secs(),
+    );
+
+    let mut output = CountingWriter { inner: output, bytes: 0 };
+    let mut builder = Builder::new(&mut output);
+    builder.append_data(&mut header, name, &spooled.file)?;
+    builder.into_inner()?;
+
+    Ok(output.bytes)
+}
+
+/// Temp file removed once dropped.
+struct SpooledFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl SpooledFile {
+    fn create() -> io::Result<SpooledFile> {
+        let path = std::env::temp_dir().join(format!("rbackup2-export-{}", Uuid::new_v4()));
+        let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+
+        Ok(SpooledFile { file, path })
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove spooled export {:?}: {}", self.path, e);
+        }
+    }
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    bytes: u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Tells whether the previously exported file looks exactly like the one about to be unpacked.
+fn is_unchanged(previous: &Path, header: &Header, owner: Option<(u32, u32)>, options: &RestoreOptions) -> io::Result<bool> {
+    let meta = match fs::symlink_metadata(previous) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    Ok(meta.is_file()
+        && meta.len() == header.size()?
+        && meta.mtime() == header.mtime()? as i64
+        && (options.no_perms || meta.mode() & 0o7777 == header.mode()? & 0o7777)
+        && owner.map_or(true, |(uid, gid)| meta.uid() == uid && meta.gid() == gid))
+}
+
+fn unpack_tree(input: impl Read, dest: &Path, link_dest: Option<&Path>, options: &RestoreOptions) -> io::Result<TreeStats> {
+    let mut archive = Archive::new(input);
+    archive.set_preserve_permissions(!options.no_perms);
+    archive.set_preserve_mtime(true);
+
+    let chown = options.should_chown();
+
+    fs::create_dir_all(dest)?;
+
+    let mut stats = TreeStats::default();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let relative = entry.path()?.to_path_buf();
+
+        if integrity::is_manifest(&relative) {
+            stats.manifest = Some(Manifest::parse(&mut entry)?);
+            continue;
+        }
+
+        stats.bytes += entry.size();
+
+        // paths are used outside of `unpack_in` (which checks them on its own), don't let them escape `dest`
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid path in snapshot: {:?}", relative),
+            ));
+        }
+        let path = dest.join(&relative);
+        progress::set_current_file(&path);
+        let (uid, gid) = options.resolve_owner(entry.header())?;
+        let mode = entry.header().mode()?;
+        let entry_type = entry.header().entry_type();
+        let is_symlink = entry_type == EntryType::Symlink;
+
+        if let Some(link_dest) = link_dest.filter(|_| entry_type == EntryType::Regular) {
+            let previous = link_dest.join(&relative);
+            let owner = if chown { Some((uid, gid)) } else { None };
+
+            if is_unchanged(&previous, entry.header(), owner, options)? {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                // the data of the entry gets skipped by moving to the next one
+                fs::hard_link(&previous, &path)?;
+                stats.linked_files += 1;
+                stats.linked_bytes += entry.size();
+                continue;
+            }
+        }
+
+        entry.unpack_in(dest)?;
+
+        if chown {
+            if let Err(e) = fchownat(
+                None,
+                &path,
+                Some(Uid::from_raw(uid)),
+                Some(Gid::from_raw(gid)),
+                FchownatFlags::NoFollowSymlink,
+            ) {
+                warn!("Could not set owner of {:?} to {}:{}: {}", path, uid, gid, e);
+                continue;
+            }
+
+            // chown clears setuid/setgid bits
+            if !options.no_perms && !is_symlink {
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+            }
+        }
+    }
+
+    Ok(stats)
+}
@@ -0,0 +1,183 @@
+//! Set of strings (object paths, digests) which doesn't have to fit into memory - repositories with millions of chunks
+//! make sets of them too big for small machines.
+//!
+//! Items are kept in memory up to a bound, then spilled to a temp file as a sorted run; iterating the set merges the
+//! runs, yielding the items sorted and without duplicates.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
+use std::path::PathBuf;
+
+use log::*;
+use uuid::Uuid;
+
+/// Rough overhead of an item in memory besides its bytes
+const ITEM_OVERHEAD: usize = 64;
+
+pub struct SpillSet {
+    max_bytes: usize,
+    memory: BTreeSet<String>,
+    bytes: usize,
+    /// Created with the first run
+    dir: Option<TempDir>,
+    runs: Vec<PathBuf>,
+}
+
+impl SpillSet {
+    /// Set holding up to about `max_bytes` in memory.
+    pub fn new(max_bytes: usize) -> SpillSet {
+        SpillSet {
+            max_bytes,
+            memory: BTreeSet::new(),
+            bytes: 0,
+            dir: None,
+            runs: Vec::new(),
+        }
+    }
+
+    /// `item` must not contain newlines.
+    pub fn insert(&mut self, item: String) -> io::Result<()> {
+        debug_assert!(!item.contains('\n'), "Spilled items are separated by newlines");
+
+        let bytes = item.len() + ITEM_OVERHEAD;
+        if self.memory.insert(item) {
+            self.bytes += bytes;
+        }
+
+        if self.bytes > self.max_bytes {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.dir.is_none() {
+            self.dir = Some(TempDir::create()?);
+        }
+
+        let run = self.dir.as_ref().unwrap().path.join(format!("run-{}", self.runs.len()));
+        debug!("Spilling {} items ({}B) to {:?}", self.memory.len(), self.bytes, run);
+
+        let mut writer = BufWriter::new(File::create(&run)?);
+        for item in &self.memory {
+            writer.write_all(item.as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        self.runs.push(run);
+        self.memory.clear();
+        self.bytes = 0;
+
+        Ok(())
+    }
+
+    /// Items of the set, sorted.
+    pub fn into_sorted(mut self) -> io::Result<Sorted> {
+        if self.runs.is_empty() {
+            return Ok(Sorted {
+                source: Source::Memory(std::mem::take(&mut self.memory).into_iter()),
+                last: None,
+                _dir: None,
+            });
+        }
+
+        self.spill()?;
+
+        let mut runs = Vec::with_capacity(self.runs.len());
+        let mut heap = BinaryHeap::new();
+
+        for (index, run) in self.runs.iter().enumerate() {
+            let mut lines = BufReader::new(File::open(run)?).lines();
+            if let Some(line) = lines.next() {
+                heap.push(Reverse((line?, index)));
+            }
+            runs.push(lines);
+        }
+
+        Ok(Sorted {
+            source: Source::Runs { runs, heap },
+            last: None,
+            _dir: self.dir.take(),
+        })
+    }
+}
+
+enum Source {
+    Memory(std::collections::btree_set::IntoIter<String>),
+    Runs {
+        runs: Vec<Lines<BufReader<File>>>,
+        /// Smallest unread item of each run
+        heap: BinaryHeap<Reverse<(String, usize)>>,
+    },
+}
+
+/// Sorted items of a `SpillSet`; the spilled runs are removed once dropped.
+pub struct Sorted {
+    source: Source,
+    /// Merged runs may hold the same item, only the first one is yielded
+    last: Option<String>,
+    _dir: Option<TempDir>,
+}
+
+impl Sorted {
+    fn next_merged(&mut self) -> Option<io::Result<String>> {
+        match &mut self.source {
+            Source::Memory(items) => items.next().map(Ok),
+            Source::Runs { runs, heap } => {
+                let Reverse((item, index)) = heap.pop()?;
+
+                match runs[index].next() {
+                    Some(Ok(line)) => heap.push(Reverse((line, index))),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => (),
+                }
+
+                Some(Ok(item))
+            }
+        }
+    }
+}
+
+impl Iterator for Sorted {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_merged()? {
+                Ok(item) if self.last.as_ref() == Some(&item) => continue,
+                Ok(item) => {
+                    self.last = Some(item.clone());
+                    return Some(Ok(item));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    fn create() -> io::Result<TempDir> {
+        let path = std::env::temp_dir().join(format!("rbackup2-spill-{}", Uuid::new_v4()));
+        fs::create_dir(&path)?;
+
+        Ok(TempDir { path })
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Could not remove spilled set {:?}: {}", self.path, e);
+        }
+    }
+}
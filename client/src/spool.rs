@@ -0,0 +1,119 @@
+//! Format of repository objects the client keeps on the local disk for later - uploads queued while offline and the
+//! cached config - optionally compressed by zstd (`--spool-compression`), trading CPU for disk space of laptops spending
+//! days offline.
+//!
+//! Each file starts with a header carrying the SHA-256 of the object, so a file damaged on the disk (or truncated by a
+//! crash) is detected rather than uploaded or used. Files without the header are of older clients, taken as they are.
+//!
+//! With `--encrypt-local`, the (compressed) object is sealed by the local key (see `local_crypt`); the checksum is of
+//! the sealed payload then, so it doesn't tell anything about the object.
+
+use std::io;
+use std::io::{Error, ErrorKind};
+
+use err_context::AnyError;
+use log::*;
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+
+use crate::local_crypt::LocalKey;
+
+const MAGIC: &[u8] = b"RBSPOOL1";
+const FLAG_ZSTD: u8 = 1;
+const FLAG_SEALED: u8 = 2;
+const DIGEST_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 1 + DIGEST_LEN;
+
+/// Levels zstd accepts
+const MIN_LEVEL: i32 = 1;
+const MAX_LEVEL: i32 = 22;
+
+static COMPRESSION: OnceCell<i32> = OnceCell::new();
+
+/// Compresses spooled objects written from now on by zstd `level`.
+pub fn set_compression(level: i32) -> Result<(), AnyError> {
+    if !(MIN_LEVEL..=MAX_LEVEL).contains(&level) {
+        return Err(AnyError::from(format!(
+            "Spool compression level must be {} to {}, not {}",
+            MIN_LEVEL, MAX_LEVEL, level
+        )));
+    }
+
+    debug!("Compressing spooled objects by zstd level {}", level);
+    COMPRESSION.set(level).map_err(|_| AnyError::from("Spool compression already set"))
+}
+
+/// Content of the file `data` are spooled in, encrypted when there's a `key`.
+pub(crate) fn encode(data: &[u8], key: Option<&LocalKey>) -> io::Result<Vec<u8>> {
+    let (mut flags, payload) = match COMPRESSION.get() {
+        Some(level) => (FLAG_ZSTD, zstd::encode_all(data, *level)?),
+        None => (0, data.to_vec()),
+    };
+
+    let (digest, payload) = match key {
+        Some(key) => {
+            flags |= FLAG_SEALED;
+            let sealed = key.seal(&payload);
+            (Sha256::digest(&sealed), sealed)
+        }
+        None => (Sha256::digest(data), payload),
+    };
+
+    let mut file = Vec::with_capacity(HEADER_LEN + payload.len());
+    file.extend_from_slice(MAGIC);
+    file.push(flags);
+    file.extend_from_slice(&digest);
+    file.extend_from_slice(&payload);
+
+    Ok(file)
+}
+
+/// Data spooled in a file with content `file`, failing when they don't match their checksum. Encrypted files need the
+/// `key` they were written with.
+pub(crate) fn decode(file: Vec<u8>, key: Option<&LocalKey>) -> io::Result<Vec<u8>> {
+    if !file.starts_with(MAGIC) {
+        return Ok(file);
+    }
+
+    if file.len() < HEADER_LEN {
+        return Err(corrupted());
+    }
+
+    let flags = file[MAGIC.len()];
+    let digest = &file[MAGIC.len() + 1..HEADER_LEN];
+    let payload = &file[HEADER_LEN..];
+
+    if flags & !(FLAG_ZSTD | FLAG_SEALED) != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Spooled object in unknown format {}, written by a newer client?", flags),
+        ));
+    }
+
+    let payload = if flags & FLAG_SEALED != 0 {
+        if Sha256::digest(payload).as_slice() != digest {
+            return Err(corrupted());
+        }
+
+        let key = key.ok_or_else(|| Error::new(ErrorKind::InvalidData, "Spooled object is encrypted, --encrypt-local required"))?;
+        key.open(payload)?
+    } else {
+        payload.to_vec()
+    };
+
+    let data = if flags & FLAG_ZSTD != 0 {
+        zstd::decode_all(payload.as_slice()).map_err(|_| corrupted())?
+    } else {
+        payload
+    };
+
+    if flags & FLAG_SEALED == 0 && Sha256::digest(&data).as_slice() != digest {
+        return Err(corrupted());
+    }
+
+    Ok(data)
+}
+
+fn corrupted() -> Error {
+    Error::new(ErrorKind::InvalidData, "Spooled object is corrupted")
+}
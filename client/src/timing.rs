@@ -0,0 +1,131 @@
+//! Where the time of requests to the server goes - the network, waiting on the server, or its storage.
+//!
+//! The server reports its side of every request in response headers (see `libcommon::timing`); the client sums them
+//! up with its own measurement, the difference being the network.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use libcommon::timing::{BodyBytes, ServerTiming, BODY_BYTES_HEADER, SERVER_TIMING_HEADER};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::progress;
+use crate::transport::{Request, Response, Transport};
+
+static REPORT: Lazy<Mutex<TimingReport>> = Lazy::new(|| Mutex::new(TimingReport::default()));
+
+/// Sums over all requests; concurrent requests overlap, so the times may exceed the wall time of the command.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TimingReport {
+    pub requests: u64,
+    /// Requests answered by servers not reporting their timing (older ones)
+    pub unreported: u64,
+    /// Until the response heads, as measured by the client
+    pub client_ms: f64,
+    /// Handling by the server, including receiving request bodies
+    pub server_ms: f64,
+    /// Waiting for a backend thread on the server
+    pub queue_ms: f64,
+    /// Storage of the server
+    pub backend_ms: f64,
+    /// Bytes of request bodies received by the server
+    pub sent_bytes: u64,
+    /// Bytes of response bodies, not counting streamed ones
+    pub received_bytes: u64,
+}
+
+impl TimingReport {
+    /// Time spent outside the server.
+    pub fn network_ms(&self) -> f64 {
+        (self.client_ms - self.server_ms).max(0.0)
+    }
+
+    fn add(&mut self, elapsed_ms: f64, resp: &Response) {
+        self.requests += 1;
+        self.client_ms += elapsed_ms;
+
+        let header = |name| resp.headers().get(name).and_then(|v| v.to_str().ok());
+
+        match header(SERVER_TIMING_HEADER) {
+            Some(value) => {
+                let timing = ServerTiming::parse(value);
+                self.server_ms += timing.total_ms;
+                self.queue_ms += timing.queue_ms;
+                self.backend_ms += timing.backend_ms;
+            }
+            None => {
+                self.unreported += 1;
+                // nothing known, all of it counts as the server's
+                self.server_ms += elapsed_ms;
+            }
+        }
+
+        if let Some(value) = header(BODY_BYTES_HEADER) {
+            let bytes = BodyBytes::parse(value);
+            self.sent_bytes += bytes.received;
+            self.received_bytes += bytes.sent.unwrap_or(0);
+        }
+    }
+}
+
+/// Timing of all requests sent so far.
+pub fn report() -> TimingReport {
+    REPORT.lock().unwrap().clone()
+}
+
+pub fn print_report(report: &TimingReport) {
+    let share = |ms: f64| {
+        if report.client_ms > 0.0 {
+            ms / report.client_ms * 100.0
+        } else {
+            0.0
+        }
+    };
+    let handler_ms = (report.server_ms - report.queue_ms - report.backend_ms).max(0.0);
+
+    eprintln!("Requests: {} ({}ms in total)", report.requests, report.client_ms.round());
+    eprintln!(
+        "  network:        {:>10}ms {:>5.1}%",
+        report.network_ms().round(),
+        share(report.network_ms())
+    );
+    eprintln!(
+        "  server queue:   {:>10}ms {:>5.1}%",
+        report.queue_ms.round(),
+        share(report.queue_ms)
+    );
+    eprintln!(
+        "  server storage: {:>10}ms {:>5.1}%",
+        report.backend_ms.round(),
+        share(report.backend_ms)
+    );
+    eprintln!("  server other:   {:>10}ms {:>5.1}%", handler_ms.round(), share(handler_ms));
+    eprintln!("Sent {}B, received {}B", report.sent_bytes, report.received_bytes);
+
+    if report.unreported > 0 {
+        eprintln!("{} responses came without timing, counted as server time", report.unreported);
+    }
+}
+
+/// `transport` recording the timing of its requests.
+pub fn wrap(transport: Arc<dyn Transport>) -> Arc<dyn Transport> {
+    Arc::new(TimedTransport { inner: transport })
+}
+
+pub struct TimedTransport {
+    inner: Arc<dyn Transport>,
+}
+
+impl Transport for TimedTransport {
+    fn send(&self, request: Request) -> io::Result<Response> {
+        let _in_flight = progress::request();
+        let start = Instant::now();
+        let resp = self.inner.send(request)?;
+
+        REPORT.lock().unwrap().add(start.elapsed().as_secs_f64() * 1000.0, &resp);
+
+        Ok(resp)
+    }
+}
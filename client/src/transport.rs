@@ -0,0 +1,387 @@
+//! Transport of the remote backend requests, so the protocol logic (hashing, signing, batching, lock waiting) doesn't
+//! depend on the HTTP client and can run against a mock.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac, NewMac};
+use libcommon::request_signature::{self, REQUEST_NONCE_HEADER, REQUEST_SIGNATURE_HEADER, REQUEST_TIMESTAMP_HEADER};
+
+use reqwest::blocking::{Body, Client};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::Sha256;
+use url::Url;
+use uuid::Uuid;
+
+pub enum RequestBody {
+    Empty,
+    Bytes(Vec<u8>),
+    /// Body of unknown length, sent as it's read
+    Stream(Box<dyn Read + Send>),
+}
+
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: RequestBody,
+}
+
+pub struct Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Box<dyn Read + Send>,
+}
+
+impl Response {
+    pub fn new(status: StatusCode, headers: HeaderMap, body: impl Read + Send + 'static) -> Response {
+        Response {
+            status,
+            headers,
+            body: Box::new(body),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn bytes(mut self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.body.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn text(self) -> io::Result<String> {
+        Ok(String::from_utf8_lossy(&self.bytes()?).into_owned())
+    }
+
+    pub fn json<T: DeserializeOwned>(self) -> io::Result<T> {
+        serde_json::from_reader(self.body).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// Reads the body as it's received.
+impl Read for Response {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+impl fmt::Debug for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .finish()
+    }
+}
+
+/// Delivers requests to the server. Failures to deliver are `BrokenPipe` errors; any response, whatever its status, is
+/// a success.
+pub trait Transport: Send + Sync {
+    fn send(&self, request: Request) -> io::Result<Response>;
+}
+
+pub struct RequestBuilder {
+    transport: Arc<dyn Transport>,
+    request: Request,
+    /// Invalid header or body, reported once sent
+    error: Option<Error>,
+    /// Key the request is signed by once sent, see `libcommon::request_signature`
+    signing_key: Option<Vec<u8>>,
+}
+
+impl RequestBuilder {
+    pub fn new(transport: Arc<dyn Transport>, method: Method, url: Url) -> RequestBuilder {
+        RequestBuilder {
+            transport,
+            request: Request {
+                method,
+                url,
+                headers: HeaderMap::new(),
+                body: RequestBody::Empty,
+            },
+            error: None,
+            signing_key: None,
+        }
+    }
+
+    /// `name` must be lowercase.
+    pub fn header(self, name: &'static str, value: impl AsRef<str>) -> RequestBuilder {
+        self.header_name(HeaderName::from_static(name), value.as_ref())
+    }
+
+    pub fn bearer_auth(self, token: &str) -> RequestBuilder {
+        self.header_name(AUTHORIZATION, &format!("Bearer {}", token))
+    }
+
+    fn header_name(mut self, name: HeaderName, value: &str) -> RequestBuilder {
+        match HeaderValue::try_from(value) {
+            Ok(value) => {
+                self.request.headers.insert(name, value);
+            }
+            Err(e) => self.error = Some(Error::new(ErrorKind::InvalidInput, e)),
+        }
+
+        self
+    }
+
+    pub fn json<T: Serialize + ?Sized>(mut self, body: &T) -> RequestBuilder {
+        match serde_json::to_vec(body) {
+            Ok(body) => {
+                self.request.body = RequestBody::Bytes(body);
+                self.request
+                    .headers
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            }
+            Err(e) => self.error = Some(Error::new(ErrorKind::InvalidInput, e)),
+        }
+
+        self
+    }
+
+    pub fn body(mut self, body: impl Read + Send + 'static) -> RequestBuilder {
+        self.request.body = RequestBody::Stream(Box::new(body));
+        self
+    }
+
+    /// Signs the request (with its final query and body) by `key` when sent; streamed bodies can't be signed.
+    pub fn sign(mut self, key: &[u8]) -> RequestBuilder {
+        self.signing_key = Some(key.to_vec());
+        self
+    }
+
+    fn add_signature(&mut self, key: &[u8]) -> io::Result<()> {
+        let body: &[u8] = match &self.request.body {
+            RequestBody::Empty => &[],
+            RequestBody::Bytes(data) => data,
+            RequestBody::Stream(_) => return Err(Error::new(ErrorKind::InvalidInput, "Streamed requests can't be signed")),
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?
+            .as_secs();
+        let nonce = Uuid::new_v4().to_string();
+
+        let message = request_signature::message(
+            self.request.method.as_str(),
+            self.request.url.path(),
+            self.request.url.query().unwrap_or_default(),
+            timestamp,
+            &nonce,
+            body,
+        );
+
+        let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+        mac.update(&message);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let headers = &mut self.request.headers;
+        headers.insert(
+            REQUEST_SIGNATURE_HEADER,
+            HeaderValue::try_from(signature).expect("Hex is a valid header"),
+        );
+        headers.insert(REQUEST_TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        headers.insert(REQUEST_NONCE_HEADER, HeaderValue::try_from(nonce).expect("UUID is a valid header"));
+
+        Ok(())
+    }
+
+    pub fn send(mut self) -> io::Result<Response> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        if let Some(key) = self.signing_key.take() {
+            self.add_signature(&key)?;
+        }
+
+        self.transport.send(self.request)
+    }
+}
+
+/// The default transport, using blocking reqwest client.
+pub struct BlockingTransport {
+    client: Client,
+}
+
+impl BlockingTransport {
+    pub fn new(client: Client) -> BlockingTransport {
+        BlockingTransport { client }
+    }
+}
+
+impl Transport for BlockingTransport {
+    fn send(&self, request: Request) -> io::Result<Response> {
+        let mut req = self.client.request(request.method, request.url).headers(request.headers);
+
+        req = match request.body {
+            RequestBody::Empty => req,
+            RequestBody::Bytes(data) => req.body(data),
+            RequestBody::Stream(reader) => req.body(Body::new(reader)),
+        };
+
+        let resp = req.send().map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?;
+
+        Ok(Response::new(resp.status(), resp.headers().clone(), resp))
+    }
+}
+
+/// Received body parts buffered ahead of the reader.
+const BODY_BUFFERS: usize = 16;
+
+/// Transport using async reqwest client, driven by its own runtime; requests of many threads share its connections
+/// without a runtime per client as the blocking one has.
+pub struct AsyncTransport {
+    client: reqwest::Client,
+    runtime: tokio02::runtime::Runtime,
+}
+
+impl AsyncTransport {
+    pub fn new(client: reqwest::Client) -> io::Result<AsyncTransport> {
+        let runtime = tokio02::runtime::Builder::new()
+            .threaded_scheduler()
+            .enable_all()
+            .thread_name("http")
+            .build()?;
+
+        Ok(AsyncTransport { client, runtime })
+    }
+}
+
+impl Transport for AsyncTransport {
+    fn send(&self, request: Request) -> io::Result<Response> {
+        // written objects are limited in size by the server, buffering them is fine
+        let body = match request.body {
+            RequestBody::Empty => None,
+            RequestBody::Bytes(data) => Some(data),
+            RequestBody::Stream(mut reader) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                Some(data)
+            }
+        };
+
+        let mut req = self.client.request(request.method, request.url).headers(request.headers);
+
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+
+        let (tx, rx) = tokio02::sync::mpsc::channel(BODY_BUFFERS);
+        let handle = self.runtime.handle().clone();
+
+        let sent = self.runtime.handle().spawn(async move {
+            let mut resp = req.send().await?;
+            let status = resp.status();
+            let headers = resp.headers().clone();
+
+            // the body is passed on as it comes (listings, GC events are streamed)
+            handle.spawn(async move {
+                let mut tx = tx;
+
+                loop {
+                    let part = match resp.chunk().await {
+                        Ok(Some(chunk)) => Ok(chunk.to_vec()),
+                        Ok(None) => break,
+                        Err(e) => Err(Error::new(ErrorKind::BrokenPipe, e)),
+                    };
+
+                    let failed = part.is_err();
+                    // the reader is gone
+                    if tx.send(part).await.is_err() || failed {
+                        break;
+                    }
+                }
+            });
+
+            Ok::<_, reqwest::Error>((status, headers))
+        });
+
+        let (status, headers) = futures::executor::block_on(sent)
+            .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?
+            .map_err(|e| Error::new(ErrorKind::BrokenPipe, e))?;
+
+        Ok(Response::new(
+            status,
+            headers,
+            BodyReader {
+                rx,
+                current: Vec::new(),
+                position: 0,
+            },
+        ))
+    }
+}
+
+struct BodyReader {
+    rx: tokio02::sync::mpsc::Receiver<io::Result<Vec<u8>>>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.current.len() {
+            match futures::executor::block_on(self.rx.recv()) {
+                Some(part) => {
+                    self.current = part?;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.current.len() - self.position);
+        buf[..len].copy_from_slice(&self.current[self.position..self.position + len]);
+        self.position += len;
+
+        Ok(len)
+    }
+}
+
+type MockHandler = dyn Fn(Request) -> io::Result<Response> + Send + Sync;
+
+/// Answers requests by given handler instead of sending them anywhere, recording them for later inspection.
+pub struct MockTransport {
+    handler: Box<MockHandler>,
+    requests: Mutex<Vec<(Method, Url)>>,
+}
+
+impl MockTransport {
+    pub fn new<F>(handler: F) -> MockTransport
+    where
+        F: Fn(Request) -> io::Result<Response> + Send + Sync + 'static,
+    {
+        MockTransport {
+            handler: Box::new(handler),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Requests received so far.
+    pub fn requests(&self) -> Vec<(Method, Url)> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, request: Request) -> io::Result<Response> {
+        self.requests.lock().unwrap().push((request.method.clone(), request.url.clone()));
+
+        (self.handler)(request)
+    }
+}
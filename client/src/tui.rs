@@ -0,0 +1,188 @@
+//! Live dashboard of a running store or restore (`--tui`), an alternative to following logs of long runs.
+//!
+//! Drawn by its own thread from `progress`, in the alternate screen of the terminal; `q` or Ctrl-C aborts the run.
+
+use std::io;
+use std::io::Stdout;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossterm::cursor::Show;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use log::*;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::progress::{self, Progress};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Exit code of runs aborted from the dashboard, same as of those interrupted by SIGINT
+const ABORTED_EXIT_CODE: i32 = 130;
+
+/// Running dashboard; dropping it gives the terminal back.
+pub struct Dashboard {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub fn start() -> io::Result<Dashboard> {
+    enable_raw_mode()?;
+
+    let mut stdout = io::stdout();
+    if let Err(e) = execute!(stdout, EnterAlternateScreen) {
+        restore_terminal();
+        return Err(e);
+    }
+
+    let terminal = match Terminal::new(CrosstermBackend::new(stdout)) {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            restore_terminal();
+            return Err(e);
+        }
+    };
+
+    let (stop, stopped) = mpsc::channel();
+
+    let thread = thread::Builder::new()
+        .name("tui".to_string())
+        .spawn(move || run(terminal, stopped))
+        .expect("Could not start dashboard thread");
+
+    Ok(Dashboard {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                restore_terminal();
+            }
+        }
+    }
+}
+
+fn run(mut terminal: Terminal<CrosstermBackend<Stdout>>, stopped: Receiver<()>) {
+    loop {
+        let progress = progress::current();
+
+        if let Err(e) = terminal.draw(|frame| draw(frame, &progress)) {
+            warn!("Could not draw the dashboard: {}", e);
+            break;
+        }
+
+        match stopped.try_recv() {
+            Err(TryRecvError::Empty) => (),
+            _ => break,
+        }
+
+        // waits for keys up to the refresh interval
+        match event::poll(REFRESH_INTERVAL) {
+            Ok(true) => {
+                if let Ok(Event::Key(key)) = event::read() {
+                    if is_abort(&key) {
+                        restore_terminal();
+                        eprintln!("Aborted");
+                        std::process::exit(ABORTED_EXIT_CODE);
+                    }
+                }
+            }
+            Ok(false) => (),
+            Err(_) => thread::sleep(REFRESH_INTERVAL),
+        }
+    }
+
+    restore_terminal();
+}
+
+/// Ctrl-C doesn't raise SIGINT in raw mode.
+fn is_abort(key: &KeyEvent) -> bool {
+    match key.code {
+        KeyCode::Char('q') => true,
+        KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+        _ => false,
+    }
+}
+
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+}
+
+fn draw(frame: &mut Frame, progress: &Progress) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Length(9), Constraint::Min(0)])
+        .split(frame.size());
+
+    let title = format!(" {} {} ", progress.operation.unwrap_or("starting"), progress.name);
+    let label = match progress.total {
+        Some(total) => format!("{} / {}", format_bytes(progress.bytes), format_bytes(total)),
+        None => format_bytes(progress.bytes),
+    };
+    let gauge = Gauge::default()
+        .block(Block::default().borders(Borders::ALL).title(title))
+        .ratio(progress.ratio().unwrap_or(0.0))
+        .label(label);
+    frame.render_widget(gauge, rows[0]);
+
+    let current_file = progress
+        .current_file
+        .as_ref()
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let eta = progress
+        .eta()
+        .map(|eta| humantime::format_duration(Duration::from_secs(eta.as_secs())).to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let dedup = progress
+        .dedup_ratio()
+        .map(|ratio| format!("{:.2}x", ratio))
+        .unwrap_or_else(|| "-".to_string());
+
+    let lines = vec![
+        Line::from(format!("File:        {}", current_file)),
+        Line::from(format!("Throughput:  {}/s", format_bytes(progress.throughput() as u64))),
+        Line::from(format!(
+            "Elapsed:     {}",
+            humantime::format_duration(Duration::from_secs(progress.elapsed.as_secs()))
+        )),
+        Line::from(format!("ETA:         {}", eta)),
+        Line::from(format!("In flight:   {} requests", progress.in_flight)),
+        Line::from(format!("Retries:     {}", progress.retries)),
+        Line::from(format!("Dedup ratio: {} ({} sent)", dedup, format_bytes(progress.sent_bytes))),
+    ];
+    frame.render_widget(Paragraph::new(lines).block(Block::default().borders(Borders::ALL)), rows[1]);
+
+    frame.render_widget(Paragraph::new("q: abort"), rows[2]);
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
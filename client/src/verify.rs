@@ -0,0 +1,176 @@
+use std::io;
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use err_context::AnyError;
+use log::*;
+use rdedup_lib::{DecryptHandle, Repo as RdedupRepo};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::errors::{self, FailureClass};
+use crate::reports::{NameVerifyReport, VerifyReport};
+
+/// Reads of chunks left out of the sample fail with this; rdedup reports them like any other corrupted chunk.
+const SAMPLED_OUT: &str = "Chunk not in the verified sample";
+
+/// Sampled verification - only a part of the chunks is checked (indexes and names always are), chosen by the seed, so
+/// a failing run can be reproduced.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Sample {
+    pub percent: f64,
+    pub seed: u64,
+}
+
+impl Sample {
+    /// Seed taken from the clock unless given; the choice of chunks is random by the hashing anyway.
+    pub fn new(percent: f64, seed: Option<u64>) -> Sample {
+        let seed = seed.unwrap_or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+            now.as_nanos() as u64
+        });
+
+        Sample { percent, seed }
+    }
+
+    /// Chunk at `path` is verified; the same chunks are chosen for the same seed, whatever name they belong to.
+    pub fn includes(&self, path: &Path) -> bool {
+        let mut hasher = Sha256::new();
+        hasher.update(self.seed.to_le_bytes());
+        hasher.update(path.to_string_lossy().as_bytes());
+
+        let mut head = [0; 8];
+        head.copy_from_slice(&hasher.finalize()[..8]);
+
+        (u64::from_le_bytes(head) as f64) < (u64::MAX as f64) * self.percent / 100.0
+    }
+
+    /// Parses percentage like `5%` (or just `5`).
+    pub fn parse_percent(s: &str) -> Result<f64, AnyError> {
+        let percent: f64 = s
+            .trim()
+            .trim_end_matches('%')
+            .parse()
+            .map_err(|_| AnyError::from(format!("Invalid percentage {:?}", s)))?;
+
+        if percent > 0.0 && percent <= 100.0 {
+            Ok(percent)
+        } else {
+            Err(AnyError::from(format!("Percentage {:?} out of range (0-100]", s)))
+        }
+    }
+}
+
+pub(crate) fn sampled_out() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, SAMPLED_OUT)
+}
+
+/// Verifies all `names` using `jobs` worker threads. Results are sorted by name.
+pub fn verify_names(repo: &RdedupRepo, dec: &DecryptHandle, names: Vec<String>, jobs: usize) -> VerifyReport {
+    let queue = Arc::new(Mutex::new(names));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..jobs.max(1))
+        .map(|_| {
+            let repo = repo.clone();
+            let dec = dec.clone();
+            let queue = Arc::clone(&queue);
+            let tx = tx.clone();
+
+            thread::spawn(move || loop {
+                let name = match queue.lock().unwrap().pop() {
+                    Some(name) => name,
+                    None => break,
+                };
+
+                debug!("Verifying name {}", name);
+
+                let report = match repo.verify(&name, &dec) {
+                    Ok(r) => {
+                        let (skipped, corrupted): (Vec<_>, Vec<_>) = r
+                            .errors
+                            .iter()
+                            .map(|(d, e)| (hex::encode(d), e.to_string()))
+                            .partition(|(_, e)| e.contains(SAMPLED_OUT));
+
+                        NameVerifyReport {
+                            name,
+                            scanned_bytes: r.scanned_bytes,
+                            failure_class: if corrupted.is_empty() {
+                                None
+                            } else {
+                                Some(FailureClass::Corruption)
+                            },
+                            corrupted,
+                            skipped_chunks: skipped.len() as u64,
+                            failure: None,
+                        }
+                    }
+                    Err(e) => NameVerifyReport {
+                        name,
+                        scanned_bytes: 0,
+                        corrupted: Vec::new(),
+                        skipped_chunks: 0,
+                        failure: Some(e.to_string()),
+                        failure_class: Some(errors::classify(&e)),
+                    },
+                };
+
+                tx.send(report).expect("Could not send verify result");
+            })
+        })
+        .collect();
+
+    drop(tx);
+
+    let mut names: Vec<NameVerifyReport> = rx.iter().collect();
+
+    for worker in workers {
+        worker.join().expect("Verify worker panicked");
+    }
+
+    names.sort_by(|a, b| a.name.cmp(&b.name));
+
+    VerifyReport {
+        scanned_bytes: names.iter().map(|n| n.scanned_bytes).sum(),
+        failed: names.iter().filter(|n| !n.is_ok()).count(),
+        names,
+        served_by: None,
+        sample: None,
+    }
+}
+
+pub fn print_report(report: &VerifyReport) {
+    for v in &report.names {
+        match &v.failure {
+            Some(e) => println!("{}: verification failed: {}", v.name, e),
+            None if v.corrupted.is_empty() => println!("{}: OK ({}B scanned)", v.name, v.scanned_bytes),
+            None => {
+                println!("{}: {} corrupted chunks ({}B scanned)", v.name, v.corrupted.len(), v.scanned_bytes);
+                for (digest, e) in &v.corrupted {
+                    println!("  {}: {}", digest, e);
+                }
+            }
+        }
+    }
+
+    println!(
+        "Verified {} names, {} failed, {}B scanned in total",
+        report.names.len(),
+        report.failed,
+        report.scanned_bytes
+    );
+
+    if let Some(sample) = &report.sample {
+        println!(
+            "Checked {}% sample of chunks ({} skipped); reproduce by --sample {}% --seed {}",
+            sample.percent,
+            report.names.iter().map(|n| n.skipped_chunks).sum::<u64>(),
+            sample.percent,
+            sample.seed
+        );
+    }
+}
@@ -0,0 +1,101 @@
+#!/usr/bin/env bash
+# Generation suite: reads racing rdedup GC, which moves the objects still in use to a new generation directory, must
+# find them in the generation they were moved to instead of failing. The race is simulated by moving the objects of a
+# repository aside the same way between storing and reading them.
+#
+# Requires `rdedup` in PATH (to create the repository) and built server and client (`cargo build` in both
+# directories). The server listens on its default port, nothing else may be using it.
+
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+SERVER_BIN="${SERVER_BIN:-$ROOT/server/target/debug/rbackup2-server}"
+CLIENT_BIN="${CLIENT_BIN:-$ROOT/client/target/debug/rbackup2-client}"
+SERVER_URL="http://localhost:8090"
+ADMIN_TOKEN="generations-admin"
+
+export RDEDUP_PASSPHRASE="generations-test"
+export RBACKUP_PASSPHRASE="$RDEDUP_PASSPHRASE"
+
+WORK="$(mktemp -d)"
+REPO="$WORK/repo"
+SERVER_PID=""
+
+cleanup() {
+    if [ -n "$SERVER_PID" ]; then
+        kill "$SERVER_PID" 2>/dev/null || true
+        wait "$SERVER_PID" 2>/dev/null || true
+    fi
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+fail() {
+    echo "FAIL: $*" >&2
+    exit 1
+}
+
+step() {
+    echo "== $*"
+}
+
+client() {
+    "$CLIENT_BIN" --server "$SERVER_URL" --token "$ADMIN_TOKEN" --state-dir "$WORK/state" "$@"
+}
+
+# prints HTTP status of reading repository path $1, the body goes to file $2
+read_object() {
+    curl -s -o "$2" -w '%{http_code}' -H "Authorization: Bearer $ADMIN_TOKEN" \
+        --get --data-urlencode "path=$1" "$SERVER_URL/read"
+}
+
+rdedup --dir "$REPO" init
+
+step "Starting server"
+cat > "$WORK/server.toml" <<TOML
+data_dir = "$REPO"
+admin_tokens = ["$ADMIN_TOKEN"]
+TOML
+RBACKUP_CONFIG="$WORK/server.toml" "$SERVER_BIN" > "$WORK/server.log" 2>&1 &
+SERVER_PID=$!
+
+for _ in $(seq 1 50); do
+    curl -sf "$SERVER_URL/version" > /dev/null && break
+    sleep 0.1
+done
+curl -sf "$SERVER_URL/version" > /dev/null || fail "server didn't start, see log: $(cat "$WORK/server.log")"
+
+step "Storing data"
+head -c 5000000 /dev/urandom > "$WORK/data.bin"
+client store "$WORK/data.bin" data
+
+chunk="$(cd "$REPO" && find . -path '*/chunk/*' -type f | sed 's|^\./||' | head -n 1)"
+index="$(cd "$REPO" && find . -path '*/index/*' -type f | sed 's|^\./||' | head -n 1)"
+[ -n "$chunk" ] && [ -n "$index" ] || fail "no chunks or indexes stored"
+
+generation="${chunk%%/*}"
+[ "$generation" != "chunk" ] || fail "repository has no generation directories"
+# sorts after the old one, as newer generations do
+moved="$generation-next"
+
+cp "$REPO/$chunk" "$WORK/chunk.expected"
+cp "$REPO/$index" "$WORK/index.expected"
+
+step "Moving objects to a new generation, as GC does"
+mkdir -p "$REPO/$moved"
+mv "$REPO/$generation/chunk" "$REPO/$generation/index" "$REPO/$moved/"
+
+step "Reading by the paths in the old generation"
+for object in chunk index; do
+    path="$(eval echo "\$$object")"
+    status="$(read_object "$path" "$WORK/$object.read")"
+    [ "$status" = 200 ] || fail "$object $path moved to another generation not found (HTTP $status)"
+    cmp "$WORK/$object.expected" "$WORK/$object.read" || fail "$object read from another generation differs"
+done
+
+step "Missing objects are still missing"
+missing="$generation/chunk/00/00/$(printf '0%.0s' $(seq 1 64))"
+status="$(read_object "$missing" "$WORK/missing.read")"
+[ "$status" = 404 ] || fail "missing chunk read with HTTP $status"
+
+echo "All generation checks passed"
@@ -0,0 +1,96 @@
+#!/usr/bin/env bash
+# Compatibility suite: a repository created by plain rdedup CLI must be served by the server unmodified, and data
+# written through the client must be readable by plain rdedup.
+#
+# Requires `rdedup` in PATH and built server and client (`cargo build` in both directories). The server listens on its
+# default port, nothing else may be using it.
+
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+SERVER_BIN="${SERVER_BIN:-$ROOT/server/target/debug/rbackup2-server}"
+CLIENT_BIN="${CLIENT_BIN:-$ROOT/client/target/debug/rbackup2-client}"
+SERVER_URL="http://localhost:8090"
+TOKEN="compat-client"
+
+export RDEDUP_PASSPHRASE="compat-test"
+export RBACKUP_PASSPHRASE="$RDEDUP_PASSPHRASE"
+
+WORK="$(mktemp -d)"
+REPO="$WORK/repo"
+SERVER_PID=""
+
+cleanup() {
+    if [ -n "$SERVER_PID" ]; then
+        kill "$SERVER_PID" 2>/dev/null || true
+        wait "$SERVER_PID" 2>/dev/null || true
+    fi
+    rm -rf "$WORK"
+}
+trap cleanup EXIT
+
+fail() {
+    echo "FAIL: $*" >&2
+    exit 1
+}
+
+step() {
+    echo "== $*"
+}
+
+client() {
+    "$CLIENT_BIN" --server "$SERVER_URL" --token "$TOKEN" --state-dir "$WORK/state" "$@"
+}
+
+step "Creating repository with plain rdedup"
+rdedup --dir "$REPO" init
+head -c 5000000 /dev/urandom > "$WORK/plain.bin"
+rdedup --dir "$REPO" store plain < "$WORK/plain.bin"
+
+# snapshot of the repository content, to prove the server doesn't touch it until something is written (the lock
+# file is created by whoever locks the repository first)
+(cd "$REPO" && find . -type f ! -name lock | sort | xargs sha256sum) > "$WORK/before.sums"
+
+step "Starting server"
+cat > "$WORK/server.toml" <<TOML
+data_dir = "$REPO"
+
+[[tokens]]
+token = "$TOKEN"
+role = "read-write"
+TOML
+RBACKUP_CONFIG="$WORK/server.toml" "$SERVER_BIN" > "$WORK/server.log" 2>&1 &
+SERVER_PID=$!
+
+for _ in $(seq 1 50); do
+    curl -sf "$SERVER_URL/version" > /dev/null && break
+    sleep 0.1
+done
+curl -sf "$SERVER_URL/version" > /dev/null || fail "server didn't start, see log: $(cat "$WORK/server.log")"
+
+step "Reading plain rdedup data through the client"
+client names | grep -q plain || fail "name stored by rdedup not listed"
+client restore plain "$WORK/plain.restored"
+cmp "$WORK/plain.bin" "$WORK/plain.restored" || fail "data stored by rdedup restored differently"
+client --json verify plain | grep -q '"failed": 0' || fail "verification of data stored by rdedup failed"
+
+(cd "$REPO" && find . -type f ! -name lock | sort | xargs sha256sum) > "$WORK/after.sums"
+diff "$WORK/before.sums" "$WORK/after.sums" || fail "reading modified the repository"
+
+step "Writing through the client"
+head -c 5000000 /dev/urandom > "$WORK/client.bin"
+cat "$WORK/plain.bin" >> "$WORK/client.bin"
+client store "$WORK/client.bin" client
+
+step "Stopping server"
+kill "$SERVER_PID"
+wait "$SERVER_PID" 2>/dev/null || true
+SERVER_PID=""
+
+step "Reading client data with plain rdedup"
+rdedup --dir "$REPO" load client > "$WORK/client.restored"
+cmp "$WORK/client.bin" "$WORK/client.restored" || fail "data stored by the client loaded differently"
+rdedup --dir "$REPO" verify client || fail "rdedup verification of data stored by the client failed"
+rdedup --dir "$REPO" verify plain || fail "rdedup verification of its own data failed"
+
+echo "All compatibility checks passed"
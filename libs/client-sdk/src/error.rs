@@ -0,0 +1,88 @@
+use std::fmt;
+
+use libcommon::structs::{LockHolder, HASH_MISMATCH_HEADER, MAINTENANCE_HEADER};
+use reqwest::{Response, StatusCode};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request couldn't be sent or its response received
+    Http(reqwest::Error),
+    /// Server is in maintenance (or read-only) and refuses the request; the message is meant for users
+    Maintenance(String),
+    /// Repository is locked exclusively (e.g. by GC)
+    Locked(LockHolder),
+    /// Missing, invalid or insufficient token
+    Forbidden(String),
+    NotFound,
+    /// Written data got corrupted on the way to the server, see [`HASH_MISMATCH_HEADER`]
+    HashMismatch,
+    /// Server refused the request for another reason, or failed
+    Status {
+        status: StatusCode,
+        message: String,
+    },
+    /// Response doesn't follow the protocol
+    InvalidResponse(String),
+}
+
+impl Error {
+    /// Error described by a response with unexpected status.
+    pub(crate) async fn from_response(resp: Response) -> Error {
+        let status = resp.status();
+        let maintenance = resp.headers().contains_key(MAINTENANCE_HEADER);
+        let hash_mismatch = resp.headers().contains_key(HASH_MISMATCH_HEADER);
+
+        if status == StatusCode::LOCKED {
+            return match resp.json::<LockHolder>().await {
+                Ok(holder) => Error::Locked(holder),
+                Err(e) => Error::InvalidResponse(format!("Invalid lock holder: {}", e)),
+            };
+        }
+
+        let message = resp.text().await.unwrap_or_default();
+
+        match status {
+            _ if maintenance => Error::Maintenance(message),
+            StatusCode::CONFLICT if hash_mismatch => Error::HashMismatch,
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Error::Forbidden(message),
+            StatusCode::NOT_FOUND => Error::NotFound,
+            status => Error::Status { status, message },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "Request failed: {}", e),
+            Error::Maintenance(message) => f.write_str(message),
+            Error::Locked(holder) => {
+                write!(f, "Repository is locked exclusively by {}", holder.holder)
+            }
+            Error::Forbidden(message) => write!(f, "Forbidden: {}", message),
+            Error::NotFound => f.write_str("Not found"),
+            Error::HashMismatch => f.write_str("Data got corrupted on the way to the server"),
+            Error::Status { status, message } => {
+                write!(f, "Server responded with {}: {}", status, message)
+            }
+            Error::InvalidResponse(message) => write!(f, "Invalid response: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Http(e)
+    }
+}
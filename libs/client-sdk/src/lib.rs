@@ -0,0 +1,533 @@
+//! Client of the rbackup2 server protocol, for tools talking to the server directly - monitoring dashboards, sync
+//! scripts and similar - without rdedup.
+//!
+//! [`Client`] has an async function for every endpoint of the server. Objects are addressed by their paths in the
+//! server storage (e.g. `name/docs`); their content is whatever rdedup stored there, this crate doesn't interpret it.
+//! Request and response types are shared with the server, see [`types`].
+//!
+//! ```no_run
+//! use rbackup2_client_sdk::Client;
+//!
+//! async fn show_names() -> rbackup2_client_sdk::Result<()> {
+//!     let client = Client::new("http://localhost:8090".parse().unwrap(), Some("token".to_string()), None)?;
+//!
+//!     for name in client.names().await? {
+//!         println!("{}: {}B", name.name, name.size);
+//!     }
+//!
+//!     Ok(())
+//! }
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::header::HeaderValue;
+use reqwest::{Method, Request, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+use uuid::Uuid;
+
+use libcommon::build_info::BuildInfo;
+use libcommon::paths;
+use libcommon::request_signature::{self, REQUEST_NONCE_HEADER, REQUEST_SIGNATURE_HEADER, REQUEST_TIMESTAMP_HEADER};
+use libcommon::structs::{
+    CapabilitiesResponse, CapacityReport, CatalogEntry, CleanupReport, ExclusiveLockResponse, GcStatus, ListResponse, LocksResponse,
+    LogEvent, MaintenanceRequest, NameInfo, NamesResponse, OperationsResponse, Priority, RenameBatchRequest, RenameBatchResponse,
+    RenameEntry, SharedLockResponse, StatsResponse, ThrottleLimits, WriteBatchEntry, WriteBatchRequest, WriteBatchResponse,
+    EXCLUSIVE_LOCK_HEADER, GENERATION_HEADER, PRIORITY_HEADER, SESSION_HEADER, SIGNATURE_HEADER,
+};
+
+pub use crate::error::{Error, Result};
+pub use crate::stream::Event;
+
+mod error;
+mod stream;
+
+/// Writes corrupted on the way to the server (see [`Error::HashMismatch`]) are sent again this many times
+pub const HASH_MISMATCH_RETRIES: usize = 3;
+
+/// Request and response types of the protocol.
+pub mod types {
+    pub use libcommon::build_info::BuildInfo;
+    pub use libcommon::structs::*;
+}
+
+/// Metadata of a stored object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectMetadata {
+    pub len: u64,
+    pub is_file: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WriteOptions {
+    /// Stage the object aside; it becomes visible once committed by [`Client::commit_name`]
+    pub pending: bool,
+}
+
+/// Filter of [`Client::log_stream`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LogStreamQuery {
+    /// Least severe level streamed (`error`, `warn`, `info`, `debug`, `trace`); everything kept by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    /// Only events of this server module (and its submodules), e.g. `rbackup2_server::gc`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    /// Start with the recent events kept by the server, not just the new ones
+    pub recent: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    server: Url,
+    token: Option<String>,
+    /// Repository secret written objects are signed with, when the server requires it
+    signing_key: Option<String>,
+    /// Lets the server serve the client by the same backend thread
+    session: String,
+    /// Sent with each request, see [`Client::set_priority`]
+    priority: Priority,
+    /// See [`Client::set_exclusive_lock`]
+    exclusive_lock: Option<Uuid>,
+}
+
+impl Client {
+    pub fn new(server: Url, token: Option<String>, signing_key: Option<String>) -> Result<Client> {
+        Ok(Client {
+            http: reqwest::Client::builder().build()?,
+            server,
+            token,
+            signing_key,
+            session: Uuid::new_v4().to_string(),
+            priority: Priority::Normal,
+            exclusive_lock: None,
+        })
+    }
+
+    /// Makes the server serve requests of the client before or after those of others while it's contended, e.g.
+    /// [`Priority::Low`] for scrubs.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Makes the client write as the holder of exclusive lock `lock_id` (see [`Client::lock_exclusive`]), writes of
+    /// others are refused meanwhile.
+    pub fn set_exclusive_lock(&mut self, lock_id: Option<Uuid>) {
+        self.exclusive_lock = lock_id;
+    }
+
+    fn request(&self, method: Method, endpoint: &str) -> RequestBuilder {
+        let mut url = self.server.clone();
+        url.set_path(endpoint);
+
+        let mut req = self
+            .http
+            .request(method, url)
+            .header(SESSION_HEADER, &self.session)
+            .header(PRIORITY_HEADER, self.priority.as_str());
+
+        if let Some(lock_id) = &self.exclusive_lock {
+            req = req.header(EXCLUSIVE_LOCK_HEADER, lock_id.to_string());
+        }
+
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+
+    /// Sends the request, turning responses of other than `expected` status into errors. Lock and admin requests are
+    /// signed when the client has the signing key, see [`request_signature`].
+    async fn send(&self, req: RequestBuilder, expected: StatusCode) -> Result<Response> {
+        let mut req = req.build()?;
+
+        if let Some(key) = &self.signing_key {
+            if request_signature::is_signed(req.url().path()) {
+                Client::sign(key, &mut req);
+            }
+        }
+
+        let resp = self.http.execute(req).await?;
+
+        if resp.status() != expected {
+            return Err(Error::from_response(resp).await);
+        }
+
+        Ok(resp)
+    }
+
+    async fn json<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<T> {
+        let resp = self.send(req, StatusCode::OK).await?;
+        resp.json().await.map_err(|e| Error::InvalidResponse(e.to_string()))
+    }
+
+    /// Adds the signature of `req`, see [`request_signature`].
+    fn sign(key: &str, req: &mut Request) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Clock before Unix epoch")
+            .as_secs();
+        let nonce = Uuid::new_v4().to_string();
+        let body = req.body().and_then(|b| b.as_bytes()).unwrap_or_default();
+
+        let message = request_signature::message(
+            req.method().as_str(),
+            req.url().path(),
+            req.url().query().unwrap_or_default(),
+            timestamp,
+            &nonce,
+            body,
+        );
+
+        let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(&message);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let headers = req.headers_mut();
+        headers.insert(
+            REQUEST_SIGNATURE_HEADER,
+            HeaderValue::from_str(&signature).expect("Hex is a valid header"),
+        );
+        headers.insert(REQUEST_TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+        headers.insert(REQUEST_NONCE_HEADER, HeaderValue::from_str(&nonce).expect("UUID is a valid header"));
+    }
+
+    fn path_query(path: &Path) -> [(&'static str, String); 1] {
+        [("path", path.to_string_lossy().to_string())]
+    }
+
+    /// Hex HMAC of the object, see [`SIGNATURE_HEADER`].
+    fn signature(&self, path: &Path, data: &[u8]) -> Option<String> {
+        let key = self.signing_key.as_ref()?;
+
+        let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(path.to_string_lossy().as_bytes());
+        mac.update(&[0]);
+        mac.update(data);
+
+        Some(hex::encode(mac.finalize().into_bytes()))
+    }
+
+    pub async fn capabilities(&self) -> Result<CapabilitiesResponse> {
+        self.json(self.request(Method::GET, "capabilities")).await
+    }
+
+    /// Exact build of the server.
+    pub async fn version(&self) -> Result<BuildInfo> {
+        self.json(self.request(Method::GET, "version")).await
+    }
+
+    /// Entries of the directory at `path`.
+    pub async fn list(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        let resp: ListResponse = self
+            .json(self.request(Method::GET, "list").query(&Client::path_query(path)))
+            .await?;
+        Ok(resp.paths)
+    }
+
+    /// Same as [`Client::list`], streaming the entries as they come, for large directories.
+    pub async fn list_stream(&self, path: &Path) -> Result<BoxStream<'static, Result<PathBuf>>> {
+        let req = self.request(Method::GET, "list-stream").query(&Client::path_query(path));
+        let resp = self.send(req, StatusCode::OK).await?;
+
+        Ok(stream::json_lines(resp.bytes_stream()))
+    }
+
+    /// Usage of the repository; walks all its objects, so it takes a while for large ones.
+    pub async fn stats(&self) -> Result<StatsResponse> {
+        self.json(self.request(Method::GET, "stats")).await
+    }
+
+    pub async fn read(&self, path: &Path) -> Result<Bytes> {
+        let mut req = self.request(Method::GET, "read").query(&Client::path_query(path));
+
+        if let Some(generation) = paths::generation(path) {
+            req = req.header(GENERATION_HEADER, generation);
+        }
+
+        Ok(self.send(req, StatusCode::OK).await?.bytes().await?)
+    }
+
+    pub async fn read_metadata(&self, path: &Path) -> Result<ObjectMetadata> {
+        self.json(self.request(Method::GET, "read-metadata").query(&Client::path_query(path)))
+            .await
+    }
+
+    /// Writes the object; signed when the client has the signing key. Data corrupted on the way are sent again, up to
+    /// [`HASH_MISMATCH_RETRIES`] times.
+    pub async fn write(&self, path: &Path, data: Vec<u8>, options: &WriteOptions) -> Result<()> {
+        let hash = hex::encode(Sha256::digest(&data));
+        let signature = self.signature(path, &data);
+        let mut retries = 0;
+
+        loop {
+            let mut req = self
+                .request(Method::POST, "write")
+                .header("path", path.to_string_lossy().as_ref())
+                .header("hash", hash.as_str());
+
+            if let Some(signature) = &signature {
+                req = req.header(SIGNATURE_HEADER, signature.as_str());
+            }
+
+            if let Some(generation) = paths::generation(path) {
+                req = req.header(GENERATION_HEADER, generation);
+            }
+
+            if options.pending {
+                req = req.header("pending", "true");
+            }
+
+            match self.send(req.body(data.clone()), StatusCode::OK).await {
+                Err(Error::HashMismatch) if retries < HASH_MISMATCH_RETRIES => retries += 1,
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+
+    /// Writes many (small) objects in one request; each of them succeeds or fails on its own.
+    pub async fn write_batch(&self, objects: Vec<(PathBuf, Vec<u8>)>) -> Result<WriteBatchResponse> {
+        let writes = objects
+            .iter()
+            .map(|(path, data)| WriteBatchEntry {
+                path: path.clone(),
+                hash: hex::encode(Sha256::digest(data)),
+                len: data.len(),
+                signature: self.signature(path, data),
+                generation: paths::generation(path).map(str::to_string),
+            })
+            .collect();
+
+        let mut body = serde_json::to_vec(&WriteBatchRequest { writes }).map_err(|e| Error::InvalidResponse(e.to_string()))?;
+        body.push(b'\n');
+        for (_, data) in &objects {
+            body.extend_from_slice(data);
+        }
+
+        self.json(self.request(Method::POST, "write-batch").body(body)).await
+    }
+
+    /// Makes a name written as pending visible, optionally protecting it from removal until `retain_until` (Unix
+    /// timestamp, seconds).
+    pub async fn commit_name(&self, path: &Path, retain_until: Option<u64>) -> Result<()> {
+        let mut req = self.request(Method::POST, "commit-name").query(&Client::path_query(path));
+        if let Some(until) = retain_until {
+            req = req.query(&[("retain_until", until)]);
+        }
+
+        self.send(req, StatusCode::OK).await?;
+        Ok(())
+    }
+
+    /// Records a finished store into the catalog the capacity report is made of.
+    pub async fn record_catalog(&self, entry: &CatalogEntry) -> Result<()> {
+        self.send(self.request(Method::POST, "catalog").json(entry), StatusCode::OK).await?;
+        Ok(())
+    }
+
+    /// Stored names with their sizes and retention.
+    pub async fn names(&self) -> Result<Vec<NameInfo>> {
+        let resp: NamesResponse = self.json(self.request(Method::GET, "names")).await?;
+        Ok(resp.names)
+    }
+
+    /// Same as [`Client::names`], including names staged and not committed (yet); requires admin token.
+    pub async fn names_including_pending(&self) -> Result<Vec<NameInfo>> {
+        let req = self.request(Method::GET, "names").query(&[("include-pending", "true")]);
+        let resp: NamesResponse = self.json(req).await?;
+        Ok(resp.names)
+    }
+
+    pub async fn remove(&self, path: &Path) -> Result<()> {
+        self.send(
+            self.request(Method::DELETE, "remove").query(&Client::path_query(path)),
+            StatusCode::OK,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Removes a whole generation directory other than the newest one, like GC does once it moved the objects still in
+    /// use out of it.
+    pub async fn remove_dir(&self, path: &Path) -> Result<()> {
+        self.send(
+            self.request(Method::DELETE, "remove-dir").query(&Client::path_query(path)),
+            StatusCode::OK,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let entry = RenameEntry {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+        };
+
+        self.send(self.request(Method::POST, "rename").json(&entry), StatusCode::OK).await?;
+        Ok(())
+    }
+
+    /// Renames many objects in one request; each of them succeeds or fails on its own.
+    pub async fn rename_batch(&self, renames: Vec<RenameEntry>) -> Result<RenameBatchResponse> {
+        self.json(self.request(Method::POST, "rename-batch").json(&RenameBatchRequest { renames }))
+            .await
+    }
+
+    pub async fn locks(&self) -> Result<LocksResponse> {
+        self.json(self.request(Method::GET, "locks")).await
+    }
+
+    /// Takes a shared lock; it must be renewed (see [`Client::renew_shared_lock`]) before its TTL passes.
+    pub async fn lock_shared(&self) -> Result<SharedLockResponse> {
+        let resp = self.send(self.request(Method::PUT, "lock-shared"), StatusCode::CREATED).await?;
+        resp.json().await.map_err(|e| Error::InvalidResponse(e.to_string()))
+    }
+
+    /// Extends the lease of the lock; fails with [`Error::NotFound`] once it's expired.
+    pub async fn renew_shared_lock(&self, lock_id: &Uuid) -> Result<SharedLockResponse> {
+        self.json(
+            self.request(Method::POST, "lock-shared/renew")
+                .query(&[("lock_id", lock_id.to_string())]),
+        )
+        .await
+    }
+
+    /// Requests the exclusive lock; it's held once the state is `ExclusiveHeld`, until then holders of shared locks
+    /// finish their work - renew it (see [`Client::renew_exclusive_lock`]) to learn when.
+    pub async fn lock_exclusive(&self) -> Result<ExclusiveLockResponse> {
+        let resp = self.send(self.request(Method::PUT, "lock-exclusive"), StatusCode::CREATED).await?;
+        resp.json().await.map_err(|e| Error::InvalidResponse(e.to_string()))
+    }
+
+    /// Extends the lease of the exclusive lock; fails with [`Error::NotFound`] once it's expired.
+    pub async fn renew_exclusive_lock(&self, lock_id: &Uuid) -> Result<ExclusiveLockResponse> {
+        self.json(
+            self.request(Method::POST, "lock-exclusive/renew")
+                .query(&[("lock_id", lock_id.to_string())]),
+        )
+        .await
+    }
+
+    pub async fn release_exclusive_lock(&self, lock_id: &Uuid) -> Result<()> {
+        let req = self
+            .request(Method::DELETE, "lock-exclusive")
+            .query(&[("lock_id", lock_id.to_string())]);
+        self.send(req, StatusCode::OK).await?;
+        Ok(())
+    }
+
+    pub async fn release_shared_lock(&self, lock_id: &Uuid) -> Result<()> {
+        let req = self
+            .request(Method::DELETE, "lock-shared")
+            .query(&[("lock_id", lock_id.to_string())]);
+        self.send(req, StatusCode::OK).await?;
+        Ok(())
+    }
+
+    /// Enters or leaves maintenance mode (admin).
+    pub async fn set_maintenance(&self, request: &MaintenanceRequest) -> Result<()> {
+        self.send(self.request(Method::POST, "admin/maintenance").json(request), StatusCode::OK)
+            .await?;
+        Ok(())
+    }
+
+    /// Starts GC on the server (admin), keeping unreachable data younger than `grace_time` (seconds); see
+    /// [`Client::gc_events`] for its progress.
+    pub async fn start_gc(&self, grace_time: Option<u64>) -> Result<()> {
+        let mut req = self.request(Method::POST, "admin/gc");
+        if let Some(grace_time) = grace_time {
+            req = req.query(&[("grace_time", grace_time)]);
+        }
+
+        self.send(req, StatusCode::ACCEPTED).await?;
+        Ok(())
+    }
+
+    /// Status of the current (or last) GC run (admin), until it ends.
+    pub async fn gc_events(&self) -> Result<BoxStream<'static, Result<Event<GcStatus>>>> {
+        let resp = self.send(self.request(Method::GET, "admin/gc/events"), StatusCode::OK).await?;
+        Ok(stream::events(resp.bytes_stream()))
+    }
+
+    /// Starts a pass migrating cold chunks to the secondary storage (admin).
+    pub async fn start_tiering(&self) -> Result<()> {
+        self.send(self.request(Method::POST, "admin/tiering"), StatusCode::ACCEPTED).await?;
+        Ok(())
+    }
+
+    /// Removes garbage left in the server staging areas by crashes (admin), returning what was removed.
+    pub async fn run_cleanup(&self) -> Result<CleanupReport> {
+        self.json(self.request(Method::POST, "admin/cleanup")).await
+    }
+
+    /// Report of the last finished cleanup, scheduled or not (admin); fails with [`Error::NotFound`] when there's none.
+    pub async fn last_cleanup(&self) -> Result<CleanupReport> {
+        self.json(self.request(Method::GET, "admin/cleanup")).await
+    }
+
+    /// Follows the server log (admin); `last_event_id` continues a previous stream.
+    pub async fn log_stream(
+        &self,
+        query: &LogStreamQuery,
+        last_event_id: Option<u64>,
+    ) -> Result<BoxStream<'static, Result<Event<LogEvent>>>> {
+        let mut req = self.request(Method::GET, "admin/logs/stream").query(query);
+        if let Some(id) = last_event_id {
+            req = req.header("last-event-id", id.to_string());
+        }
+
+        let resp = self.send(req, StatusCode::OK).await?;
+        Ok(stream::events(resp.bytes_stream()))
+    }
+
+    /// I/O limits of background jobs (admin).
+    pub async fn io_throttle(&self) -> Result<ThrottleLimits> {
+        self.json(self.request(Method::GET, "admin/io-throttle")).await
+    }
+
+    pub async fn set_io_throttle(&self, limits: &ThrottleLimits) -> Result<()> {
+        self.send(self.request(Method::PUT, "admin/io-throttle").json(limits), StatusCode::OK)
+            .await?;
+        Ok(())
+    }
+
+    /// Requests being handled by the server (admin).
+    pub async fn operations(&self) -> Result<OperationsResponse> {
+        self.json(self.request(Method::GET, "admin/operations")).await
+    }
+
+    /// Cancels a request being handled by the server (admin).
+    pub async fn cancel_operation(&self, id: u64) -> Result<()> {
+        self.send(self.request(Method::DELETE, &format!("admin/operations/{}", id)), StatusCode::OK)
+            .await?;
+        Ok(())
+    }
+
+    /// Name decoded by the server as a tar archive (admin); available when the server has the passphrase, see
+    /// [`CapabilitiesResponse::tar_export`].
+    pub async fn export_tar(&self, name: &str) -> Result<BoxStream<'static, Result<Bytes>>> {
+        let req = self.request(Method::GET, "admin/export").query(&[("name", name)]);
+        let resp = self.send(req, StatusCode::OK).await?;
+
+        Ok(resp.bytes_stream().map(|chunk| chunk.map_err(Error::from)).boxed())
+    }
+
+    /// Capacity planning report with `top` largest names (admin).
+    pub async fn capacity_report(&self, top: Option<usize>) -> Result<CapacityReport> {
+        let mut req = self.request(Method::GET, "admin/report");
+        if let Some(top) = top {
+            req = req.query(&[("top", top)]);
+        }
+
+        self.json(req).await
+    }
+}
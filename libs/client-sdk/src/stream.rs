@@ -0,0 +1,124 @@
+//! Streamed responses - newline-delimited JSON and server-sent events.
+
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+
+use crate::error::{Error, Result};
+
+/// Server-sent event; comments (keep-alives) are skipped.
+#[derive(Debug, Clone)]
+pub struct Event<T> {
+    /// Sent back as `Last-Event-ID` to continue after reconnection
+    pub id: Option<u64>,
+    pub data: T,
+}
+
+struct Records {
+    body: BoxStream<'static, reqwest::Result<Bytes>>,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+/// Splits `body` into records terminated by `separator`; the last one may be unterminated.
+fn records<S>(body: S, separator: &'static [u8]) -> impl Stream<Item = Result<Vec<u8>>>
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+{
+    let state = Records {
+        body: body.boxed(),
+        buf: Vec::new(),
+        done: false,
+    };
+
+    stream::unfold(state, move |mut state| async move {
+        loop {
+            if let Some(pos) = state.buf.windows(separator.len()).position(|w| w == separator) {
+                let record = state.buf[..pos].to_vec();
+                state.buf.drain(..pos + separator.len());
+                return Some((Ok(record), state));
+            }
+
+            if state.done {
+                if state.buf.is_empty() {
+                    return None;
+                }
+                let record = std::mem::take(&mut state.buf);
+                return Some((Ok(record), state));
+            }
+
+            match state.body.next().await {
+                Some(Ok(bytes)) => state.buf.extend_from_slice(&bytes),
+                Some(Err(e)) => {
+                    // a partial record is of no use
+                    state.buf.clear();
+                    state.done = true;
+                    return Some((Err(Error::from(e)), state));
+                }
+                None => state.done = true,
+            }
+        }
+    })
+}
+
+fn parse<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    serde_json::from_slice(data).map_err(|e| Error::InvalidResponse(e.to_string()))
+}
+
+/// Items of a newline-delimited JSON body.
+pub(crate) fn json_lines<T, S>(body: S) -> BoxStream<'static, Result<T>>
+where
+    T: DeserializeOwned + Send + 'static,
+    S: Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+{
+    records(body, b"\n")
+        .filter(|line| futures::future::ready(!matches!(line, Ok(line) if line.is_empty())))
+        .map(|line| parse(&line?))
+        .boxed()
+}
+
+fn parse_event<T: DeserializeOwned>(record: &[u8]) -> Option<Result<Event<T>>> {
+    let record = match std::str::from_utf8(record) {
+        Ok(record) => record,
+        Err(e) => return Some(Err(Error::InvalidResponse(e.to_string()))),
+    };
+
+    let mut id = None;
+    let mut data: Option<String> = None;
+
+    for line in record.lines() {
+        if let Some(value) = line.strip_prefix("id:") {
+            id = value.trim().parse().ok();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match &mut data {
+                Some(data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            }
+        }
+    }
+
+    // comment only
+    let data = data?;
+
+    Some(parse(data.as_bytes()).map(|data| Event { id, data }))
+}
+
+/// Server-sent events of a `text/event-stream` body with JSON data.
+pub(crate) fn events<T, S>(body: S) -> BoxStream<'static, Result<Event<T>>>
+where
+    T: DeserializeOwned + Send + 'static,
+    S: Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+{
+    records(body, b"\n\n")
+        .filter_map(|record| {
+            futures::future::ready(match record {
+                Ok(record) => parse_event(&record),
+                Err(e) => Some(Err(e)),
+            })
+        })
+        .boxed()
+}
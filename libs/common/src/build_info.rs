@@ -0,0 +1,37 @@
+//! Identification of the exact build, so bug reports can be matched with it.
+
+use serde::{Deserialize, Serialize};
+
+pub const GIT_COMMIT: &str = env!("RBACKUP_GIT_COMMIT");
+/// `YYYY-MM-DD` (UTC)
+pub const BUILD_DATE: &str = env!("RBACKUP_BUILD_DATE");
+/// Target triple, e.g. `x86_64-unknown-linux-musl`
+pub const BUILD_TARGET: &str = env!("RBACKUP_BUILD_TARGET");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub commit: String,
+    pub build_date: String,
+    pub target: String,
+}
+
+impl BuildInfo {
+    /// Build of a crate with given version (`CARGO_PKG_VERSION` of the binary, not of this lib).
+    pub fn new(version: &str) -> BuildInfo {
+        BuildInfo {
+            version: version.to_string(),
+            commit: GIT_COMMIT.to_string(),
+            build_date: BUILD_DATE.to_string(),
+            target: BUILD_TARGET.to_string(),
+        }
+    }
+
+    /// One line form for `--version` and logs.
+    pub fn long_version(&self) -> String {
+        format!(
+            "{} ({} {}, {})",
+            self.version, self.commit, self.build_date, self.target
+        )
+    }
+}
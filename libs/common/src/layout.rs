@@ -0,0 +1,87 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::paths;
+
+/// Directory levels rdedup shards content-addressed objects by, one byte of the digest each
+const RDEDUP_SHARD_LEVELS: usize = 2;
+
+/// Response header carrying layout version of the repository, attached to reads of repository config.
+pub const LAYOUT_VERSION_HEADER: &str = "layout-version";
+
+/// Version of the layout the repository objects are stored in on the server.
+///
+/// Clients address objects by plain rdedup paths; the layout defines how those map to server storage paths, so the
+/// server can migrate its storage without breaking clients which know the layout. The server detects the layout of the
+/// repository it serves and translates the paths of its own rdedup operations (e.g. GC) the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Layout {
+    /// Plain rdedup layout, paths are used as-is
+    V1,
+    /// Chunks and indexes sharded by a single level (`chunk/ab/<digest>`), as laid out by newer rdedup
+    V2,
+}
+
+impl Layout {
+    pub const CURRENT: Layout = Layout::V1;
+
+    pub fn from_version(version: u32) -> Option<Layout> {
+        match version {
+            1 => Some(Layout::V1),
+            2 => Some(Layout::V2),
+            _ => None,
+        }
+    }
+
+    pub fn version(self) -> u32 {
+        match self {
+            Layout::V1 => 1,
+            Layout::V2 => 2,
+        }
+    }
+
+    /// Translates rdedup path into path in the server storage.
+    pub fn to_storage(self, path: &Path) -> PathBuf {
+        match self {
+            Layout::V1 => path.to_path_buf(),
+            Layout::V2 => reshard(path, 1),
+        }
+    }
+
+    /// Translates path in the server storage back into rdedup path.
+    pub fn from_storage(self, path: &Path) -> PathBuf {
+        match self {
+            Layout::V1 => path.to_path_buf(),
+            Layout::V2 => reshard(path, RDEDUP_SHARD_LEVELS),
+        }
+    }
+}
+
+/// Shards content-addressed `path` by `levels` directories; other paths are kept as they are.
+fn reshard(path: &Path, levels: usize) -> PathBuf {
+    let digest = match paths::path_digest(path) {
+        Some(digest) => digest,
+        None => return path.to_path_buf(),
+    };
+
+    let mut resharded = PathBuf::new();
+
+    // generation directories may precede the role directory
+    for component in path.components() {
+        resharded.push(component);
+
+        if matches!(
+            component.as_os_str().to_str(),
+            Some("chunk") | Some("index")
+        ) {
+            break;
+        }
+    }
+
+    for level in 0..levels {
+        resharded.push(&digest[level * 2..level * 2 + 2]);
+    }
+
+    resharded.join(digest)
+}
@@ -1,2 +1,7 @@
+pub mod build_info;
+pub mod layout;
+pub mod paths;
+pub mod request_signature;
 pub mod structs;
+pub mod timing;
 pub mod utils;
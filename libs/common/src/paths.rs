@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+/// Directory (relative to the repository root) where not-yet-committed objects are staged.
+pub const PENDING_DIR: &str = ".pending";
+
+/// Directory (relative to the repository root) holding names.
+pub const NAMES_DIR: &str = "name";
+
+/// Well-known entries of the repository root (the last being key slots of the client); everything else besides hidden
+/// entries is a generation directory.
+const ROOT_ENTRIES: &[&str] = &[NAMES_DIR, "config.yml", "lock", "keys"];
+
+/// Separates namespace of a name (e.g. hostname of the machine) from the name itself, so machines sharing a repository
+/// don't collide on names.
+pub const NAMESPACE_SEPARATOR: char = ':';
+
+/// Name `name` is stored under in `namespace`.
+pub fn namespaced_name(namespace: &str, name: &str) -> String {
+    format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, name)
+}
+
+/// Name within `namespace` of stored name `stored`; `None` when it belongs to another namespace (or none).
+pub fn strip_namespace<'a>(namespace: &str, stored: &'a str) -> Option<&'a str> {
+    stored
+        .strip_prefix(namespace)?
+        .strip_prefix(NAMESPACE_SEPARATOR)
+}
+
+/// Role of an object inside rdedup repository, derived purely from its path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    Config,
+    Name,
+    Index,
+    Chunk,
+    Other,
+}
+
+impl ObjectType {
+    pub fn of(path: &Path) -> ObjectType {
+        if path.file_name().map(|f| f == "config.yml").unwrap_or(false) {
+            return ObjectType::Config;
+        }
+
+        // generation directories may prefix the role directory, so look at all the components
+        for component in path.components() {
+            match component.as_os_str().to_str() {
+                Some("name") => return ObjectType::Name,
+                Some("index") => return ObjectType::Index,
+                Some("chunk") => return ObjectType::Chunk,
+                _ => continue,
+            }
+        }
+
+        ObjectType::Other
+    }
+}
+
+/// Whether entry `name` of the repository root is a generation directory - rdedup GC moves the objects still in use
+/// to a new one and removes the old.
+pub fn is_generation_dir(name: &str) -> bool {
+    !name.starts_with('.') && !ROOT_ENTRIES.contains(&name)
+}
+
+/// Generation directory content-addressed object at `path` lies in, if any.
+pub fn generation(path: &Path) -> Option<&str> {
+    if !matches!(ObjectType::of(path), ObjectType::Chunk | ObjectType::Index) {
+        return None;
+    }
+
+    let first = path.components().next()?.as_os_str().to_str()?;
+    Some(first).filter(|f| is_generation_dir(f) && !matches!(*f, "chunk" | "index"))
+}
+
+/// `path` of an object in a generation directory moved to `generation`.
+pub fn in_generation(path: &Path, generation: &str) -> PathBuf {
+    let mut components = path.components();
+    components.next();
+    Path::new(generation).join(components.as_path())
+}
+
+/// Shortest digest (in bytes) rdedup addresses chunks by.
+const MIN_DIGEST_LEN: usize = 16;
+
+/// Returns hex digest a chunk or index is addressed by, if `path` is a well-formed content address: the file name is
+/// the digest and each directory below the role directory is a prefix of it.
+pub fn path_digest(path: &Path) -> Option<&str> {
+    if !matches!(ObjectType::of(path), ObjectType::Chunk | ObjectType::Index) {
+        return None;
+    }
+
+    let digest = path.file_name()?.to_str()?;
+
+    if digest.len() < MIN_DIGEST_LEN * 2
+        || digest.len() % 2 != 0
+        || !digest.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return None;
+    }
+
+    let mut rest = digest;
+    let mut components = path.parent()?.components().map(|c| c.as_os_str().to_str());
+
+    // skip up to the role directory
+    components.find(|c| matches!(c, Some("chunk") | Some("index")))?;
+
+    for component in components {
+        rest = rest.strip_prefix(component?)?;
+    }
+
+    Some(digest)
+}
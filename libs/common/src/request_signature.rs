@@ -0,0 +1,58 @@
+//! Signatures of requests manipulating locks or administering the server, so an attacker on the path between a proxy
+//! terminating TLS and the server can't tamper with them or replay them.
+//!
+//! When the server has a signing key, such requests carry a hex HMAC-SHA256 keyed by it in
+//! [`REQUEST_SIGNATURE_HEADER`], covering the [`message`] made of the method, path, query, body, a timestamp and a
+//! random nonce. The server refuses requests with timestamps further than [`MAX_CLOCK_SKEW_SECS`] from its clock and
+//! nonces it has seen already.
+
+/// Request header with hex HMAC-SHA256 of the [`message`] of the request.
+pub const REQUEST_SIGNATURE_HEADER: &str = "request-signature";
+
+/// Request header with the time the request was signed at, Unix timestamp (seconds).
+pub const REQUEST_TIMESTAMP_HEADER: &str = "request-timestamp";
+
+/// Request header with a random value unique to the request.
+pub const REQUEST_NONCE_HEADER: &str = "request-nonce";
+
+/// Largest difference between the timestamp of a signed request and the server clock.
+pub const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+/// Whether requests of the endpoint at `path` (e.g. `/admin/gc`) are signed.
+pub fn is_signed(path: &str) -> bool {
+    path.starts_with("/admin/")
+        || path == "/lock-shared"
+        || path.starts_with("/lock-shared/")
+        || path == "/lock-exclusive"
+        || path.starts_with("/lock-exclusive/")
+}
+
+/// Data the request signature is calculated of; `query` is the raw query string, empty when there's none.
+pub fn message(
+    method: &str,
+    path: &str,
+    query: &str,
+    timestamp: u64,
+    nonce: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    let timestamp = timestamp.to_string();
+
+    let mut message = Vec::with_capacity(
+        method.len() + path.len() + query.len() + timestamp.len() + nonce.len() + body.len() + 5,
+    );
+
+    for part in &[
+        method.as_bytes(),
+        path.as_bytes(),
+        query.as_bytes(),
+        timestamp.as_bytes(),
+        nonce.as_bytes(),
+    ] {
+        message.extend_from_slice(part);
+        message.push(0);
+    }
+    message.extend_from_slice(body);
+
+    message
+}
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use uuid::Uuid;
 
@@ -10,4 +11,349 @@ pub struct ListResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SharedLockResponse {
     pub lock_id: Uuid,
+    /// Time (milliseconds) the lock is held without renewal; measured by the server, so clocks of the client and the
+    /// server don't need to agree. Never expires when not set.
+    #[serde(default)]
+    pub ttl_ms: Option<u64>,
+}
+
+/// Exclusive lock of a client, e.g. for GC over the remote backend; renewed the same way as shared locks.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExclusiveLockResponse {
+    pub lock_id: Uuid,
+    /// See [`SharedLockResponse::ttl_ms`]
+    #[serde(default)]
+    pub ttl_ms: Option<u64>,
+    /// `Draining` while holders of shared locks finish their work, the lock is held once `ExclusiveHeld`
+    pub state: RepoState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsResponse {
+    /// All objects, in any storage tier
+    pub objects: u64,
+    pub bytes: u64,
+    /// Chunks migrated to the cold tier
+    #[serde(default)]
+    pub cold_objects: u64,
+    #[serde(default)]
+    pub cold_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NameInfo {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Unix timestamp (seconds) before which the name can't be removed or overwritten
+    pub retain_until: Option<u64>,
+    /// Staged by a client, not committed (yet); such names are listed only on request of an admin
+    #[serde(default)]
+    pub pending: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NamesResponse {
+    pub names: Vec<NameInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CapabilitiesResponse {
+    pub layout_version: u32,
+    /// UDP port of the HTTP/3 listener, when the server runs one
+    #[serde(default)]
+    pub http3_port: Option<u16>,
+    /// Server accepts small objects in batches (`/write-batch`)
+    #[serde(default)]
+    pub write_batch: bool,
+    /// Server looks for content-addressed objects missing from their generation in the other ones itself (`/read`), so
+    /// clients don't have to
+    #[serde(default)]
+    pub generation_fallback: bool,
+    /// Server can decode names itself, exporting them as tar streams (`/admin/export`)
+    #[serde(default)]
+    pub tar_export: bool,
+}
+
+/// Request header with hex HMAC-SHA256 of a written object, keyed by the repository signing key.
+///
+/// The MAC covers the `path` header value, a zero byte and the body, so signed data can't be replayed under other paths.
+pub const SIGNATURE_HEADER: &str = "signature";
+
+/// Request header of writes and reads of chunks and indexes naming the generation directory their path lies in, so the
+/// server can refuse writes into generations GC sealed already.
+pub const GENERATION_HEADER: &str = "generation";
+
+/// Request header identifying the client session (one per opened repository), so the server can keep serving it by the
+/// same backend thread.
+pub const SESSION_HEADER: &str = "session";
+
+/// Request header with the [`Priority`] class of the operation the request is part of; requests without it are
+/// [`Priority::Normal`].
+pub const PRIORITY_HEADER: &str = "priority";
+
+/// How urgently the server should serve a request while backend threads, read buffers or background I/O are contended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    /// Scrubs and verifications, nobody waits for them
+    Low,
+    /// Backups
+    #[default]
+    Normal,
+    /// Restores, someone is waiting for the data
+    High,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::Low, Priority::Normal, Priority::High];
+
+    /// Value of the header.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Priority> {
+        Priority::ALL.iter().copied().find(|p| p.as_str() == value)
+    }
+}
+
+/// Request header with id of the exclusive lock the client holds; writes of others are refused meanwhile.
+pub const EXCLUSIVE_LOCK_HEADER: &str = "exclusive-lock";
+
+/// Response header of writes refused (with `409 Conflict`) because the received body doesn't match its `hash` - it got
+/// corrupted on the way, sending it again should do. The value is the hash of the body as received.
+pub const HASH_MISMATCH_HEADER: &str = "hash-mismatch";
+
+/// Response header marking refusals caused by server maintenance; body contains message for the user.
+pub const MAINTENANCE_HEADER: &str = "maintenance";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MaintenanceRequest {
+    pub enabled: bool,
+    /// Free-form description of when the maintenance ends, shown to users
+    pub until: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenameEntry {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameBatchRequest {
+    pub renames: Vec<RenameEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameResult {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Set when this rename failed; other renames of the batch are independent of it
+    pub error: Option<String>,
+    /// The source didn't exist
+    pub not_found: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenameBatchResponse {
+    pub results: Vec<RenameResult>,
+}
+
+/// Object of a write batch. The batch body is the [`WriteBatchRequest`] as a single JSON line followed by data of the
+/// objects, concatenated in the order of the entries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteBatchEntry {
+    pub path: PathBuf,
+    /// Same as the `hash` header of a single write
+    pub hash: String,
+    pub len: usize,
+    /// Same as the [`SIGNATURE_HEADER`] of a single write
+    pub signature: Option<String>,
+    /// Same as the [`GENERATION_HEADER`] of a single write
+    #[serde(default)]
+    pub generation: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteBatchRequest {
+    pub writes: Vec<WriteBatchEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteResult {
+    pub path: PathBuf,
+    /// Set when this write failed; other writes of the batch are independent of it
+    pub error: Option<String>,
+    /// The data of this write don't match its hash, see [`HASH_MISMATCH_HEADER`]
+    #[serde(default)]
+    pub hash_mismatch: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WriteBatchResponse {
+    pub results: Vec<WriteResult>,
+}
+
+/// State of a GC run executed by the server, sent as server-sent events while it runs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GcStatus {
+    /// Unix timestamp (seconds) of the start
+    pub started: u64,
+    pub elapsed_ms: u128,
+    pub finished: bool,
+    /// Set when the GC failed
+    pub error: Option<String>,
+}
+
+/// Result of a pass removing garbage left in staging areas by crashes, see `/admin/cleanup`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CleanupReport {
+    /// Unix timestamp (seconds) of the start
+    pub started: u64,
+    pub elapsed_ms: u128,
+    /// Staged writes and spooled uploads removed from the temp dir
+    pub temp_files: u64,
+    pub temp_bytes: u64,
+    /// Pending objects (names staged and never committed) removed
+    pub pending_files: u64,
+    pub pending_bytes: u64,
+    /// Stale files which could not be removed, see the server log
+    pub failed: u64,
+}
+
+/// Server log event, streamed to administrators as server-sent events.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEvent {
+    /// Increasing sequence number, the SSE event id
+    pub seq: u64,
+    /// Unix timestamp in milliseconds
+    pub timestamp_ms: u64,
+    pub level: String,
+    /// Module the event comes from
+    pub target: String,
+    pub message: String,
+}
+
+/// I/O limits of server background jobs (e.g. GC), so they don't slow down live backups; `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleLimits {
+    pub max_mb_per_sec: Option<f64>,
+    pub max_ops_per_sec: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockHolder {
+    /// Id of a shared lock, none for the exclusive one
+    pub lock_id: Option<Uuid>,
+    /// Description of who holds the lock (client address, server job)
+    pub holder: String,
+    /// Unix timestamp (seconds) since when the lock is held
+    pub since: u64,
+    /// Time (milliseconds) left until the lock expires unless renewed
+    #[serde(default)]
+    pub ttl_ms: Option<u64>,
+}
+
+/// What the repository is used for; decides which operations may proceed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum RepoState {
+    #[default]
+    Idle,
+    SharedHeld {
+        count: usize,
+    },
+    /// Exclusive lock requested, waiting for `count` shared locks to be released; no new ones are handed out
+    Draining {
+        count: usize,
+    },
+    /// Only the holder of the exclusive lock works with the repository, writes of others are refused
+    ExclusiveHeld,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocksResponse {
+    pub exclusive: Option<LockHolder>,
+    pub shared: Vec<LockHolder>,
+    #[serde(default)]
+    pub state: RepoState,
+}
+
+/// Request being handled by the server, see `/admin/operations`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperationInfo {
+    /// Id the operation can be cancelled by
+    pub id: u64,
+    pub method: String,
+    pub endpoint: String,
+    /// Object path the request works with
+    pub path: Option<String>,
+    /// Address of the client
+    pub peer: Option<String>,
+    /// Request body bytes received so far
+    pub bytes: u64,
+    pub elapsed_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OperationsResponse {
+    /// The longest running first
+    pub operations: Vec<OperationInfo>,
+}
+
+/// Record of a stored snapshot, sent by the client once the store finishes; the server keeps them for capacity
+/// planning.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub name: String,
+    /// Unix timestamp (seconds) of the store
+    pub timestamp: u64,
+    pub source_bytes: u64,
+    /// Bytes actually added to the repository
+    pub new_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WeeklyGrowth {
+    /// Unix timestamp (seconds) of the week start
+    pub week_start: u64,
+    pub snapshots: u64,
+    pub source_bytes: u64,
+    pub new_bytes: u64,
+    /// Source bytes per stored byte
+    pub dedup_ratio: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapacityReport {
+    pub generated_at: u64,
+    pub disk_total_bytes: u64,
+    pub disk_free_bytes: u64,
+    pub weekly_growth: Vec<WeeklyGrowth>,
+    /// Latest snapshots of the largest existing names
+    pub largest_names: Vec<CatalogEntry>,
+    /// Unix timestamp (seconds) when the disk fills at the recent growth rate; none when not growing
+    pub projected_full: Option<u64>,
+}
+
+/// Latencies of storage operations of the server since its start, by operation (`read`, `write`, `list`, ...).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyResponse {
+    pub operations: BTreeMap<String, LatencyStats>,
+}
+
+/// Percentiles are upper bounds of histogram buckets, about 20% wide.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
 }
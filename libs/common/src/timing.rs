@@ -0,0 +1,83 @@
+//! Server-side statistics of a request, sent with every response so clients can tell whether their slowness is the
+//! network, server queueing or its disk.
+
+/// Response header with durations (milliseconds) of the request on the server, in `Server-Timing` syntax.
+pub const SERVER_TIMING_HEADER: &str = "server-timing";
+
+/// Response header with sizes of the request and response bodies as seen by the server, `received=<B>, sent=<B>`;
+/// `sent` is missing for streamed responses.
+pub const BODY_BYTES_HEADER: &str = "body-bytes";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ServerTiming {
+    /// Handling of the request until the response head, including the request body being received
+    pub total_ms: f64,
+    /// Waiting for a backend thread
+    pub queue_ms: f64,
+    /// Storage operations
+    pub backend_ms: f64,
+}
+
+impl ServerTiming {
+    pub fn to_header(&self) -> String {
+        format!(
+            "total;dur={:.3}, queue;dur={:.3}, backend;dur={:.3}",
+            self.total_ms, self.queue_ms, self.backend_ms
+        )
+    }
+
+    /// Parses the header value; unknown metrics are ignored, missing ones are zero.
+    pub fn parse(value: &str) -> ServerTiming {
+        let mut timing = ServerTiming::default();
+
+        for metric in value.split(',') {
+            let mut params = metric.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let duration = params
+                .filter_map(|p| p.strip_prefix("dur="))
+                .find_map(|d| d.parse().ok())
+                .unwrap_or(0.0);
+
+            match name {
+                "total" => timing.total_ms = duration,
+                "queue" => timing.queue_ms = duration,
+                "backend" => timing.backend_ms = duration,
+                _ => (),
+            }
+        }
+
+        timing
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BodyBytes {
+    pub received: u64,
+    /// Unknown for streamed responses
+    pub sent: Option<u64>,
+}
+
+impl BodyBytes {
+    pub fn to_header(&self) -> String {
+        match self.sent {
+            Some(sent) => format!("received={}, sent={}", self.received, sent),
+            None => format!("received={}", self.received),
+        }
+    }
+
+    pub fn parse(value: &str) -> BodyBytes {
+        let mut bytes = BodyBytes::default();
+
+        for param in value.split(',') {
+            let mut pair = param.trim().splitn(2, '=');
+
+            match (pair.next(), pair.next().and_then(|v| v.parse().ok())) {
+                (Some("received"), Some(received)) => bytes.received = received,
+                (Some("sent"), Some(sent)) => bytes.sent = Some(sent),
+                _ => (),
+            }
+        }
+
+        bytes
+    }
+}
@@ -0,0 +1,29 @@
+#!/usr/bin/env bash
+# Release builds: static (musl) binaries of the server and the client for every supported architecture, collected in
+# `dist/` as `rbackup2-<server|client>-<target>`.
+#
+# Requires the musl targets (`rustup target add ...`) and, for cross builds, the musl linkers configured in
+# `.cargo/config.toml`. The commit and the build date are embedded (see `libcommon::build_info`); set
+# `SOURCE_DATE_EPOCH` for reproducible builds.
+
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")/.." && pwd)"
+TARGETS="${TARGETS:-x86_64-unknown-linux-musl aarch64-unknown-linux-musl}"
+DIST="$ROOT/dist"
+
+mkdir -p "$DIST"
+
+for target in $TARGETS; do
+    echo "Building for $target"
+
+    (cd "$ROOT/server" && cargo build --release --target "$target")
+    # there's no system OpenSSL to link statically against
+    (cd "$ROOT/client" && cargo build --release --target "$target" --features vendored-openssl)
+
+    for bin in server client; do
+        cp "$ROOT/$bin/target/$target/release/rbackup2-$bin" "$DIST/rbackup2-$bin-$target"
+    done
+done
+
+ls -l "$DIST"
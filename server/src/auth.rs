@@ -0,0 +1,163 @@
+use std::path::{Component, Path};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::HeaderMap;
+use actix_web::{error, Error, HttpResponse};
+use futures::future::LocalBoxFuture;
+use libcommon::paths::{self, ObjectType, PENDING_DIR};
+use log::*;
+
+use crate::config;
+use crate::config::Role;
+use crate::locks::RDEDUP_LOCK_FILE;
+
+/// Endpoints served without a token even when one is required: the version for health checks, the web UI which takes
+/// the token in its login form.
+const PUBLIC_ENDPOINTS: &[&str] = &["/version", "/ui"];
+
+/// Extracts token from the `Authorization: Bearer <token>` header.
+pub fn token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Role of a configured token; none for unknown ones.
+pub fn token_role(token: &str) -> Option<Role> {
+    let config = config::get();
+
+    if config.admin_tokens.iter().any(|t| t == token) {
+        return Some(Role::Admin);
+    }
+
+    config.tokens.iter().find(|t| t.token == token).map(|t| t.role)
+}
+
+/// Middleware refusing requests without a configured token unless `allow_anonymous` is set, so the repository isn't open
+/// to anyone who can reach the server.
+pub fn middleware<S, B>(req: ServiceRequest, srv: &mut S) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    let public = PUBLIC_ENDPOINTS
+        .iter()
+        .any(|e| req.path() == *e || req.path().starts_with(&format!("{}/", e)));
+
+    if !config::get().allow_anonymous && !public && token(req.headers()).and_then(token_role).is_none() {
+        warn!(
+            "Refusing {} {} without a valid token from {:?}",
+            req.method(),
+            req.path(),
+            req.connection_info().realip_remote_addr()
+        );
+        return Box::pin(async { Err(error::ErrorUnauthorized("Valid token required")) });
+    }
+
+    Box::pin(srv.call(req))
+}
+
+pub fn role(headers: &HeaderMap) -> Role {
+    token(headers).and_then(token_role).unwrap_or(config::get().default_role)
+}
+
+/// Namespace the names of the request are confined to, see `TokenConfig::namespace`.
+pub fn namespace(headers: &HeaderMap) -> Option<&'static str> {
+    let token = token(headers)?;
+
+    config::get()
+        .tokens
+        .iter()
+        .find(|t| t.token == token)
+        .and_then(|t| t.namespace.as_deref())
+}
+
+/// Whether the request may use (see, store, remove) the object at `path` - anything but names outside the namespace of
+/// its token.
+pub fn may_use_name(headers: &HeaderMap, path: &Path) -> bool {
+    let namespace = match namespace(headers) {
+        Some(namespace) if ObjectType::of(path) == ObjectType::Name => namespace,
+        _ => return true,
+    };
+
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| paths::strip_namespace(namespace, name))
+        .is_some()
+}
+
+/// Response to a use of a name outside the namespace of the request's token.
+pub fn name_refusal(headers: &HeaderMap, path: &Path) -> Option<HttpResponse> {
+    if may_use_name(headers, path) {
+        return None;
+    }
+
+    warn!("Refusing access to name {:?} outside the namespace of the token", path);
+    Some(HttpResponse::Forbidden().body("Name outside the namespace of the token"))
+}
+
+pub fn is_admin(headers: &HeaderMap) -> bool {
+    role(headers) == Role::Admin
+}
+
+/// Whether mutation of existing objects must be refused for this request.
+pub fn append_only_applies(headers: &HeaderMap) -> bool {
+    match role(headers) {
+        Role::ReadOnly | Role::AppendOnly => true,
+        Role::ReadWrite => config::get().append_only,
+        Role::Admin => false,
+    }
+}
+
+/// Response to a write (of any kind) the request's token doesn't allow.
+pub fn write_refusal(headers: &HeaderMap) -> Option<HttpResponse> {
+    match role(headers) {
+        Role::ReadOnly => Some(HttpResponse::Forbidden().body("Token is read-only")),
+        _ => None,
+    }
+}
+
+/// Whether clients may modify (write, remove, rename) the object at `path`, whatever their role: anything but the
+/// server internals - dot-prefixed entries (retention, lock registry, catalog, staging, ...) the server keeps for itself.
+/// Objects staged in `PENDING_DIR` are the exception, the upload protocol works with them.
+pub fn may_modify(path: &Path) -> bool {
+    path.components().enumerate().all(|(i, c)| match c {
+        Component::Normal(name) if i == 0 && name == PENDING_DIR => true,
+        Component::Normal(name) => name.to_str().map(|n| !n.starts_with('.')).unwrap_or(false),
+        Component::CurDir => true,
+        _ => false,
+    })
+}
+
+/// Response to a modification of server internals, see `may_modify`.
+pub fn internal_refusal(path: &Path) -> Option<HttpResponse> {
+    if may_modify(path) {
+        return None;
+    }
+
+    warn!("Refusing modification of server internal {:?}", path);
+    Some(HttpResponse::Forbidden().body("Server internals can't be modified"))
+}
+
+/// Whether the object at `path` may be seen (read, listed) by the request. Read-only clients only see the repository
+/// objects - no locks, no server internals (staged objects, retention, catalog). Names outside the namespace of the
+/// token are not seen by anyone.
+pub fn may_see(headers: &HeaderMap, path: &Path) -> bool {
+    if !may_use_name(headers, path) {
+        return false;
+    }
+
+    if role(headers) != Role::ReadOnly {
+        return true;
+    }
+
+    let internal = path.components().any(|c| match c {
+        Component::Normal(name) => name.to_str().map(|n| n.starts_with('.')).unwrap_or(true),
+        _ => false,
+    });
+
+    let lock = path.file_name().map(|f| f == RDEDUP_LOCK_FILE).unwrap_or(false);
+
+    !internal && !lock
+}
@@ -1,32 +1,255 @@
-use std::ops::Deref;
-use std::path::PathBuf;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use actix_rt::time::delay_for;
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::HeaderMap;
+use actix_web::HttpResponse;
+use libcommon::structs::{Priority, SESSION_HEADER};
 use log::*;
 use object_pool::{Pool, Reusable};
 use once_cell::sync::Lazy;
 use rdedup_lib::backends::local::Local;
 use rdedup_lib::backends::{Backend, BackendThread};
 
-static BACKEND: Lazy<Arc<Local>> = Lazy::new(|| Arc::new(Local::new(PathBuf::from_str("/home/jenda/dev/rbackup2-poc/data").unwrap())));
+use crate::config;
+use crate::priority;
+use crate::slowlog;
+use crate::slowlog::TimedThread;
+use crate::tiering::TieredThread;
 
-static BACKEND_POOL: Lazy<Pool<PooledBackend>> = Lazy::new(|| {
-    Pool::new(20, || {
-        let backend = Arc::clone(&BACKEND);
-        let thread = backend.new_thread().expect("Could not create new backend thread");
+/// How often requests waiting for a backend thread check the pool.
+const POOL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Clients refused for the exhausted pool are told to retry after this many seconds.
+const RETRY_AFTER_SECS: u64 = 5;
+/// Low priority requests aren't delayed longer, they'd time out on the client otherwise.
+const LOW_PRIORITY_MAX_DELAY: Duration = Duration::from_secs(5);
 
-        PooledBackend { backend, thread }
+static BACKEND: Lazy<Arc<Local>> = Lazy::new(|| Arc::new(Local::new(data_dir().to_path_buf())));
+
+/// Directory with the served repository - any rdedup repository, including ones created by plain rdedup CLI.
+pub fn data_dir() -> &'static Path {
+    config::get().data_dir.as_deref().expect("Data directory required by config::init")
+}
+
+fn new_backend() -> PooledBackend {
+    let backend = Arc::clone(&BACKEND);
+    let thread = backend.new_thread().expect("Could not create new backend thread");
+    let thread = Box::new(TimedThread::new(Box::new(TieredThread::new(thread))));
+
+    PooledBackend { backend, thread }
+}
+
+static BACKEND_POOL: Lazy<Pool<PooledBackend>> = Lazy::new(|| Pool::new(20, new_backend));
+
+/// Spare threads of high priority requests existing, see `pull_for`.
+static SPARES: AtomicUsize = AtomicUsize::new(0);
+
+/// Backend threads pinned to client sessions, see `pull_for`.
+static SESSIONS: Lazy<Mutex<HashMap<String, Session>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Session {
+    /// None while borrowed
+    backend: Option<PooledBackend>,
+    last_used: Instant,
+}
+
+/// Borrows a pooled backend thread, waiting up to `backend_pool.queue_timeout_secs` for one to be returned while
+/// the pool is exhausted; the wait is reported as server queueing (see `slowlog`). Requests getting none should be
+/// answered by [`unavailable`].
+pub async fn pull() -> Option<Borrowed> {
+    slowlog::queue_wait(async {
+        let deadline = Instant::now() + Duration::from_secs(config::get().backend_pool.queue_timeout_secs);
+
+        loop {
+            if let Some(borrowed) = try_pull() {
+                return Some(borrowed);
+            }
+
+            if Instant::now() >= deadline {
+                warn!("No backend thread returned to the pool in time");
+                return None;
+            }
+
+            // the worker serves other requests meanwhile, those return the threads
+            delay_for(POOL_POLL_INTERVAL).await;
+        }
     })
-});
+    .await
+}
+
+/// Response to a request which got no backend thread in time.
+pub fn unavailable() -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .header(RETRY_AFTER, RETRY_AFTER_SECS.to_string())
+        .body("Server is overloaded, try again later")
+}
 
-pub fn pull<'a>() -> Option<Reusable<'a, PooledBackend>> {
+fn try_pull() -> Option<Borrowed> {
     BACKEND_POOL.try_pull().map(|b| {
         trace!("Borrowing pooled backend");
-        b
+        Borrowed::Pooled(b)
     })
 }
 
+/// Same as `pull`, but with backend affinity enabled, requests of one client session (see [`SESSION_HEADER`]) get the
+/// same backend thread, so related operations (e.g. a burst of writes into one directory) benefit from its caches.
+/// Concurrent requests of the session, and sessions over the limit, get a pooled one.
+///
+/// High priority requests (see [`priority`]) get a spare thread once the pool is exhausted (up to
+/// `backend_pool.max_spares` of them), so restores keep going during mass backups. Low priority ones are delayed while
+/// the pool is tight.
+pub async fn pull_for(headers: &HeaderMap) -> Option<Borrowed> {
+    let session = headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok());
+    let priority = priority::of(headers);
+
+    // before anything is borrowed, so the delayed request holds nothing others could use
+    if priority == Priority::Low {
+        slowlog::queue_wait(yield_to_others()).await;
+    }
+
+    let pinned = match session {
+        Some(session) if config::get().backend_affinity.enabled => slowlog::queue_time(|| pin(session)).map(Borrowed::Pinned),
+        _ => None,
+    };
+
+    // high priority requests don't queue while there's a spare thread left
+    let borrowed = pinned.or_else(try_pull).or_else(|| match priority {
+        Priority::High => spare(),
+        _ => None,
+    });
+
+    match borrowed {
+        Some(borrowed) => Some(borrowed),
+        None => pull().await,
+    }
+}
+
+/// Waits while no more than `backend_pool.low_priority_reserve` threads are free, for a while at most; the worker
+/// serves other requests meanwhile.
+async fn yield_to_others() {
+    let reserve = config::get().backend_pool.low_priority_reserve;
+    let deadline = Instant::now() + LOW_PRIORITY_MAX_DELAY;
+
+    while BACKEND_POOL.len() <= reserve && Instant::now() < deadline {
+        delay_for(POOL_POLL_INTERVAL).await;
+    }
+}
+
+fn spare() -> Option<Borrowed> {
+    let max_spares = config::get().backend_pool.max_spares;
+
+    if SPARES
+        .fetch_update(
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+            |n| if n < max_spares { Some(n + 1) } else { None },
+        )
+        .is_err()
+    {
+        debug!("Backend pool exhausted, no spare backend thread left for high priority request");
+        return None;
+    }
+
+    debug!("Backend pool exhausted, creating spare backend thread for high priority request");
+
+    Some(Borrowed::Spare(Spare { backend: new_backend() }))
+}
+
+fn pin(session: &str) -> Option<Pinned> {
+    let affinity = &config::get().backend_affinity;
+    let idle = Duration::from_secs(affinity.idle_secs);
+
+    let mut sessions = SESSIONS.lock().unwrap();
+
+    // threads of sessions gone idle are released
+    sessions.retain(|_, s| s.backend.is_none() || s.last_used.elapsed() < idle);
+
+    if !sessions.contains_key(session) {
+        if sessions.len() >= affinity.max_sessions {
+            return None;
+        }
+
+        trace!("Pinning backend thread to session {}", session);
+        sessions.insert(
+            session.to_string(),
+            Session {
+                backend: Some(new_backend()),
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    let entry = sessions.get_mut(session)?;
+    let backend = entry.backend.take()?;
+    entry.last_used = Instant::now();
+
+    Some(Pinned {
+        session: session.to_string(),
+        backend: Some(backend),
+    })
+}
+
+/// Backend borrowed for a request; returned once dropped.
+pub enum Borrowed {
+    Pooled(Reusable<'static, PooledBackend>),
+    Pinned(Pinned),
+    /// Created over the pool size, dropped once returned
+    Spare(Spare),
+}
+
+impl Deref for Borrowed {
+    type Target = PooledBackend;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Borrowed::Pooled(backend) => &**backend,
+            Borrowed::Pinned(pinned) => pinned.backend.as_ref().expect("Pinned backend returned already"),
+            Borrowed::Spare(spare) => &spare.backend,
+        }
+    }
+}
+
+impl DerefMut for Borrowed {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self {
+            Borrowed::Pooled(backend) => &mut **backend,
+            Borrowed::Pinned(pinned) => pinned.backend.as_mut().expect("Pinned backend returned already"),
+            Borrowed::Spare(spare) => &mut spare.backend,
+        }
+    }
+}
+
+/// Backend thread of a client session.
+pub struct Pinned {
+    session: String,
+    backend: Option<PooledBackend>,
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        if let Some(entry) = SESSIONS.lock().unwrap().get_mut(&self.session) {
+            entry.backend = self.backend.take();
+            entry.last_used = Instant::now();
+        }
+    }
+}
+
+/// Backend thread of a high priority request over the pool size.
+pub struct Spare {
+    backend: PooledBackend,
+}
+
+impl Drop for Spare {
+    fn drop(&mut self) {
+        SPARES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 pub struct PooledBackend {
     pub backend: Arc<dyn Backend>,
     pub thread: Box<dyn BackendThread>,
@@ -0,0 +1,173 @@
+//! Catalog of stored snapshots (reported by the clients), kept as JSON lines in the data directory for capacity
+//! planning.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::Mutex;
+
+use libcommon::structs::{CapacityReport, CatalogEntry, WeeklyGrowth};
+use log::*;
+use once_cell::sync::Lazy;
+
+use crate::backend_pool;
+use crate::retention;
+
+const CATALOG_FILE: &str = ".catalog.jsonl";
+
+const WEEK_SECS: u64 = 7 * 24 * 3600;
+
+/// Number of recent weeks the growth rate for the projection is computed from.
+const PROJECTION_WEEKS: u64 = 4;
+
+/// Serializes appends, so concurrent records can't interleave.
+static APPEND_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+pub fn record(entry: &CatalogEntry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+
+    let _guard = APPEND_LOCK.lock().unwrap();
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(backend_pool::data_dir().join(CATALOG_FILE))?;
+
+    file.write_all(&line)
+}
+
+fn load() -> io::Result<Vec<CatalogEntry>> {
+    let file = match fs::File::open(backend_pool::data_dir().join(CATALOG_FILE)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = Vec::new();
+
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => entries.push(entry),
+            // crash while appending
+            Err(e) => warn!("Skipping invalid catalog record: {}", e),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Rewrites the catalog without records left invalid by crashes while appending, which are otherwise skipped (and
+/// warned about) by every report.
+pub fn drop_invalid_records() -> io::Result<()> {
+    let _guard = APPEND_LOCK.lock().unwrap();
+
+    let entries = load()?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let mut content = Vec::new();
+    for entry in &entries {
+        serde_json::to_writer(&mut content, entry)?;
+        content.push(b'\n');
+    }
+
+    let file = backend_pool::data_dir().join(CATALOG_FILE);
+    let temp = file.with_extension("jsonl.tmp");
+    fs::write(&temp, content)?;
+    fs::rename(temp, file)
+}
+
+/// Latest `count` records, newest first.
+pub fn recent(count: usize) -> io::Result<Vec<CatalogEntry>> {
+    let mut entries = load()?;
+    entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    entries.truncate(count);
+
+    Ok(entries)
+}
+
+/// Total and free (for unprivileged users) bytes of the filesystem holding the data.
+pub fn disk_space() -> io::Result<(u64, u64)> {
+    let path = CString::new(backend_pool::data_dir().as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: the path is a valid C string and the struct is written by the call
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: initialized by the successful call
+    let stat = unsafe { stat.assume_init() };
+    let block = stat.f_frsize as u64;
+
+    Ok((stat.f_blocks as u64 * block, stat.f_bavail as u64 * block))
+}
+
+/// Builds the report; `existing_names` limits the largest names to those not removed yet.
+pub fn report(existing_names: &HashSet<String>, top: usize) -> io::Result<CapacityReport> {
+    let entries = load()?;
+    let (disk_total_bytes, disk_free_bytes) = disk_space()?;
+    let now = retention::now();
+
+    let mut weeks: BTreeMap<u64, WeeklyGrowth> = BTreeMap::new();
+
+    for entry in &entries {
+        let week_start = entry.timestamp / WEEK_SECS * WEEK_SECS;
+
+        let week = weeks.entry(week_start).or_insert(WeeklyGrowth {
+            week_start,
+            snapshots: 0,
+            source_bytes: 0,
+            new_bytes: 0,
+            dedup_ratio: 0.0,
+        });
+
+        week.snapshots += 1;
+        week.source_bytes += entry.source_bytes;
+        week.new_bytes += entry.new_bytes;
+    }
+
+    for week in weeks.values_mut() {
+        week.dedup_ratio = week.source_bytes as f64 / week.new_bytes.max(1) as f64;
+    }
+
+    let mut latest: HashMap<&str, &CatalogEntry> = HashMap::new();
+    for entry in entries.iter().filter(|e| existing_names.contains(&e.name)) {
+        match latest.get(entry.name.as_str()) {
+            Some(known) if known.timestamp > entry.timestamp => (),
+            _ => {
+                latest.insert(&entry.name, entry);
+            }
+        }
+    }
+
+    let mut largest_names: Vec<CatalogEntry> = latest.into_iter().map(|(_, entry)| entry.clone()).collect();
+    largest_names.sort_by(|a, b| b.source_bytes.cmp(&a.source_bytes));
+    largest_names.truncate(top);
+
+    let recent_since = (now / WEEK_SECS).saturating_sub(PROJECTION_WEEKS - 1) * WEEK_SECS;
+    let recent_bytes: u64 = weeks.range(recent_since..).map(|(_, week)| week.new_bytes).sum();
+    let weekly_rate = recent_bytes / PROJECTION_WEEKS;
+
+    let projected_full = if weekly_rate > 0 {
+        Some(now + (disk_free_bytes as f64 / weekly_rate as f64 * WEEK_SECS as f64) as u64)
+    } else {
+        None
+    };
+
+    Ok(CapacityReport {
+        generated_at: now,
+        disk_total_bytes,
+        disk_free_bytes,
+        weekly_growth: weeks.into_iter().map(|(_, week)| week).collect(),
+        largest_names,
+        projected_full,
+    })
+}
@@ -0,0 +1,170 @@
+//! Removal of garbage crashes leave in staging areas: files in the temp dir (writes staged there, uploads spooled - see
+//! `upload`) and pending objects (names staged by clients which never committed them).
+//!
+//! Only files not modified for the configured age are removed, writes in progress keep touching theirs. Directories
+//! left empty go too, unless modified recently - a write may be just placing an object into one of them.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use err_context::AnyError;
+use libcommon::paths::PENDING_DIR;
+use libcommon::structs::CleanupReport;
+use log::*;
+use once_cell::sync::Lazy;
+
+use crate::backend_pool;
+use crate::config;
+use crate::handlers::NAME_COMMIT;
+use crate::retention;
+use crate::storage;
+
+/// A cleanup pass is running
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Report of the last finished pass.
+static LAST: Lazy<Mutex<Option<CleanupReport>>> = Lazy::new(|| Mutex::new(None));
+
+#[derive(Debug, Default)]
+struct Removed {
+    files: u64,
+    bytes: u64,
+    failed: u64,
+}
+
+fn is_stale(metadata: &fs::Metadata, max_age: Duration) -> io::Result<bool> {
+    Ok(metadata.modified()?.elapsed().unwrap_or_default() >= max_age)
+}
+
+/// Removes files below `dir` not modified for `max_age`, and stale directories left empty; `lock` is held over each
+/// removal of a file.
+fn remove_stale(dir: &Path, max_age: Duration, lock: Option<&Mutex<()>>, removed: &mut Removed) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            remove_stale(&path, max_age, lock, removed)?;
+
+            if fs::metadata(&path).and_then(|m| is_stale(&m, max_age)).unwrap_or(false) {
+                // fails when not empty, that's fine
+                let _ = fs::remove_dir(&path);
+            }
+            continue;
+        }
+
+        let _lock = lock.map(|l| l.lock().unwrap());
+
+        // checked under the lock, the object may have been committed meanwhile
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        if !is_stale(&metadata, max_age)? {
+            continue;
+        }
+
+        debug!("Removing stale {:?}", path);
+
+        match fs::remove_file(&path) {
+            Ok(()) => {
+                removed.files += 1;
+                removed.bytes += metadata.len();
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                warn!("Could not remove stale {:?}: {}", path, e);
+                removed.failed += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_pass() -> io::Result<CleanupReport> {
+    let cleanup = &config::get().cleanup;
+    let started = retention::now();
+    let start = Instant::now();
+
+    let mut temp = Removed::default();
+    remove_stale(
+        &storage::temp_dir(),
+        Duration::from_secs(cleanup.temp_max_age_secs),
+        None,
+        &mut temp,
+    )?;
+
+    // commits rename pending names into place, they can't interleave with their removal
+    let mut pending = Removed::default();
+    remove_stale(
+        &backend_pool::data_dir().join(PENDING_DIR),
+        Duration::from_secs(cleanup.pending_max_age_secs),
+        Some(&NAME_COMMIT),
+        &mut pending,
+    )?;
+
+    Ok(CleanupReport {
+        started,
+        elapsed_ms: start.elapsed().as_millis(),
+        temp_files: temp.files,
+        temp_bytes: temp.bytes,
+        pending_files: pending.files,
+        pending_bytes: pending.bytes,
+        failed: temp.failed + pending.failed,
+    })
+}
+
+/// Runs a cleanup pass; fails when one is already running.
+pub fn run() -> Result<CleanupReport, AnyError> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(AnyError::from("Cleanup is already running"));
+    }
+
+    let result = run_pass();
+    RUNNING.store(false, Ordering::SeqCst);
+    let report = result?;
+
+    info!(
+        "Cleanup finished: {} temp files ({}B) and {} pending objects ({}B) removed, {} failed",
+        report.temp_files, report.temp_bytes, report.pending_files, report.pending_bytes, report.failed
+    );
+
+    *LAST.lock().unwrap() = Some(report.clone());
+
+    Ok(report)
+}
+
+/// Report of the last finished pass, if any.
+pub fn last() -> Option<CleanupReport> {
+    LAST.lock().unwrap().clone()
+}
+
+/// Starts cleanup passes every `interval_secs`, when configured.
+pub fn schedule() {
+    let interval = match config::get().cleanup.interval_secs {
+        Some(secs) => Duration::from_secs(secs),
+        None => return,
+    };
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        if let Err(e) = run() {
+            warn!("Scheduled cleanup failed: {}", e);
+        }
+    });
+}
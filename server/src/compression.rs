@@ -0,0 +1,54 @@
+//! Transfer compression of responses. Objects stored by rdedup are mostly compressed and encrypted already, so only the
+//! types configured in `compression` (the API responses by default) get compressed, saving the CPU where it wouldn't
+//! help.
+
+use std::path::Path;
+
+use actix_web::dev::{BodyEncoding, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::ContentEncoding;
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+use libcommon::paths::ObjectType;
+
+use crate::config;
+
+/// Endpoint returning stored objects as they are
+const READ_ENDPOINT: &str = "/read";
+
+/// Server-sent events would be held back by the encoder's buffering
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// Middleware excluding responses from the compression (done by the outer `Compress` middleware) by the type of object
+/// they carry.
+pub fn middleware<S, B>(req: ServiceRequest, srv: &mut S) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    let object_type = if req.path() == READ_ENDPOINT {
+        url::form_urlencoded::parse(req.query_string().as_bytes())
+            .find(|(k, _)| k == "path")
+            .map(|(_, v)| ObjectType::of(Path::new(v.as_ref())))
+    } else {
+        None
+    };
+
+    let response = srv.call(req);
+
+    Box::pin(async move {
+        let mut res = response.await?;
+
+        let event_stream = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|v| v.as_bytes().starts_with(EVENT_STREAM.as_bytes()))
+            .unwrap_or(false);
+
+        if event_stream || !config::get().compression.for_type(object_type) {
+            res.response_mut().encoding(ContentEncoding::Identity);
+        }
+
+        Ok(res)
+    })
+}
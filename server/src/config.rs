@@ -0,0 +1,564 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use err_context::AnyError;
+use libcommon::paths::ObjectType;
+use libcommon::structs::ThrottleLimits;
+use log::*;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Address the server listens on when not configured.
+const DEFAULT_LISTEN: ([u8; 4], u16) = ([0, 0, 0, 0], 8090);
+
+/// Command line of the server; each option can be set by its env variable as well, and takes precedence over the
+/// config file.
+#[derive(Debug, StructOpt)]
+pub struct Opts {
+    /// Config file (TOML); defaults are used when not set
+    #[structopt(long, env = "RBACKUP_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Directory with the served repository; required unless set by the config file
+    #[structopt(long, env = "RBACKUP_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Address to listen on, e.g. `0.0.0.0:8090`
+    #[structopt(long, env = "RBACKUP_LISTEN")]
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Directory with the served repository, required (here or by the command line)
+    pub data_dir: Option<PathBuf>,
+    /// Address to listen on, `0.0.0.0:8090` when not set
+    pub listen: Option<SocketAddr>,
+    /// Rejects removal, renaming and overwriting of existing objects unless the request comes with an admin token.
+    pub append_only: bool,
+    pub admin_tokens: Vec<String>,
+    /// Tokens of regular clients and what they may do
+    pub tokens: Vec<TokenConfig>,
+    /// Role of requests without a configured token, see `allow_anonymous`
+    pub default_role: Role,
+    /// Serves requests without a configured token with `default_role`; they're refused otherwise (see
+    /// `auth::middleware`), so the repository isn't open to anyone who can reach the server unless asked for
+    pub allow_anonymous: bool,
+    /// Repository secret; when set, every write must carry its HMAC so a stolen token alone isn't enough to forge data,
+    /// and lock and admin requests a signature against tampering and replays (see `request_signing`).
+    pub signing_key: Option<String>,
+    pub body_limits: BodyLimits,
+    pub compression: Compression,
+    pub read_buffers: ReadBuffers,
+    pub storage: Storage,
+    /// Limits of background jobs' I/O (GC)
+    pub background_io: ThrottleLimits,
+    /// Shared locks not renewed for this long (seconds) are released, so crashed clients don't block GC forever
+    pub lock_lease_secs: Option<u64>,
+    /// Keeps locks of clients in the data directory, so they hold over server restarts (see `locks`)
+    pub persist_locks: bool,
+    /// HTTP/3 listener, available with the `http3` feature
+    pub http3: Option<Http3>,
+    pub slow_log: SlowLog,
+    pub log_tail: LogTail,
+    pub backend_affinity: BackendAffinity,
+    pub backend_pool: BackendPool,
+    /// Migration of cold chunks to secondary storage, disabled when not set
+    pub tiering: Option<Tiering>,
+    pub migrations: Migrations,
+    /// Export of names decoded by the server, disabled when not set
+    pub export: Option<Export>,
+    /// Scheduled backups of local directories into the served repository, available with the `standalone` feature
+    pub standalone: Option<Standalone>,
+    pub cleanup: Cleanup,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("data_dir", &self.data_dir)
+            .field("listen", &self.listen)
+            .field("append_only", &self.append_only)
+            .field("admin_tokens", &vec![Redacted; self.admin_tokens.len()])
+            .field("tokens", &self.tokens)
+            .field("default_role", &self.default_role)
+            .field("allow_anonymous", &self.allow_anonymous)
+            .field("signing_key", &self.signing_key.as_ref().map(|_| Redacted))
+            .field("body_limits", &self.body_limits)
+            .field("compression", &self.compression)
+            .field("read_buffers", &self.read_buffers)
+            .field("storage", &self.storage)
+            .field("background_io", &self.background_io)
+            .field("lock_lease_secs", &self.lock_lease_secs)
+            .field("persist_locks", &self.persist_locks)
+            .field("http3", &self.http3)
+            .field("slow_log", &self.slow_log)
+            .field("log_tail", &self.log_tail)
+            .field("backend_affinity", &self.backend_affinity)
+            .field("backend_pool", &self.backend_pool)
+            .field("tiering", &self.tiering)
+            .field("migrations", &self.migrations)
+            .field("export", &self.export)
+            .field("standalone", &self.standalone)
+            .field("cleanup", &self.cleanup)
+            .finish()
+    }
+}
+
+/// Secret of the config in its debug output; the config is logged on start and the log can be tailed over the API.
+#[derive(Clone, Copy)]
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Removal of garbage left in staging areas by crashes, see `cleanup`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Cleanup {
+    /// Runs the cleanup periodically (seconds); otherwise it's started through the admin API only
+    pub interval_secs: Option<u64>,
+    /// Files in the temp dir not modified for this long (seconds) are removed
+    pub temp_max_age_secs: u64,
+    /// Pending objects not modified for this long (seconds) are removed
+    pub pending_max_age_secs: u64,
+}
+
+impl Default for Cleanup {
+    fn default() -> Self {
+        Cleanup {
+            interval_secs: Some(3600),
+            temp_max_age_secs: 24 * 3600,
+            pending_max_age_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// See `standalone`.
+#[derive(Deserialize)]
+pub struct Standalone {
+    /// Passphrase of the repository the backups are encrypted by
+    pub passphrase: String,
+    /// Token the backups authenticate by, as any other client; must be allowed to write
+    pub token: Option<String>,
+    /// Runs all the backups every this many seconds
+    pub interval_secs: u64,
+    pub backups: Vec<StandaloneBackup>,
+    /// Client state dir of the backups; with it, backups interrupted by a restart of the server are cleaned up after and
+    /// run again right after the start (see `runs` of the client)
+    pub state_dir: Option<PathBuf>,
+}
+
+impl fmt::Debug for Standalone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Standalone")
+            .field("passphrase", &Redacted)
+            .field("token", &self.token.as_ref().map(|_| Redacted))
+            .field("interval_secs", &self.interval_secs)
+            .field("backups", &self.backups)
+            .field("state_dir", &self.state_dir)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StandaloneBackup {
+    /// Local directory or file
+    pub source: PathBuf,
+    /// Name the source is stored under, replaced by each run
+    pub name: String,
+}
+
+/// See `export`.
+#[derive(Deserialize)]
+pub struct Export {
+    /// Passphrase of the repository; the server can read all the stored data with it, so it's meant for servers
+    /// trusted that much, e.g. to recover data without a working client
+    pub passphrase: String,
+}
+
+impl fmt::Debug for Export {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Export").field("passphrase", &Redacted).finish()
+    }
+}
+
+fn default_min_age_secs() -> u64 {
+    30 * 24 * 3600
+}
+
+fn default_keep_generations() -> usize {
+    1
+}
+
+/// See `tiering`.
+#[derive(Debug, Deserialize)]
+pub struct Tiering {
+    /// Secondary storage of the cold chunks, e.g. a mounted object storage
+    pub cold_dir: PathBuf,
+    /// Chunks written more recently (seconds) stay where they are
+    #[serde(default = "default_min_age_secs")]
+    pub min_age_secs: u64,
+    /// Chunks of this many newest generations stay where they are
+    #[serde(default = "default_keep_generations")]
+    pub keep_generations: usize,
+    /// Runs the migration periodically (seconds); otherwise it's started through the admin API only
+    pub interval_secs: Option<u64>,
+}
+
+/// Pinning of backend threads to client sessions, see `backend_pool::pull_for`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BackendAffinity {
+    pub enabled: bool,
+    /// Sessions idle for this long (seconds) lose their thread
+    pub idle_secs: u64,
+    /// Sessions with a pinned thread at a time; others use the pool
+    pub max_sessions: usize,
+}
+
+impl Default for BackendAffinity {
+    fn default() -> Self {
+        BackendAffinity {
+            enabled: false,
+            idle_secs: 30,
+            max_sessions: 16,
+        }
+    }
+}
+
+/// Sharing of backend threads by priority of the requests, see `backend_pool::pull_for`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BackendPool {
+    /// Threads created over the pool for high priority requests once it's exhausted, at a time; the requests over it
+    /// wait as any other
+    pub max_spares: usize,
+    /// Low priority requests are delayed while this many threads or fewer are free, so they don't take the last ones
+    pub low_priority_reserve: usize,
+    /// Requests wait for a thread returned to the exhausted pool this long (seconds) at most
+    pub queue_timeout_secs: u64,
+}
+
+impl Default for BackendPool {
+    fn default() -> Self {
+        BackendPool {
+            max_spares: 4,
+            low_priority_reserve: 4,
+            queue_timeout_secs: 30,
+        }
+    }
+}
+
+/// Detailed logging of requests, see `slowlog`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SlowLog {
+    /// Requests taking longer (in milliseconds) are logged; disabled when not set
+    pub threshold_ms: Option<u64>,
+    /// Traces every n-th request regardless of its duration
+    pub sample_every: Option<u64>,
+}
+
+/// Recent log events kept for administrators, see `logtail`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct LogTail {
+    /// Least severe level kept (`error`, `warn`, `info`, `debug`, `trace`), regardless of `RUST_LOG`
+    pub level: String,
+    /// Number of kept events
+    pub capacity: usize,
+}
+
+impl Default for LogTail {
+    fn default() -> Self {
+        LogTail {
+            level: "info".to_string(),
+            capacity: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TokenConfig {
+    pub token: String,
+    pub role: Role,
+    /// Names the token may see and store, e.g. hostname of the machine in a shared repository; all when not set
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+impl fmt::Debug for TokenConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenConfig")
+            .field("token", &Redacted)
+            .field("role", &self.role)
+            .field("namespace", &self.namespace)
+            .finish()
+    }
+}
+
+/// What a client may do with objects, by their role in the repository (see `auth`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Reads repository objects (chunks, indexes, names, config), never sees locks or server internals
+    ReadOnly,
+    /// Creates new objects, never modifies or removes existing names and config
+    AppendOnly,
+    ReadWrite,
+    /// Anything, including the admin API; tokens listed in `admin_tokens`
+    Admin,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::ReadOnly
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Http3 {
+    /// UDP port to listen on
+    pub port: u16,
+    /// PEM certificate chain and private key; QUIC can't run without TLS
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Max accepted size of written objects (in bytes), by object type.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BodyLimits {
+    pub chunk: usize,
+    pub index: usize,
+    pub name: usize,
+    /// Config and anything not recognized
+    pub other: usize,
+    /// Whole write batch (see `/write-batch`)
+    pub batch: usize,
+    /// Bodies of writes larger than this are spooled into the staging directory as they come instead of being held in
+    /// memory, so the limits above can be raised without the memory of the server growing with them
+    pub in_memory: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        BodyLimits {
+            chunk: 1_000_000,
+            index: 1_000_000,
+            name: 1_000_000,
+            other: 1_000_000,
+            batch: 16_000_000,
+            in_memory: 1_000_000,
+        }
+    }
+}
+
+impl BodyLimits {
+    pub fn for_type(&self, object_type: ObjectType) -> usize {
+        match object_type {
+            ObjectType::Chunk => self.chunk,
+            ObjectType::Index => self.index,
+            ObjectType::Name => self.name,
+            ObjectType::Config | ObjectType::Other => self.other,
+        }
+    }
+}
+
+/// Which responses get compressed (when the client accepts it), by object type; see `compression`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Compression {
+    /// Chunks are compressed and encrypted by rdedup already
+    pub chunk: bool,
+    /// Encrypted too
+    pub index: bool,
+    pub name: bool,
+    /// Config and anything not recognized
+    pub other: bool,
+    /// Responses of the API - JSON and the like
+    pub api: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            chunk: false,
+            index: false,
+            name: false,
+            other: true,
+            api: true,
+        }
+    }
+}
+
+impl Compression {
+    /// Whether objects of the type get compressed; `None` stands for API responses.
+    pub fn for_type(&self, object_type: Option<ObjectType>) -> bool {
+        match object_type {
+            Some(ObjectType::Chunk) => self.chunk,
+            Some(ObjectType::Index) => self.index,
+            Some(ObjectType::Name) => self.name,
+            Some(ObjectType::Config) | Some(ObjectType::Other) => self.other,
+            None => self.api,
+        }
+    }
+}
+
+/// Memory held by responses of reads in progress, see `read_buffers`.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ReadBuffers {
+    /// Reads over it wait until responses of others are sent
+    pub max_bytes: usize,
+}
+
+impl Default for ReadBuffers {
+    fn default() -> Self {
+        ReadBuffers { max_bytes: 256_000_000 }
+    }
+}
+
+/// One-time migrations of the data directory run at startup, see `migrations`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Migrations {
+    /// Only log the pending migrations and exit, without serving
+    pub dry_run: bool,
+}
+
+/// How written objects get to the disk.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Storage {
+    /// Where objects are staged before being moved into place; must be on the same filesystem as the data.
+    /// Defaults to `.tmp` inside the data directory.
+    pub temp_dir: Option<PathBuf>,
+    pub default: WritePolicy,
+    /// Overrides of the default policy by object type
+    pub chunk: Option<WritePolicy>,
+    pub index: Option<WritePolicy>,
+    pub name: Option<WritePolicy>,
+    pub other: Option<WritePolicy>,
+}
+
+impl Storage {
+    pub fn policy_for(&self, object_type: ObjectType) -> WritePolicy {
+        let policy = match object_type {
+            ObjectType::Chunk => self.chunk,
+            ObjectType::Index => self.index,
+            ObjectType::Name => self.name,
+            ObjectType::Config | ObjectType::Other => self.other,
+        };
+
+        policy.unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+    /// Every write goes through to the disk (`O_DSYNC`)
+    Always,
+    /// The file is synced once written completely, before it's moved into place
+    OnClose,
+    /// Left to the OS
+    Never,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct WritePolicy {
+    pub fsync: FsyncPolicy,
+    /// Bypass the page cache (`O_DIRECT`) so large writes don't evict data useful for reads
+    pub direct_io: bool,
+}
+
+impl Default for WritePolicy {
+    fn default() -> Self {
+        WritePolicy {
+            fsync: FsyncPolicy::OnClose,
+            direct_io: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, AnyError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn listen(&self) -> SocketAddr {
+        self.listen.unwrap_or_else(|| SocketAddr::from(DEFAULT_LISTEN))
+    }
+}
+
+/// Loads config from the file given by `opts`, falling back to defaults when not set, and applies the options over it.
+pub fn init(opts: Opts) -> Result<(), AnyError> {
+    let mut config = match &opts.config {
+        Some(path) => {
+            info!("Loading config from {:?}", path);
+            Config::load(path)?
+        }
+        None => Config::default(),
+    };
+
+    if let Some(data_dir) = opts.data_dir {
+        config.data_dir = Some(data_dir);
+    }
+    if let Some(listen) = opts.listen {
+        config.listen = Some(listen);
+    }
+
+    if config.data_dir.is_none() {
+        return Err(AnyError::from(
+            "Data directory not set, use --data-dir, RBACKUP_DATA_DIR or `data_dir` of the config file",
+        ));
+    }
+
+    debug!("Using config {:?}", config);
+
+    CONFIG.set(config).map_err(|_| AnyError::from("Config already initialized"))
+}
+
+pub fn get() -> &'static Config {
+    CONFIG.get().expect("Config not initialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_output_hides_secrets() {
+        let config: Config = toml::from_str(
+            r#"
+            admin_tokens = ["admin-secret"]
+            signing_key = "signing-secret"
+
+            [[tokens]]
+            token = "client-secret"
+            role = "read-write"
+
+            [export]
+            passphrase = "export-secret"
+            "#,
+        )
+        .unwrap();
+
+        let debug = format!("{:?}", config);
+
+        for secret in &["admin-secret", "signing-secret", "client-secret", "export-secret"] {
+            assert!(!debug.contains(secret), "{} in {}", secret, debug);
+        }
+        assert!(debug.contains("ReadWrite"));
+    }
+}
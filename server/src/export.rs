@@ -0,0 +1,248 @@
+//! Export of stored names as plain tar streams decoded by the server itself (`/admin/export`), so data stay recoverable
+//! with standard tools even without a working client. Requires the server to have the repository passphrase (see
+//! `config::Export`).
+//!
+//! Directory snapshots are tar streams already and are sent as they are; plain files are wrapped into an archive with a
+//! single entry named after the name.
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Error, ErrorKind, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
+
+use actix_web::web::Bytes;
+use err_context::AnyError;
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::SinkExt;
+use log::*;
+use rdedup_lib::backends::local::Local;
+use rdedup_lib::backends::Backend;
+use rdedup_lib::Repo as RdedupRepo;
+use tar::{Builder, EntryType, Header};
+use uuid::Uuid;
+
+use crate::backend_pool;
+use crate::config;
+use crate::locks;
+use crate::retention;
+use crate::storage::{self, LayoutBackend};
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC_OFFSET: usize = 257;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Parts of the stream buffered before the reading waits for the client
+const CHANNEL_CAPACITY: usize = 16;
+
+pub type ExportStream = mpsc::Receiver<Result<Bytes, actix_web::Error>>;
+
+fn open_repo() -> Result<RdedupRepo, AnyError> {
+    let url = url1::Url::from_directory_path(backend_pool::data_dir()).map_err(|_| AnyError::from("Invalid data directory path"))?;
+
+    let create_backend = |_: &url1::Url| -> io::Result<Box<dyn Backend + Send + Sync>> {
+        let local = Local::new(backend_pool::data_dir().to_path_buf());
+        Ok(Box::new(LayoutBackend::new(local, storage::layout())))
+    };
+
+    Ok(RdedupRepo::open_custom(&url, &create_backend, None)?)
+}
+
+pub fn is_enabled() -> bool {
+    config::get().export.is_some()
+}
+
+/// Starts exporting `name`, holding a shared lock until the export ends. Fails with `NotFound` for unknown names and
+/// with `WouldBlock` when the repository is locked exclusively.
+pub fn start(name: String) -> Result<ExportStream, AnyError> {
+    let passphrase = match &config::get().export {
+        Some(export) => export.passphrase.clone(),
+        None => return Err(AnyError::from("Export is not configured")),
+    };
+
+    let lock = SharedLock::take()?;
+    let repo = open_repo()?;
+
+    if !repo.list_names()?.contains(&name) {
+        return Err(Error::new(ErrorKind::NotFound, format!("Name {} not found", name)).into());
+    }
+
+    let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+    thread::spawn(move || {
+        info!("Exporting {}", name);
+
+        let mut output = TarOutput::new(&name, ChannelWriter { sender, lock });
+
+        match export(&repo, &name, &passphrase, &mut output) {
+            Ok(()) => info!("Exported {}", name),
+            Err(e) => {
+                warn!("Export of {} failed: {}", name, e);
+                // the client sees the stream broken rather than ending as if complete
+                let _ = block_on(output.inner.sender.send(Err(actix_web::error::ErrorInternalServerError(e))));
+            }
+        }
+    });
+
+    Ok(receiver)
+}
+
+fn export(repo: &RdedupRepo, name: &str, passphrase: &str, output: &mut TarOutput) -> io::Result<()> {
+    let rh = repo.unlock_decrypt(&|| Ok(passphrase.to_string()))?;
+    repo.read(name, &mut *output, &rh)?;
+
+    output.finish()
+}
+
+/// Shared lock of the export, so GC doesn't remove the data being read.
+struct SharedLock {
+    id: Uuid,
+    renewed: Instant,
+}
+
+impl SharedLock {
+    fn take() -> io::Result<SharedLock> {
+        Ok(SharedLock {
+            id: locks::add_shared("server export".to_string())?,
+            renewed: Instant::now(),
+        })
+    }
+
+    fn renew(&mut self) {
+        if self.renewed.elapsed() > locks::lease() / 3 {
+            locks::renew_shared(&self.id);
+            self.renewed = Instant::now();
+        }
+    }
+}
+
+impl Drop for SharedLock {
+    fn drop(&mut self) {
+        locks::remove_shared(&self.id);
+    }
+}
+
+/// Sends written data to the response stream.
+struct ChannelWriter {
+    sender: mpsc::Sender<Result<Bytes, actix_web::Error>>,
+    lock: SharedLock,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock.renew();
+
+        block_on(self.sender.send(Ok(Bytes::copy_from_slice(buf))))
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "Client disconnected"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum State {
+    /// Beginning of the data, until it's known whether they're a directory snapshot
+    Head(Vec<u8>),
+    Tree,
+    /// Plain file, spooled as its size must be known before it's archived
+    File(File, PathBuf),
+}
+
+/// Turns the data of a name into a tar stream.
+struct TarOutput {
+    name: String,
+    inner: ChannelWriter,
+    state: State,
+}
+
+impl TarOutput {
+    fn new(name: &str, inner: ChannelWriter) -> TarOutput {
+        TarOutput {
+            name: name.to_string(),
+            inner,
+            state: State::Head(Vec::with_capacity(TAR_BLOCK_SIZE)),
+        }
+    }
+
+    /// Leaves the `Head` state once the beginning of the data is known (or there are no more data).
+    fn decide(&mut self) -> io::Result<()> {
+        let head = match &mut self.state {
+            State::Head(head) => std::mem::take(head),
+            _ => return Ok(()),
+        };
+
+        if head.len() == TAR_BLOCK_SIZE && &head[TAR_MAGIC_OFFSET..TAR_MAGIC_OFFSET + TAR_MAGIC.len()] == TAR_MAGIC {
+            self.inner.write_all(&head)?;
+            self.state = State::Tree;
+        } else {
+            let path = std::env::temp_dir().join(format!("rbackup2-export-{}", Uuid::new_v4()));
+            let mut file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+            file.write_all(&head)?;
+            self.state = State::File(file, path);
+        }
+
+        Ok(())
+    }
+
+    /// Archives the spooled file; tar streams of directory snapshots are complete already, including their end.
+    fn finish(&mut self) -> io::Result<()> {
+        self.decide()?;
+
+        if let State::File(file, _) = &mut self.state {
+            let size = file.seek(SeekFrom::End(0))?;
+            file.seek(SeekFrom::Start(0))?;
+
+            let mut header = Header::new_gnu();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(size);
+            header.set_mode(0o644);
+            header.set_mtime(retention::now());
+
+            // the output outlives a failed builder, so the failure can still break the stream
+            let mut builder = Builder::new(&mut self.inner);
+            builder.append_data(&mut header, &self.name, file)?;
+            builder.into_inner()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for TarOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.state {
+            State::Head(head) => {
+                let taken = buf.len().min(TAR_BLOCK_SIZE - head.len());
+                head.extend_from_slice(&buf[..taken]);
+
+                if head.len() == TAR_BLOCK_SIZE {
+                    self.decide()?;
+                }
+
+                Ok(taken)
+            }
+            State::Tree => self.inner.write(buf),
+            State::File(file, _) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for TarOutput {
+    fn drop(&mut self) {
+        if let State::File(_, path) = &self.state {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Could not remove spooled export {:?}: {}", path, e);
+            }
+        }
+    }
+}
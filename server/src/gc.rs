@@ -0,0 +1,103 @@
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use err_context::AnyError;
+use libcommon::structs::GcStatus;
+use log::*;
+use once_cell::sync::Lazy;
+use rdedup_lib::backends::local::Local;
+use rdedup_lib::backends::Backend;
+use rdedup_lib::Repo as RdedupRepo;
+
+use crate::backend_pool;
+use crate::locks;
+use crate::retention;
+use crate::storage::{self, LayoutBackend};
+use crate::throttle::ThrottledBackend;
+
+/// How often progress is reported to the clients watching the GC.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+struct GcRun {
+    started: u64,
+    start: Instant,
+    /// Duration of the run once it ended, with error if it failed
+    result: Option<(Duration, Option<String>)>,
+}
+
+/// The last GC run (finished or not).
+static LAST_RUN: Lazy<Mutex<Option<GcRun>>> = Lazy::new(|| Mutex::new(None));
+
+fn run_gc(grace_time_secs: u64) -> Result<(), AnyError> {
+    let url = url1::Url::from_directory_path(backend_pool::data_dir()).map_err(|_| AnyError::from("Invalid data directory path"))?;
+
+    // GC is a background job, it must not slow down live backups
+    let create_backend = |_: &url1::Url| -> io::Result<Box<dyn Backend + Send + Sync>> {
+        let local = Local::new(backend_pool::data_dir().to_path_buf());
+        Ok(Box::new(LayoutBackend::new(ThrottledBackend::new(local), storage::layout())))
+    };
+
+    let repo = RdedupRepo::open_custom(&url, &create_backend, None)?;
+    repo.gc(grace_time_secs)?;
+
+    Ok(())
+}
+
+/// Starts GC of the repository in a background thread; fails if one is already running.
+pub fn start(grace_time_secs: u64) -> Result<(), AnyError> {
+    let mut last_run = LAST_RUN.lock().unwrap();
+
+    if matches!(&*last_run, Some(run) if run.result.is_none()) {
+        return Err(AnyError::from("GC is already running"));
+    }
+
+    let exclusive = locks::begin_exclusive("server GC")
+        .map_err(|holder| AnyError::from(format!("Repository is locked exclusively by {}", holder.holder)))?;
+
+    info!("Starting GC with grace time {}s", grace_time_secs);
+
+    *last_run = Some(GcRun {
+        started: retention::now(),
+        start: Instant::now(),
+        result: None,
+    });
+
+    thread::spawn(move || {
+        // clients working with the repository finish first, new ones wait for the GC
+        exclusive.wait_drained();
+
+        let result = run_gc(grace_time_secs);
+        drop(exclusive);
+
+        match &result {
+            Ok(()) => info!("GC finished"),
+            Err(e) => warn!("GC failed: {}", e),
+        }
+
+        let mut last_run = LAST_RUN.lock().unwrap();
+        let run = last_run.as_mut().expect("GC run disappeared");
+        run.result = Some((run.start.elapsed(), result.err().map(|e| e.to_string())));
+    });
+
+    Ok(())
+}
+
+/// Returns status of the last GC run, if there was any.
+pub fn status() -> Option<GcStatus> {
+    LAST_RUN.lock().unwrap().as_ref().map(|run| match &run.result {
+        Some((duration, error)) => GcStatus {
+            started: run.started,
+            elapsed_ms: duration.as_millis(),
+            finished: true,
+            error: error.clone(),
+        },
+        None => GcStatus {
+            started: run.started,
+            elapsed_ms: run.start.elapsed().as_millis(),
+            finished: false,
+            error: None,
+        },
+    })
+}
@@ -0,0 +1,113 @@
+//! Checks of the generation directory requests declare (see `GENERATION_HEADER`) against those of the repository.
+//!
+//! GC moves the objects still in use to a new generation and removes the old ones, so all but the newest generation are
+//! sealed - a write into one of them means the client and GC don't agree on the state of the repository, and the
+//! object would be lost with the generation. Requests without the header (older clients) aren't checked.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use actix_web::error;
+use actix_web::http::HeaderMap;
+use actix_web::HttpResponse;
+use libcommon::paths;
+use libcommon::structs::GENERATION_HEADER;
+use log::*;
+
+use crate::backend_pool;
+
+/// Generation directories of the repository, oldest first.
+pub fn current() -> io::Result<Vec<String>> {
+    let mut generations = Vec::new();
+
+    for entry in fs::read_dir(backend_pool::data_dir())? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if entry.file_type()?.is_dir() && paths::is_generation_dir(&name) {
+            generations.push(name);
+        }
+    }
+
+    generations.sort();
+
+    Ok(generations)
+}
+
+/// Generation declared by the request, checked to be the one `path` lies in.
+fn declared(headers: &HeaderMap, path: &Path) -> Result<Option<String>, error::Error> {
+    let declared = match headers.get(GENERATION_HEADER) {
+        Some(value) => value.to_str().map_err(|_| error::ErrorBadRequest("Malformed generation header"))?,
+        None => return Ok(None),
+    };
+
+    if paths::generation(path) != Some(declared) {
+        warn!("Refusing {:?} declared to be in generation {}", path, declared);
+        return Err(error::ErrorBadRequest(format!(
+            "Path {:?} doesn't lie in declared generation {}",
+            path, declared
+        )));
+    }
+
+    Ok(Some(declared.to_string()))
+}
+
+fn unavailable(e: io::Error) -> error::Error {
+    warn!("Could not list generations: {}", e);
+    error::ErrorInternalServerError(format!("Error: {:?}", e))
+}
+
+/// Refuses writes of `path` into a generation other than the newest one.
+pub fn check_write(headers: &HeaderMap, path: &Path) -> Result<(), error::Error> {
+    let declared = match declared(headers, path)? {
+        Some(declared) => declared,
+        None => return Ok(()),
+    };
+
+    let generations = current().map_err(unavailable)?;
+
+    match generations.last() {
+        // the first write of a new repository creates its generation
+        None => Ok(()),
+        Some(newest) if *newest == declared => Ok(()),
+        Some(newest) if generations.contains(&declared) => {
+            warn!("Refusing write of {:?} into sealed generation {}", path, declared);
+            Err(error::ErrorConflict(format!(
+                "Generation {} is sealed, writes go to generation {}",
+                declared, newest
+            )))
+        }
+        Some(newest) => {
+            warn!("Refusing write of {:?} into unknown generation {}", path, declared);
+            Err(error::ErrorConflict(format!(
+                "Generation {} doesn't exist, writes go to generation {}",
+                declared, newest
+            )))
+        }
+    }
+}
+
+/// Response to a read of `path` declaring a generation newer than any of the repository - the client can't know of it.
+/// Reads of older generations pass, GC may have removed them right after the client resolved the path (see
+/// `handlers::read_other_generation`).
+pub fn read_refusal(headers: &HeaderMap, path: &Path) -> Option<HttpResponse> {
+    let declared = match declared(headers, path) {
+        Ok(Some(declared)) => declared,
+        Ok(None) => return None,
+        Err(e) => return Some(HttpResponse::from_error(e)),
+    };
+
+    let generations = match current() {
+        Ok(generations) => generations,
+        Err(e) => return Some(HttpResponse::from_error(unavailable(e))),
+    };
+
+    match generations.last() {
+        Some(newest) if declared > *newest => {
+            warn!("Refusing read of {:?} from unknown generation {}", path, declared);
+            Some(HttpResponse::Conflict().body(format!("Generation {} doesn't exist, the newest is {}", declared, newest)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use actix_rt::time::delay_for;
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse, Responder};
+use libcommon::paths::NAMES_DIR;
+use libcommon::structs::{MaintenanceRequest, OperationsResponse, ThrottleLimits};
+use log::*;
+use serde::Deserialize;
+
+use crate::auth;
+use crate::backend_pool;
+use crate::catalog;
+use crate::cleanup;
+use crate::export;
+use crate::gc;
+use crate::logtail;
+use crate::maintenance;
+use crate::metrics;
+use crate::operations;
+use crate::request_signing;
+use crate::throttle;
+use crate::tiering;
+
+fn default_grace_time() -> u64 {
+    24 * 3600
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GcQuery {
+    /// Unreachable data younger than this (seconds) are kept
+    #[serde(default = "default_grace_time")]
+    pub grace_time: u64,
+}
+
+/// How often log streams check for new events
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Idle log streams send a comment this often, so disconnected clients are noticed
+const LOG_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize)]
+pub struct LogQuery {
+    /// Least severe level streamed; everything kept by default
+    pub level: Option<String>,
+    /// Only events of this module (and its submodules), e.g. `rbackup2_server::gc`
+    pub module: Option<String>,
+    /// Start with the kept recent events, not just the new ones
+    #[serde(default)]
+    pub recent: bool,
+}
+
+fn default_top() -> usize {
+    10
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    /// Number of the largest names reported
+    #[serde(default = "default_top")]
+    pub top: usize,
+}
+
+/// Refuses requests without an admin token, or with an invalid signature of the request with `body`.
+fn admin_guard(request: &HttpRequest, body: &[u8]) -> Option<HttpResponse> {
+    if !auth::is_admin(request.headers()) {
+        return Some(HttpResponse::Forbidden().body("Admin token required"));
+    }
+
+    request_signing::refusal(request, body)
+}
+
+#[post("/admin/maintenance")]
+pub async fn set_maintenance(request: HttpRequest, body: web::Bytes) -> impl Responder {
+    trace!("set_maintenance");
+
+    if let Some(refusal) = admin_guard(&request, &body) {
+        return refusal;
+    }
+
+    // the signature covers the body as received
+    let body: MaintenanceRequest = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid request: {}", e)),
+    };
+
+    if body.enabled {
+        maintenance::enable(body.until);
+    } else {
+        maintenance::disable();
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+/// Runs GC of the repository on the server itself, without moving any data over the network.
+#[post("/admin/gc")]
+pub async fn start_gc(request: HttpRequest, query: web::Query<GcQuery>) -> impl Responder {
+    trace!("start_gc {:?}", *query);
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    if let Some(refusal) = maintenance::write_refusal() {
+        return refusal;
+    }
+
+    match gc::start(query.grace_time) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => HttpResponse::Conflict().body(e.to_string()),
+    }
+}
+
+/// Starts a pass migrating cold chunks to the secondary storage, see `tiering`.
+#[post("/admin/tiering")]
+pub async fn start_tiering(request: HttpRequest) -> impl Responder {
+    trace!("start_tiering");
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    if let Some(refusal) = maintenance::write_refusal() {
+        return refusal;
+    }
+
+    match tiering::start() {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => HttpResponse::Conflict().body(e.to_string()),
+    }
+}
+
+/// Removes garbage left in staging areas by crashes right away, see `cleanup`; responds with the report once done.
+#[post("/admin/cleanup")]
+pub async fn run_cleanup(request: HttpRequest) -> impl Responder {
+    trace!("run_cleanup");
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    if let Some(refusal) = maintenance::write_refusal() {
+        return refusal;
+    }
+
+    match web::block(cleanup::run).await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(BlockingError::Error(e)) => HttpResponse::Conflict().body(e.to_string()),
+        Err(BlockingError::Canceled) => HttpResponse::InternalServerError().body("Cleanup canceled"),
+    }
+}
+
+/// Report of the last finished cleanup pass, scheduled or not.
+#[get("/admin/cleanup")]
+pub async fn last_cleanup(request: HttpRequest) -> impl Responder {
+    trace!("last_cleanup");
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    match cleanup::last() {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().body("No cleanup finished yet"),
+    }
+}
+
+/// Streams status of the current (or last) GC run as server-sent events until it ends.
+#[get("/admin/gc/events")]
+pub async fn gc_events(request: HttpRequest) -> impl Responder {
+    trace!("gc_events");
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    if gc::status().is_none() {
+        return HttpResponse::NotFound().body("No GC has been run");
+    }
+
+    // state: whether this is the first event; `None` once the GC has ended
+    let events = futures::stream::unfold(Some(true), |state| async move {
+        let first = state?;
+
+        if !first {
+            delay_for(gc::PROGRESS_INTERVAL).await;
+        }
+
+        let status = gc::status()?;
+        let event = format!("data: {}\n\n", serde_json::to_string(&status).ok()?);
+        let next = if status.finished { None } else { Some(false) };
+
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(event)), next))
+    });
+
+    HttpResponse::Ok().content_type("text/event-stream").streaming(events)
+}
+
+/// Streams server log events as server-sent events - the recent ones (when asked for), then the live ones.
+///
+/// Reconnecting clients sending `Last-Event-ID` continue where they stopped, as long as the events are still kept.
+#[get("/admin/logs/stream")]
+pub async fn log_stream(request: HttpRequest, query: web::Query<LogQuery>) -> impl Responder {
+    trace!("log_stream {:?}", *query);
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    let query = query.into_inner();
+
+    let level = match query.level.as_deref().map(log::LevelFilter::from_str) {
+        None => log::LevelFilter::Trace,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => return HttpResponse::BadRequest().body("Invalid log level"),
+    };
+
+    let filter = logtail::Filter {
+        level,
+        module: query.module,
+    };
+
+    let last_event_id = request
+        .headers()
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    let after = match last_event_id {
+        Some(seq) => seq,
+        None if query.recent => 0,
+        None => logtail::last_seq(),
+    };
+
+    let events = futures::stream::unfold(after, move |after| {
+        let filter = filter.clone();
+
+        async move {
+            let idle_since = Instant::now();
+
+            loop {
+                let events = logtail::events_after(after, &filter);
+
+                if let Some(last) = events.last() {
+                    let next = last.seq;
+                    let body: String = events
+                        .iter()
+                        .filter_map(|e| Some(format!("id: {}\ndata: {}\n\n", e.seq, serde_json::to_string(e).ok()?)))
+                        .collect();
+
+                    return Some((Ok::<_, actix_web::Error>(web::Bytes::from(body)), next));
+                }
+
+                if idle_since.elapsed() >= LOG_KEEP_ALIVE {
+                    return Some((Ok(web::Bytes::from_static(b": keep-alive\n\n")), after));
+                }
+
+                delay_for(LOG_POLL_INTERVAL).await;
+            }
+        }
+    });
+
+    HttpResponse::Ok().content_type("text/event-stream").streaming(events)
+}
+
+#[get("/admin/io-throttle")]
+pub async fn get_io_throttle(request: HttpRequest) -> impl Responder {
+    trace!("get_io_throttle");
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    HttpResponse::Ok().json(throttle::limits())
+}
+
+/// Adjusts I/O limits of background jobs; applies to running jobs immediately.
+#[put("/admin/io-throttle")]
+pub async fn set_io_throttle(request: HttpRequest, body: web::Bytes) -> impl Responder {
+    trace!("set_io_throttle");
+
+    if let Some(refusal) = admin_guard(&request, &body) {
+        return refusal;
+    }
+
+    let limits: ThrottleLimits = match serde_json::from_slice(&body) {
+        Ok(limits) => limits,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid request: {}", e)),
+    };
+
+    throttle::set_limits(limits);
+
+    HttpResponse::Ok().finish()
+}
+
+/// Latency percentiles of storage operations since the server started.
+#[get("/admin/latency")]
+pub async fn backend_latency(request: HttpRequest) -> impl Responder {
+    trace!("backend_latency");
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    HttpResponse::Ok().json(metrics::latencies())
+}
+
+/// Requests being handled right now, the longest running first.
+#[get("/admin/operations")]
+pub async fn list_operations(request: HttpRequest) -> impl Responder {
+    trace!("list_operations");
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    HttpResponse::Ok().json(OperationsResponse {
+        operations: operations::list(),
+    })
+}
+
+/// Cancels a request being handled; its client gets 503.
+#[delete("/admin/operations/{id}")]
+pub async fn cancel_operation(request: HttpRequest, id: web::Path<u64>) -> impl Responder {
+    trace!("cancel_operation {}", *id);
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    if operations::cancel(*id) {
+        info!("Cancelled operation {}", *id);
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::NotFound().body("No such operation")
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub name: String,
+}
+
+/// Streams name decoded by the server as a tar archive, see `export`.
+#[get("/admin/export")]
+pub async fn export_name(request: HttpRequest, query: web::Query<ExportQuery>) -> impl Responder {
+    trace!("export_name {:?}", *query);
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    if !export::is_enabled() {
+        return HttpResponse::NotImplemented().body("Server has no passphrase to decode names with");
+    }
+
+    match export::start(query.name.clone()) {
+        Ok(stream) => HttpResponse::Ok().content_type("application/x-tar").streaming(stream),
+        Err(e) => match e.downcast_ref::<io::Error>().map(io::Error::kind) {
+            Some(io::ErrorKind::NotFound) => HttpResponse::NotFound().body(e.to_string()),
+            Some(io::ErrorKind::WouldBlock) => HttpResponse::build(StatusCode::LOCKED).body(e.to_string()),
+            _ => {
+                warn!("Could not export {}: {}", query.name, e);
+                HttpResponse::InternalServerError().body(format!("Error: {}", e))
+            }
+        },
+    }
+}
+
+/// Capacity planning report - growth per week, the largest names, dedup efficiency and when the disk fills.
+#[get("/admin/report")]
+pub async fn capacity_report(request: HttpRequest, query: web::Query<ReportQuery>) -> impl Responder {
+    trace!("capacity_report {:?}", *query);
+
+    if let Some(refusal) = admin_guard(&request, &[]) {
+        return refusal;
+    }
+
+    let mut backend = match backend_pool::pull().await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable(),
+    };
+
+    let names: HashSet<String> = match backend.thread.list(PathBuf::from(NAMES_DIR)) {
+        Ok(entries) => entries
+            .iter()
+            .filter_map(|entry| Path::new(entry.file_name()?).file_stem())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+        Err(e) => {
+            warn!("Error while listing names: {}", e);
+            return HttpResponse::InternalServerError().body(format!("Error: {:?}", e));
+        }
+    };
+
+    match catalog::report(&names, query.top) {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => {
+            warn!("Error while generating capacity report: {}", e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use actix_http::Request;
+use actix_web::http::Method;
+use actix_web::{error, Error, HttpResponse};
+use log::*;
+
+use crate::backend_pool;
+use crate::handlers::{check_write, WriteCheck};
+
+/// Handles `Expect: 100-continue`; writes are checked before the client is told to send the body, so refused or
+/// redundant uploads don't waste bandwidth.
+pub async fn expect(request: Request) -> Result<Request, Error> {
+    if request.method() != Method::POST || request.path() != "/write" {
+        return Ok(request);
+    }
+
+    trace!("expect write {:?}", request.headers().get("path"));
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return Err(error::InternalError::from_response("No backend thread available", backend_pool::unavailable()).into()),
+    };
+
+    match check_write(request.headers(), &mut backend)? {
+        WriteCheck::Accept => Ok(request),
+        // the final response is sent instead of `100 Continue`, even though it's not a failure
+        WriteCheck::Skip => Err(error::InternalError::from_response("Object already exists", HttpResponse::Ok().finish()).into()),
+    }
+}
@@ -1,39 +1,226 @@
+use std::fs;
 use std::io;
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::time::Instant;
 
-use actix_http::body::Body;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::header::{ETAG, IF_MATCH, IF_NONE_MATCH};
+use actix_web::http::{HeaderMap, StatusCode};
 use actix_web::{delete, error, get, post, put, web, HttpRequest, HttpResponse, Responder};
 use futures::StreamExt;
-use libcommon::structs::{ListResponse, SharedLockResponse};
+use libcommon::build_info::BuildInfo;
+use libcommon::layout::LAYOUT_VERSION_HEADER;
+use libcommon::paths::{self, ObjectType, PENDING_DIR};
+use libcommon::structs::{
+    CapabilitiesResponse, CatalogEntry, ExclusiveLockResponse, ListResponse, SharedLockResponse, StatsResponse, HASH_MISMATCH_HEADER,
+    SIGNATURE_HEADER,
+};
 use log::*;
+use once_cell::sync::Lazy;
 use serde::Deserialize;
 use sgdata::SGData;
 use sha2::*;
 use uuid::Uuid;
 
+use crate::auth;
 use crate::backend_pool;
+use crate::backend_pool::PooledBackend;
+use crate::catalog;
+use crate::config;
+use crate::config::Role;
+use crate::generations;
+use crate::locks;
+use crate::maintenance;
+use crate::priority;
+use crate::read_buffers::{self, Reservation};
+use crate::request_signing;
+use crate::retention;
+use crate::slowlog;
+use crate::storage;
+use crate::tiering;
+use crate::upload::Upload;
 
-const MAX_SIZE: usize = 1_000_000; // up to 1M sized chunks
+pub mod admin;
+pub mod expect;
+pub mod names;
+pub mod rename;
+pub mod write_batch;
+
+/// Serializes commits of names (see `name_precondition_holds`) and keeps name listings from seeing them half-done
+pub(crate) static NAME_COMMIT: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+const APPEND_ONLY_MESSAGE: &str = "Repository is append-only, existing objects can't be modified";
+
+fn hidden(path: &Path) -> HttpResponse {
+    warn!("Refusing access to {:?} hidden from the token", path);
+    HttpResponse::Forbidden().body("Path not accessible with this token")
+}
+
+/// Response to a write which can't proceed - maintenance, a server job holding the exclusive lock or a read-only token.
+fn write_refusal(headers: &HeaderMap) -> Option<HttpResponse> {
+    maintenance::write_refusal()
+        .or_else(|| locks::write_refusal(headers))
+        .or_else(|| auth::write_refusal(headers))
+}
+
+fn object_exists(backend: &mut PooledBackend, path: &Path) -> bool {
+    backend.thread.read_metadata(path.to_path_buf()).is_ok()
+}
+
+/// Outcome of checks of a write done before its body is received.
+pub enum WriteCheck {
+    Accept,
+    /// Content-addressed object already exists, its body doesn't need to be sent at all
+    Skip,
+}
+
+/// Checks the signature of a written object (see `SIGNATURE_HEADER`).
+fn verify_signature(headers: &HeaderMap, upload: &Upload) -> Result<(), error::Error> {
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| hex::decode(v).ok())
+        .ok_or_else(|| error::ErrorForbidden("Missing or malformed signature"))?;
+
+    if upload.is_signed_by(&signature) {
+        Ok(())
+    } else {
+        Err(error::ErrorForbidden("Invalid signature"))
+    }
+}
+
+fn header_path(headers: &HeaderMap) -> Result<PathBuf, error::Error> {
+    headers
+        .get("path")
+        .and_then(|v| v.to_str().ok())
+        .map(PathBuf::from)
+        .ok_or_else(|| error::ErrorBadRequest("Missing path header"))
+}
+
+/// Checks whether the write described by given headers would be accepted, without touching its body.
+///
+/// Runs from the `Expect: 100-continue` handler as well as from the write itself (for clients not sending `Expect`).
+fn check_write(headers: &HeaderMap, backend: &mut PooledBackend) -> Result<WriteCheck, error::Error> {
+    if let Some(refusal) = write_refusal(headers) {
+        return Err(error::InternalError::from_response("Writes are refused", refusal).into());
+    }
+
+    let path = header_path(headers)?;
+    let pending = headers.get("pending").is_some();
+    let object_type = ObjectType::of(&path);
+
+    if let Some(refusal) = auth::internal_refusal(&path) {
+        return Err(error::InternalError::from_response("Server internals can't be modified", refusal).into());
+    }
+
+    if let Some(refusal) = auth::name_refusal(headers, &path) {
+        return Err(error::InternalError::from_response("Name outside the namespace", refusal).into());
+    }
+
+    if config::get().signing_key.is_some() && !headers.contains_key(SIGNATURE_HEADER) {
+        warn!("Refusing unsigned write of {:?}", path);
+        return Err(error::ErrorForbidden("Writes must be signed"));
+    }
+
+    generations::check_write(headers, &path)?;
+
+    // chunks and indexes are content-addressed so rewriting them is harmless; names and config are not
+    if !pending
+        && matches!(object_type, ObjectType::Name | ObjectType::Config)
+        && auth::append_only_applies(headers)
+        && object_exists(backend, &path)
+    {
+        warn!("Refusing to overwrite {:?} in append-only mode", path);
+        return Err(error::ErrorForbidden(APPEND_ONLY_MESSAGE));
+    }
+
+    if !pending && object_type == ObjectType::Name && retention::is_retained(backend, &path)? {
+        warn!("Refusing to overwrite retained name {:?}", path);
+        return Err(error::ErrorForbidden("Name is under retention"));
+    }
+
+    let max_size = config::get().body_limits.for_type(object_type);
+    let content_length: Option<usize> = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    if let Some(content_length) = content_length.filter(|l| *l > max_size) {
+        return Err(error::ErrorPayloadTooLarge(format!(
+            "Max {}B supported, {}B announced",
+            max_size, content_length
+        )));
+    }
+
+    if !pending && matches!(object_type, ObjectType::Chunk | ObjectType::Index) && object_exists(backend, &path) {
+        return Ok(WriteCheck::Skip);
+    }
+
+    Ok(WriteCheck::Accept)
+}
 
 #[derive(Debug, Deserialize)]
 pub struct PathQuery {
     pub path: PathBuf,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct CommitQuery {
+    pub path: PathBuf,
+    /// Unix timestamp (seconds) until which the name must be kept
+    pub retain_until: Option<u64>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UnlockQuery {
     pub lock_id: Uuid,
 }
 
+#[get("/capabilities")]
+pub async fn capabilities() -> impl Responder {
+    trace!("capabilities");
+
+    #[cfg(feature = "http3")]
+    let http3_port = crate::http3::port();
+    #[cfg(not(feature = "http3"))]
+    let http3_port = None;
+
+    HttpResponse::Ok().json(CapabilitiesResponse {
+        layout_version: storage::layout().version(),
+        http3_port,
+        write_batch: true,
+        generation_fallback: true,
+        tar_export: config::get().export.is_some(),
+    })
+}
+
+/// Exact build of the server, for bug reports.
+#[get("/version")]
+pub async fn version() -> impl Responder {
+    trace!("version");
+
+    HttpResponse::Ok().json(BuildInfo::new(env!("CARGO_PKG_VERSION")))
+}
+
 #[get("/list")]
-pub async fn list(query: web::Query<PathQuery>) -> impl Responder {
+pub async fn list(request: HttpRequest, query: web::Query<PathQuery>) -> impl Responder {
     trace!("list {:?}", *query);
 
-    let mut backend = backend_pool::pull().expect("Unavailable backend thread");
+    if !auth::may_see(request.headers(), &query.path) {
+        return hidden(&query.path).await;
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable().await,
+    };
 
     match backend.thread.list(query.path.clone()) {
-        Ok(result) => HttpResponse::Ok().json(ListResponse { paths: result }),
+        Ok(mut result) => {
+            result.retain(|p| auth::may_see(request.headers(), &query.path.join(p)));
+            HttpResponse::Ok().json(ListResponse { paths: result })
+        }
         Err(e) => {
             warn!("Error while listing path {:?}: {}", query.path, e);
             HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
@@ -42,11 +229,133 @@ pub async fn list(query: web::Query<PathQuery>) -> impl Responder {
     .await
 }
 
+/// Same as `/list` but streams the result as newline-delimited JSON (one path per line) instead of building one big
+/// document. The directory is read page by page on the blocking thread pool as the connection takes the lines, so
+/// even huge ones aren't held in memory.
+#[get("/list-stream")]
+pub async fn list_stream(request: HttpRequest, query: web::Query<PathQuery>) -> impl Responder {
+    trace!("list_stream {:?}", *query);
+
+    if !auth::may_see(request.headers(), &query.path) {
+        return hidden(&query.path).await;
+    }
+
+    // read directly, not through a backend thread
+    if !query.path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return HttpResponse::BadRequest().body("Invalid path").await;
+    }
+
+    let dir = match storage::open_dir(&query.path) {
+        Ok(dir) => dir,
+        Err(e) => {
+            warn!("Error while listing path {:?}: {}", query.path, e);
+            return HttpResponse::InternalServerError().body(format!("Error: {:?}", e)).await;
+        }
+    };
+
+    let (headers, parent) = (request.headers().clone(), query.into_inner().path);
+
+    let lines = futures::stream::unfold(Some(dir), move |dir| {
+        let (headers, parent) = (headers.clone(), parent.clone());
+
+        async move {
+            let mut dir = dir?;
+
+            let page = web::block(move || storage::list_page(&mut dir).map(|page| (dir, page))).await;
+
+            let (dir, page) = match page {
+                Ok((_, page)) if page.is_empty() => return None,
+                Ok(listed) => listed,
+                // ends the stream, the client sees it broken
+                Err(e) => return Some((Err(e.into()), None)),
+            };
+
+            let mut lines = Vec::new();
+            for path in page.iter().filter(|p| auth::may_see(&headers, &parent.join(p))) {
+                if let Err(e) = serde_json::to_writer(&mut lines, path) {
+                    return Some((Err(e.into()), None));
+                }
+                lines.push(b'\n');
+            }
+
+            Some((Ok::<_, actix_web::Error>(web::Bytes::from(lines)), Some(dir)))
+        }
+    });
+
+    HttpResponse::Ok().content_type("application/x-ndjson").streaming(lines).await
+}
+
+/// Usage of the repository, walking all its objects.
+pub fn repo_stats(backend: &mut PooledBackend) -> io::Result<StatsResponse> {
+    let (tx, rx) = mpsc::channel();
+    backend.thread.list_recursively(PathBuf::new(), tx);
+
+    let mut stats = StatsResponse {
+        objects: 0,
+        bytes: 0,
+        cold_objects: 0,
+        cold_bytes: 0,
+    };
+
+    for batch in rx {
+        let paths = batch?;
+
+        for path in paths {
+            match backend.thread.read_metadata(path.clone()) {
+                Ok(meta) if meta.is_file => {
+                    stats.objects += 1;
+                    stats.bytes += meta.len;
+
+                    match tiering::cold_len(&path) {
+                        Ok(Some(len)) => {
+                            stats.cold_objects += 1;
+                            stats.cold_bytes += len;
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!("Could not check tier of {:?}: {}", path, e),
+                    }
+                }
+                Ok(_) => (),
+                // removed concurrently
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+#[get("/stats")]
+pub async fn stats() -> impl Responder {
+    trace!("stats");
+
+    let mut backend = match backend_pool::pull().await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable().await,
+    };
+
+    match repo_stats(&mut backend) {
+        Ok(stats) => HttpResponse::Ok().json(stats).await,
+        Err(e) => {
+            warn!("Error while collecting repository stats: {}", e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e)).await
+        }
+    }
+}
+
 #[get("/read-metadata")]
-pub async fn read_metadata(query: web::Query<PathQuery>) -> impl Responder {
+pub async fn read_metadata(request: HttpRequest, query: web::Query<PathQuery>) -> impl Responder {
     trace!("read_metadata {:?}", *query);
 
-    let mut backend = backend_pool::pull().expect("Unavailable backend thread");
+    if !auth::may_see(request.headers(), &query.path) {
+        return hidden(&query.path).await;
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable().await,
+    };
 
     match backend.thread.read_metadata(query.path.clone()) {
         Ok(result) => HttpResponse::Ok().json(result),
@@ -60,78 +369,556 @@ pub async fn read_metadata(query: web::Query<PathQuery>) -> impl Responder {
 }
 
 #[get("/read")]
-pub async fn read(query: web::Query<PathQuery>) -> impl Responder {
+pub async fn read(request: HttpRequest, query: web::Query<PathQuery>) -> impl Responder {
     trace!("read {:?}", *query);
 
-    let mut backend = backend_pool::pull().expect("Unavailable backend thread");
+    if !auth::may_see(request.headers(), &query.path) {
+        return hidden(&query.path).await;
+    }
 
-    match backend.thread.read(query.path.clone()) {
-        Ok(result) => HttpResponse::Ok().body(Body::from(result.to_linear_vec())), // TODO streaming?
-        Err(e) if e.kind() == io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
-        Err(e) => {
-            warn!("Error while reading {:?}: {}", query.path, e);
-            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+    if let Some(refusal) = generations::read_refusal(request.headers(), &query.path) {
+        return refusal.await;
+    }
+
+    let object_type = ObjectType::of(&query.path);
+
+    // the size isn't known before the object is read, but it can't be over the limit of its writes (unless written
+    // with a higher one)
+    let mut reservation = read_buffers::reserve(config::get().body_limits.for_type(object_type), priority::of(request.headers())).await;
+
+    if let Some(result) = open_chunk_directly(&query.path).await {
+        match result {
+            Ok((file, len)) => return HttpResponse::Ok().streaming(read_buffers::file_body(file, len, reservation)).await,
+            // may have been moved to another generation meanwhile, see `read_other_generation`
+            Err(e) if e.kind() == io::ErrorKind::NotFound && paths::generation(&query.path).is_some() => (),
+            Err(e) => return read_failure(&query.path, e).await,
+        }
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable().await,
+    };
+
+    let result = match backend.thread.read(query.path.clone()) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => read_other_generation(&mut backend, &query.path).unwrap_or(Err(e)),
+        result => result,
+    };
+
+    match result {
+        Ok(data) => {
+            // content addressed objects never change, their ETags would be just a waste of time; those of names are used
+            // by conditional commits
+            let etag = if matches!(object_type, ObjectType::Config | ObjectType::Name | ObjectType::Other) {
+                Some(etag(&data.to_linear()))
+            } else {
+                None
+            };
+
+            let not_modified = match (&etag, request.headers().get(IF_NONE_MATCH)) {
+                (Some(etag), Some(if_none_match)) => if_none_match.as_bytes() == etag.as_bytes(),
+                _ => false,
+            };
+
+            let mut response = if not_modified {
+                HttpResponse::NotModified()
+            } else {
+                HttpResponse::Ok()
+            };
+
+            if let Some(etag) = &etag {
+                response.header(ETAG, etag.as_str());
+            }
+
+            // lets clients detect a layout migration which happened since they connected
+            if object_type == ObjectType::Config {
+                response.header(LAYOUT_VERSION_HEADER, storage::layout().version().to_string());
+            }
+
+            if not_modified {
+                response.finish()
+            } else {
+                stream_object(&mut response, data, reservation)
+            }
         }
+        Err(e) => read_failure(&query.path, e),
     }
     .await
 }
 
+/// Sends `data` of an object read, holding their `reservation` of read buffers until they are sent.
+fn stream_object(response: &mut HttpResponseBuilder, data: SGData, mut reservation: Reservation) -> HttpResponse {
+    reservation.resize(data.len());
+
+    response.streaming(read_buffers::body(data, reservation))
+}
+
+/// GC moves the objects still in use to a new generation directory, so a read racing the move (the client resolved the
+/// path before) finds nothing. Content-addressed objects are looked for once in the other generations then, newest
+/// first, the same way rdedup falls back to older generations itself; `None` when they are in none of them.
+fn read_other_generation(backend: &mut PooledBackend, path: &Path) -> Option<io::Result<SGData>> {
+    let generation = paths::generation(path)?;
+
+    let mut generations: Vec<String> = match backend.thread.list(PathBuf::new()) {
+        Ok(entries) => entries
+            .iter()
+            .filter_map(|p| p.file_name().map(|f| f.to_string_lossy().to_string()))
+            .filter(|f| paths::is_generation_dir(f) && f != generation)
+            .collect(),
+        Err(e) => return Some(Err(e)),
+    };
+    generations.sort_by(|a, b| b.cmp(a));
+
+    for other in generations {
+        match backend.thread.read(paths::in_generation(path, &other)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => {
+                debug!("{:?} was moved to generation {} meanwhile", path, other);
+                return Some(result);
+            }
+        }
+    }
+
+    None
+}
+
+fn etag(data: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(Sha256::digest(data)))
+}
+
+fn read_failure(path: &Path, e: io::Error) -> HttpResponse {
+    if e.kind() == io::ErrorKind::NotFound {
+        return HttpResponse::NotFound().finish();
+    }
+
+    warn!("Error while reading {:?}: {}", path, e);
+    HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+}
+
+/// Chunks are content addressed and never change once written, so they are streamed straight from the filesystem on
+/// the blocking thread pool, without waiting for a backend thread - restores read mostly chunks. Returns the opened
+/// file with its size; `None` when the object has to go through a backend thread: it's not a chunk, or it may be a stub
+/// of a chunk in the cold tier.
+async fn open_chunk_directly(path: &Path) -> Option<io::Result<(fs::File, u64)>> {
+    let well_formed = paths::path_digest(path).is_some() && path.components().all(|c| matches!(c, Component::Normal(_)));
+
+    if ObjectType::of(path) != ObjectType::Chunk || !well_formed {
+        return None;
+    }
+
+    let file = backend_pool::data_dir().join(path);
+    let start = Instant::now();
+    let result = web::block(move || {
+        let file = fs::File::open(file)?;
+        let len = file.metadata()?.len();
+        Ok::<_, io::Error>((file, len))
+    })
+    .await;
+    slowlog::add_backend_time("read", start.elapsed());
+
+    match result {
+        Ok((_, len)) if tiering::may_be_stub(len) => None,
+        Ok((file, len)) => Some(Ok((file, len))),
+        Err(error::BlockingError::Error(e)) => Some(Err(e)),
+        Err(error::BlockingError::Canceled) => Some(Err(io::Error::new(io::ErrorKind::Other, "Chunk read canceled"))),
+    }
+}
+
 #[post("/write")]
 pub async fn write(request: HttpRequest, mut payload: web::Payload) -> impl Responder {
     let headers = request.headers();
-    let path = PathBuf::from_str(headers.get("path").unwrap().to_str().unwrap()).unwrap();
-    let hash_reported = headers.get("hash").unwrap().to_str().unwrap();
+    let path = header_path(headers)?;
+    let hash_reported = headers
+        .get("hash")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| error::ErrorBadRequest("Missing hash header"))?;
+    // pending objects are staged aside and become visible only after `/commit-name`
+    let pending = headers.get("pending").is_some();
+
+    trace!("write {:?} {} pending={}", path, hash_reported, pending);
 
-    trace!("write {:?} {}", path, hash_reported);
+    // the backend thread isn't held while the body is received, slow clients would keep it from others
+    let check = match backend_pool::pull_for(request.headers()).await {
+        Some(mut backend) => check_write(headers, &mut backend)?,
+        None => return Ok(backend_pool::unavailable()),
+    };
+
+    if let WriteCheck::Skip = check {
+        trace!("Object {:?} already exists, skipping write", path);
+        return HttpResponse::Ok().finish().await;
+    }
 
-    let mut backend = backend_pool::pull().expect("Unavailable backend thread");
+    let max_size = config::get().body_limits.for_type(ObjectType::of(&path));
 
-    let mut body = Vec::with_capacity(MAX_SIZE);
+    // limits may be generous for some object types, don't preallocate more than announced; chunked bodies announce
+    // nothing, the buffer grows as they come
+    let content_length = headers
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+
+    let mut upload = Upload::new(headers, content_length.min(max_size));
 
     while let Some(chunk) = payload.next().await {
         let chunk = chunk?;
-        // limit max size of in-memory payload
-        if (body.len() + chunk.len()) > MAX_SIZE {
+        if (upload.len() + chunk.len()) > max_size {
             return Err(error::ErrorPayloadTooLarge(format!(
-                "Max {}B supported, {:?}B sent",
-                MAX_SIZE,
-                headers.get("content-length")
+                "Max {}B supported, over {}B sent",
+                max_size,
+                upload.len() + chunk.len()
             )));
         }
-        body.extend_from_slice(&chunk);
+        upload.push(&chunk).map_err(|e| write_error(&path, e))?;
+    }
+
+    store_object(headers, path, pending, upload)?;
+
+    HttpResponse::Ok().finish().await
+}
+
+/// Stores received object described by `headers` (checked by `check_write` already).
+fn store_object(headers: &HeaderMap, path: PathBuf, pending: bool, upload: Upload) -> Result<(), error::Error> {
+    let hash_reported = headers.get("hash").and_then(|v| v.to_str().ok()).unwrap_or_default();
+
+    if config::get().signing_key.is_some() {
+        if let Err(e) = verify_signature(headers, &upload) {
+            warn!("Refusing write of {:?} with invalid signature", path);
+            return Err(e);
+        }
     }
 
-    let mut hasher = Sha256::new();
-    hasher.update(&*body);
-    let hash = hex::encode(&hasher.finalize());
+    // digests in paths of chunks and indexes are taken over the plaintext, the body is what can be checked here
+    let hash = upload.hash();
+
+    if !hash.eq_ignore_ascii_case(hash_reported) {
+        warn!(
+            "Refusing write of {:?}, hash {} of the received body doesn't match reported {}",
+            path, hash, hash_reported
+        );
+        return Err(hash_mismatch(&hash));
+    }
 
     trace!(
         "Writing path {:?} length {}B hash {} reported hash {}",
         path,
-        body.len(),
+        upload.len(),
         hash,
         hash_reported
     );
 
-    match backend.thread.write(path.clone(), SGData::from_single(body), true) {
-        Ok(_) => HttpResponse::Ok().finish(),
+    let path = if pending { Path::new(PENDING_DIR).join(path) } else { path };
+
+    let policy = config::get().storage.policy_for(ObjectType::of(&path));
+
+    slowlog::backend_time("write", || upload.store(&path, policy)).map_err(|e| write_error(&path, e))
+}
+
+/// Error of a write whose body got corrupted on the way, see `HASH_MISMATCH_HEADER`.
+fn hash_mismatch(hash: &str) -> error::Error {
+    let response = HttpResponse::Conflict()
+        .header(HASH_MISMATCH_HEADER, hash)
+        .body("Hash of the received data doesn't match the reported one");
+
+    error::InternalError::from_response("Hash mismatch", response).into()
+}
+
+/// Whether `e` refused a write as corrupted on the way.
+pub(crate) fn is_hash_mismatch(e: &error::Error) -> bool {
+    e.as_response_error().error_response().headers().contains_key(HASH_MISMATCH_HEADER)
+}
+
+fn write_error(path: &Path, e: io::Error) -> error::Error {
+    warn!("Error while writing path {:?}: {}", path, e);
+
+    // clients tell a full disk from other failures by the status
+    match e.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) => {
+            error::InternalError::new(format!("Error: {}", e), StatusCode::INSUFFICIENT_STORAGE).into()
+        }
+        _ => error::ErrorInternalServerError(format!("Error: {:?}", e)),
+    }
+}
+
+#[post("/commit-name")]
+pub async fn commit_name(request: HttpRequest, query: web::Query<CommitQuery>) -> impl Responder {
+    trace!("commit_name {:?}", *query);
+
+    if let Some(refusal) = write_refusal(request.headers())
+        .or_else(|| auth::internal_refusal(&query.path))
+        .or_else(|| auth::name_refusal(request.headers(), &query.path))
+    {
+        return Ok(refusal);
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return Ok(backend_pool::unavailable()),
+    };
+
+    let response = {
+        // the checks, retention and the rename can't interleave with another commit or a listing of names
+        let _commit = NAME_COMMIT.lock().unwrap();
+
+        commit_locked(&mut backend, request.headers(), &query)
+    };
+
+    response.await
+}
+
+/// Commits the staged name, with `NAME_COMMIT` held so that nothing replaces the name between the checks and the rename.
+fn commit_locked(backend: &mut PooledBackend, headers: &HeaderMap, query: &CommitQuery) -> HttpResponse {
+    if auth::append_only_applies(headers) && object_exists(backend, &query.path) {
+        warn!("Refusing to overwrite name {:?} in append-only mode", query.path);
+        return HttpResponse::Forbidden().body(APPEND_ONLY_MESSAGE);
+    }
+
+    // retention can't be lifted even by admins, that's the whole point of it
+    match retention::get(backend, &query.path) {
+        Ok(Some(until)) if until > retention::now() => {
+            warn!("Refusing to overwrite name {:?} retained until {}", query.path, until);
+            return HttpResponse::Forbidden().body(format!("Name is under retention until {}", until));
+        }
+        Ok(_) => (),
+        Err(e) => {
+            warn!("Error while reading retention of {:?}: {}", query.path, e);
+            return HttpResponse::InternalServerError().body(format!("Error: {:?}", e));
+        }
+    }
+
+    let pending_path = Path::new(PENDING_DIR).join(&query.path);
+
+    match name_precondition_holds(backend, headers, &query.path) {
+        Ok(true) => match commit_retained(backend, query, pending_path) {
+            Ok(_) => HttpResponse::Ok().finish(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
+            Err(e) => {
+                warn!("Error while committing name {:?}: {}", query.path, e);
+                HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+            }
+        },
+        Ok(false) => {
+            warn!("Name {:?} changed since the committing client has seen it", query.path);
+            HttpResponse::PreconditionFailed().body("Name changed concurrently")
+        }
+        Err(e) => {
+            warn!("Error while reading name {:?}: {}", query.path, e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+        }
+    }
+}
+
+/// Moves the staged name into place, setting its retention first so the name never becomes visible unprotected.
+fn commit_retained(backend: &mut PooledBackend, query: &CommitQuery, pending_path: PathBuf) -> io::Result<()> {
+    if let Some(until) = query.retain_until {
+        retention::set(backend, &query.path, until)?;
+    }
+
+    backend.thread.rename(pending_path, query.path.clone())
+}
+
+/// Optimistic concurrency of name commits: `If-Match` carries ETag of the name as the client has seen it (see `/read`),
+/// `If-None-Match: *` says there was no such name. Commits without either always proceed.
+fn name_precondition_holds(backend: &mut PooledBackend, headers: &HeaderMap, path: &Path) -> io::Result<bool> {
+    let if_match = headers.get(IF_MATCH);
+    let if_none_match = headers.get(IF_NONE_MATCH);
+
+    if if_match.is_none() && if_none_match.is_none() {
+        return Ok(true);
+    }
+
+    let current = match backend.thread.read(path.to_path_buf()) {
+        Ok(data) => Some(etag(&data.to_linear_vec())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+
+    Ok(match (if_match, current) {
+        (Some(expected), Some(current)) => expected.as_bytes() == current.as_bytes(),
+        (Some(_), None) => false,
+        (None, current) => if_none_match.map(|v| v.as_bytes() != b"*").unwrap_or(true) || current.is_none(),
+    })
+}
+
+/// Records a finished store into the catalog of snapshots.
+#[post("/catalog")]
+pub async fn record_catalog(request: HttpRequest, body: web::Json<CatalogEntry>) -> impl Responder {
+    trace!("record_catalog {:?}", *body);
+
+    if let Some(refusal) = write_refusal(request.headers()) {
+        return refusal;
+    }
+
+    match catalog::record(&body) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            warn!("Error while recording {:?} into catalog: {}", body.name, e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+        }
+    }
+}
+
+#[delete("/remove")]
+pub async fn remove(request: HttpRequest, query: web::Query<PathQuery>) -> impl Responder {
+    trace!("remove {:?}", *query);
+
+    if let Some(refusal) = write_refusal(request.headers())
+        .or_else(|| auth::internal_refusal(&query.path))
+        .or_else(|| auth::name_refusal(request.headers(), &query.path))
+    {
+        return Ok(refusal);
+    }
+
+    if auth::append_only_applies(request.headers()) {
+        warn!("Refusing to remove {:?} in append-only mode", query.path);
+        return HttpResponse::Forbidden().body(APPEND_ONLY_MESSAGE).await;
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return Ok(backend_pool::unavailable()),
+    };
+
+    let is_name = ObjectType::of(&query.path) == ObjectType::Name;
+
+    if is_name {
+        match retention::is_retained(&mut backend, &query.path) {
+            Ok(true) => {
+                warn!("Refusing to remove retained name {:?}", query.path);
+                return HttpResponse::Forbidden().body("Name is under retention").await;
+            }
+            Ok(false) => (),
+            Err(e) => {
+                warn!("Error while reading retention of {:?}: {}", query.path, e);
+                return HttpResponse::InternalServerError().body(format!("Error: {:?}", e)).await;
+            }
+        }
+    }
+
+    match backend.thread.remove(query.path.clone()) {
+        Ok(_) => {
+            if is_name {
+                if let Err(e) = retention::clear(&mut backend, &query.path) {
+                    warn!("Could not clear retention of removed name {:?}: {}", query.path, e);
+                }
+            }
+
+            HttpResponse::Ok().finish()
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
         Err(e) => {
-            warn!("Error while writing path {:?}: {}", path, e);
+            warn!("Error while removing {:?}: {}", query.path, e);
             HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
         }
     }
     .await
 }
 
+/// Why directory `path` can't be removed, if it can't: only whole generation directories other than the newest one are
+/// (rdedup GC removes the old ones once it moved the objects still in use), never names or server internals.
+fn remove_dir_refusal(path: &Path) -> Option<HttpResponse> {
+    let generation = match path.components().collect::<Vec<_>>().as_slice() {
+        [Component::Normal(name)] => name.to_str().filter(|name| paths::is_generation_dir(name)),
+        _ => None,
+    };
+
+    let generation = match generation {
+        Some(generation) if ObjectType::of(path) == ObjectType::Other => generation,
+        _ => {
+            warn!("Refusing to remove {:?}, not a generation directory", path);
+            return Some(HttpResponse::BadRequest().body("Only generation directories can be removed"));
+        }
+    };
+
+    match generations::current() {
+        Ok(current) if current.last().map(String::as_str) == Some(generation) => {
+            warn!("Refusing to remove the newest generation {}", generation);
+            Some(HttpResponse::Conflict().body(format!("Generation {} is the newest one", generation)))
+        }
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Could not list generations: {}", e);
+            Some(HttpResponse::InternalServerError().body(format!("Error: {:?}", e)))
+        }
+    }
+}
+
+#[delete("/remove-dir")]
+pub async fn remove_dir(request: HttpRequest, query: web::Query<PathQuery>) -> impl Responder {
+    trace!("remove dir {:?}", *query);
+
+    if let Some(refusal) = write_refusal(request.headers()) {
+        return refusal;
+    }
+
+    if auth::append_only_applies(request.headers()) {
+        warn!("Refusing to remove {:?} in append-only mode", query.path);
+        return HttpResponse::Forbidden().body(APPEND_ONLY_MESSAGE);
+    }
+
+    // generations are shared by all the namespaces
+    if auth::namespace(request.headers()).is_some() {
+        warn!("Refusing to remove {:?} with a namespaced token", query.path);
+        return HttpResponse::Forbidden().body("Namespaced tokens can't remove directories");
+    }
+
+    if let Some(refusal) = remove_dir_refusal(&query.path) {
+        return refusal;
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable(),
+    };
+
+    debug!("Removing directory {:?}", query.path);
+
+    match backend.thread.remove_dir_all(query.path.clone()) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            warn!("Error while removing directory {:?}: {}", query.path, e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+        }
+    }
+}
+
+#[get("/locks")]
+pub async fn list_locks() -> impl Responder {
+    trace!("list_locks");
+
+    HttpResponse::Ok().json(locks::status())
+}
+
 #[put("/lock-shared")]
-pub async fn lock_shared_add() -> impl Responder {
+pub async fn lock_shared_add(request: HttpRequest) -> impl Responder {
     trace!("lock shared add");
 
-    let backend = backend_pool::pull().expect("Unavailable backend thread");
+    if let Some(refusal) = request_signing::refusal(&request, &[]) {
+        return refusal;
+    }
+
+    if let Some(refusal) = maintenance::refusal() {
+        return refusal;
+    }
+
+    if let Some(exclusive) = locks::exclusive() {
+        debug!("Refusing shared lock, repository is exclusively locked by {}", exclusive.holder);
+        return HttpResponse::build(StatusCode::LOCKED).json(exclusive);
+    }
+
+    let holder = request.connection_info().realip_remote_addr().unwrap_or("unknown").to_string();
 
-    // TODO save shared lock to prevent dropping!
-    match backend.lock_shared() {
-        Ok(_) => HttpResponse::Created().json(SharedLockResponse { lock_id: Uuid::new_v4() }),
+    match locks::add_shared(holder) {
+        Ok(lock_id) => HttpResponse::Created().json(SharedLockResponse {
+            lock_id,
+            ttl_ms: Some(locks::lease().as_millis() as u64),
+        }),
+        // exclusively locked meanwhile
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => match locks::exclusive() {
+            Some(exclusive) => HttpResponse::build(StatusCode::LOCKED).json(exclusive),
+            None => HttpResponse::ServiceUnavailable().body("Repository lock is contended, try again"),
+        },
         Err(e) => {
             warn!("Error while creating shared lock: {}", e);
             HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
@@ -139,9 +926,117 @@ pub async fn lock_shared_add() -> impl Responder {
     }
 }
 
+/// Extends lease of a shared lock; clients renew their locks based on the returned TTL, not on their own clocks.
+#[post("/lock-shared/renew")]
+pub async fn lock_shared_renew(request: HttpRequest, query: web::Query<UnlockQuery>) -> impl Responder {
+    trace!("lock shared renew {:?}", *query);
+
+    if let Some(refusal) = request_signing::refusal(&request, &[]) {
+        return refusal;
+    }
+
+    match locks::renew_shared(&query.lock_id) {
+        Some(ttl) => HttpResponse::Ok().json(SharedLockResponse {
+            lock_id: query.lock_id,
+            ttl_ms: Some(ttl.as_millis() as u64),
+        }),
+        None => {
+            warn!("Renewal of unknown (expired) shared lock {}", query.lock_id);
+            HttpResponse::NotFound().body("Lock expired")
+        }
+    }
+}
+
 #[delete("/lock-shared")]
-pub async fn lock_shared_remove(query: web::Query<UnlockQuery>) -> impl Responder {
+pub async fn lock_shared_remove(request: HttpRequest, query: web::Query<UnlockQuery>) -> impl Responder {
     trace!("lock shared remove {:?}", *query);
 
-    HttpResponse::Ok()
+    if let Some(refusal) = request_signing::refusal(&request, &[]) {
+        return refusal;
+    }
+
+    if !locks::remove_shared(&query.lock_id) {
+        debug!("Removing unknown shared lock {}", query.lock_id);
+    }
+
+    HttpResponse::Ok().finish()
+}
+
+/// Renews exclusive lock `lock_id`, telling the client whether it's drained already; `status` of the response when it's
+/// renewed.
+fn exclusive_lock_response(lock_id: Uuid, status: StatusCode) -> HttpResponse {
+    match locks::renew_exclusive(&lock_id) {
+        Ok(Some((ttl, state))) => HttpResponse::build(status).json(ExclusiveLockResponse {
+            lock_id,
+            ttl_ms: Some(ttl.as_millis() as u64),
+            state,
+        }),
+        Ok(None) => {
+            warn!("Renewal of unknown (expired) exclusive lock {}", lock_id);
+            HttpResponse::NotFound().body("Lock expired")
+        }
+        Err(e) => {
+            warn!("Error while locking the repository exclusively: {}", e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+        }
+    }
+}
+
+/// Requests the exclusive lock for a client (e.g. GC over the remote backend). The repository is `Draining` until
+/// holders of shared locks finish, the client renews the lock (see `/lock-exclusive/renew`) until it's
+/// `ExclusiveHeld`.
+#[put("/lock-exclusive")]
+pub async fn lock_exclusive_add(request: HttpRequest) -> impl Responder {
+    trace!("lock exclusive add");
+
+    if let Some(refusal) = request_signing::refusal(&request, &[]) {
+        return refusal;
+    }
+
+    if let Some(refusal) = maintenance::write_refusal() {
+        return refusal;
+    }
+
+    // the lock is for rewriting the repository, others would only block everyone
+    if !matches!(auth::role(request.headers()), Role::ReadWrite | Role::Admin) || auth::append_only_applies(request.headers()) {
+        warn!("Refusing exclusive lock to a token which can't modify the repository");
+        return HttpResponse::Forbidden().body("Exclusive lock requires a read-write token");
+    }
+
+    let holder = request.connection_info().realip_remote_addr().unwrap_or("unknown").to_string();
+
+    match locks::add_exclusive(holder) {
+        Ok(lock_id) => exclusive_lock_response(lock_id, StatusCode::CREATED),
+        Err(exclusive) => {
+            debug!("Refusing exclusive lock, repository is exclusively locked by {}", exclusive.holder);
+            HttpResponse::build(StatusCode::LOCKED).json(exclusive)
+        }
+    }
+}
+
+/// Extends lease of a client's exclusive lock, reporting whether shared locks are released already.
+#[post("/lock-exclusive/renew")]
+pub async fn lock_exclusive_renew(request: HttpRequest, query: web::Query<UnlockQuery>) -> impl Responder {
+    trace!("lock exclusive renew {:?}", *query);
+
+    if let Some(refusal) = request_signing::refusal(&request, &[]) {
+        return refusal;
+    }
+
+    exclusive_lock_response(query.lock_id, StatusCode::OK)
+}
+
+#[delete("/lock-exclusive")]
+pub async fn lock_exclusive_remove(request: HttpRequest, query: web::Query<UnlockQuery>) -> impl Responder {
+    trace!("lock exclusive remove {:?}", *query);
+
+    if let Some(refusal) = request_signing::refusal(&request, &[]) {
+        return refusal;
+    }
+
+    if !locks::remove_exclusive(&query.lock_id) {
+        debug!("Removing unknown exclusive lock {}", query.lock_id);
+    }
+
+    HttpResponse::Ok().finish()
 }
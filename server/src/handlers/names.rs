@@ -0,0 +1,105 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use libcommon::paths::{NAMES_DIR, PENDING_DIR};
+use libcommon::structs::{NameInfo, NamesResponse};
+use log::*;
+use serde::Deserialize;
+
+use crate::auth;
+use crate::backend_pool;
+use crate::backend_pool::PooledBackend;
+use crate::handlers::NAME_COMMIT;
+use crate::retention;
+
+#[derive(Debug, Deserialize)]
+pub struct NamesQuery {
+    /// Lists also names staged by clients and not committed (yet), for diagnostics of admins
+    #[serde(default, rename = "include-pending")]
+    pub include_pending: bool,
+}
+
+/// Info of name `entry` listed in directory `dir` - the names one, or that of the staged ones when `pending`.
+fn name_info(backend: &mut PooledBackend, dir: &Path, entry: &Path, pending: bool) -> io::Result<NameInfo> {
+    // the backend may return either bare file names or full paths
+    let file_name = entry.file_name().unwrap_or_else(|| entry.as_os_str());
+    let path = PathBuf::from(NAMES_DIR).join(file_name);
+
+    let metadata = backend.thread.read_metadata(dir.join(file_name))?;
+
+    Ok(NameInfo {
+        name: Path::new(file_name).file_stem().unwrap_or(file_name).to_string_lossy().to_string(),
+        size: metadata.len,
+        retain_until: retention::get(backend, &path)?,
+        path,
+        pending,
+    })
+}
+
+fn list_dir(backend: &mut PooledBackend, dir: &Path, pending: bool) -> io::Result<Vec<NameInfo>> {
+    let entries = match backend.thread.list(dir.to_path_buf()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut names = Vec::with_capacity(entries.len());
+
+    for entry in entries {
+        match name_info(backend, dir, &entry, pending) {
+            Ok(info) => names.push(info),
+            // removed (or committed) concurrently
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(names)
+}
+
+/// Stored names with their sizes and retention, and the staged ones when `include_pending`.
+///
+/// Commits can't run meanwhile, so the listing never shows a name half-committed - e.g. with the retention of its new
+/// version, but still the old version.
+fn snapshot(backend: &mut PooledBackend, include_pending: bool) -> io::Result<Vec<NameInfo>> {
+    let _commit = NAME_COMMIT.lock().unwrap();
+
+    let mut names = list_dir(backend, Path::new(NAMES_DIR), false)?;
+
+    if include_pending {
+        names.extend(list_dir(backend, &Path::new(PENDING_DIR).join(NAMES_DIR), true)?);
+    }
+
+    Ok(names)
+}
+
+/// All committed names with their sizes and retention.
+pub fn list(backend: &mut PooledBackend) -> io::Result<Vec<NameInfo>> {
+    snapshot(backend, false)
+}
+
+#[get("/names")]
+pub async fn list_names(request: HttpRequest, query: web::Query<NamesQuery>) -> impl Responder {
+    trace!("list_names {:?}", *query);
+
+    if query.include_pending && !auth::is_admin(request.headers()) {
+        return HttpResponse::Forbidden().body("Admin token required").await;
+    }
+
+    let mut backend = match backend_pool::pull().await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable().await,
+    };
+
+    match snapshot(&mut backend, query.include_pending) {
+        Ok(mut names) => {
+            names.retain(|name| auth::may_use_name(request.headers(), &name.path));
+            HttpResponse::Ok().json(NamesResponse { names }).await
+        }
+        Err(e) => {
+            warn!("Error while listing names: {}", e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e)).await
+        }
+    }
+}
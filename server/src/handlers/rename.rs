@@ -0,0 +1,130 @@
+use std::io;
+
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use libcommon::paths::ObjectType;
+use libcommon::structs::{RenameBatchRequest, RenameBatchResponse, RenameEntry, RenameResult};
+use log::*;
+
+use crate::auth;
+use crate::backend_pool;
+use crate::backend_pool::PooledBackend;
+use crate::config;
+use crate::handlers::{object_exists, write_refusal, APPEND_ONLY_MESSAGE};
+use crate::retention;
+
+fn rename_one(backend: &mut PooledBackend, entry: &RenameEntry) -> io::Result<()> {
+    if ObjectType::of(&entry.from) == ObjectType::Name && retention::is_retained(backend, &entry.from)? {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Name is under retention"));
+    }
+
+    // the rename would replace the target
+    let to_type = ObjectType::of(&entry.to);
+
+    if to_type == ObjectType::Name && retention::is_retained(backend, &entry.to)? {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Target name is under retention"));
+    }
+
+    // only admins get here in append-only mode; content-addressed targets hold the same data, names and config don't
+    if config::get().append_only && matches!(to_type, ObjectType::Name | ObjectType::Config) && object_exists(backend, &entry.to) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, APPEND_ONLY_MESSAGE));
+    }
+
+    backend.thread.rename(entry.from.clone(), entry.to.clone())
+}
+
+/// Renames a single object, atomically on the local filesystem.
+#[post("/rename")]
+pub async fn rename(request: HttpRequest, body: web::Json<RenameEntry>) -> impl Responder {
+    trace!("rename {:?} -> {:?}", body.from, body.to);
+
+    if let Some(refusal) = write_refusal(request.headers()) {
+        return refusal;
+    }
+
+    if auth::append_only_applies(request.headers()) {
+        warn!("Refusing to rename in append-only mode");
+        return HttpResponse::Forbidden().body(APPEND_ONLY_MESSAGE);
+    }
+
+    if let Some(refusal) = auth::internal_refusal(&body.from)
+        .or_else(|| auth::internal_refusal(&body.to))
+        .or_else(|| auth::name_refusal(request.headers(), &body.from))
+        .or_else(|| auth::name_refusal(request.headers(), &body.to))
+    {
+        return refusal;
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable(),
+    };
+
+    match rename_one(&mut backend, &body) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HttpResponse::NotFound().finish(),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!("Refusing to rename {:?}: {}", body.from, e);
+            HttpResponse::Forbidden().body(e.to_string())
+        }
+        Err(e) => {
+            warn!("Error while renaming {:?} to {:?}: {}", body.from, body.to, e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+        }
+    }
+}
+
+/// Renames many objects in one request (e.g. chunks moved to a new generation during GC).
+///
+/// Each rename succeeds or fails on its own; the response carries result of every one of them.
+#[post("/rename-batch")]
+pub async fn rename_batch(request: HttpRequest, body: web::Json<RenameBatchRequest>) -> impl Responder {
+    trace!("rename batch of {} entries", body.renames.len());
+
+    if let Some(refusal) = write_refusal(request.headers()) {
+        return refusal;
+    }
+
+    if auth::append_only_applies(request.headers()) {
+        warn!("Refusing to rename in append-only mode");
+        return HttpResponse::Forbidden().body(APPEND_ONLY_MESSAGE);
+    }
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable(),
+    };
+
+    let results = body
+        .into_inner()
+        .renames
+        .into_iter()
+        .map(|entry| {
+            let result = if !auth::may_modify(&entry.from) || !auth::may_modify(&entry.to) {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Server internals can't be modified",
+                ))
+            } else if auth::may_use_name(request.headers(), &entry.from) && auth::may_use_name(request.headers(), &entry.to) {
+                rename_one(&mut backend, &entry)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Name outside the namespace of the token",
+                ))
+            };
+
+            if let Err(e) = &result {
+                debug!("Could not rename {:?} to {:?}: {}", entry.from, entry.to, e);
+            }
+
+            RenameResult {
+                not_found: matches!(&result, Err(e) if e.kind() == io::ErrorKind::NotFound),
+                error: result.err().map(|e| e.to_string()),
+                from: entry.from,
+                to: entry.to,
+            }
+        })
+        .collect();
+
+    HttpResponse::Ok().json(RenameBatchResponse { results })
+}
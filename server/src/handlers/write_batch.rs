@@ -0,0 +1,118 @@
+use std::convert::TryFrom;
+
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use actix_web::http::HeaderMap;
+use actix_web::{error, post, web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
+use libcommon::structs::{WriteBatchEntry, WriteBatchRequest, WriteBatchResponse, WriteResult, GENERATION_HEADER, SIGNATURE_HEADER};
+use log::*;
+
+use crate::backend_pool;
+use crate::backend_pool::PooledBackend;
+use crate::config;
+use crate::handlers::{check_write, is_hash_mismatch, store_object, write_refusal, WriteCheck};
+use crate::upload::Upload;
+
+/// Headers of a single write of `entry`, so the batch goes through the very same checks.
+fn entry_headers(request: &HeaderMap, entry: &WriteBatchEntry) -> Result<HeaderMap, error::Error> {
+    let value = |v: &str| HeaderValue::try_from(v).map_err(|_| error::ErrorBadRequest("Invalid write batch entry"));
+
+    let mut headers = request.clone();
+    headers.remove("pending");
+    headers.remove(SIGNATURE_HEADER);
+    headers.remove(GENERATION_HEADER);
+
+    let path = entry.path.to_str().ok_or_else(|| error::ErrorBadRequest("Invalid path"))?;
+    headers.insert(HeaderName::from_static("path"), value(path)?);
+    headers.insert(HeaderName::from_static("hash"), value(&entry.hash)?);
+    headers.insert(CONTENT_LENGTH, value(&entry.len.to_string())?);
+
+    if let Some(signature) = &entry.signature {
+        headers.insert(HeaderName::from_static(SIGNATURE_HEADER), value(signature)?);
+    }
+
+    if let Some(generation) = &entry.generation {
+        headers.insert(HeaderName::from_static(GENERATION_HEADER), value(generation)?);
+    }
+
+    Ok(headers)
+}
+
+fn write_one(request: &HeaderMap, backend: &mut PooledBackend, entry: &WriteBatchEntry, data: &[u8]) -> Result<(), error::Error> {
+    let headers = entry_headers(request, entry)?;
+
+    match check_write(&headers, backend)? {
+        WriteCheck::Accept => {
+            let mut upload = Upload::new(&headers, data.len());
+            upload.push(data).map_err(error::ErrorInternalServerError)?;
+            store_object(&headers, entry.path.clone(), false, upload)
+        }
+        WriteCheck::Skip => {
+            trace!("Object {:?} already exists, skipping write", entry.path);
+            Ok(())
+        }
+    }
+}
+
+/// Writes many small objects (e.g. indexes of a large file) in one request.
+///
+/// Each write succeeds or fails on its own; the response carries result of every one of them.
+#[post("/write-batch")]
+pub async fn write_batch(request: HttpRequest, mut payload: web::Payload) -> impl Responder {
+    if let Some(refusal) = write_refusal(request.headers()) {
+        return Ok(refusal);
+    }
+
+    let max_size = config::get().body_limits.batch;
+    let mut body = Vec::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) > max_size {
+            return Err(error::ErrorPayloadTooLarge(format!("Max {}B batch supported", max_size)));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let header_end = body
+        .iter()
+        .position(|b| *b == b'\n')
+        .ok_or_else(|| error::ErrorBadRequest("Missing write batch header"))?;
+    let batch: WriteBatchRequest = serde_json::from_slice(&body[..header_end]).map_err(error::ErrorBadRequest)?;
+
+    let mut data = &body[header_end + 1..];
+
+    if batch.writes.iter().map(|w| w.len).sum::<usize>() != data.len() {
+        return Err(error::ErrorBadRequest("Write batch data don't match its header"));
+    }
+
+    trace!("write batch of {} entries", batch.writes.len());
+
+    let mut backend = match backend_pool::pull_for(request.headers()).await {
+        Some(backend) => backend,
+        None => return Ok(backend_pool::unavailable()),
+    };
+
+    let results = batch
+        .writes
+        .into_iter()
+        .map(|entry| {
+            let (object, rest) = data.split_at(entry.len);
+            data = rest;
+
+            let result = write_one(request.headers(), &mut backend, &entry, object);
+
+            if let Err(e) = &result {
+                debug!("Could not write {:?}: {}", entry.path, e);
+            }
+
+            WriteResult {
+                hash_mismatch: matches!(&result, Err(e) if is_hash_mismatch(e)),
+                error: result.err().map(|e| e.to_string()),
+                path: entry.path,
+            }
+        })
+        .collect();
+
+    HttpResponse::Ok().json(WriteBatchResponse { results }).await
+}
@@ -0,0 +1,169 @@
+//! HTTP/3 (QUIC) listener - its loss recovery helps long uploads over lossy connections.
+//!
+//! Requests are forwarded to the HTTP/1.1 listener over loopback, so all the handlers (and their checks) are shared.
+//! QUIC needs tokio 1, the listener runs on its own runtime next to the actix one.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use bytes::{Buf, Bytes};
+use err_context::AnyError;
+use futures::StreamExt;
+use h3::quic::BidiStream;
+use h3::server::RequestStream;
+use http::header::{HeaderName, CONNECTION, TRANSFER_ENCODING};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use log::*;
+use once_cell::sync::OnceCell;
+
+use crate::config::Http3;
+
+type BackendClient = hyper::Client<HttpConnector>;
+
+/// Port of the running listener.
+static PORT: OnceCell<u16> = OnceCell::new();
+
+/// Connection-specific headers, forbidden in HTTP/3.
+const HOP_BY_HOP_HEADERS: [HeaderName; 2] = [CONNECTION, TRANSFER_ENCODING];
+
+fn tls_config(config: &Http3) -> Result<rustls::ServerConfig, AnyError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&config.cert)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(&config.key)?))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| AnyError::from(format!("No PKCS#8 private key found in {:?}", config.key)))?;
+
+    let mut tls = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, rustls::PrivateKey(key))?;
+    tls.alpn_protocols = vec![b"h3".to_vec()];
+
+    Ok(tls)
+}
+
+/// Starts the listener in a background thread; `backend` is the address of the HTTP/1.1 listener.
+pub fn start(config: &Http3, backend: SocketAddr) -> Result<(), AnyError> {
+    let server_config = quinn::ServerConfig::with_crypto(Arc::new(tls_config(config)?));
+    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+
+    let runtime = tokio1::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("http3")
+        .build()?;
+
+    // the socket must be registered with the runtime it's polled by
+    let (endpoint, mut incoming) = {
+        let _guard = runtime.enter();
+        quinn::Endpoint::server(server_config, addr)?
+    };
+
+    info!("Starting HTTP/3 listener on {}", addr);
+
+    thread::Builder::new().name("http3".to_string()).spawn(move || {
+        let client = BackendClient::new();
+
+        runtime.block_on(async move {
+            while let Some(connecting) = incoming.next().await {
+                let client = client.clone();
+
+                tokio1::spawn(async move {
+                    if let Err(e) = serve_connection(connecting, client, backend).await {
+                        debug!("HTTP/3 connection ended: {}", e);
+                    }
+                });
+            }
+
+            drop(endpoint);
+        })
+    })?;
+
+    let _ = PORT.set(config.port);
+
+    Ok(())
+}
+
+/// Port clients may connect to, once the listener is running.
+pub fn port() -> Option<u16> {
+    PORT.get().copied()
+}
+
+async fn serve_connection(connecting: quinn::Connecting, client: BackendClient, backend: SocketAddr) -> Result<(), AnyError> {
+    let connection = connecting.await?;
+    let remote = connection.connection.remote_address();
+
+    debug!("HTTP/3 connection from {}", remote);
+
+    let mut h3_conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await?;
+
+    while let Some((request, stream)) = h3_conn.accept().await? {
+        let client = client.clone();
+
+        tokio1::spawn(async move {
+            if let Err(e) = forward(request, stream, client, backend, remote).await {
+                warn!("Error while forwarding HTTP/3 request from {}: {}", remote, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn forward<S>(
+    request: http::Request<()>,
+    mut stream: RequestStream<S, Bytes>,
+    client: BackendClient,
+    backend: SocketAddr,
+    remote: SocketAddr,
+) -> Result<(), AnyError>
+where
+    S: BidiStream<Bytes>,
+{
+    trace!("HTTP/3 {} {}", request.method(), request.uri());
+
+    // written objects are limited in size (see body limits), buffering them is fine
+    let mut body = Vec::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        body.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+
+    let (parts, ()) = request.into_parts();
+    let path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+
+    let mut backend_request = hyper::Request::builder()
+        .method(parts.method)
+        .uri(format!("http://{}{}", backend, path));
+
+    for (name, value) in &parts.headers {
+        backend_request = backend_request.header(name, value);
+    }
+
+    // the handlers identify clients (e.g. lock holders) by their address
+    backend_request = backend_request.header("x-forwarded-for", remote.ip().to_string());
+
+    let response = client.request(backend_request.body(hyper::Body::from(body))?).await?;
+    let (mut parts, mut body) = response.into_parts();
+
+    for header in &HOP_BY_HOP_HEADERS {
+        parts.headers.remove(header);
+    }
+
+    stream.send_response(http::Response::from_parts(parts, ())).await?;
+
+    // streamed responses (listings, GC events) are passed on as they come
+    while let Some(data) = body.data().await {
+        stream.send_data(data?).await?;
+    }
+
+    stream.finish().await?;
+
+    Ok(())
+}
@@ -0,0 +1,808 @@
+//! Concurrency model of the repository.
+//!
+//! Clients work with the repository under shared locks, jobs rewriting it (GC of the server, or of a client over the
+//! remote backend) under the exclusive one. The state (see [`RepoState`]) is derived from the locks held, and only these transitions are possible:
+//!
+//! - `Idle` / `SharedHeld` -> `SharedHeld` - a shared lock is taken
+//! - `SharedHeld` -> `SharedHeld` / `Idle` - a shared lock is released (or expires)
+//! - `Idle` -> `ExclusiveHeld` - the exclusive lock is taken right away
+//! - `SharedHeld` -> `Draining` - the exclusive lock is requested; no new shared locks are handed out, the existing
+//!   holders finish their work
+//! - `Draining` -> `Draining` / `ExclusiveHeld` - shared locks are released, the last one hands over to the exclusive
+//! - `ExclusiveHeld` -> `Idle` - the exclusive lock is released
+//!
+//! Anything else is refused - shared locks while `Draining` or `ExclusiveHeld`, a second exclusive lock, and any writes
+//! while `ExclusiveHeld` (they would race with the job rewriting the repository), except those of the client holding
+//! the lock (see `EXCLUSIVE_LOCK_HEADER`). Exclusive locks of clients expire like the shared ones, so a crashed client
+//! doesn't lock the repository forever.
+//!
+//! Locks of clients are kept in memory; with `persist_locks`, they're also written into [`REGISTRY_FILE`] and restored
+//! once the server restarts, so clients working with the repository meanwhile keep their locks.
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::{HeaderMap, StatusCode};
+use actix_web::HttpResponse;
+use libcommon::structs::{LockHolder, LocksResponse, RepoState, EXCLUSIVE_LOCK_HEADER};
+use log::*;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::backend_pool;
+use crate::config;
+use crate::retention;
+
+/// Lock file of rdedup's local backend. Shared locks of the clients hold it too, so plain rdedup processes working
+/// with the repository directly (e.g. `rdedup gc`) and clients of this server respect each other.
+pub const RDEDUP_LOCK_FILE: &str = "lock";
+
+/// File in the data directory locks of clients are persisted in, see `persist_locks`.
+pub const REGISTRY_FILE: &str = ".locks.json";
+
+const DEFAULT_LEASE: Duration = Duration::from_secs(300);
+
+/// How often a draining exclusive lock checks for expired shared locks.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+struct SharedLock {
+    holder: LockHolder,
+    /// Monotonic, so changes of the wall clock (on either side) don't release locks early or keep them forever
+    expires: Instant,
+    /// The on-disk lock is released once the file is closed
+    _file: File,
+}
+
+struct ExclusiveLock {
+    holder: LockHolder,
+    /// Locks of clients expire unless renewed, those of server jobs are held until released
+    expires: Option<Instant>,
+    /// On-disk lock of a client lock, taken once drained; rdedup's local backend takes its own for server jobs
+    file: Option<File>,
+}
+
+#[derive(Default)]
+struct Locks {
+    /// Shared locks handed out to clients and not released yet
+    shared: HashMap<Uuid, SharedLock>,
+    /// Job holding (or waiting for) the exclusive lock
+    exclusive: Option<ExclusiveLock>,
+    /// Locks of clients changed since they were last persisted
+    changed: bool,
+    /// Incremented with each persisted snapshot, see `persist`
+    generation: u64,
+}
+
+impl Locks {
+    fn state(&self) -> RepoState {
+        match (&self.exclusive, self.shared.len()) {
+            (None, 0) => RepoState::Idle,
+            (None, count) => RepoState::SharedHeld { count },
+            (Some(_), 0) => RepoState::ExclusiveHeld,
+            (Some(_), count) => RepoState::Draining { count },
+        }
+    }
+
+    /// Refuses a shared lock while the exclusive one is held or requested (`Draining`, `ExclusiveHeld`).
+    fn check_shared(&self) -> io::Result<()> {
+        match &self.exclusive {
+            Some(exclusive) => {
+                debug!(
+                    "Refusing shared lock in state {:?}, requested by {}",
+                    self.state(),
+                    exclusive.holder.holder
+                );
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "Repository is locked exclusively"))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Refuses a second exclusive lock, failing with the holder of the first one.
+    fn check_exclusive(&self) -> Result<(), LockHolder> {
+        match &self.exclusive {
+            Some(exclusive) => Err(exclusive.holder.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    /// Releases locks expired at `now`.
+    fn expire_at(&mut self, now: Instant) {
+        self.shared.retain(|lock_id, lock| {
+            let alive = lock.expires > now;
+            if !alive {
+                warn!("Releasing expired shared lock {} of {}", lock_id, lock.holder.holder);
+            }
+            alive
+        });
+
+        if let Some(exclusive) = &self.exclusive {
+            if exclusive.expires.map(|expires| expires <= now).unwrap_or(false) {
+                warn!("Releasing expired exclusive lock of {}", exclusive.holder.holder);
+                self.exclusive = None;
+            }
+        }
+    }
+}
+
+static LOCKS: Lazy<Mutex<Locks>> = Lazy::new(|| Mutex::new(Locks::default()));
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedLock {
+    holder: LockHolder,
+    /// Unix timestamp (milliseconds) the lock expires at unless renewed
+    expires_ms: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Registry {
+    shared: Vec<PersistedLock>,
+    exclusive: Option<PersistedLock>,
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+fn write_registry(registry: &Registry) -> io::Result<()> {
+    let path = backend_pool::data_dir().join(REGISTRY_FILE);
+    let temp = path.with_extension("tmp");
+
+    let data = serde_json::to_vec(registry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(&temp, data)?;
+    fs::rename(&temp, &path)
+}
+
+/// Generation of the registry written last, see `persist`.
+static WRITTEN: Lazy<Mutex<u64>> = Lazy::new(|| Mutex::new(0));
+
+fn registry(locks: &Locks) -> Registry {
+    let (now, now_ms) = (Instant::now(), now_ms());
+    let persisted = |holder: &LockHolder, expires: Instant| PersistedLock {
+        holder: holder.clone(),
+        expires_ms: now_ms + expires.saturating_duration_since(now).as_millis() as u64,
+    };
+
+    Registry {
+        shared: locks.shared.values().map(|l| persisted(&l.holder, l.expires)).collect(),
+        // server jobs don't survive restarts
+        exclusive: locks.exclusive.as_ref().and_then(|e| Some(persisted(&e.holder, e.expires?))),
+    }
+}
+
+/// Releases `locks`, writing locks of clients into the registry file when they changed and they're persisted. The file
+/// is written once the locks are released, renewals come all the time and must not wait for the disk of each other.
+fn persist(mut locks: MutexGuard<'static, Locks>) {
+    if !locks.changed || !config::get().persist_locks {
+        return;
+    }
+
+    locks.changed = false;
+    locks.generation += 1;
+    let (generation, registry) = (locks.generation, registry(&locks));
+    drop(locks);
+
+    // snapshots may come out of order, a newer one is written already then
+    let mut written = WRITTEN.lock().unwrap();
+    if *written > generation {
+        return;
+    }
+
+    if let Err(e) = write_registry(&registry) {
+        warn!("Could not persist locks into {}: {}", REGISTRY_FILE, e);
+    }
+    *written = generation;
+}
+
+/// Restores locks of clients persisted before the server restarted; those expired meanwhile are dropped.
+pub fn restore() {
+    if !config::get().persist_locks {
+        return;
+    }
+
+    let path = backend_pool::data_dir().join(REGISTRY_FILE);
+
+    let registry: Registry = match fs::read(&path) {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(registry) => registry,
+            Err(e) => {
+                warn!("Ignoring invalid lock registry {:?}: {}", path, e);
+                return;
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("Could not read lock registry {:?}: {}", path, e);
+            return;
+        }
+    };
+
+    let (now, now_ms) = (Instant::now(), now_ms());
+    let expires = |lock: &PersistedLock| (lock.expires_ms > now_ms).then(|| now + Duration::from_millis(lock.expires_ms - now_ms));
+
+    let mut locks = LOCKS.lock().unwrap();
+
+    for lock in registry.shared {
+        let (lock_id, expires) = match (lock.holder.lock_id, expires(&lock)) {
+            (Some(lock_id), Some(expires)) => (lock_id, expires),
+            _ => continue,
+        };
+
+        match lock_on_disk() {
+            Ok(file) => {
+                locks.shared.insert(
+                    lock_id,
+                    SharedLock {
+                        holder: lock.holder,
+                        expires,
+                        _file: file,
+                    },
+                );
+            }
+            Err(e) => warn!("Could not restore shared lock {} of {}: {}", lock_id, lock.holder.holder, e),
+        }
+    }
+
+    if let Some(lock) = registry.exclusive {
+        if let Some(expires) = expires(&lock) {
+            // the lock file gets locked again once renewed
+            locks.exclusive = Some(ExclusiveLock {
+                holder: lock.holder,
+                expires: Some(expires),
+                file: None,
+            });
+        }
+    }
+
+    info!(
+        "Restored {} shared and {} exclusive lock(s) of clients",
+        locks.shared.len(),
+        locks.exclusive.iter().count()
+    );
+
+    locks.changed = true;
+    persist(locks);
+}
+
+/// Signalled whenever a shared lock is released.
+static RELEASED: Lazy<Condvar> = Lazy::new(Condvar::new);
+
+/// Time a shared lock is held without renewal.
+pub fn lease() -> Duration {
+    config::get().lock_lease_secs.map(Duration::from_secs).unwrap_or(DEFAULT_LEASE)
+}
+
+/// Locks with the expired shared ones released.
+fn locks() -> MutexGuard<'static, Locks> {
+    let mut locks = LOCKS.lock().unwrap();
+
+    let count = locks.shared.len();
+    let exclusive = locks.exclusive.is_some();
+    locks.expire();
+    if locks.shared.len() != count {
+        RELEASED.notify_all();
+    }
+    if locks.shared.len() != count || locks.exclusive.is_some() != exclusive {
+        locks.changed = true;
+    }
+
+    locks
+}
+
+/// Takes lock of the lock file (`libc::LOCK_SH` or `libc::LOCK_EX`) without waiting; fails with `WouldBlock` when it's
+/// locked in a conflicting way.
+fn lock_on_disk_as(operation: libc::c_int) -> io::Result<File> {
+    let path = backend_pool::data_dir().join(RDEDUP_LOCK_FILE);
+
+    // read-only data dir can be locked too, as long as the file exists
+    let file = match File::open(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => OpenOptions::new().write(true).create(true).open(&path)?,
+        result => result?,
+    };
+
+    // SAFETY: the descriptor is valid as long as the file is open
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(file)
+}
+
+/// Takes shared lock of the lock file without waiting; fails with `WouldBlock` when it's locked exclusively.
+fn lock_on_disk() -> io::Result<File> {
+    lock_on_disk_as(libc::LOCK_SH)
+}
+
+/// Holder of the on-disk exclusive lock, not taken through the server.
+fn direct_holder() -> LockHolder {
+    LockHolder {
+        lock_id: None,
+        holder: "rdedup process working with the repository directly".to_string(),
+        since: retention::now(),
+        ttl_ms: None,
+    }
+}
+
+/// Fails with `WouldBlock` when the repository is (about to be) locked exclusively.
+pub fn add_shared(holder: String) -> io::Result<Uuid> {
+    // expired locks may be the only ones keeping the on-disk lock
+    let mut locks = locks();
+    locks.check_shared()?;
+
+    let file = lock_on_disk()?;
+    let lock_id = Uuid::new_v4();
+
+    let lock = SharedLock {
+        holder: LockHolder {
+            lock_id: Some(lock_id),
+            holder,
+            since: retention::now(),
+            ttl_ms: None,
+        },
+        expires: Instant::now() + lease(),
+        _file: file,
+    };
+
+    locks.shared.insert(lock_id, lock);
+    locks.changed = true;
+    persist(locks);
+
+    Ok(lock_id)
+}
+
+/// Extends the lease of the lock, returning the time it's held for now; none when the lock expired already.
+pub fn renew_shared(lock_id: &Uuid) -> Option<Duration> {
+    let lease = lease();
+    let mut locks = locks();
+
+    let renewed = locks.shared.get_mut(lock_id).map(|lock| {
+        lock.expires = Instant::now() + lease;
+        lease
+    });
+    locks.changed |= renewed.is_some();
+    persist(locks);
+
+    renewed
+}
+
+pub fn remove_shared(lock_id: &Uuid) -> bool {
+    let mut locks = locks();
+    let removed = locks.shared.remove(lock_id).is_some();
+
+    if removed {
+        locks.changed = true;
+        persist(locks);
+        RELEASED.notify_all();
+    }
+
+    removed
+}
+
+/// Exclusive lock of a server job; released once dropped.
+pub struct ExclusiveGuard {
+    _private: (),
+}
+
+impl ExclusiveGuard {
+    /// Waits until all shared locks are released (or expire), i.e. the repository is `ExclusiveHeld`.
+    pub fn wait_drained(&self) {
+        let mut locks = locks();
+
+        while !locks.shared.is_empty() {
+            debug!("Waiting for {} shared locks to be released", locks.shared.len());
+
+            locks = RELEASED.wait_timeout(locks, DRAIN_POLL_INTERVAL).unwrap().0;
+            locks.expire();
+        }
+    }
+}
+
+impl Drop for ExclusiveGuard {
+    fn drop(&mut self) {
+        let exclusive = locks().exclusive.take().expect("Exclusive lock released twice");
+        debug!("Exclusive lock of {} released", exclusive.holder.holder);
+    }
+}
+
+/// Requests the exclusive lock for `holder`; the repository is `Draining` until shared locks are released, see
+/// [`ExclusiveGuard::wait_drained`].
+pub fn begin_exclusive(holder: &str) -> Result<ExclusiveGuard, LockHolder> {
+    let mut locks = locks();
+    locks.check_exclusive()?;
+
+    locks.exclusive = Some(ExclusiveLock {
+        holder: LockHolder {
+            lock_id: None,
+            holder: holder.to_string(),
+            since: retention::now(),
+            ttl_ms: None,
+        },
+        expires: None,
+        file: None,
+    });
+
+    debug!("Exclusive lock requested by {}, state {:?}", holder, locks.state());
+
+    Ok(ExclusiveGuard { _private: () })
+}
+
+/// Requests the exclusive lock for a client `holder`; the repository is `Draining` until shared locks are released,
+/// see [`renew_exclusive`]. Fails with the holder of the exclusive lock when there's one already.
+pub fn add_exclusive(holder: String) -> Result<Uuid, LockHolder> {
+    let mut locks = locks();
+    locks.check_exclusive()?;
+
+    if let Err(e) = lock_on_disk() {
+        if e.kind() == io::ErrorKind::WouldBlock {
+            return Err(direct_holder());
+        }
+    }
+
+    let lock_id = Uuid::new_v4();
+
+    debug!("Exclusive lock {} requested by {}, state {:?}", lock_id, holder, locks.state());
+
+    locks.exclusive = Some(ExclusiveLock {
+        holder: LockHolder {
+            lock_id: Some(lock_id),
+            holder,
+            since: retention::now(),
+            ttl_ms: None,
+        },
+        expires: Some(Instant::now() + lease()),
+        file: None,
+    });
+    locks.changed = true;
+    persist(locks);
+
+    Ok(lock_id)
+}
+
+/// Extends the lease of exclusive lock `lock_id` of a client, returning the time it's held for now with the state of
+/// the repository; none when the lock expired already. The lock file is locked once the shared locks are released, the
+/// state stays `Draining` while a plain rdedup process holds it.
+pub fn renew_exclusive(lock_id: &Uuid) -> io::Result<Option<(Duration, RepoState)>> {
+    let lease = lease();
+    let mut locks = locks();
+    let state = locks.state();
+
+    match &mut locks.exclusive {
+        Some(exclusive) if exclusive.holder.lock_id == Some(*lock_id) => exclusive.expires = Some(Instant::now() + lease),
+        _ => return Ok(None),
+    }
+    locks.changed = true;
+
+    let state = hold_exclusive(&mut locks, state);
+    persist(locks);
+
+    Ok(Some((lease, state?)))
+}
+
+/// State of the repository in `state` with the renewed exclusive lock of a client, locking the lock file once drained.
+fn hold_exclusive(locks: &mut Locks, state: RepoState) -> io::Result<RepoState> {
+    let exclusive = locks.exclusive.as_mut().expect("Exclusive lock renewed already");
+
+    if state == RepoState::ExclusiveHeld && exclusive.file.is_none() {
+        match lock_on_disk_as(libc::LOCK_EX) {
+            Ok(file) => {
+                debug!("Exclusive lock {:?} of {} held", exclusive.holder.lock_id, exclusive.holder.holder);
+                exclusive.file = Some(file);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(RepoState::Draining { count: 0 }),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(state)
+}
+
+pub fn remove_exclusive(lock_id: &Uuid) -> bool {
+    let mut locks = locks();
+
+    match &locks.exclusive {
+        Some(exclusive) if exclusive.holder.lock_id == Some(*lock_id) => {
+            debug!("Exclusive lock {} of {} released", lock_id, exclusive.holder.holder);
+        }
+        _ => return false,
+    }
+
+    locks.exclusive = None;
+    locks.changed = true;
+    persist(locks);
+
+    true
+}
+
+/// Response to a write while a job rewrites the repository - unless the request comes from the client holding the
+/// exclusive lock.
+pub fn write_refusal(headers: &HeaderMap) -> Option<HttpResponse> {
+    let locks = locks();
+
+    let exclusive = match (locks.state(), &locks.exclusive) {
+        (RepoState::ExclusiveHeld, Some(exclusive)) => exclusive,
+        _ => return None,
+    };
+
+    let declared = headers
+        .get(EXCLUSIVE_LOCK_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| Uuid::parse_str(v).ok());
+
+    match exclusive.holder.lock_id {
+        Some(lock_id) if declared == Some(lock_id) => None,
+        _ => Some(HttpResponse::build(StatusCode::LOCKED).json(&exclusive.holder)),
+    }
+}
+
+/// Holder of the exclusive lock - a server job (GC), a client, or a plain rdedup process working with the repository
+/// directly.
+pub fn exclusive() -> Option<LockHolder> {
+    let locks = locks();
+
+    locks.exclusive.as_ref().map(|e| e.holder.clone()).or_else(|| match lock_on_disk() {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Some(direct_holder()),
+        _ => None,
+    })
+}
+
+pub fn status() -> LocksResponse {
+    let now = Instant::now();
+    let exclusive = exclusive();
+    let locks = locks();
+
+    let mut shared: Vec<LockHolder> = locks
+        .shared
+        .values()
+        .map(|l| LockHolder {
+            ttl_ms: Some(l.expires.saturating_duration_since(now).as_millis() as u64),
+            ..l.holder.clone()
+        })
+        .collect();
+    shared.sort_by_key(|l| l.since);
+
+    LocksResponse {
+        exclusive,
+        shared,
+        state: locks.state(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Once;
+
+    use super::*;
+
+    /// The locks are global, tests take turns
+    static SERIAL: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+    static CONFIG: Once = Once::new();
+
+    /// Fresh locks of a scratch data dir, persisted; the test runs alone while it holds the guard.
+    fn setup() -> MutexGuard<'static, ()> {
+        let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+
+        CONFIG.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("rbackup2-locks-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let config = dir.join("config.toml");
+            fs::write(&config, "persist_locks = true").unwrap();
+
+            config::init(config::Opts {
+                config: Some(config),
+                data_dir: Some(dir),
+                listen: None,
+            })
+            .unwrap();
+        });
+
+        *LOCKS.lock().unwrap() = Locks::default();
+        let _ = fs::remove_file(backend_pool::data_dir().join(REGISTRY_FILE));
+
+        serial
+    }
+
+    fn state() -> RepoState {
+        locks().state()
+    }
+
+    /// Shared locks expire, as if their holders stopped renewing them.
+    fn lapse_shared() {
+        let now = Instant::now();
+        LOCKS.lock().unwrap().shared.values_mut().for_each(|lock| lock.expires = now);
+    }
+
+    /// Exclusive lock of a client expires.
+    fn lapse_exclusive() {
+        let now = Instant::now();
+        if let Some(exclusive) = LOCKS.lock().unwrap().exclusive.as_mut() {
+            exclusive.expires = exclusive.expires.map(|_| now);
+        }
+    }
+
+    /// Server restarts, the locks of clients are restored from the registry.
+    fn restart() {
+        *LOCKS.lock().unwrap() = Locks::default();
+        restore();
+    }
+
+    #[test]
+    fn shared_locks_are_counted() {
+        let _serial = setup();
+        assert_eq!(state(), RepoState::Idle);
+
+        let first = add_shared("first".to_string()).unwrap();
+        assert_eq!(state(), RepoState::SharedHeld { count: 1 });
+        let second = add_shared("second".to_string()).unwrap();
+        assert_eq!(state(), RepoState::SharedHeld { count: 2 });
+
+        assert!(remove_shared(&first));
+        assert!(!remove_shared(&first));
+        assert_eq!(state(), RepoState::SharedHeld { count: 1 });
+        assert!(remove_shared(&second));
+        assert_eq!(state(), RepoState::Idle);
+    }
+
+    #[test]
+    fn exclusive_is_taken_right_away_when_idle() {
+        let _serial = setup();
+
+        let guard = begin_exclusive("gc").unwrap();
+        assert_eq!(state(), RepoState::ExclusiveHeld);
+        guard.wait_drained();
+
+        drop(guard);
+        assert_eq!(state(), RepoState::Idle);
+    }
+
+    #[test]
+    fn client_exclusive_lock_drains_shared_locks() {
+        let _serial = setup();
+        let first = add_shared("first".to_string()).unwrap();
+        let second = add_shared("second".to_string()).unwrap();
+
+        let lock_id = add_exclusive("client".to_string()).unwrap();
+        assert_eq!(state(), RepoState::Draining { count: 2 });
+        assert_eq!(
+            renew_exclusive(&lock_id).unwrap(),
+            Some((lease(), RepoState::Draining { count: 2 }))
+        );
+
+        remove_shared(&first);
+        assert_eq!(
+            renew_exclusive(&lock_id).unwrap(),
+            Some((lease(), RepoState::Draining { count: 1 }))
+        );
+
+        remove_shared(&second);
+        assert_eq!(renew_exclusive(&lock_id).unwrap(), Some((lease(), RepoState::ExclusiveHeld)));
+        assert!(LOCKS.lock().unwrap().exclusive.as_ref().unwrap().file.is_some());
+
+        assert!(remove_exclusive(&lock_id));
+        assert!(!remove_exclusive(&lock_id));
+        assert_eq!(state(), RepoState::Idle);
+    }
+
+    #[test]
+    fn no_shared_locks_while_draining_or_exclusive() {
+        let _serial = setup();
+        let shared = add_shared("shared".to_string()).unwrap();
+        let lock_id = add_exclusive("client".to_string()).unwrap();
+
+        let e = add_shared("late".to_string()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(state(), RepoState::Draining { count: 1 });
+
+        remove_shared(&shared);
+        renew_exclusive(&lock_id).unwrap();
+        let e = add_shared("late".to_string()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(state(), RepoState::ExclusiveHeld);
+    }
+
+    #[test]
+    fn second_exclusive_is_refused() {
+        let _serial = setup();
+        let lock_id = add_exclusive("client".to_string()).unwrap();
+
+        let holder = add_exclusive("other".to_string()).unwrap_err();
+        assert_eq!(holder.lock_id, Some(lock_id));
+        assert!(matches!(begin_exclusive("gc"), Err(holder) if holder.lock_id == Some(lock_id)));
+    }
+
+    #[test]
+    fn unknown_locks_are_not_renewed() {
+        let _serial = setup();
+        add_shared("shared".to_string()).unwrap();
+        add_exclusive("client".to_string()).unwrap();
+
+        assert_eq!(renew_shared(&Uuid::new_v4()), None);
+        assert_eq!(renew_exclusive(&Uuid::new_v4()).unwrap(), None);
+    }
+
+    #[test]
+    fn expired_shared_locks_are_released() {
+        let _serial = setup();
+        let first = add_shared("first".to_string()).unwrap();
+        add_shared("second".to_string()).unwrap();
+
+        assert_eq!(renew_shared(&first), Some(lease()));
+        assert_eq!(state(), RepoState::SharedHeld { count: 2 });
+
+        lapse_shared();
+        assert_eq!(state(), RepoState::Idle);
+        assert_eq!(renew_shared(&first), None);
+    }
+
+    #[test]
+    fn expired_shared_locks_hand_over_to_exclusive() {
+        let _serial = setup();
+        add_shared("shared".to_string()).unwrap();
+        let lock_id = add_exclusive("client".to_string()).unwrap();
+
+        lapse_shared();
+        assert_eq!(renew_exclusive(&lock_id).unwrap(), Some((lease(), RepoState::ExclusiveHeld)));
+    }
+
+    #[test]
+    fn expired_client_exclusive_lock_is_released() {
+        let _serial = setup();
+        let lock_id = add_exclusive("client".to_string()).unwrap();
+        renew_exclusive(&lock_id).unwrap();
+
+        lapse_exclusive();
+        assert_eq!(state(), RepoState::Idle);
+        assert_eq!(renew_exclusive(&lock_id).unwrap(), None);
+        add_shared("shared".to_string()).unwrap();
+    }
+
+    #[test]
+    fn server_job_exclusive_lock_never_expires() {
+        let _serial = setup();
+        let _guard = begin_exclusive("gc").unwrap();
+
+        lapse_exclusive();
+        assert_eq!(state(), RepoState::ExclusiveHeld);
+    }
+
+    #[test]
+    fn client_locks_survive_restart() {
+        let _serial = setup();
+        let shared = add_shared("shared".to_string()).unwrap();
+        let lock_id = add_exclusive("client".to_string()).unwrap();
+
+        restart();
+        assert_eq!(state(), RepoState::Draining { count: 1 });
+        assert_eq!(renew_shared(&shared), Some(lease()));
+
+        remove_shared(&shared);
+        restart();
+        assert_eq!(renew_exclusive(&lock_id).unwrap(), Some((lease(), RepoState::ExclusiveHeld)));
+    }
+
+    #[test]
+    fn server_job_exclusive_lock_is_not_persisted() {
+        let _serial = setup();
+        let shared = add_shared("shared".to_string()).unwrap();
+        let guard = begin_exclusive("gc").unwrap();
+
+        remove_shared(&shared);
+        restart();
+        assert_eq!(state(), RepoState::Idle);
+
+        // released by the restart already
+        std::mem::forget(guard);
+    }
+}
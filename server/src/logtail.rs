@@ -0,0 +1,141 @@
+//! Tail of the server log for administrators (`/admin/logs/stream`), so a failing backup can be diagnosed without shell
+//! access to the server host.
+//!
+//! The logger passes everything to `env_logger` as before, and keeps recent events (of configured level and above) in
+//! memory; streams of the tail poll for new ones.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use err_context::AnyError;
+use libcommon::structs::LogEvent;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+use crate::config;
+
+struct Tail {
+    events: VecDeque<(Level, LogEvent)>,
+    next_seq: u64,
+}
+
+static TAIL: Lazy<Mutex<Tail>> = Lazy::new(|| {
+    Mutex::new(Tail {
+        events: VecDeque::new(),
+        next_seq: 1,
+    })
+});
+
+/// Levels as `LevelFilter as usize`, so they can be changed once the config is loaded
+static LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+static CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_CAPACITY);
+
+const DEFAULT_CAPACITY: usize = 1000;
+
+fn level() -> LevelFilter {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+struct TailLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for TailLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata) || metadata.level() <= level()
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.log(record);
+
+        if record.level() > level() {
+            return;
+        }
+
+        let mut tail = TAIL.lock().unwrap();
+
+        let event = LogEvent {
+            seq: tail.next_seq,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_millis() as u64)
+                .unwrap_or(0),
+            level: record.level().to_string(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        };
+
+        tail.next_seq += 1;
+        tail.events.push_back((record.level(), event));
+
+        while tail.events.len() > CAPACITY.load(Ordering::Relaxed) {
+            tail.events.pop_front();
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Installs the logger; configured by `RUST_LOG` like plain `env_logger`.
+pub fn init() {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter().max(level());
+
+    log::set_boxed_logger(Box::new(TailLogger { inner })).expect("Logger already set");
+    log::set_max_level(max_level);
+}
+
+/// Applies the config, once it's loaded.
+pub fn configure() -> Result<(), AnyError> {
+    let config = &config::get().log_tail;
+
+    let level = LevelFilter::from_str(&config.level).map_err(|_| AnyError::from(format!("Invalid log tail level {:?}", config.level)))?;
+
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    CAPACITY.store(config.capacity, Ordering::Relaxed);
+    log::set_max_level(log::max_level().max(level));
+
+    Ok(())
+}
+
+/// Which events a stream of the tail is interested in.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub level: LevelFilter,
+    /// Prefix of the target (module path)
+    pub module: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, level: Level, event: &LogEvent) -> bool {
+        level <= self.level && self.module.as_ref().map(|m| event.target.starts_with(m.as_str())).unwrap_or(true)
+    }
+}
+
+/// Kept events newer than `after` (sequence number) matching `filter`.
+pub fn events_after(after: u64, filter: &Filter) -> Vec<LogEvent> {
+    let tail = TAIL.lock().unwrap();
+
+    tail.events
+        .iter()
+        .filter(|(level, event)| event.seq > after && filter.matches(*level, event))
+        .map(|(_, event)| event.clone())
+        .collect()
+}
+
+/// Sequence number of the newest event, so a stream can skip the kept ones.
+pub fn last_seq() -> u64 {
+    TAIL.lock().unwrap().next_seq - 1
+}
@@ -1,32 +1,203 @@
 use std::net::SocketAddr;
-use std::str::FromStr;
 
-use actix_web::{App, HttpServer};
+use actix_http::HttpService;
+use actix_server::Server;
+use actix_service::{fn_service, map_config};
+use actix_web::dev::AppConfig;
+use actix_web::middleware::Compress;
+use actix_web::App;
+use libcommon::build_info::BuildInfo;
 use log::*;
+use structopt::StructOpt;
 
+mod auth;
 mod backend_pool;
+mod catalog;
+mod cleanup;
+mod compression;
+mod config;
+mod export;
+mod gc;
+mod generations;
 mod handlers;
+#[cfg(feature = "http3")]
+mod http3;
+mod locks;
+mod logtail;
+mod maintenance;
+mod metrics;
+mod migrations;
+mod operations;
+mod priority;
+mod read_buffers;
+mod request_signing;
+mod retention;
+mod selftest;
+mod slowlog;
+#[cfg(feature = "standalone")]
+mod standalone;
+mod storage;
+mod throttle;
+mod tiering;
+mod upload;
+#[cfg(feature = "web-ui")]
+mod webui;
 
 #[actix_rt::main]
 async fn main() {
-    env_logger::init();
+    let opts = config::Opts::from_args();
 
-    let addr = SocketAddr::from_str("0.0.0.0:8090").expect("Could not parse listen address!"); // let it fail
+    logtail::init();
+
+    info!("rbackup2 server {}", BuildInfo::new(env!("CARGO_PKG_VERSION")).long_version());
+
+    if let Err(e) = config::init(opts) {
+        error!("Could not load config: {}", e);
+        std::process::exit(1);
+    }
+    logtail::configure().expect("Invalid log tail config"); // let it fail
+
+    let read_only = match selftest::run(backend_pool::data_dir()) {
+        Ok(selftest::Outcome::Passed) => {
+            info!("Data directory self-test passed");
+            false
+        }
+        Ok(selftest::Outcome::ReadOnly(reason)) => {
+            maintenance::set_read_only(reason);
+            true
+        }
+        Err(e) => {
+            error!("Data directory self-test failed, refusing to start: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let dry_run = config::get().migrations.dry_run;
+
+    if let Err(e) = migrations::run(backend_pool::data_dir(), dry_run, read_only) {
+        error!("Migrations failed, refusing to start: {}", e);
+        std::process::exit(1);
+    }
+
+    if dry_run {
+        info!("Migrations dry run done, exiting");
+        return;
+    }
+
+    locks::restore();
+
+    let config = config::get();
+
+    if config.allow_anonymous {
+        warn!(
+            "Requests without a token are served as {:?}, anyone reaching the server has that access",
+            config.default_role
+        );
+    } else if config.admin_tokens.is_empty() && config.tokens.is_empty() {
+        warn!("No tokens configured and `allow_anonymous` not set, all requests will be refused");
+    }
+
+    if !config.allow_anonymous && config.standalone.as_ref().map_or(false, |s| s.token.is_none()) {
+        warn!("Standalone backups have no token and `allow_anonymous` isn't set, they will be refused");
+    }
+
+    let addr = config::get().listen();
 
     info!("Starting server on {}", addr);
 
-    HttpServer::new(move || {
-        App::new()
-            .service(handlers::list)
-            .service(handlers::write)
-            .service(handlers::read)
-            .service(handlers::read_metadata)
-            .service(handlers::lock_shared_add)
-            .service(handlers::lock_shared_remove)
-    })
-    .bind(addr)
-    .unwrap() // let it fail
-    .run()
-    .await
-    .unwrap();
+    if let Some(http3) = &config::get().http3 {
+        start_http3(http3, addr);
+    }
+
+    tiering::schedule();
+    cleanup::schedule();
+
+    if let Some(standalone) = &config::get().standalone {
+        start_standalone(standalone, addr);
+    }
+
+    // plain `HttpServer` doesn't allow to customize handling of `Expect: 100-continue`
+    Server::build()
+        .bind("rbackup2", addr, || {
+            let app = App::new()
+                .wrap_fn(auth::middleware)
+                .wrap_fn(slowlog::middleware)
+                .wrap_fn(operations::middleware)
+                .wrap_fn(compression::middleware)
+                .wrap(Compress::default())
+                .service(handlers::capabilities)
+                .service(handlers::version)
+                .service(handlers::list)
+                .service(handlers::list_stream)
+                .service(handlers::stats)
+                .service(handlers::write)
+                .service(handlers::write_batch::write_batch)
+                .service(handlers::commit_name)
+                .service(handlers::record_catalog)
+                .service(handlers::names::list_names)
+                .service(handlers::read)
+                .service(handlers::read_metadata)
+                .service(handlers::remove)
+                .service(handlers::remove_dir)
+                .service(handlers::rename::rename)
+                .service(handlers::rename::rename_batch)
+                .service(handlers::list_locks)
+                .service(handlers::lock_shared_add)
+                .service(handlers::lock_shared_renew)
+                .service(handlers::lock_shared_remove)
+                .service(handlers::lock_exclusive_add)
+                .service(handlers::lock_exclusive_renew)
+                .service(handlers::lock_exclusive_remove)
+                .service(handlers::admin::set_maintenance)
+                .service(handlers::admin::start_gc)
+                .service(handlers::admin::start_tiering)
+                .service(handlers::admin::run_cleanup)
+                .service(handlers::admin::last_cleanup)
+                .service(handlers::admin::gc_events)
+                .service(handlers::admin::log_stream)
+                .service(handlers::admin::get_io_throttle)
+                .service(handlers::admin::set_io_throttle)
+                .service(handlers::admin::capacity_report)
+                .service(handlers::admin::list_operations)
+                .service(handlers::admin::backend_latency)
+                .service(handlers::admin::export_name)
+                .service(handlers::admin::cancel_operation);
+
+            #[cfg(feature = "web-ui")]
+            let app = app.service(webui::index).service(webui::login).service(webui::logout);
+
+            HttpService::build()
+                .expect(fn_service(handlers::expect::expect))
+                .finish(map_config(app, |_| AppConfig::default()))
+                .tcp()
+        })
+        .unwrap() // let it fail
+        .run()
+        .await
+        .unwrap();
+}
+
+/// Clients fall back to HTTP/1.1 when the HTTP/3 listener is not available.
+#[cfg(feature = "http3")]
+fn start_http3(config: &config::Http3, addr: SocketAddr) {
+    let backend = SocketAddr::from(([127, 0, 0, 1], addr.port()));
+
+    if let Err(e) = http3::start(config, backend) {
+        error!("Could not start HTTP/3 listener, serving HTTP/1.1 only: {}", e);
+    }
+}
+
+#[cfg(not(feature = "http3"))]
+fn start_http3(_config: &config::Http3, _addr: SocketAddr) {
+    warn!("HTTP/3 listener configured, but the server is built without the `http3` feature");
+}
+
+#[cfg(feature = "standalone")]
+fn start_standalone(config: &'static config::Standalone, addr: SocketAddr) {
+    standalone::schedule(config, SocketAddr::from(([127, 0, 0, 1], addr.port())));
+}
+
+#[cfg(not(feature = "standalone"))]
+fn start_standalone(_config: &config::Standalone, _addr: SocketAddr) {
+    warn!("Standalone backups configured, but the server is built without the `standalone` feature");
 }
@@ -0,0 +1,67 @@
+use std::sync::RwLock;
+
+use actix_web::HttpResponse;
+use libcommon::structs::MAINTENANCE_HEADER;
+use log::*;
+use once_cell::sync::{Lazy, OnceCell};
+
+/// Message shown to users while the server is in maintenance mode; `None` when not in maintenance.
+static MAINTENANCE: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+
+/// Reason why the server can't accept any writes at all; set on startup, lasts until restart.
+static READ_ONLY: OnceCell<String> = OnceCell::new();
+
+pub fn set_read_only(reason: String) {
+    warn!("Switching to read-only mode: {}", reason);
+    let _ = READ_ONLY.set(reason);
+}
+
+pub fn enable(until: Option<String>) {
+    let message = match until {
+        Some(until) => format!("Server is in maintenance until {}", until),
+        None => "Server is in maintenance".to_string(),
+    };
+
+    info!("Entering maintenance mode: {}", message);
+    *MAINTENANCE.write().unwrap() = Some(message);
+}
+
+pub fn disable() {
+    info!("Leaving maintenance mode");
+    *MAINTENANCE.write().unwrap() = None;
+}
+
+/// Why the server refuses writes (read-only or maintenance mode), if it does.
+pub fn status() -> Option<String> {
+    match READ_ONLY.get() {
+        Some(reason) => Some(format!("Server is read-only: {}", reason)),
+        None => MAINTENANCE.read().unwrap().clone(),
+    }
+}
+
+/// Returns response refusing the request when the server is in maintenance mode.
+///
+/// Only modifying operations (writes, new locks) should be guarded, reads continue to work.
+pub fn refusal() -> Option<HttpResponse> {
+    MAINTENANCE.read().unwrap().as_ref().map(|message| {
+        debug!("Refusing request due to maintenance");
+        HttpResponse::ServiceUnavailable()
+            .header(MAINTENANCE_HEADER, "true")
+            .body(message.clone())
+    })
+}
+
+/// Returns response refusing a write when the server is read-only or in maintenance mode.
+pub fn write_refusal() -> Option<HttpResponse> {
+    match READ_ONLY.get() {
+        Some(reason) => {
+            debug!("Refusing write in read-only mode");
+            Some(
+                HttpResponse::ServiceUnavailable()
+                    .header(MAINTENANCE_HEADER, "true")
+                    .body(format!("Server is read-only: {}", reason)),
+            )
+        }
+        None => refusal(),
+    }
+}
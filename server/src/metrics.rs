@@ -0,0 +1,94 @@
+//! Latency histograms of storage operations (the `BackendThread` methods, plus reads and writes handlers do on the
+//! filesystem directly), so operators can tell slow disks from slow networks and size the backend pool.
+//!
+//! Buckets grow exponentially, four per doubling, so percentiles are accurate to about 20% over the whole range.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use libcommon::structs::{LatencyResponse, LatencyStats};
+use once_cell::sync::Lazy;
+
+/// Upper bound of the first bucket
+const FIRST_BOUND_MICROS: f64 = 10.0;
+const BUCKETS_PER_DOUBLING: f64 = 4.0;
+/// The last bucket takes everything over ~5 minutes
+const BUCKETS: usize = 100;
+
+static HISTOGRAMS: Lazy<Mutex<BTreeMap<&'static str, Histogram>>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+struct Histogram {
+    counts: [u64; BUCKETS],
+    total: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            counts: [0; BUCKETS],
+            total: 0,
+        }
+    }
+
+    fn add(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_secs_f64() * 1_000_000.0;
+        let bucket = if micros <= FIRST_BOUND_MICROS {
+            0
+        } else {
+            ((micros / FIRST_BOUND_MICROS).log2() * BUCKETS_PER_DOUBLING).ceil() as usize
+        };
+
+        self.counts[bucket.min(BUCKETS - 1)] += 1;
+        self.total += 1;
+    }
+
+    /// Upper bound (in ms) of the bucket holding `quantile` of the observations.
+    fn percentile(&self, quantile: f64) -> f64 {
+        let rank = ((self.total as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bound_ms(bucket);
+            }
+        }
+
+        bound_ms(BUCKETS - 1)
+    }
+}
+
+fn bound_ms(bucket: usize) -> f64 {
+    FIRST_BOUND_MICROS * 2f64.powf(bucket as f64 / BUCKETS_PER_DOUBLING) / 1000.0
+}
+
+/// Records one `operation` which took `elapsed`.
+pub fn observe(operation: &'static str, elapsed: Duration) {
+    HISTOGRAMS
+        .lock()
+        .unwrap()
+        .entry(operation)
+        .or_insert_with(Histogram::new)
+        .add(elapsed);
+}
+
+pub fn latencies() -> LatencyResponse {
+    let histograms = HISTOGRAMS.lock().unwrap();
+
+    LatencyResponse {
+        operations: histograms
+            .iter()
+            .map(|(operation, histogram)| {
+                let stats = LatencyStats {
+                    count: histogram.total,
+                    p50_ms: histogram.percentile(0.5),
+                    p95_ms: histogram.percentile(0.95),
+                    p99_ms: histogram.percentile(0.99),
+                };
+
+                (operation.to_string(), stats)
+            })
+            .collect(),
+    }
+}
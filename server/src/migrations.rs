@@ -0,0 +1,109 @@
+//! Ordered one-time migrations of the data directory (formats of server state files, layout changes), run at startup
+//! before any request is served.
+//!
+//! Applied steps are recorded in a state file in the data directory, each right after it's done; a step interrupted by
+//! a crash runs again at the next start, so steps must cope with their own partial results. Steps are never removed or
+//! renumbered once released - new ones get the next version.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use err_context::AnyError;
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::catalog;
+
+const STATE_FILE: &str = ".migrations.json";
+
+struct Migration {
+    version: u32,
+    name: &'static str,
+    run: fn() -> io::Result<()>,
+}
+
+/// All the steps, by ascending version.
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    name: "drop-invalid-catalog-records",
+    run: catalog::drop_invalid_records,
+}];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    applied: Vec<Applied>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Applied {
+    version: u32,
+    name: String,
+    /// Unix timestamp
+    applied_at: u64,
+}
+
+fn load(data_dir: &Path) -> Result<State, AnyError> {
+    let file = data_dir.join(STATE_FILE);
+
+    match fs::read(&file) {
+        Ok(content) => Ok(serde_json::from_slice(&content).map_err(|e| format!("Invalid migrations state {:?}: {}", file, e))?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(data_dir: &Path, state: &State) -> io::Result<()> {
+    let file = data_dir.join(STATE_FILE);
+    let temp = file.with_extension("json.tmp");
+
+    fs::write(&temp, serde_json::to_vec_pretty(state)?)?;
+    fs::rename(temp, file)
+}
+
+/// Runs the migrations not applied to `data_dir` yet; with `dry_run` (or a `read_only` data directory) they're only
+/// logged.
+pub fn run(data_dir: &Path, dry_run: bool, read_only: bool) -> Result<(), AnyError> {
+    let mut state = load(data_dir)?;
+
+    let applied: BTreeSet<u32> = state.applied.iter().map(|a| a.version).collect();
+    if let Some(unknown) = applied.iter().find(|v| !MIGRATIONS.iter().any(|m| m.version == **v)) {
+        return Err(format!("Data directory was migrated by a newer server (migration {})", unknown).into());
+    }
+
+    let pending: Vec<_> = MIGRATIONS.iter().filter(|m| !applied.contains(&m.version)).collect();
+
+    if pending.is_empty() {
+        debug!("No migrations pending");
+        return Ok(());
+    }
+
+    if dry_run || read_only {
+        for migration in &pending {
+            info!("Migration {} ({}) pending", migration.version, migration.name);
+        }
+
+        if read_only {
+            warn!("Data directory is read-only, {} migrations not applied", pending.len());
+        }
+
+        return Ok(());
+    }
+
+    for migration in pending {
+        info!("Running migration {} ({})", migration.version, migration.name);
+
+        (migration.run)().map_err(|e| format!("Migration {} ({}) failed: {}", migration.version, migration.name, e))?;
+
+        state.applied.push(Applied {
+            version: migration.version,
+            name: migration.name.to_string(),
+            applied_at: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs(),
+        });
+        save(data_dir, &state)?;
+    }
+
+    Ok(())
+}
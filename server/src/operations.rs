@@ -0,0 +1,117 @@
+//! Registry of requests being handled, so operators can see what the server is busy with when it seems stuck, and
+//! cancel an operation (see `/admin/operations`).
+//!
+//! Cancelling drops the handler at its next await point; a storage operation already running on a backend thread
+//! finishes first.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse};
+use actix_web::{error, Error};
+use futures::future::{AbortHandle, Abortable, LocalBoxFuture};
+use futures::StreamExt;
+use libcommon::structs::OperationInfo;
+use once_cell::sync::Lazy;
+
+use crate::slowlog;
+
+static OPERATIONS: Lazy<Mutex<HashMap<u64, Operation>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Operation {
+    method: String,
+    endpoint: String,
+    path: Option<String>,
+    peer: Option<String>,
+    started: Instant,
+    bytes: Arc<AtomicU64>,
+    abort: AbortHandle,
+}
+
+/// Operations in progress, the longest running first.
+pub fn list() -> Vec<OperationInfo> {
+    let operations = OPERATIONS.lock().unwrap();
+
+    let mut list: Vec<_> = operations
+        .iter()
+        .map(|(id, op)| OperationInfo {
+            id: *id,
+            method: op.method.clone(),
+            endpoint: op.endpoint.clone(),
+            path: op.path.clone(),
+            peer: op.peer.clone(),
+            bytes: op.bytes.load(Ordering::Relaxed),
+            elapsed_ms: op.started.elapsed().as_millis() as u64,
+        })
+        .collect();
+
+    list.sort_by(|a, b| b.elapsed_ms.cmp(&a.elapsed_ms));
+    list
+}
+
+/// Cancels operation `id`; false when there's no such (it has finished already).
+pub fn cancel(id: u64) -> bool {
+    match OPERATIONS.lock().unwrap().get(&id) {
+        Some(op) => {
+            op.abort.abort();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Removes the operation from the registry once handled, however it ends.
+struct Registered(u64);
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        OPERATIONS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Middleware registering the requests.
+pub fn middleware<S, B>(mut req: ServiceRequest, srv: &mut S) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let bytes = Arc::new(AtomicU64::new(0));
+    let (abort, registration) = AbortHandle::new_pair();
+
+    let operation = Operation {
+        method: req.method().to_string(),
+        endpoint: req.path().to_string(),
+        path: slowlog::object_path(&req),
+        peer: req.connection_info().realip_remote_addr().map(str::to_string),
+        started: Instant::now(),
+        bytes: Arc::clone(&bytes),
+        abort,
+    };
+
+    let payload = req.take_payload().map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            bytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        chunk
+    });
+    req.set_payload(Payload::Stream(Box::pin(payload)));
+
+    OPERATIONS.lock().unwrap().insert(id, operation);
+    let registered = Registered(id);
+
+    let response = Abortable::new(srv.call(req), registration);
+
+    Box::pin(async move {
+        let _registered = registered;
+
+        match response.await {
+            Ok(result) => result,
+            Err(_) => Err(error::ErrorServiceUnavailable("Operation cancelled by the server administrator")),
+        }
+    })
+}
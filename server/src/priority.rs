@@ -0,0 +1,15 @@
+//! Priority classes of requests, declared by clients in `PRIORITY_HEADER`. While contended, backend threads (see
+//! `backend_pool::pull_for`) and read buffers (see `read_buffers::reserve`) serve interactive restores first, and
+//! background jobs yield to them (see `throttle::pace`).
+
+use actix_web::http::HeaderMap;
+use libcommon::structs::{Priority, PRIORITY_HEADER};
+
+/// Priority of a request with `headers`; requests of older clients, and with unknown priorities, are normal.
+pub fn of(headers: &HeaderMap) -> Priority {
+    headers
+        .get(PRIORITY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Priority::parse)
+        .unwrap_or_default()
+}
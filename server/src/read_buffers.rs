@@ -0,0 +1,197 @@
+//! Limit of memory held by responses of reads in progress. Backend threads return whole objects, so every read holds its
+//! object in memory until the response is sent - many concurrent restores would add up to more than the server has.
+//!
+//! Reads reserve an upper estimate of the object size before reading it, waiting while the budget is exhausted, and
+//! keep the reservation until the response body is sent. Chunks read straight from files (see `file_body`) hold just a
+//! piece of the file at a time.
+//!
+//! Those waiting are served by the priority of their requests (see `PRIORITY_HEADER`), so restores don't queue behind
+//! reads of scrubs.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+
+use actix_web::web::{self, Bytes};
+use actix_web::Error;
+use futures::channel::oneshot;
+use futures::{stream, Stream, StreamExt};
+use libcommon::structs::Priority;
+use once_cell::sync::Lazy;
+use sgdata::SGData;
+
+use crate::config;
+
+/// Largest piece of a response body sent at once, so large objects start flowing right away
+const PIECE_SIZE: usize = 64 * 1024;
+
+static BUDGET: Lazy<Mutex<Budget>> = Lazy::new(|| Mutex::new(Budget::default()));
+
+#[derive(Default)]
+struct Budget {
+    used: usize,
+    /// Higher priorities first, then first come, first served, so large objects don't starve
+    waiting: VecDeque<(usize, Priority, oneshot::Sender<Reservation>)>,
+    /// Reservations of high priority reads held
+    interactive: usize,
+}
+
+impl Budget {
+    /// Grants reservations to those waiting which fit now; they must be sent after the budget is unlocked.
+    fn grant(&mut self) -> Vec<(oneshot::Sender<Reservation>, Reservation)> {
+        let mut granted = Vec::new();
+
+        while let Some((bytes, _, _)) = self.waiting.front() {
+            if self.used + bytes > limit() {
+                break;
+            }
+
+            let (bytes, priority, sender) = self.waiting.pop_front().unwrap();
+            granted.push((sender, self.take(bytes, priority)));
+        }
+
+        granted
+    }
+
+    fn take(&mut self, bytes: usize, priority: Priority) -> Reservation {
+        self.used += bytes;
+        if priority == Priority::High {
+            self.interactive += 1;
+        }
+
+        Reservation { bytes, priority }
+    }
+}
+
+fn limit() -> usize {
+    config::get().read_buffers.max_bytes.max(1)
+}
+
+/// Hands the granted reservations over; those of reads gone meanwhile are dropped, returning them to the budget.
+fn hand_over(granted: Vec<(oneshot::Sender<Reservation>, Reservation)>) {
+    for (sender, reservation) in granted {
+        let _ = sender.send(reservation);
+    }
+}
+
+/// Number of high priority reads in progress; background jobs yield to them, see `throttle::pace`.
+pub fn interactive_reads() -> usize {
+    BUDGET.lock().unwrap().interactive
+}
+
+/// Reserves `bytes` of the budget for a read of `priority`, waiting until they are available.
+pub async fn reserve(bytes: usize, priority: Priority) -> Reservation {
+    // objects larger than the whole budget are read alone
+    let bytes = bytes.min(limit());
+
+    let granted = {
+        let mut budget = BUDGET.lock().unwrap();
+
+        // reads of lower priorities waiting don't hold it back
+        let position = budget
+            .waiting
+            .iter()
+            .position(|(_, p, _)| *p < priority)
+            .unwrap_or(budget.waiting.len());
+
+        if position == 0 && budget.used + bytes <= limit() {
+            return budget.take(bytes, priority);
+        }
+
+        let (sender, granted) = oneshot::channel();
+        budget.waiting.insert(position, (bytes, priority, sender));
+        granted
+    };
+
+    // senders are dropped only by sending a reservation
+    granted.await.expect("Read buffer reservation lost")
+}
+
+/// Part of the budget, returned once dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    bytes: usize,
+    priority: Priority,
+}
+
+impl Reservation {
+    /// Adjusts the reservation to the real size of the object read; growing doesn't wait, the object is in memory
+    /// already.
+    pub fn resize(&mut self, bytes: usize) {
+        let granted = {
+            let mut budget = BUDGET.lock().unwrap();
+            budget.used = budget.used - self.bytes + bytes;
+            self.bytes = bytes;
+            budget.grant()
+        };
+
+        hand_over(granted);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let granted = {
+            let mut budget = BUDGET.lock().unwrap();
+            budget.used -= self.bytes;
+            if self.priority == Priority::High {
+                budget.interactive -= 1;
+            }
+            budget.grant()
+        };
+
+        hand_over(granted);
+    }
+}
+
+/// Response body sending `data` part by part as they are taken by the connection, keeping `reservation` until the
+/// whole body is sent (or the connection is gone).
+pub fn body(mut data: SGData, reservation: Reservation) -> impl Stream<Item = Result<Bytes, Error>> {
+    // slices of the parts share them, nothing gets copied
+    let pieces: Vec<Bytes> = data
+        .as_vec_mut()
+        .drain(..)
+        .map(Bytes::from)
+        .flat_map(|part| {
+            (0..part.len())
+                .step_by(PIECE_SIZE)
+                .map(move |start| part.slice(start..part.len().min(start + PIECE_SIZE)))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    stream::iter(pieces).map(move |piece| {
+        let _reservation = &reservation;
+        Ok(piece)
+    })
+}
+
+/// Response body sending contents of `file` of `len` bytes, read piece by piece on the blocking thread pool as the
+/// connection takes them; `reservation` shrinks to a single piece.
+pub fn file_body(file: File, len: u64, mut reservation: Reservation) -> impl Stream<Item = Result<Bytes, Error>> {
+    reservation.resize(PIECE_SIZE.min(len as usize));
+
+    stream::unfold(Some(file), move |file| {
+        let _reservation = &reservation;
+
+        async move {
+            let mut file = file?;
+
+            let read = web::block(move || {
+                let mut piece = vec![0; PIECE_SIZE];
+                let read = file.read(&mut piece)?;
+                piece.truncate(read);
+                Ok::<_, std::io::Error>((file, piece))
+            })
+            .await;
+
+            match read {
+                Ok((_, piece)) if piece.is_empty() => None,
+                Ok((file, piece)) => Some((Ok(Bytes::from(piece)), Some(file))),
+                // ends the stream, the client sees it broken
+                Err(e) => Some((Err(e.into()), None)),
+            }
+        }
+    })
+}
@@ -0,0 +1,81 @@
+//! Checks of signed lock and admin requests, see `libcommon::request_signature`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac, NewMac};
+use libcommon::request_signature::{self, MAX_CLOCK_SKEW_SECS, REQUEST_NONCE_HEADER, REQUEST_SIGNATURE_HEADER, REQUEST_TIMESTAMP_HEADER};
+use log::*;
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+
+use crate::config;
+
+/// Nonces of accepted requests, with their timestamps; kept until the requests would be refused as stale anyway.
+static NONCES: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Clock before Unix epoch")
+        .as_secs()
+}
+
+fn header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Response to a request with `body` whose signature is missing, invalid, stale or replayed; none when the server has
+/// no signing key.
+pub fn refusal(request: &HttpRequest, body: &[u8]) -> Option<HttpResponse> {
+    let key = config::get().signing_key.as_ref()?;
+
+    match verify(key, request, body) {
+        Ok(()) => None,
+        Err(reason) => {
+            warn!("Refusing {} {}: {}", request.method(), request.path(), reason);
+            Some(HttpResponse::Forbidden().body(reason))
+        }
+    }
+}
+
+fn verify(key: &str, request: &HttpRequest, body: &[u8]) -> Result<(), &'static str> {
+    let signature = header(request, REQUEST_SIGNATURE_HEADER)
+        .and_then(|v| hex::decode(v).ok())
+        .ok_or("Missing or malformed request signature")?;
+    let timestamp: u64 = header(request, REQUEST_TIMESTAMP_HEADER)
+        .and_then(|v| v.parse().ok())
+        .ok_or("Missing or malformed request timestamp")?;
+    let nonce = header(request, REQUEST_NONCE_HEADER)
+        .filter(|v| !v.is_empty())
+        .ok_or("Missing request nonce")?;
+
+    let message = request_signature::message(
+        request.method().as_str(),
+        request.path(),
+        request.query_string(),
+        timestamp,
+        nonce,
+        body,
+    );
+
+    let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(&message);
+    mac.verify(&signature).map_err(|_| "Invalid request signature")?;
+
+    let now = now();
+    if timestamp + MAX_CLOCK_SKEW_SECS < now || timestamp > now + MAX_CLOCK_SKEW_SECS {
+        return Err("Request timestamp too far from the server clock");
+    }
+
+    let mut nonces = NONCES.lock().unwrap();
+    nonces.retain(|_, seen| *seen + MAX_CLOCK_SKEW_SECS >= now);
+
+    if nonces.insert(nonce.to_string(), timestamp).is_some() {
+        return Err("Replayed request");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use sgdata::SGData;
+
+use crate::backend_pool::PooledBackend;
+
+/// Directory (relative to the repository root) mirroring names which have retention set.
+///
+/// Retention of a name covers its chunks transitively - GC never removes data reachable from an existing name.
+const RETENTION_DIR: &str = ".retention";
+
+fn retention_path(name_path: &Path) -> PathBuf {
+    Path::new(RETENTION_DIR).join(name_path)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_secs()
+}
+
+/// Returns retention timestamp of given name, if any.
+pub fn get(backend: &mut PooledBackend, name_path: &Path) -> io::Result<Option<u64>> {
+    match backend.thread.read(retention_path(name_path)) {
+        Ok(data) => {
+            let content = String::from_utf8(data.to_linear_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let until = content.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Some(until))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn set(backend: &mut PooledBackend, name_path: &Path, until: u64) -> io::Result<()> {
+    debug!("Setting retention of {:?} until {}", name_path, until);
+
+    backend.thread.write(
+        retention_path(name_path),
+        SGData::from_single(until.to_string().into_bytes()),
+        false,
+    )
+}
+
+/// Drops retention record of a removed name.
+pub fn clear(backend: &mut PooledBackend, name_path: &Path) -> io::Result<()> {
+    match backend.thread.remove(retention_path(name_path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Whether the name is still under retention, i.e. must not be removed or overwritten.
+pub fn is_retained(backend: &mut PooledBackend, name_path: &Path) -> io::Result<bool> {
+    Ok(get(backend, name_path)?.map(|until| until > now()).unwrap_or(false))
+}
@@ -0,0 +1,143 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use err_context::AnyError;
+use libcommon::layout::Layout;
+use libcommon::paths::{NAMES_DIR, PENDING_DIR};
+use log::*;
+
+use crate::storage;
+
+/// File in the data directory holding version of the layout the data are stored in.
+pub const LAYOUT_MARKER: &str = ".layout-version";
+
+const PROBE_FILE: &str = ".selftest";
+
+const CONFIG_FILE: &str = "config.yml";
+
+pub enum Outcome {
+    Passed,
+    /// The data are usable, but can't be modified
+    ReadOnly(String),
+}
+
+/// Validates the data directory before the server starts serving it.
+///
+/// Returns error when the data can't be served at all.
+pub fn run(data_dir: &Path) -> Result<Outcome, AnyError> {
+    let meta = fs::metadata(data_dir).map_err(|e| format!("Data directory {:?} is not accessible: {}", data_dir, e))?;
+
+    if !meta.is_dir() {
+        return Err(format!("Data directory {:?} is not a directory", data_dir).into());
+    }
+
+    let writable = check_writable(data_dir);
+    if let Err(e) = &writable {
+        warn!("Data directory {:?} is not writable: {}", data_dir, e);
+    }
+
+    storage::set_layout(detect_layout(data_dir, writable.is_ok())?);
+
+    if !is_initialized(data_dir)? {
+        warn!("Data directory {:?} doesn't contain initialized repository yet", data_dir);
+    }
+
+    let leftovers = count_files(&data_dir.join(PENDING_DIR))?;
+    if leftovers > 0 {
+        warn!("Found {} leftover pending writes in {:?}", leftovers, data_dir.join(PENDING_DIR));
+    }
+
+    let temp_dir = storage::temp_dir();
+    let leftovers = count_files(&temp_dir)?;
+    if leftovers > 0 {
+        warn!("Found {} leftover temp files in {:?}", leftovers, temp_dir);
+    }
+
+    Ok(match writable {
+        Ok(()) => Outcome::Passed,
+        Err(e) => Outcome::ReadOnly(format!("data directory is not writable: {}", e)),
+    })
+}
+
+/// Layout of the repository in `data_dir`, by its marker; new repositories get the current one, marked when `writable`.
+pub(crate) fn detect_layout(data_dir: &Path, writable: bool) -> Result<Layout, AnyError> {
+    let marker = data_dir.join(LAYOUT_MARKER);
+    let layout = match fs::read_to_string(&marker) {
+        Ok(content) => {
+            let version: u32 = content
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid layout marker {:?}: '{}'", marker, content.trim()))?;
+
+            debug!("Data directory uses layout version {}", version);
+
+            Layout::from_version(version).ok_or_else(|| format!("Data directory uses unsupported layout version {}", version))?
+        }
+        // repositories created by plain rdedup are served unmodified, they're in the plain layout
+        Err(e) if e.kind() == io::ErrorKind::NotFound && is_initialized(data_dir)? => {
+            info!(
+                "No layout marker found, serving plain rdedup repository (layout version {})",
+                Layout::V1.version()
+            );
+            Layout::V1
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("No layout marker found, assuming layout version {}", Layout::CURRENT.version());
+
+            if writable {
+                fs::write(&marker, Layout::CURRENT.version().to_string())?;
+            }
+            Layout::CURRENT
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(layout)
+}
+
+fn check_writable(data_dir: &Path) -> io::Result<()> {
+    let probe = data_dir.join(PROBE_FILE);
+
+    fs::write(&probe, b"probe")?;
+    fs::remove_file(&probe)
+}
+
+/// The repo config is either in the root or in a generation directory.
+fn is_initialized(data_dir: &Path) -> io::Result<bool> {
+    if data_dir.join(CONFIG_FILE).exists() || data_dir.join(NAMES_DIR).exists() {
+        return Ok(true);
+    }
+
+    for entry in fs::read_dir(data_dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() && path.join(CONFIG_FILE).exists() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn count_files(dir: &Path) -> io::Result<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut count = 0;
+
+    for entry in entries {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            count += count_files(&entry.path())?;
+        } else {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,282 @@
+//! Slow-log: requests taking longer than configured threshold are logged in detail (target `slowlog`), as is each
+//! n-th request when sampling is enabled (target `trace`), so occasional slowness can be debugged without verbose logs.
+//!
+//! Time spent in the storage (and waiting for a backend thread) is accounted to the request whose handler is being
+//! polled - see [`Scoped`]. Every response carries the timing in headers (see `libcommon::timing`), so clients can
+//! tell where their requests spend time without access to the server log.
+
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_http::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::HeaderMap;
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+use libcommon::timing::{BodyBytes, ServerTiming, BODY_BYTES_HEADER, SERVER_TIMING_HEADER};
+use log::*;
+use rdedup_lib::backends::{BackendThread, Metadata};
+use serde::Serialize;
+use sgdata::SGData;
+
+use crate::config;
+use crate::metrics;
+
+#[derive(Debug, Default)]
+struct Timing {
+    backend: Cell<Duration>,
+    backend_ops: Cell<u32>,
+    queue: Cell<Duration>,
+}
+
+thread_local! {
+    /// Timing of the request being handled on this thread right now
+    static CURRENT: RefCell<Option<Rc<Timing>>> = RefCell::new(None);
+}
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    method: &'a str,
+    endpoint: &'a str,
+    /// Object path the request works with
+    path: Option<&'a str>,
+    size: Option<u64>,
+    status: Option<u16>,
+    total_ms: f64,
+    backend_ms: f64,
+    queue_ms: f64,
+    handler_ms: f64,
+    backend_ops: u32,
+}
+
+/// Accounts duration of storage `operation` `f` as storage time of the current request.
+pub fn backend_time<T>(operation: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    add_backend_time(operation, start.elapsed());
+
+    result
+}
+
+/// Accounts a storage operation which ran elsewhere (e.g. on the blocking thread pool) to the current request.
+pub fn add_backend_time(operation: &'static str, elapsed: Duration) {
+    metrics::observe(operation, elapsed);
+
+    CURRENT.with(|current| {
+        if let Some(timing) = &*current.borrow() {
+            timing.backend.set(timing.backend.get() + elapsed);
+            timing.backend_ops.set(timing.backend_ops.get() + 1);
+        }
+    });
+}
+
+/// Accounts duration of `f` as time the current request waited for a backend thread.
+pub fn queue_time<T>(f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    add_queue_time(start.elapsed());
+
+    result
+}
+
+/// Same as `queue_time`, for waiting which lets other requests of the worker run meanwhile.
+pub async fn queue_wait<F: Future>(f: F) -> F::Output {
+    let start = Instant::now();
+    let result = f.await;
+    // polled as part of the waiting request again, so it's current
+    add_queue_time(start.elapsed());
+
+    result
+}
+
+fn add_queue_time(elapsed: Duration) {
+    CURRENT.with(|current| {
+        if let Some(timing) = &*current.borrow() {
+            timing.queue.set(timing.queue.get() + elapsed);
+        }
+    });
+}
+
+/// Makes `timing` current while the inner future is polled.
+struct Scoped<F> {
+    timing: Rc<Timing>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let previous = CURRENT.with(|current| current.replace(Some(Rc::clone(&self.timing))));
+        let result = self.inner.as_mut().poll(cx);
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+
+        result
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn set_header(headers: &mut HeaderMap, name: &'static str, value: String) {
+    if let Ok(value) = HeaderValue::from_str(&value) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
+
+/// Object path the request works with - from its header (writes) or query (reads).
+pub fn object_path(req: &ServiceRequest) -> Option<String> {
+    req.headers()
+        .get("path")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| {
+            url::form_urlencoded::parse(req.query_string().as_bytes())
+                .find(|(k, _)| k == "path")
+                .map(|(_, v)| v.into_owned())
+        })
+}
+
+/// Middleware measuring the requests.
+pub fn middleware<S, B>(req: ServiceRequest, srv: &mut S) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody,
+{
+    let config = &config::get().slow_log;
+
+    let sampled = match config.sample_every {
+        Some(n) if n > 0 => REQUESTS.fetch_add(1, Ordering::Relaxed) % n == 0,
+        _ => false,
+    };
+
+    let threshold = config.threshold_ms.map(Duration::from_millis);
+
+    let method = req.method().to_string();
+    let endpoint = req.path().to_string();
+    let path = object_path(&req);
+    let size: Option<u64> = req
+        .headers()
+        .get("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    let timing = Rc::new(Timing::default());
+    let start = Instant::now();
+
+    let response = Scoped {
+        timing: Rc::clone(&timing),
+        inner: Box::pin(srv.call(req)),
+    };
+
+    Box::pin(async move {
+        let mut result = response.await;
+        let total = start.elapsed();
+
+        if let Ok(res) = &mut result {
+            let sent = match res.response().body().size() {
+                BodySize::None | BodySize::Empty => Some(0),
+                BodySize::Sized(len) => Some(len as u64),
+                BodySize::Stream => None,
+            };
+
+            let server_timing = ServerTiming {
+                total_ms: ms(total),
+                queue_ms: ms(timing.queue.get()),
+                backend_ms: ms(timing.backend.get()),
+            };
+            let bytes = BodyBytes {
+                received: size.unwrap_or(0),
+                sent,
+            };
+
+            set_header(res.headers_mut(), SERVER_TIMING_HEADER, server_timing.to_header());
+            set_header(res.headers_mut(), BODY_BYTES_HEADER, bytes.to_header());
+        }
+
+        let slow = threshold.map(|t| total >= t).unwrap_or(false);
+
+        if slow || sampled {
+            let entry = Entry {
+                method: &method,
+                endpoint: &endpoint,
+                path: path.as_deref(),
+                size,
+                status: result.as_ref().ok().map(|r| r.status().as_u16()),
+                total_ms: ms(total),
+                backend_ms: ms(timing.backend.get()),
+                queue_ms: ms(timing.queue.get()),
+                handler_ms: ms(total.saturating_sub(timing.backend.get() + timing.queue.get())),
+                backend_ops: timing.backend_ops.get(),
+            };
+
+            let entry = serde_json::to_string(&entry).unwrap_or_default();
+
+            if slow {
+                warn!(target: "slowlog", "{}", entry);
+            } else {
+                info!(target: "trace", "{}", entry);
+            }
+        }
+
+        result
+    })
+}
+
+/// Backend thread accounting all its operations as storage time.
+pub struct TimedThread {
+    inner: Box<dyn BackendThread>,
+}
+
+impl TimedThread {
+    pub fn new(inner: Box<dyn BackendThread>) -> TimedThread {
+        TimedThread { inner }
+    }
+}
+
+impl BackendThread for TimedThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        backend_time("remove_dir_all", || self.inner.remove_dir_all(path))
+    }
+
+    fn rename(&mut self, src_path: PathBuf, dst_path: PathBuf) -> io::Result<()> {
+        backend_time("rename", || self.inner.rename(src_path, dst_path))
+    }
+
+    fn write(&mut self, path: PathBuf, sg: SGData, idempotent: bool) -> io::Result<()> {
+        backend_time("write", || self.inner.write(path, sg, idempotent))
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        backend_time("read", || self.inner.read(path))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        backend_time("remove", || self.inner.remove(path))
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        backend_time("read_metadata", || self.inner.read_metadata(path))
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        backend_time("list", || self.inner.list(path))
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: Sender<io::Result<Vec<PathBuf>>>) {
+        backend_time("list_recursively", || self.inner.list_recursively(path, tx))
+    }
+}
@@ -0,0 +1,91 @@
+//! Standalone mode: the server backs up local directories into the repository it serves, so a single box (e.g. a NAS)
+//! needs no separate client. The backups go through the server's own HTTP API like those of any other client - locks,
+//! tokens and signing apply the same way, and the data can be restored remotely by the regular client.
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use err_context::AnyError;
+use log::*;
+use rbackup2_client::api::Client;
+use rbackup2_client::runs;
+use url::Url;
+
+use crate::config::{self, Standalone, StandaloneBackup};
+
+/// Backups interrupted by a restart are resumed this long after the start, once the server is listening.
+const RESUME_DELAY: Duration = Duration::from_secs(5);
+
+/// Starts running the configured backups every `interval_secs`, storing them through the server listening at `addr`.
+pub fn schedule(standalone: &'static Standalone, addr: SocketAddr) {
+    let interval = Duration::from_secs(standalone.interval_secs);
+
+    info!("Running {} standalone backup(s) every {:?}", standalone.backups.len(), interval);
+
+    let mut delay = if interrupted(standalone, addr) {
+        info!("Resuming standalone backups interrupted by the restart");
+        RESUME_DELAY
+    } else {
+        interval
+    };
+
+    thread::spawn(move || loop {
+        // the server isn't listening yet at the start
+        thread::sleep(delay);
+        delay = interval;
+
+        let client = match open(standalone, addr) {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Could not open the repository for standalone backups: {}", e);
+                continue;
+            }
+        };
+
+        for backup in &standalone.backups {
+            run(&client, standalone, backup);
+        }
+    });
+}
+
+fn url(addr: SocketAddr) -> Result<Url, AnyError> {
+    Ok(Url::parse(&format!("http://{}", addr))?)
+}
+
+/// Whether a run of the backups was interrupted; the stores clean up after it themselves when run again.
+fn interrupted(standalone: &Standalone, addr: SocketAddr) -> bool {
+    let state_dir = match &standalone.state_dir {
+        Some(state_dir) => state_dir,
+        None => return false,
+    };
+
+    match url(addr).and_then(|url| Ok(runs::interrupted(state_dir, &url)?)) {
+        Ok(runs) => !runs.is_empty(),
+        Err(e) => {
+            warn!("Could not look for interrupted standalone backups: {}", e);
+            false
+        }
+    }
+}
+
+fn open(standalone: &Standalone, addr: SocketAddr) -> Result<Client, AnyError> {
+    let (token, signing_key) = (standalone.token.clone(), config::get().signing_key.clone());
+
+    match &standalone.state_dir {
+        Some(state_dir) => Client::open_cached(url(addr)?, token, signing_key, state_dir, None),
+        None => Client::open(url(addr)?, token, signing_key),
+    }
+}
+
+fn run(client: &Client, standalone: &Standalone, backup: &StandaloneBackup) {
+    info!("Backing up {:?} as {}", backup.source, backup.name);
+
+    match client.store(&backup.source, &backup.name, None, &|| Ok(standalone.passphrase.clone())) {
+        Ok(result) => info!(
+            "Stored {} ({}B, {}B new) in {}ms",
+            result.name, result.source_bytes, result.new_bytes, result.duration_ms
+        ),
+        Err(e) => warn!("Backup of {:?} as {} failed: {}", backup.source, backup.name, e),
+    }
+}
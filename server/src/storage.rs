@@ -0,0 +1,441 @@
+use std::alloc::{alloc_zeroed, dealloc, Layout as AllocLayout};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+
+use libcommon::layout::Layout;
+use log::*;
+use once_cell::sync::OnceCell;
+use rdedup_lib::backends::{Backend, BackendThread, Lock, Metadata};
+use sgdata::SGData;
+use uuid::Uuid;
+
+use crate::backend_pool;
+use crate::config;
+use crate::config::{FsyncPolicy, WritePolicy};
+
+/// Default staging directory, relative to the data directory.
+pub const TEMP_DIR: &str = ".tmp";
+
+/// Entries `list_page` reads at once
+const LIST_PAGE_LEN: usize = 1000;
+
+/// `O_DIRECT` requires buffers, offsets and lengths aligned to the logical block size of the device.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// Layout of the served repository, detected by the self-test.
+static LAYOUT: OnceCell<Layout> = OnceCell::new();
+
+/// Layout the served repository is stored in. Clients translate their paths to it themselves (see `Layout`), the
+/// server's own rdedup operations go through `LayoutBackend`.
+pub fn layout() -> Layout {
+    LAYOUT.get().copied().unwrap_or(Layout::CURRENT)
+}
+
+pub fn set_layout(layout: Layout) {
+    info!("Serving repository in layout version {}", layout.version());
+    let _ = LAYOUT.set(layout);
+}
+
+pub fn temp_dir() -> PathBuf {
+    match &config::get().storage.temp_dir {
+        Some(dir) => dir.clone(),
+        None => backend_pool::data_dir().join(TEMP_DIR),
+    }
+}
+
+/// New unique path in the temp dir to stage an object at.
+pub fn staging_path() -> io::Result<PathBuf> {
+    let temp_dir = temp_dir();
+    fs::create_dir_all(&temp_dir)?;
+
+    Ok(temp_dir.join(Uuid::new_v4().to_string()))
+}
+
+/// Opens directory `path` (relative to the data directory) to be listed by `list_page` - directories of chunks may be
+/// too large to collect all the entries at once, as `BackendThread::list` does.
+pub fn open_dir(path: &Path) -> io::Result<fs::ReadDir> {
+    fs::read_dir(backend_pool::data_dir().join(path))
+}
+
+/// Names of the next entries of `dir`; empty once all of them are listed.
+pub fn list_page(dir: &mut fs::ReadDir) -> io::Result<Vec<PathBuf>> {
+    dir.take(LIST_PAGE_LEN).map(|entry| Ok(PathBuf::from(entry?.file_name()))).collect()
+}
+
+/// Writes object at `path` (relative to the data directory) atomically - staged in the temp dir, then moved into place.
+pub fn write(path: &Path, data: &[u8], policy: WritePolicy) -> io::Result<()> {
+    let temp = staging_path()?;
+
+    trace!("Writing {:?} through {:?} with {:?}", path, temp, policy);
+
+    match write_file(&temp, data, policy) {
+        Ok(()) => place(&temp, path, policy),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+/// Moves object staged (and synced as `policy` requires) at `temp` to `path` (relative to the data directory); the
+/// staged file is removed when that fails.
+pub fn place(temp: &Path, path: &Path, policy: WritePolicy) -> io::Result<()> {
+    let dest = backend_pool::data_dir().join(path);
+
+    let result = (|| {
+        let parent = dest.parent().expect("Object path without parent");
+        fs::create_dir_all(parent)?;
+        fs::rename(temp, &dest)?;
+
+        if policy.fsync != FsyncPolicy::Never {
+            // persist the rename itself
+            File::open(parent)?.sync_all()?;
+        }
+
+        Ok(())
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(temp);
+    }
+
+    result
+}
+
+fn write_file(path: &Path, data: &[u8], policy: WritePolicy) -> io::Result<()> {
+    let mut flags = 0;
+    if policy.direct_io {
+        flags |= libc::O_DIRECT;
+    }
+    if policy.fsync == FsyncPolicy::Always {
+        flags |= libc::O_DSYNC;
+    }
+
+    let mut file = OpenOptions::new().write(true).create_new(true).custom_flags(flags).open(path)?;
+
+    if policy.direct_io {
+        write_direct(&mut file, data)?;
+    } else {
+        file.write_all(data)?;
+    }
+
+    if policy.fsync == FsyncPolicy::OnClose {
+        file.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn write_direct(file: &mut File, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    // the padding is cut off afterwards
+    let padded_len = (data.len() + DIRECT_IO_ALIGNMENT - 1) / DIRECT_IO_ALIGNMENT * DIRECT_IO_ALIGNMENT;
+
+    let mut buffer = AlignedBuffer::new(padded_len);
+    buffer[..data.len()].copy_from_slice(data);
+
+    file.write_all(&buffer)?;
+    file.set_len(data.len() as u64)
+}
+
+/// Zeroed heap buffer aligned for direct I/O.
+struct AlignedBuffer {
+    ptr: *mut u8,
+    layout: AllocLayout,
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> AlignedBuffer {
+        assert!(len > 0, "Empty aligned buffer");
+
+        let layout = AllocLayout::from_size_align(len, DIRECT_IO_ALIGNMENT).expect("Invalid buffer layout");
+        // SAFETY: the layout has non-zero size
+        let ptr = unsafe { alloc_zeroed(layout) };
+        assert!(!ptr.is_null(), "Could not allocate aligned buffer");
+
+        AlignedBuffer { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: the pointer is valid for `layout.size()` initialized bytes
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: the pointer is valid for `layout.size()` initialized bytes and borrowed exclusively
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: allocated with this very layout
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+/// Backend wrapper translating rdedup paths of the server's own operations (e.g. GC) to the storage of a repository in
+/// `layout`, so one server build serves repositories of all the layouts.
+pub struct LayoutBackend<B: Backend> {
+    inner: B,
+    layout: Layout,
+}
+
+impl<B: Backend> LayoutBackend<B> {
+    pub fn new(inner: B, layout: Layout) -> LayoutBackend<B> {
+        LayoutBackend { inner, layout }
+    }
+}
+
+impl<B: Backend> Backend for LayoutBackend<B> {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared()
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(LayoutThread {
+            inner: self.inner.new_thread()?,
+            layout: self.layout,
+        }))
+    }
+}
+
+struct LayoutThread {
+    inner: Box<dyn BackendThread>,
+    layout: Layout,
+}
+
+impl LayoutThread {
+    fn to_storage(&self, path: &Path) -> PathBuf {
+        self.layout.to_storage(path)
+    }
+
+    fn from_storage(&self, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        paths.iter().map(|path| self.layout.from_storage(path)).collect()
+    }
+}
+
+impl BackendThread for LayoutThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        self.inner.remove_dir_all(self.to_storage(&path))
+    }
+
+    fn rename(&mut self, src_path: PathBuf, dst_path: PathBuf) -> io::Result<()> {
+        let (src_path, dst_path) = (self.to_storage(&src_path), self.to_storage(&dst_path));
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(&mut self, path: PathBuf, sg: SGData, idempotent: bool) -> io::Result<()> {
+        self.inner.write(self.to_storage(&path), sg, idempotent)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        self.inner.read(self.to_storage(&path))
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.inner.remove(self.to_storage(&path))
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        self.inner.read_metadata(self.to_storage(&path))
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        let paths = self.inner.list(self.to_storage(&path))?;
+        Ok(self.from_storage(paths))
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: Sender<io::Result<Vec<PathBuf>>>) {
+        let (inner_tx, inner_rx) = mpsc::channel();
+        self.inner.list_recursively(self.to_storage(&path), inner_tx);
+
+        // ends once the inner listing drops its sender
+        for paths in inner_rx {
+            if tx.send(paths.map(|paths| self.from_storage(paths))).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libcommon::paths;
+    use rdedup_lib::backends::local::Local;
+
+    use super::*;
+    use crate::selftest;
+
+    const CHUNK: &str = "ab12cd34ef56ab12cd34ef56ab12cd34";
+    const INDEX: &str = "12ab34cd56ef12ab34cd56ef12ab34cd";
+
+    const VERSIONS: &[Layout] = &[Layout::V1, Layout::V2];
+
+    /// Repository in `layout`, holding a chunk, an index, a name and the config.
+    fn fixture(layout: Layout) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/layouts")
+            .join(format!("v{}", layout.version()))
+    }
+
+    fn rdedup_chunk() -> PathBuf {
+        Path::new("chunk/ab/12").join(CHUNK)
+    }
+
+    fn rdedup_index() -> PathBuf {
+        Path::new("index/12/ab").join(INDEX)
+    }
+
+    /// Copy of a directory the test may modify, removed once dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn empty() -> Scratch {
+            let dir = std::env::temp_dir().join(format!("rbackup2-layouts-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        fn of(source: &Path) -> Scratch {
+            let scratch = Scratch::empty();
+            copy_dir(source, &scratch.0);
+            scratch
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+
+            if entry.file_type().unwrap().is_dir() {
+                fs::create_dir_all(&target).unwrap();
+                copy_dir(&entry.path(), &target);
+            } else {
+                fs::copy(entry.path(), &target).unwrap();
+            }
+        }
+    }
+
+    fn thread(dir: &Path, layout: Layout) -> Box<dyn BackendThread> {
+        LayoutBackend::new(Local::new(dir.to_path_buf()), layout).new_thread().unwrap()
+    }
+
+    fn read(thread: &mut Box<dyn BackendThread>, path: &Path) -> String {
+        String::from_utf8(thread.read(path.to_path_buf()).unwrap().to_linear_vec()).unwrap()
+    }
+
+    #[test]
+    fn detects_layout_of_each_version() {
+        for layout in VERSIONS {
+            assert_eq!(selftest::detect_layout(&fixture(*layout), false).unwrap(), *layout);
+        }
+    }
+
+    #[test]
+    fn marks_new_repository_with_current_layout() {
+        let scratch = Scratch::empty();
+
+        assert_eq!(selftest::detect_layout(&scratch.0, true).unwrap(), Layout::CURRENT);
+        assert_eq!(
+            fs::read_to_string(scratch.0.join(selftest::LAYOUT_MARKER)).unwrap(),
+            Layout::CURRENT.version().to_string()
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_layout_version() {
+        let scratch = Scratch::empty();
+        fs::write(scratch.0.join(selftest::LAYOUT_MARKER), "99").unwrap();
+
+        assert!(selftest::detect_layout(&scratch.0, false).is_err());
+    }
+
+    #[test]
+    fn reads_objects_by_rdedup_paths() {
+        for layout in VERSIONS {
+            let mut thread = thread(&fixture(*layout), *layout);
+
+            assert_eq!(read(&mut thread, &rdedup_chunk()), "fixture chunk\n", "{:?}", layout);
+            assert_eq!(read(&mut thread, &rdedup_index()), "fixture index\n", "{:?}", layout);
+            assert_eq!(read(&mut thread, Path::new("name/backup")), "fixture name\n", "{:?}", layout);
+            assert_eq!(read(&mut thread, Path::new("config.yml")), "fixture config\n", "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn writes_objects_where_the_layout_puts_them() {
+        let digest = "cd34ab12ef56cd34ab12ef56cd34ab12";
+        let rdedup_path = Path::new("chunk/cd/34").join(digest);
+
+        for (layout, storage_path) in &[
+            (Layout::V1, Path::new("chunk/cd/34").join(digest)),
+            (Layout::V2, Path::new("chunk/cd").join(digest)),
+        ] {
+            let scratch = Scratch::of(&fixture(*layout));
+            let mut thread = thread(&scratch.0, *layout);
+
+            thread
+                .write(rdedup_path.clone(), SGData::from_single(b"new chunk".to_vec()), true)
+                .unwrap();
+
+            assert_eq!(fs::read(scratch.0.join(storage_path)).unwrap(), b"new chunk", "{:?}", layout);
+            assert_eq!(read(&mut thread, &rdedup_path), "new chunk", "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn lists_objects_by_rdedup_paths() {
+        for layout in VERSIONS {
+            let mut thread = thread(&fixture(*layout), *layout);
+            let (tx, rx) = mpsc::channel();
+            thread.list_recursively(PathBuf::from("chunk"), tx);
+
+            let listed: Vec<PathBuf> = rx.into_iter().flat_map(|paths| paths.unwrap()).collect();
+
+            assert_eq!(listed.len(), 1, "{:?}: {:?}", layout, listed);
+            assert!(listed[0].ends_with(rdedup_chunk()), "{:?}: {:?}", layout, listed);
+            assert_eq!(paths::path_digest(&listed[0]), Some(CHUNK));
+        }
+    }
+
+    #[test]
+    fn translation_round_trips() {
+        // generation directories may precede the role directory
+        let addressed = [rdedup_chunk(), rdedup_index(), Path::new("gen1").join(rdedup_chunk())];
+
+        for layout in VERSIONS {
+            for path in &addressed {
+                assert_eq!(layout.from_storage(&layout.to_storage(path)), *path, "{:?}", layout);
+            }
+            // names and the config are never translated
+            assert_eq!(layout.to_storage(Path::new("name/backup")), Path::new("name/backup"));
+        }
+    }
+}
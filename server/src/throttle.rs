@@ -0,0 +1,152 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libcommon::structs::ThrottleLimits;
+use log::*;
+use once_cell::sync::Lazy;
+use rdedup_lib::backends::{Backend, BackendThread, Lock, Metadata};
+use sgdata::SGData;
+
+use crate::config;
+use crate::read_buffers;
+
+/// Current limits of background jobs; initialized from config, adjustable through the admin API.
+static LIMITS: Lazy<RwLock<ThrottleLimits>> = Lazy::new(|| RwLock::new(config::get().background_io));
+
+/// Time when the next background operation may start; shared by all background jobs.
+static NEXT_FREE: Lazy<Mutex<Instant>> = Lazy::new(|| Mutex::new(Instant::now()));
+
+/// Longest time an operation of a background job waits for interactive reads to finish, so a long restore doesn't
+/// stop the jobs for good
+const MAX_YIELD: Duration = Duration::from_secs(1);
+
+const YIELD_STEP: Duration = Duration::from_millis(10);
+
+pub fn limits() -> ThrottleLimits {
+    *LIMITS.read().unwrap()
+}
+
+pub fn set_limits(limits: ThrottleLimits) {
+    info!("Setting background I/O limits to {:?}", limits);
+    *LIMITS.write().unwrap() = limits;
+}
+
+/// Waits until an operation transferring `bytes` fits into the limits (ionice-like pacing). High priority reads in
+/// progress (restores, see `read_buffers::interactive_reads`) go first.
+pub fn pace(bytes: usize) {
+    yield_to_interactive();
+
+    let limits = limits();
+
+    // non-positive limits make no sense, they're treated as no limit
+    let op_cost = limits.max_ops_per_sec.filter(|ops| *ops > 0.0).map(|ops| 1.0 / ops).unwrap_or(0.0);
+    let bytes_cost = limits
+        .max_mb_per_sec
+        .filter(|mbs| *mbs > 0.0)
+        .map(|mbs| bytes as f64 / (mbs * 1_000_000.0))
+        .unwrap_or(0.0);
+    let cost = Duration::from_secs_f64(op_cost.max(bytes_cost));
+
+    if cost == Duration::from_secs(0) {
+        return;
+    }
+
+    let start = {
+        let mut next_free = NEXT_FREE.lock().unwrap();
+        let start = (*next_free).max(Instant::now());
+        *next_free = start + cost;
+        start
+    };
+
+    let now = Instant::now();
+    if start > now {
+        thread::sleep(start - now);
+    }
+}
+
+fn yield_to_interactive() {
+    let start = Instant::now();
+
+    while read_buffers::interactive_reads() > 0 && start.elapsed() < MAX_YIELD {
+        thread::sleep(YIELD_STEP);
+    }
+}
+
+/// Backend wrapper pacing all operations of a background job.
+pub struct ThrottledBackend<B: Backend> {
+    inner: B,
+}
+
+impl<B: Backend> ThrottledBackend<B> {
+    pub fn new(inner: B) -> ThrottledBackend<B> {
+        ThrottledBackend { inner }
+    }
+}
+
+impl<B: Backend> Backend for ThrottledBackend<B> {
+    fn lock_exclusive(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_exclusive()
+    }
+
+    fn lock_shared(&self) -> io::Result<Box<dyn Lock>> {
+        self.inner.lock_shared()
+    }
+
+    fn new_thread(&self) -> io::Result<Box<dyn BackendThread>> {
+        Ok(Box::new(ThrottledThread {
+            inner: self.inner.new_thread()?,
+        }))
+    }
+}
+
+struct ThrottledThread {
+    inner: Box<dyn BackendThread>,
+}
+
+impl BackendThread for ThrottledThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        pace(0);
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(&mut self, src_path: PathBuf, dst_path: PathBuf) -> io::Result<()> {
+        pace(0);
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(&mut self, path: PathBuf, sg: SGData, idempotent: bool) -> io::Result<()> {
+        pace(sg.len());
+        self.inner.write(path, sg, idempotent)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        // the size is known only afterwards, the pacing delays the next operation then
+        let data = self.inner.read(path)?;
+        pace(data.len());
+        Ok(data)
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        pace(0);
+        self.inner.remove(path)
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        pace(0);
+        self.inner.read_metadata(path)
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        pace(0);
+        self.inner.list(path)
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: Sender<io::Result<Vec<PathBuf>>>) {
+        pace(0);
+        self.inner.list_recursively(path, tx)
+    }
+}
@@ -0,0 +1,362 @@
+//! Storage tiering - chunks not referenced by recent generations are migrated to a cheaper secondary storage (the cold
+//! tier), leaving a small stub in their place.
+//!
+//! rdedup moves every chunk referenced by newly stored or collected data into the current generation, so chunks still
+//! sitting in older generations are referenced just by older names. Those older than `min_age_secs` are copied into
+//! `cold_dir` (e.g. a mounted object storage) and replaced by a stub; reads of a stub transparently fetch the chunk from
+//! the cold tier (see [`TieredThread`]). Stubs are moved around by GC like any other object; cold copies of chunks whose
+//! stubs are gone are removed by the next migration pass.
+//!
+//! Plain rdedup processes can't read stubbed chunks, the repository is served by this server only once tiering is
+//! enabled.
+
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use err_context::AnyError;
+use libcommon::paths::{self, ObjectType, NAMES_DIR, PENDING_DIR};
+use log::*;
+use rdedup_lib::backends::{BackendThread, Metadata};
+use serde::{Deserialize, Serialize};
+use sgdata::SGData;
+
+use crate::backend_pool;
+use crate::config;
+use crate::config::Tiering;
+use crate::locks;
+use crate::storage;
+use crate::throttle;
+
+const STUB_MAGIC: &[u8] = b"rbackup2-cold-stub\n";
+/// Anything larger is surely a chunk
+const MAX_STUB_LEN: u64 = 256;
+
+const CHUNK_DIR: &str = "chunk";
+
+/// A migration pass is running
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Stub {
+    /// Name of the chunk in the cold tier
+    key: String,
+    len: u64,
+}
+
+fn config() -> Option<&'static Tiering> {
+    config::get().tiering.as_ref()
+}
+
+fn parse_stub(data: &[u8]) -> Option<Stub> {
+    if data.len() as u64 > MAX_STUB_LEN {
+        return None;
+    }
+
+    serde_json::from_slice(data.strip_prefix(STUB_MAGIC)?).ok()
+}
+
+fn cold_path(cold_dir: &Path, key: &str) -> PathBuf {
+    cold_dir.join(&key[..2]).join(key)
+}
+
+/// Stub of object at `path` (relative to the data directory), if it's been migrated.
+fn read_stub(path: &Path) -> io::Result<Option<Stub>> {
+    let mut file = File::open(backend_pool::data_dir().join(path))?;
+
+    if file.metadata()?.len() > MAX_STUB_LEN {
+        return Ok(None);
+    }
+
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    Ok(parse_stub(&data))
+}
+
+/// Whether an object of `len` bytes may be a stub of a migrated chunk, to be read through a backend thread.
+pub fn may_be_stub(len: u64) -> bool {
+    config().is_some() && len <= MAX_STUB_LEN
+}
+
+/// Size of the chunk at `path` when it's in the cold tier.
+pub fn cold_len(path: &Path) -> io::Result<Option<u64>> {
+    if config().is_none() || ObjectType::of(path) != ObjectType::Chunk {
+        return Ok(None);
+    }
+
+    Ok(read_stub(path)?.map(|stub| stub.len))
+}
+
+fn recall(stub: &Stub) -> io::Result<SGData> {
+    let tiering = config().ok_or_else(|| io::Error::new(ErrorKind::Other, "Chunk is in the cold tier, but tiering is disabled"))?;
+
+    trace!("Reading {} from the cold tier", stub.key);
+
+    Ok(SGData::from_single(fs::read(cold_path(&tiering.cold_dir, &stub.key))?))
+}
+
+/// Backend thread reading migrated chunks from the cold tier.
+pub struct TieredThread {
+    inner: Box<dyn BackendThread>,
+}
+
+impl TieredThread {
+    pub fn new(inner: Box<dyn BackendThread>) -> TieredThread {
+        TieredThread { inner }
+    }
+}
+
+impl BackendThread for TieredThread {
+    fn remove_dir_all(&mut self, path: PathBuf) -> io::Result<()> {
+        self.inner.remove_dir_all(path)
+    }
+
+    fn rename(&mut self, src_path: PathBuf, dst_path: PathBuf) -> io::Result<()> {
+        self.inner.rename(src_path, dst_path)
+    }
+
+    fn write(&mut self, path: PathBuf, sg: SGData, idempotent: bool) -> io::Result<()> {
+        self.inner.write(path, sg, idempotent)
+    }
+
+    fn read(&mut self, path: PathBuf) -> io::Result<SGData> {
+        let data = self.inner.read(path)?;
+
+        if data.len() as u64 > MAX_STUB_LEN {
+            return Ok(data);
+        }
+
+        match parse_stub(&data.to_linear_vec()) {
+            Some(stub) => recall(&stub),
+            None => Ok(data),
+        }
+    }
+
+    fn remove(&mut self, path: PathBuf) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn read_metadata(&mut self, path: PathBuf) -> io::Result<Metadata> {
+        let metadata = self.inner.read_metadata(path.clone())?;
+
+        if !metadata.is_file || metadata.len > MAX_STUB_LEN {
+            return Ok(metadata);
+        }
+
+        // the size of the chunk, not of its stub
+        match cold_len(&path)? {
+            Some(len) => Ok(Metadata { len, ..metadata }),
+            None => Ok(metadata),
+        }
+    }
+
+    fn list(&mut self, path: PathBuf) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(path)
+    }
+
+    fn list_recursively(&mut self, path: PathBuf, tx: Sender<io::Result<Vec<PathBuf>>>) {
+        self.inner.list_recursively(path, tx)
+    }
+}
+
+/// Generation directories of the repository, oldest first; empty for repositories without generations.
+fn generations(data_dir: &Path) -> io::Result<Vec<String>> {
+    let mut generations = Vec::new();
+
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        if entry.file_type()?.is_dir() && data_dir.join(&name).join(CHUNK_DIR).is_dir() && !name.starts_with('.') {
+            generations.push(name);
+        }
+    }
+
+    generations.retain(|g| g != NAMES_DIR && g != PENDING_DIR);
+    generations.sort();
+
+    Ok(generations)
+}
+
+/// Chunk files below `dir`, relative to the data directory.
+fn chunk_files(data_dir: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(data_dir.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            chunk_files(data_dir, &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct PassStats {
+    migrated: u64,
+    migrated_bytes: u64,
+    removed: u64,
+}
+
+fn migrate_chunk(tiering: &Tiering, path: &Path) -> io::Result<Option<u64>> {
+    let key = match paths::path_digest(path) {
+        Some(digest) => digest.to_string(),
+        None => return Ok(None),
+    };
+
+    let source = backend_pool::data_dir().join(path);
+    let metadata = fs::metadata(&source)?;
+
+    let age = metadata.modified()?.elapsed().unwrap_or_default();
+    if age < Duration::from_secs(tiering.min_age_secs) || metadata.len() <= MAX_STUB_LEN {
+        return Ok(None);
+    }
+
+    let data = fs::read(&source)?;
+    throttle::pace(data.len());
+
+    // the cold copy must be complete before the chunk is replaced by the stub
+    let dest = cold_path(&tiering.cold_dir, &key);
+    fs::create_dir_all(dest.parent().expect("Cold path without parent"))?;
+    let temp = dest.with_extension("tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(&temp, &dest)?;
+
+    let stub = Stub {
+        key,
+        len: data.len() as u64,
+    };
+    let mut stub_data = STUB_MAGIC.to_vec();
+    stub_data.extend(serde_json::to_vec(&stub)?);
+
+    storage::write(path, &stub_data, config::get().storage.policy_for(ObjectType::Chunk))?;
+
+    Ok(Some(stub.len))
+}
+
+/// Removes cold copies not referenced by any stub, if they're older than the pass.
+fn remove_orphans(cold_dir: &Path, referenced: &HashSet<String>, pass_start: SystemTime) -> io::Result<u64> {
+    let mut removed = 0;
+
+    for prefix in fs::read_dir(cold_dir)? {
+        for entry in fs::read_dir(prefix?.path())? {
+            let entry = entry?;
+            let key = entry.file_name().to_string_lossy().to_string();
+
+            if referenced.contains(&key) || entry.metadata()?.modified()? >= pass_start {
+                continue;
+            }
+
+            trace!("Removing orphaned cold chunk {}", key);
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+fn run_pass(tiering: &Tiering) -> Result<PassStats, AnyError> {
+    let pass_start = SystemTime::now();
+    let data_dir = backend_pool::data_dir();
+    let generations = generations(data_dir)?;
+    let recent = generations.len().saturating_sub(tiering.keep_generations);
+
+    fs::create_dir_all(&tiering.cold_dir)?;
+
+    let mut stats = PassStats::default();
+    let mut referenced = HashSet::new();
+
+    for (i, generation) in generations.iter().enumerate() {
+        // GC moves chunks between generations, the pass would race with it
+        if let Some(holder) = locks::exclusive() {
+            return Err(AnyError::from(format!("Repository locked exclusively by {}", holder.holder)));
+        }
+
+        let mut files = Vec::new();
+        chunk_files(data_dir, &Path::new(generation).join(CHUNK_DIR), &mut files)?;
+
+        for path in files {
+            let result = read_stub(&path).and_then(|stub| match stub {
+                Some(stub) => {
+                    referenced.insert(stub.key);
+                    Ok(None)
+                }
+                None if i < recent => migrate_chunk(tiering, &path),
+                None => Ok(None),
+            });
+
+            match result {
+                Ok(Some(len)) => {
+                    stats.migrated += 1;
+                    stats.migrated_bytes += len;
+                    if let Some(key) = paths::path_digest(&path) {
+                        referenced.insert(key.to_string());
+                    }
+                }
+                Ok(None) => (),
+                // removed by GC meanwhile
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(AnyError::from(format!("Could not migrate {:?}: {}", path, e))),
+            }
+        }
+    }
+
+    stats.removed = remove_orphans(&tiering.cold_dir, &referenced, pass_start)?;
+
+    Ok(stats)
+}
+
+/// Runs a migration pass in a background thread; fails when tiering is disabled or a pass is already running.
+pub fn start() -> Result<(), AnyError> {
+    let tiering = config().ok_or_else(|| AnyError::from("Tiering is not configured"))?;
+
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err(AnyError::from("Tiering migration is already running"));
+    }
+
+    thread::spawn(move || {
+        info!("Starting tiering migration");
+
+        match run_pass(tiering) {
+            Ok(stats) => info!(
+                "Tiering migration finished: {} chunks ({}B) migrated, {} orphaned cold chunks removed",
+                stats.migrated, stats.migrated_bytes, stats.removed
+            ),
+            Err(e) => warn!("Tiering migration failed: {}", e),
+        }
+
+        RUNNING.store(false, Ordering::SeqCst);
+    });
+
+    Ok(())
+}
+
+/// Starts migration passes every `interval_secs`, when configured.
+pub fn schedule() {
+    let interval = match config().and_then(|t| t.interval_secs) {
+        Some(secs) => Duration::from_secs(secs),
+        None => return,
+    };
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+
+        if let Err(e) = start() {
+            warn!("Could not start scheduled tiering migration: {}", e);
+        }
+    });
+}
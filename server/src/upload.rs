@@ -0,0 +1,133 @@
+//! Bodies of writes, received as they stream in: small ones are buffered in memory, larger ones spooled into the
+//! staging directory, so writes in progress hold bounded memory whatever the size of their objects (see
+//! `BodyLimits::in_memory`). The hash and the signature of the body are calculated on the way.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use actix_web::http::HeaderMap;
+use hmac::{Hmac, Mac, NewMac};
+use log::*;
+use sha2::{Digest, Sha256};
+
+use crate::config;
+use crate::config::{FsyncPolicy, WritePolicy};
+use crate::storage;
+
+enum Body {
+    Memory(Vec<u8>),
+    /// The file stays in the staging directory until the object is stored
+    Spooled(File, PathBuf),
+}
+
+pub struct Upload {
+    body: Body,
+    len: usize,
+    hasher: Sha256,
+    /// Signature being calculated, when the server has a signing key
+    mac: Option<Hmac<Sha256>>,
+}
+
+impl Upload {
+    /// Upload of a write described by `headers`, expected to be `expected_len` bytes long.
+    pub fn new(headers: &HeaderMap, expected_len: usize) -> Upload {
+        // the MAC covers the path, a zero byte and the body, see `SIGNATURE_HEADER`
+        let mac = config::get().signing_key.as_ref().map(|key| {
+            let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).expect("HMAC accepts keys of any length");
+            mac.update(headers.get("path").map(|v| v.as_bytes()).unwrap_or_default());
+            mac.update(&[0]);
+            mac
+        });
+
+        Upload {
+            body: Body::Memory(Vec::with_capacity(expected_len.min(config::get().body_limits.in_memory))),
+            len: 0,
+            hasher: Sha256::new(),
+            mac,
+        }
+    }
+
+    /// Adds next part of the body; spools all of it into the staging directory once it grows over the memory limit.
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        if let Some(mac) = &mut self.mac {
+            mac.update(data);
+        }
+        self.len += data.len();
+
+        if let Body::Memory(buffer) = &mut self.body {
+            if self.len <= config::get().body_limits.in_memory {
+                buffer.extend_from_slice(data);
+                return Ok(());
+            }
+
+            let path = storage::staging_path()?;
+            trace!("Spooling upload into {:?}", path);
+
+            let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+            let buffered = std::mem::take(buffer);
+            // removed on failure by `Drop`
+            self.body = Body::Spooled(file.try_clone()?, path);
+            file.write_all(&buffered)?;
+        }
+
+        match &mut self.body {
+            Body::Spooled(file, _) => file.write_all(data),
+            Body::Memory(_) => unreachable!("Body spooled above"),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Hex SHA-256 of the body.
+    pub fn hash(&self) -> String {
+        hex::encode(self.hasher.clone().finalize())
+    }
+
+    /// Whether `signature` is the HMAC of the body; always false when the server has no signing key.
+    pub fn is_signed_by(&self, signature: &[u8]) -> bool {
+        match &self.mac {
+            Some(mac) => mac.clone().verify(signature).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Stores the body as object at `path` (relative to the data directory).
+    pub fn store(mut self, path: &Path, policy: WritePolicy) -> io::Result<()> {
+        match std::mem::replace(&mut self.body, Body::Memory(Vec::new())) {
+            Body::Memory(data) => storage::write(path, &data, policy),
+            // written through the page cache, direct I/O would need aligned writes of the parts as they come
+            Body::Spooled(file, temp) => {
+                let synced = if policy.fsync != FsyncPolicy::Never {
+                    file.sync_all()
+                } else {
+                    Ok(())
+                };
+
+                match synced {
+                    Ok(()) => storage::place(&temp, path, policy),
+                    Err(e) => {
+                        let _ = fs::remove_file(&temp);
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // not stored - refused or the client disconnected
+        if let Body::Spooled(_, path) = &self.body {
+            if let Err(e) = fs::remove_file(path) {
+                warn!("Could not remove spooled upload {:?}: {}", path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Minimal read-only web UI (`/ui`, built with the `web-ui` feature) - names with their sizes and dates, storage usage,
+//! active locks and recent activity, so people hosting their own server can check on their backups from a browser.
+//!
+//! Pages are rendered on the server, no scripts. Access requires a configured token (the default role isn't enough),
+//! sent as usual or entered into the login form, which keeps it in a cookie. Read-only tokens don't see locks and
+//! activity, same as through the API (see `auth::may_see`).
+
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::http::header::LOCATION;
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse};
+use libcommon::build_info::BuildInfo;
+use log::*;
+use serde::Deserialize;
+
+use crate::auth;
+use crate::backend_pool::{self, Borrowed};
+use crate::catalog;
+use crate::config::Role;
+use crate::handlers;
+use crate::handlers::names;
+use crate::locks;
+use crate::maintenance;
+use crate::storage;
+
+const TOKEN_COOKIE: &str = "rbackup2-token";
+
+/// Number of the latest stored snapshots shown
+const RECENT_ACTIVITY: usize = 20;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}table{border-collapse:collapse;margin-bottom:2em}\
+th,td{padding:.3em .8em;border-bottom:1px solid #ddd;text-align:left}td.num{text-align:right}\
+.notice{background:#fff3cd;padding:.5em 1em}";
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    token: String,
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn format_time(timestamp: u64) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(timestamp)).to_string()
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body>{}</body></html>",
+        escape(title),
+        STYLE,
+        body
+    )
+}
+
+fn html(status: StatusCode, content: String) -> HttpResponse {
+    HttpResponse::build(status).content_type("text/html; charset=utf-8").body(content)
+}
+
+fn login_page(error: Option<&str>) -> String {
+    let error = error.map(|e| format!("<p class=\"notice\">{}</p>", escape(e))).unwrap_or_default();
+
+    page(
+        "rbackup2",
+        &format!(
+            "<h1>rbackup2</h1>{}<form method=\"post\" action=\"/ui/login\">\
+             <label>Token <input type=\"password\" name=\"token\" autofocus></label> <button>Log in</button></form>",
+            error
+        ),
+    )
+}
+
+/// Role of the request's token, sent in the header or kept in the cookie; none for unknown tokens.
+fn request_role(request: &HttpRequest) -> Option<Role> {
+    match auth::token(request.headers()) {
+        Some(token) => auth::token_role(token),
+        None => auth::token_role(request.cookie(TOKEN_COOKIE)?.value()),
+    }
+}
+
+fn render(role: Role, mut backend: Borrowed) -> io::Result<String> {
+    let mut names = names::list(&mut backend)?;
+    let stats = handlers::repo_stats(&mut backend)?;
+    drop(backend);
+
+    let (disk_total, disk_free) = catalog::disk_space()?;
+    let internals = role != Role::ReadOnly;
+
+    // newest first
+    let catalog = catalog::recent(usize::MAX)?;
+    let mut last_stored: HashMap<&str, u64> = HashMap::new();
+    for entry in &catalog {
+        last_stored.entry(&entry.name).or_insert(entry.timestamp);
+    }
+
+    let mut body = String::from("<h1>rbackup2</h1>");
+
+    if let Some(status) = maintenance::status() {
+        body.push_str(&format!("<p class=\"notice\">{}</p>", escape(&status)));
+    }
+
+    body.push_str(&format!(
+        "<p>Server {}, layout version {} &middot; <form method=\"post\" action=\"/ui/logout\" style=\"display:inline\">\
+         <button>Log out</button></form></p>",
+        escape(&BuildInfo::new(env!("CARGO_PKG_VERSION")).long_version()),
+        storage::layout().version()
+    ));
+
+    body.push_str("<h2>Storage</h2><table>");
+    body.push_str(&format!(
+        "<tr><th>Repository</th><td class=\"num\">{} in {} objects</td></tr>",
+        format_size(stats.bytes),
+        stats.objects
+    ));
+    if stats.cold_objects > 0 {
+        body.push_str(&format!(
+            "<tr><th>Cold tier</th><td class=\"num\">{} in {} chunks</td></tr>",
+            format_size(stats.cold_bytes),
+            stats.cold_objects
+        ));
+    }
+    body.push_str(&format!(
+        "<tr><th>Disk</th><td class=\"num\">{} free of {}</td></tr></table>",
+        format_size(disk_free),
+        format_size(disk_total)
+    ));
+
+    names.sort_by(|a, b| a.name.cmp(&b.name));
+
+    body.push_str(&format!("<h2>Names ({})</h2>", names.len()));
+    body.push_str("<table><tr><th>Name</th><th>Size</th><th>Last stored</th><th>Retained until</th></tr>");
+    for name in &names {
+        body.push_str(&format!(
+            "<tr><td>{}</td><td class=\"num\">{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&name.name),
+            format_size(name.size),
+            last_stored
+                .get(name.name.as_str())
+                .map(|t| format_time(*t))
+                .unwrap_or_else(|| "-".to_string()),
+            name.retain_until.map(format_time).unwrap_or_else(|| "-".to_string())
+        ));
+    }
+    body.push_str("</table>");
+
+    if internals {
+        let locks = locks::status();
+
+        body.push_str(&format!("<h2>Locks</h2><p>State: {:?}</p>", locks.state));
+        body.push_str("<table><tr><th>Holder</th><th>Kind</th><th>Since</th></tr>");
+        for (lock, kind) in locks
+            .exclusive
+            .iter()
+            .map(|l| (l, "exclusive"))
+            .chain(locks.shared.iter().map(|l| (l, "shared")))
+        {
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&lock.holder),
+                kind,
+                format_time(lock.since)
+            ));
+        }
+        body.push_str("</table>");
+
+        body.push_str("<h2>Recent activity</h2>");
+        body.push_str("<table><tr><th>Stored</th><th>Name</th><th>Source</th><th>New data</th></tr>");
+        for entry in catalog.iter().take(RECENT_ACTIVITY) {
+            body.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td class=\"num\">{}</td><td class=\"num\">{}</td></tr>",
+                format_time(entry.timestamp),
+                escape(&entry.name),
+                format_size(entry.source_bytes),
+                format_size(entry.new_bytes)
+            ));
+        }
+        body.push_str("</table>");
+    }
+
+    Ok(page("rbackup2", &body))
+}
+
+#[get("/ui")]
+pub async fn index(request: HttpRequest) -> HttpResponse {
+    trace!("web UI");
+
+    let role = match request_role(&request) {
+        Some(role) => role,
+        None => return html(StatusCode::UNAUTHORIZED, login_page(None)),
+    };
+
+    let backend = match backend_pool::pull().await {
+        Some(backend) => backend,
+        None => return backend_pool::unavailable(),
+    };
+
+    match render(role, backend) {
+        Ok(content) => html(StatusCode::OK, content),
+        Err(e) => {
+            warn!("Error while rendering web UI: {}", e);
+            HttpResponse::InternalServerError().body(format!("Error: {:?}", e))
+        }
+    }
+}
+
+#[post("/ui/login")]
+pub async fn login(request: HttpRequest, form: web::Form<LoginForm>) -> HttpResponse {
+    if auth::token_role(&form.token).is_none() {
+        warn!("Web UI login with unknown token");
+        return html(StatusCode::UNAUTHORIZED, login_page(Some("Unknown token")));
+    }
+
+    let cookie = Cookie::build(TOKEN_COOKIE, form.token.clone())
+        .path("/ui")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(request.connection_info().scheme() == "https")
+        .finish();
+
+    HttpResponse::SeeOther().header(LOCATION, "/ui").cookie(cookie).finish()
+}
+
+#[post("/ui/logout")]
+pub async fn logout() -> HttpResponse {
+    let cookie = Cookie::build(TOKEN_COOKIE, "").path("/ui").finish();
+
+    HttpResponse::SeeOther().header(LOCATION, "/ui").del_cookie(&cookie).finish()
+}